@@ -18,16 +18,17 @@ use std::time::Duration;
 
 use criterion::{criterion_group, criterion_main, Criterion};
 
-fn run_query(s: &(tree_sitter::Tree, String), pattern: &str) {
+use weggli::ParsedSource;
+
+fn run_query(s: &ParsedSource, pattern: &str) {
     let tree = weggli::parse(pattern, false);
 
     let mut c = tree.walk();
     c.goto_first_child();
     let qt = weggli::builder::build_query_tree(pattern, &mut c, false, None).unwrap();
 
-    let matches = qt.matches(s.0.root_node(), &s.1);
-    for m in matches {
-        m.display(&s.1, 500, 500, false);
+    for m in s.matches(&qt) {
+        s.display(&m, 500, 500, false);
     }
 }
 
@@ -37,10 +38,7 @@ fn read_file(path: &str) -> String {
 }
 
 fn bench(c: &mut Criterion) {
-    let p = |path| {
-        let source = read_file(path);
-        (weggli::parse(&source, false), source)
-    };
+    let p = |path| ParsedSource::new(read_file(path), false);
 
     let cluster = p("./third_party/examples/cluster.c");
     c.bench_function("cluster.c ", |b| {
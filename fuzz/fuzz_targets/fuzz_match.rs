@@ -0,0 +1,14 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+// The input is a query and a source snippet, separated by the first NUL byte.
+fuzz_target!(|data: &[u8]| {
+    if let Some(split) = data.iter().position(|&b| b == 0) {
+        let (query, source) = (&data[..split], &data[split + 1..]);
+        if let Some(matches) = weggli::fuzz_match(query, source) {
+            for m in matches {
+                let _ = m.display(std::str::from_utf8(source).unwrap(), 1, 1, true);
+            }
+        }
+    }
+});
@@ -3,6 +3,8 @@ SNIPPET = "binaryninja" in globals()
 
 from binaryninja import *
 
+import argparse
+import sys
 import typing
 import weggli
 
@@ -79,6 +81,26 @@ class WeggliPlugin(object):
                 else:
                     log_error(f"Decompilation failed for {target.name}. Skipping..")
 
+    def search_all(
+        self, query: str, cpp: bool = False
+    ) -> typing.Generator[typing.Tuple[Function, str, list], None, None]:
+        """Decompile every function and yield (function, code, results) for
+        each function that matches `query`."""
+        qt = weggli.parse_query(query, cpp)
+        identifiers = weggli.identifiers(qt)
+
+        for f in self.bv.functions:
+            code = self.decompile(f)
+            if code == None:
+                log_error(f"Decompilation failed for {f.name}. Skipping..")
+                continue
+            # Cheap check before parsing the decompiled code.
+            if not all(i in code for i in identifiers):
+                continue
+            results = weggli.matches(qt, code, cpp)
+            if len(results) > 0:
+                yield f, code, results
+
 
 def run_query(bv: BinaryView):
     w = WeggliPlugin(bv)
@@ -87,8 +109,36 @@ def run_query(bv: BinaryView):
     w.run_query(query)
 
 
-if not SNIPPET:
-    PluginCommand.register("weggli", "Run a weggli query", run_query)
-else:
+def main():
+    """Headless mode: search all functions of a binary or database.
+
+    python3 binja_weggli.py [--cpp] <file.bndb> <query>
+    """
+    parser = argparse.ArgumentParser(
+        description="Search the decompiled functions of a binary with weggli."
+    )
+    parser.add_argument("file", help="binary or .bndb database")
+    parser.add_argument("query", help="weggli query")
+    parser.add_argument(
+        "--cpp", action="store_true", help="parse the query and code as C++"
+    )
+    args = parser.parse_args()
+
+    with open_view(args.file) as bv:
+        w = WeggliPlugin(bv)
+        count = 0
+        for f, code, results in w.search_all(args.query, args.cpp):
+            for r in results:
+                print(f"{f.symbol.full_name} @ {hex(f.start)}")
+                print(weggli.display(r, code, sys.stdout.isatty()))
+                count += 1
+        print(f"{count} matches", file=sys.stderr)
+
+
+if SNIPPET:
     # bv is injected into globals in the snippet / python console.
     run_query(bv)
+elif __name__ == "__main__":
+    main()
+else:
+    PluginCommand.register("weggli", "Run a weggli query", run_query)
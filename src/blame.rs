@@ -0,0 +1,146 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Blame information for results (--blame): the last commit that changed the
+//! matched lines, looked up with libgit2.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use git2::{Oid, Repository};
+use serde_json::{json, Value};
+
+#[derive(Clone)]
+struct Commit {
+    id: String,
+    author: String,
+    email: String,
+    // seconds since the epoch
+    time: i64,
+}
+
+struct Hunk {
+    // 1-based line numbers in the current version of the file
+    lines: RangeInclusive<usize>,
+    // None for lines that are not committed yet
+    commit: Option<Commit>,
+}
+
+/// Blame of all lines of a file.
+pub struct FileBlame {
+    hunks: Vec<Hunk>,
+}
+
+impl FileBlame {
+    /// Blame `source`, the current content of `path`. Uncommitted changes are
+    /// taken into account. Returns None if the file isn't tracked by git.
+    pub fn new(path: &Path, source: &str) -> Option<FileBlame> {
+        let path = path.canonicalize().ok()?;
+        let repo = Repository::discover(path.parent()?).ok()?;
+        let workdir = repo.workdir()?.canonicalize().ok()?;
+        let relative = path.strip_prefix(workdir).ok()?;
+
+        let committed = repo.blame_file(relative, None).ok()?;
+        let blame = committed.blame_buffer(source.as_bytes()).ok()?;
+
+        // The signatures of buffer blame hunks aren't always filled in, so the
+        // authors are looked up from the commits instead.
+        let mut commits: HashMap<Oid, Commit> = HashMap::new();
+        let mut hunks = Vec::new();
+        for h in blame.iter().filter(|h| h.lines_in_hunk() > 0) {
+            let start = h.final_start_line();
+            let id = h.final_commit_id();
+            let commit = if id.is_zero() {
+                None
+            } else {
+                if let Entry::Vacant(e) = commits.entry(id) {
+                    let c = repo.find_commit(id).ok()?;
+                    let author = c.author();
+                    e.insert(Commit {
+                        id: id.to_string(),
+                        author: String::from_utf8_lossy(author.name_bytes()).into_owned(),
+                        email: String::from_utf8_lossy(author.email_bytes()).into_owned(),
+                        time: author.when().seconds(),
+                    });
+                }
+                commits.get(&id).cloned()
+            };
+            hunks.push(Hunk {
+                lines: start..=start + h.lines_in_hunk() - 1,
+                commit,
+            });
+        }
+        Some(FileBlame { hunks })
+    }
+
+    /// Returns the most recent change of the 1-based `lines` as a JSON object with
+    /// the commit id, author, email and date. Uncommitted changes are reported
+    /// with a null commit.
+    pub fn last_change(&self, lines: RangeInclusive<usize>) -> Value {
+        let overlapping = self
+            .hunks
+            .iter()
+            .filter(|h| h.lines.start() <= lines.end() && lines.start() <= h.lines.end());
+
+        let mut last: Option<&Commit> = None;
+        for hunk in overlapping {
+            match &hunk.commit {
+                None => return json!({ "commit": null, "uncommitted": true }),
+                Some(c) if last.map_or(true, |l| c.time > l.time) => last = Some(c),
+                Some(_) => (),
+            }
+        }
+        match last {
+            Some(c) => json!({
+                "commit": c.id,
+                "author": c.author,
+                "email": c.email,
+                "date": iso_date(c.time),
+            }),
+            None => Value::Null,
+        }
+    }
+}
+
+/// Formats `seconds` since the epoch as an ISO 8601 date in UTC.
+fn iso_date(seconds: i64) -> String {
+    let days = seconds.div_euclid(86400);
+    let time = seconds.rem_euclid(86400);
+
+    // Convert days since 1970-01-01 to a date in the proleptic Gregorian calendar
+    // (http://howardhinnant.github.io/date_algorithms.html#civil_from_days)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
@@ -14,36 +14,212 @@ See the License for the specific language governing permissions and
 limitations under the License.
 */
 
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand};
 use simplelog::*;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
+use weggli::result::JoinMode;
+use weggli::{Anchor, LengthConstraint, NumberConstraint, VariableConstraint};
+
+use crate::diff::Changes;
+use crate::filter::Filter;
+use crate::output::FileReport;
+use crate::rules::{self, Rule};
+use crate::suppress::Baseline;
+use crate::template::Template;
 
 pub struct Args {
-    pub path: PathBuf,
+    // Directory or file to search, None if only --files-from is used
+    pub path: Option<PathBuf>,
+    // File with a list of files to search, - for STDIN (--files-from)
+    pub files_from: Option<PathBuf>,
     pub pattern: Vec<String>,
-    pub before: usize,
-    pub after: usize,
+    // before/after context for each pattern (same order as `pattern`)
+    pub contexts: Vec<(usize, usize)>,
+    // sibling statements to print around matched statements (--context-statements)
+    pub context_statements: Option<usize>,
+    // Start of the function shown above the matched lines (--function-header)
+    pub function_header: FunctionHeader,
     pub extensions: Vec<String>,
+    // Search files excluded by .gitignore, .ignore and .weggliignore files (--no-ignore)
+    pub no_ignore: bool,
     pub regexes: Vec<String>,
     pub limit: bool,
     pub cpp: bool,
+    pub auto_lang: bool,
+    pub fallback_cpp: bool,
+    // Parse .h files with both grammars (--dual-headers)
+    pub dual_headers: bool,
     pub unique: bool,
-    pub force_color: bool,
+    pub unique_by: Vec<String>,
+    pub raw_values: bool,
     pub force_query: bool,
+    pub no_normalize: bool,
     pub include: Vec<String>,
     pub exclude: Vec<String>,
     pub enable_line_numbers: bool,
+    pub invert_match: bool,
+    pub function_lines: (Option<usize>, Option<usize>),
+    pub complexity: (Option<usize>, Option<usize>),
+    pub sample: Option<usize>,
+    pub dedup_snippets: bool,
+    // Don't print anything and exit on the first result (--quiet)
+    pub quiet: bool,
+    pub list_variables: bool,
+    pub aggregate: Option<String>,
+    // Print results in sections per value of this variable (--group-by)
+    pub group_by: Option<String>,
+    // Print files instead of results (--count, --files-with-matches, --files-without-match)
+    pub file_report: Option<FileReport>,
+    // (index, count) with a 0-based shard index
+    pub shard: Option<(usize, usize)>,
+    // Only search a random subset of the files (--sample-files)
+    pub sample_files: Option<FileSample>,
+    // Files larger than this are skipped (--max-filesize). None with --force-large.
+    pub max_filesize: Option<u64>,
+    // Time limit for parsing a file and for running the queries on it (--timeout-per-file)
+    pub timeout_per_file: Option<Duration>,
+    // (--threads, --parse-threads, --query-threads)
+    pub threads: (Option<usize>, Option<usize>, Option<usize>),
+    pub save_results: Option<PathBuf>,
+    // Write results to this file instead of stdout (--output)
+    pub output: Option<PathBuf>,
+    pub join: HashMap<String, JoinMode>,
+    // Only join results of different patterns in the same function (--same-function)
+    pub same_function: bool,
+    // Report multi-pattern results as soon as they can be joined, keeping at most this
+    // many results that wait for compatible results (--stream-join, --stream-join-limit)
+    pub stream_join: Option<usize>,
+    pub format: Format,
+    // Only print the matched code instead of the enclosing function (--only-matching)
+    pub only_matching: bool,
+    // Print each result by filling in a template (--output-format)
+    pub output_template: Option<Template>,
+    // Add the last commit that changed the matched lines to results (--blame)
+    pub blame: bool,
+    // Query variables printed as columns with --format csv/tsv, filled in once the
+    // patterns are parsed
+    pub columns: Vec<String>,
+    pub string_lengths: Vec<LengthConstraint>,
+    pub num_constraints: Vec<NumberConstraint>,
+    pub variable_constraints: Vec<VariableConstraint>,
+    // Post-match filters on the captured values (--filter)
+    pub filters: Vec<Filter>,
+    // Rules loaded with --rules, in the same order as `pattern`
+    pub rules: Vec<Rule>,
+    // `pattern` is a raw tree-sitter query (--raw-tsquery)
+    pub raw_query: bool,
+    pub rewrite: Option<String>,
+    pub in_place: bool,
+    pub anchor: Anchor,
+    pub metrics: bool,
+    pub explain: bool,
+    pub use_index: Option<PathBuf>,
+    // Directory of the cache of files without matches (--no-match-cache)
+    pub no_match_cache: Option<PathBuf>,
+    pub skip_broken_rules: bool,
+    // Known results to filter out, or to record with --update-baseline (--baseline)
+    pub baseline: Option<Baseline>,
+    // Files and lines changed since the --diff revision
+    pub diff: Option<Changes>,
+    pub changed_lines: bool,
+}
+
+/// Output format for query results.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+    Sarif,
+    // Enclosing function of each result, one JSON object per line
+    Targets,
+    // path:line:col: code, one line per matched line (or statement with --only-matching)
+    Oneline,
+    // One row per result with the path, line and the value of every query variable
+    Csv,
+    Tsv,
+    // GitHub Actions workflow commands that annotate the matched lines
+    Github,
+    // A GitLab Code Quality report, written once the search is done
+    GitlabCodequality,
+}
+
+impl Format {
+    /// Returns true for formats that print one JSON object per line.
+    pub fn is_json_lines(&self) -> bool {
+        matches!(self, Format::Json | Format::Targets)
+    }
+}
+
+/// How the start of the function containing a result is shown (--function-header).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FunctionHeader {
+    // The first line of the function (default)
+    Line,
+    // The whole signature, up to the function body
+    Signature,
+    // Only the name of the function
+    Name,
+    None,
+}
+
+/// When to use colors (--color).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Enable or disable colors for all output. In auto mode, colors are used if stdout
+    /// is a terminal, following the CLICOLOR, CLICOLOR_FORCE and NO_COLOR conventions.
+    pub fn apply(self) {
+        let terminal = virtual_terminal();
+        colored::control::set_override(match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                terminal && colored::control::ShouldColorize::from_env().should_colorize()
+            }
+        });
+    }
+}
+
+/// Windows consoles need to be switched to ANSI mode to display colors.
+/// Returns false if that is not possible.
+#[cfg(target_family = "windows")]
+fn virtual_terminal() -> bool {
+    colored::control::set_virtual_terminal(true).is_ok()
+}
+
+#[cfg(not(target_family = "windows"))]
+fn virtual_terminal() -> bool {
+    true
+}
+
+pub enum Command {
+    Search(Box<Args>),
+    MergeResults(Vec<PathBuf>, HashMap<String, JoinMode>),
+    Repl(PathBuf, bool, Vec<String>),
+    Index(PathBuf, PathBuf, Vec<String>),
+    ImportSemgrep(PathBuf, Option<PathBuf>),
+    Try(String, String, bool),
+    // path, C++ mode, extensions, JSON output
+    Functions(PathBuf, bool, Vec<String>, bool),
 }
 
 /// Parse command arguments and return them inside the Args structure.
 /// The clap crate handles program exit and error messages for invalid arguments.
-pub fn parse_arguments() -> Args {
+pub fn parse_arguments() -> Command {
     let matches = App::new("weggli")
         .version("0.2.4")
         .author("Felix Wilhelm <fwilhelm@google.com>")
         .about(help::ABOUT)
         .setting(clap::AppSettings::ArgRequiredElseHelp)
         .setting(clap::AppSettings::UnifiedHelpMessage)
+        .setting(clap::AppSettings::SubcommandsNegateReqs)
         .template(help::TEMPLATE)
         .help_message("Prints help information.")
         .version_message("Prints version information.")
@@ -51,7 +227,9 @@ pub fn parse_arguments() -> Args {
             Arg::with_name("PATTERN")
                 .help("Search pattern.")
                 .long_help(help::PATTERN)
-                .required(true)
+                // With --rules or --raw-tsquery, the search path is the first positional
+                // argument, which is optional with --files-from.
+                .required_unless("files-from")
                 .index(1),
         )
         .arg(
@@ -67,9 +245,18 @@ pub fn parse_arguments() -> Args {
             Arg::with_name("PATH")
                 .help("A file or directory to search.")
                 .long_help(help::PATH)
-                .required(true)
+                .required_unless_one(&["rules", "raw-tsquery", "files-from"])
+                .conflicts_with_all(&["rules", "raw-tsquery"])
                 .index(2),
         )
+        .arg(
+            Arg::with_name("files-from")
+                .long("files-from")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Search the files listed in FILE (- for STDIN), one per line.")
+                .long_help(help::FILES_FROM),
+        )
         .arg(
             Arg::with_name("v")
                 .long("verbose")
@@ -77,6 +264,24 @@ pub fn parse_arguments() -> Args {
                 .multiple(true)
                 .help("Sets the level of verbosity."),
         )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help("Format of log messages and progress events on stderr.")
+                .long_help(help::LOG_FORMAT),
+        )
+        .arg(
+            Arg::with_name("max-warnings")
+                .long("max-warnings")
+                .takes_value(true)
+                .value_name("N")
+                .validator(is_number)
+                .help("Show at most N distinct warnings.")
+                .long_help(help::MAX_WARNINGS),
+        )
         .arg(
             Arg::with_name("extensions")
                 .long("extensions")
@@ -85,6 +290,13 @@ pub fn parse_arguments() -> Args {
                 .multiple(true)
                 .help("File extensions to include in the search."),
         )
+        .arg(
+            Arg::with_name("no-ignore")
+                .long("no-ignore")
+                .takes_value(false)
+                .help("Don't skip files excluded by .gitignore, .ignore or .weggliignore files.")
+                .long_help(help::NO_IGNORE),
+        )
         .arg(
             Arg::with_name("before")
                 .long("before")
@@ -99,6 +311,52 @@ pub fn parse_arguments() -> Args {
                 .takes_value(true)
                 .help("Lines to print after a match. Default = 5."),
         )
+        .arg(
+            Arg::with_name("whole-function")
+                .long("whole-function")
+                .short("W")
+                .takes_value(false)
+                .help("Print the whole function for each match."),
+        )
+        .arg(
+            Arg::with_name("context-statements")
+                .long("context-statements")
+                .takes_value(true)
+                .value_name("N")
+                .validator(is_number)
+                .conflicts_with("whole-function")
+                .help("Print N statements before and after the matched statements.")
+                .long_help(help::CONTEXT_STATEMENTS),
+        )
+        .arg(
+            Arg::with_name("function-header")
+                .long("function-header")
+                .takes_value(true)
+                .value_name("MODE")
+                .possible_values(&["line", "signature", "name", "none"])
+                .help("How to show the function containing a match (default: line).")
+                .long_help(help::FUNCTION_HEADER),
+        )
+        .arg(
+            Arg::with_name("anchor")
+                .long("anchor")
+                .takes_value(true)
+                .possible_values(&["function", "class", "file", "compound"])
+                .conflicts_with("invert-match")
+                .help("Node that matches are anchored to (default: function).")
+                .long_help(help::ANCHOR),
+        )
+        .arg(
+            Arg::with_name("pattern-context")
+                .long("pattern-context")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(false)
+                .validator(|v| parse_pattern_context(&v).map(|_| ()))
+                .help("Override the context lines for a single pattern.")
+                .long_help(help::PATTERN_CONTEXT),
+        )
         .arg(
             Arg::with_name("limit")
                 .long("limit")
@@ -116,6 +374,26 @@ pub fn parse_arguments() -> Args {
                 .help("Enforce that a variable has to (not) match a regex.")
                 .long_help(help::REGEX),
         )
+        .arg(
+            Arg::with_name("string-length")
+                .long("string-length")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|v| v.parse::<LengthConstraint>().map(|_| ()))
+                .help("Constrain the length of string literals matched by a variable.")
+                .long_help(help::STRING_LENGTH),
+        )
+        .arg(
+            Arg::with_name("num-constraint")
+                .long("num-constraint")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|v| v.parse::<NumberConstraint>().map(|_| ()))
+                .help("Constrain the value of number literals matched by a variable.")
+                .long_help(help::NUM_CONSTRAINT),
+        )
         .arg(
             Arg::with_name("cpp")
                 .short("X")
@@ -123,12 +401,41 @@ pub fn parse_arguments() -> Args {
                 .takes_value(false)
                 .help("Enable C++ mode."),
         )
+        .arg(
+            Arg::with_name("auto-lang")
+                .long("auto-lang")
+                .takes_value(false)
+                .conflicts_with("cpp")
+                .help("Detect C or C++ mode for each file.")
+                .long_help(help::AUTO_LANG),
+        )
+        .arg(
+            Arg::with_name("fallback-cpp")
+                .long("fallback-cpp")
+                .takes_value(false)
+                .help("Use C++ mode for patterns that are only valid C++.")
+                .long_help(help::FALLBACK_CPP),
+        )
+        .arg(
+            Arg::with_name("dual-headers")
+                .long("dual-headers")
+                .takes_value(false)
+                .conflicts_with("invert-match")
+                .help("Search .h files in both C and C++ mode.")
+                .long_help(help::DUAL_HEADERS),
+        )
         .arg(
             Arg::with_name("color")
-                .short("C)")
+                .short("C")
                 .long("color")
-                .takes_value(false)
-                .help("Force enable color output."),
+                .value_name("WHEN")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .possible_values(&["auto", "always", "never"])
+                .global(true)
+                .help("When to use colors: auto (default), always or never. -C means always.")
+                .long_help(help::COLOR),
         )
         .arg(
             Arg::with_name("force")
@@ -137,6 +444,13 @@ pub fn parse_arguments() -> Args {
                 .takes_value(false)
                 .help("Force a search even if the queries contains syntax errors."),
         )
+        .arg(
+            Arg::with_name("no-normalize")
+                .long("no-normalize")
+                .takes_value(false)
+                .help("Don't add a missing ';' or {} around the pattern.")
+                .long_help(help::NO_NORMALIZE),
+        )
         .arg(
             Arg::with_name("unique")
                 .long("unique")
@@ -145,6 +459,43 @@ pub fn parse_arguments() -> Args {
                 .help("Enforce uniqueness of variable matches.")
                 .long_help(help::UNIQUE),
         )
+        .arg(
+            Arg::with_name("constraint")
+                .long("constraint")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|v| v.parse::<VariableConstraint>().map(|_| ()))
+                .help("Require two variables to match equal ($a==$b) or different ($a!=$b) values.")
+                .long_help(help::CONSTRAINT),
+        )
+        .arg(
+            Arg::with_name("filter")
+                .long("filter")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("EXPR")
+                .validator(|v| v.parse::<Filter>().map(|_| ()))
+                .help("Only report matches whose captured values satisfy EXPR.")
+                .long_help(help::FILTER),
+        )
+        .arg(
+            Arg::with_name("unique-by")
+                .long("unique-by")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Only show the first match for each distinct value of a variable.")
+                .long_help(help::UNIQUE_BY),
+        )
+        .arg(
+            Arg::with_name("raw-values")
+                .long("raw-values")
+                .takes_value(false)
+                .help("Don't ignore comments and whitespace when comparing variable values.")
+                .long_help(help::RAW_VALUES),
+        )
         .arg(
             Arg::with_name("exclude")
                 .long("exclude")
@@ -159,6 +510,59 @@ pub fn parse_arguments() -> Args {
                 .multiple(true)
                 .help("Only search files that match the given regex."),
         )
+        .arg(
+            Arg::with_name("max-filesize")
+                .long("max-filesize")
+                .alias("max-file-size")
+                .takes_value(true)
+                .value_name("SIZE")
+                .validator(|v| parse_size(&v).map(|_| ()))
+                .help("Skip files larger than SIZE (default: 10M).")
+                .long_help(help::MAX_FILESIZE),
+        )
+        .arg(
+            Arg::with_name("force-large")
+                .long("force-large")
+                .takes_value(false)
+                .conflicts_with("max-filesize")
+                .help("Search files of any size."),
+        )
+        .arg(
+            Arg::with_name("timeout-per-file")
+                .long("timeout-per-file")
+                .takes_value(true)
+                .value_name("SECS")
+                .validator(|v| parse_timeout(&v).map(|_| ()))
+                .help("Stop parsing or searching a file after SECS seconds.")
+                .long_help(help::TIMEOUT_PER_FILE),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .takes_value(true)
+                .value_name("N")
+                .validator(is_number)
+                .help("Use at most N worker threads (default: one per CPU).")
+                .long_help(help::THREADS),
+        )
+        .arg(
+            Arg::with_name("parse-threads")
+                .long("parse-threads")
+                .takes_value(true)
+                .value_name("N")
+                .validator(is_number)
+                .help("Parse files with a separate pool of N threads.")
+                .long_help(help::THREADS),
+        )
+        .arg(
+            Arg::with_name("query-threads")
+                .long("query-threads")
+                .takes_value(true)
+                .value_name("N")
+                .validator(is_number)
+                .help("Run queries with a separate pool of N threads.")
+                .long_help(help::THREADS),
+        )
         .arg(
             Arg::with_name("line-numbers")
                 .long("line-numbers")
@@ -166,7 +570,514 @@ pub fn parse_arguments() -> Args {
                 .takes_value(false)
                 .help("Enable line numbers"),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("invert-match")
+                .long("invert-match")
+                .takes_value(false)
+                .help("List functions that do not contain a match.")
+                .long_help(help::INVERT_MATCH),
+        )
+        .arg(
+            Arg::with_name("min-function-lines")
+                .long("min-function-lines")
+                .takes_value(true)
+                .validator(is_number)
+                .help("Only report matches in functions with at least this many lines."),
+        )
+        .arg(
+            Arg::with_name("max-function-lines")
+                .long("max-function-lines")
+                .takes_value(true)
+                .validator(is_number)
+                .help("Only report matches in functions with at most this many lines."),
+        )
+        .arg(
+            Arg::with_name("min-complexity")
+                .long("min-complexity")
+                .takes_value(true)
+                .validator(is_number)
+                .help("Only report matches in functions with at least this cyclomatic complexity.")
+                .long_help(help::COMPLEXITY),
+        )
+        .arg(
+            Arg::with_name("max-complexity")
+                .long("max-complexity")
+                .takes_value(true)
+                .validator(is_number)
+                .help("Only report matches in functions with at most this cyclomatic complexity.")
+                .long_help(help::COMPLEXITY),
+        )
+        .arg(
+            Arg::with_name("sample")
+                .long("sample")
+                .takes_value(true)
+                .validator(is_number)
+                .help("Only print a random sample of N matches."),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .long("quiet")
+                .short("q")
+                .takes_value(false)
+                .conflicts_with_all(&["files-without-match", "output", "save-results", "rewrite"])
+                .help("Don't print anything and exit with 0 on the first match.")
+                .long_help(help::QUIET),
+        )
+        .arg(
+            Arg::with_name("dedup-snippets")
+                .long("dedup-snippets")
+                .takes_value(false)
+                .help("Report matches with identical code only once.")
+                .long_help(help::DEDUP_SNIPPETS),
+        )
+        .arg(
+            Arg::with_name("list-variables")
+                .long("list-variables")
+                .takes_value(false)
+                .conflicts_with_all(&["invert-match", "rewrite", "save-results"])
+                .help("Print the distinct values of each query variable after the search.")
+                .long_help(help::LIST_VARIABLES),
+        )
+        .arg(
+            Arg::with_name("aggregate")
+                .long("aggregate")
+                .takes_value(true)
+                .value_name("VAR")
+                .conflicts_with_all(&["invert-match", "rewrite", "save-results", "list-variables"])
+                .help("Print how often each value of a variable was matched instead of matches.")
+                .long_help(help::AGGREGATE),
+        )
+        .arg(
+            Arg::with_name("group-by")
+                .long("group-by")
+                .takes_value(true)
+                .value_name("VAR")
+                .conflicts_with_all(&[
+                    "invert-match",
+                    "rewrite",
+                    "save-results",
+                    "aggregate",
+                    "dedup-snippets",
+                    "sample",
+                ])
+                .help("Print matches in sections per value of a variable.")
+                .long_help(help::GROUP_BY),
+        )
+        .arg(
+            Arg::with_name("count")
+                .long("count")
+                .short("c")
+                .takes_value(false)
+                .conflicts_with_all(&FILE_REPORT_CONFLICTS)
+                .help("Only print the number of matches in each file with matches.")
+                .long_help(help::FILE_REPORT),
+        )
+        .arg(
+            Arg::with_name("files-with-matches")
+                .long("files-with-matches")
+                .takes_value(false)
+                .conflicts_with_all(&FILE_REPORT_CONFLICTS)
+                .conflicts_with("count")
+                .help("Only print the names of files with matches.")
+                .long_help(help::FILE_REPORT),
+        )
+        .arg(
+            Arg::with_name("files-without-match")
+                .long("files-without-match")
+                .short("L")
+                .takes_value(false)
+                .conflicts_with_all(&FILE_REPORT_CONFLICTS)
+                .conflicts_with_all(&["count", "files-with-matches"])
+                .help("Only print the names of searched files without matches.")
+                .long_help(help::FILE_REPORT),
+        )
+        .arg(
+            Arg::with_name("shard")
+                .long("shard")
+                .takes_value(true)
+                .validator(|v| parse_shard(&v).map(|_| ()))
+                .help("Only search the K-th of N partitions of the input files (K/N).")
+                .long_help(help::SHARD),
+        )
+        .arg(
+            Arg::with_name("sample-files")
+                .long("sample-files")
+                .takes_value(true)
+                .value_name("N|P%")
+                .validator(|v| parse_file_sample(&v).map(|_| ()))
+                .help("Only search a random sample of N files or P percent of the files.")
+                .long_help(help::SAMPLE_FILES),
+        )
+        .arg(
+            Arg::with_name("save-results")
+                .long("save-results")
+                .takes_value(true)
+                .conflicts_with("invert-match")
+                .help("Write all results to a file for use with merge-results.")
+                .long_help(help::SHARD),
+        )
+        .arg(
+            Arg::with_name("join")
+                .long("join")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|v| parse_join(&v).map(|_| ()))
+                .help("Set how a variable is compared across patterns (VAR=MODE).")
+                .long_help(help::JOIN),
+        )
+        .arg(
+            Arg::with_name("same-function")
+                .long("same-function")
+                .takes_value(false)
+                .requires("p")
+                .conflicts_with("save-results")
+                .help("Only combine results of different patterns in the same function.")
+                .long_help(help::SAME_FUNCTION),
+        )
+        .arg(
+            Arg::with_name("stream-join")
+                .long("stream-join")
+                .takes_value(false)
+                .requires("p")
+                .conflicts_with("save-results")
+                .help("Report results of multiple patterns as soon as they can be combined.")
+                .long_help(help::STREAM_JOIN),
+        )
+        .arg(
+            Arg::with_name("stream-join-limit")
+                .long("stream-join-limit")
+                .takes_value(true)
+                .value_name("N")
+                .validator(is_number)
+                .requires("stream-join")
+                .help("Keep at most N results waiting for --stream-join (default: 100000).")
+                .long_help(help::STREAM_JOIN),
+        )
+        .arg(
+            Arg::with_name("rules")
+                .long("rules")
+                .takes_value(true)
+                .conflicts_with_all(&["p", "invert-match", "save-results"])
+                .help("Run all rules in a rule file or directory instead of PATTERN.")
+                .long_help(help::RULES),
+        )
+        .arg(
+            Arg::with_name("raw-tsquery")
+                .long("raw-tsquery")
+                .takes_value(true)
+                .value_name("SEXP")
+                .conflicts_with_all(&["p", "rules"])
+                .help("Search with a raw tree-sitter query instead of PATTERN.")
+                .long_help(help::RAW_TSQUERY),
+        )
+        .arg(
+            Arg::with_name("skip-broken-rules")
+                .long("skip-broken-rules")
+                .takes_value(false)
+                .requires("rules")
+                .help("Report rules with invalid patterns at the end instead of exiting."),
+        )
+        .arg(
+            Arg::with_name("baseline")
+                .long("baseline")
+                .takes_value(true)
+                .value_name("FILE")
+                .conflicts_with("invert-match")
+                .help("Don't report results that are recorded in the baseline FILE.")
+                .long_help(help::BASELINE),
+        )
+        .arg(
+            Arg::with_name("update-baseline")
+                .long("update-baseline")
+                .takes_value(false)
+                .requires("baseline")
+                .help("Record all results in the --baseline file instead of filtering them."),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&[
+                    "text",
+                    "json",
+                    "sarif",
+                    "targets",
+                    "oneline",
+                    "csv",
+                    "tsv",
+                    "github",
+                    "gitlab-codequality",
+                ])
+                .conflicts_with("json")
+                .help("Output format for results.")
+                .long_help(help::FORMAT),
+        )
+        .arg(
+            Arg::with_name("output-format")
+                .long("output-format")
+                .takes_value(true)
+                .value_name("TEMPLATE")
+                .conflicts_with_all(&["format", "json", "only-matching"])
+                .validator(|v| v.parse::<Template>().map(|_| ()))
+                .help("Print each result as TEMPLATE, e.g. '{file}:{line} {$func}'.")
+                .long_help(help::OUTPUT_FORMAT),
+        )
+        .arg(
+            Arg::with_name("only-matching")
+                .long("only-matching")
+                .takes_value(false)
+                .conflicts_with_all(&["whole-function", "context-statements", "explain-results"])
+                .help("Only print the matched code instead of the enclosing function.")
+                .long_help(help::ONLY_MATCHING),
+        )
+        .arg(
+            Arg::with_name("blame")
+                .long("blame")
+                .takes_value(false)
+                .conflicts_with("invert-match")
+                .help(
+                    "Add the last commit that changed the matched lines to JSON and SARIF results.",
+                )
+                .long_help(help::BLAME),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .takes_value(true)
+                .value_name("FILE")
+                .conflicts_with_all(&["rewrite", "save-results"])
+                .help("Write results to FILE instead of stdout (compressed if FILE ends in .zst).")
+                .long_help(help::OUTPUT),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .takes_value(false)
+                .help("Print results as JSON objects, one per line.")
+                .long_help(help::JSON),
+        )
+        .arg(
+            Arg::with_name("metrics")
+                .long("metrics")
+                .takes_value(false)
+                .help("Add metrics of the enclosing function to JSON and SARIF results.")
+                .long_help(help::METRICS),
+        )
+        .arg(
+            Arg::with_name("explain-results")
+                .long("explain-results")
+                .takes_value(false)
+                .conflicts_with("invert-match")
+                .help("Show which part of the pattern produced each captured node.")
+                .long_help(help::EXPLAIN_RESULTS),
+        )
+        .arg(
+            Arg::with_name("use-index")
+                .long("use-index")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .value_name("DIR")
+                .help("Skip files based on an index created with weggli index.")
+                .long_help(help::USE_INDEX),
+        )
+        .arg(
+            Arg::with_name("no-match-cache")
+                .long("no-match-cache")
+                .takes_value(true)
+                .value_name("DIR")
+                .conflicts_with("invert-match")
+                .help("Remember files without matches and skip them in later runs.")
+                .long_help(help::NO_MATCH_CACHE),
+        )
+        .arg(
+            Arg::with_name("diff")
+                .long("diff")
+                .takes_value(true)
+                .value_name("REV")
+                .help("Only search files that changed since the git revision REV.")
+                .long_help(help::DIFF),
+        )
+        .arg(
+            Arg::with_name("changed-lines")
+                .long("changed-lines")
+                .takes_value(false)
+                .requires("diff")
+                .conflicts_with("invert-match")
+                .help("Only report results that include lines changed since --diff REV."),
+        )
+        .arg(
+            Arg::with_name("rewrite")
+                .long("rewrite")
+                .takes_value(true)
+                .conflicts_with_all(&["p", "rules", "invert-match", "save-results"])
+                .help("Replace matches with a template and print a diff of the changes.")
+                .long_help(help::REWRITE),
+        )
+        .arg(
+            Arg::with_name("in-place")
+                .long("in-place")
+                .takes_value(false)
+                .requires("rewrite")
+                .help("Write the changes made by --rewrite back to the files."),
+        )
+        .subcommand(
+            SubCommand::with_name("merge-results")
+                .about("Combine results saved with --save-results.")
+                .arg(
+                    Arg::with_name("FILES")
+                        .help("Result files.")
+                        .required(true)
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("join")
+                        .long("join")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .validator(|v| parse_join(&v).map(|_| ()))
+                        .help("Set how a variable is compared across patterns (VAR=MODE).")
+                        .long_help(help::JOIN),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("index")
+                .about("Create or update the index used by --use-index.")
+                .arg(
+                    Arg::with_name("PATH")
+                        .help("Directory to index.")
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("index-dir")
+                        .long("index-dir")
+                        .takes_value(true)
+                        .help("Directory to store the index in (default: PATH/.weggli)."),
+                )
+                .arg(
+                    Arg::with_name("extensions")
+                        .long("extensions")
+                        .short("e")
+                        .takes_value(true)
+                        .multiple(true)
+                        .help("File extensions to index (default: C and C++ files)."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import-semgrep")
+                .about("Convert Semgrep rules for C and C++ into weggli rules.")
+                .long_about(help::IMPORT_SEMGREP)
+                .arg(
+                    Arg::with_name("FILE")
+                        .help("Semgrep rule file (YAML).")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .help("Write one rule file per rule to DIR instead of printing them."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("repl")
+                .about("Parse a code base once and interactively run queries against it.")
+                .arg(
+                    Arg::with_name("PATH")
+                        .help("Input directory or file to search.")
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("cpp")
+                        .short("X")
+                        .long("cpp")
+                        .help("Enable C++ mode."),
+                )
+                .arg(
+                    Arg::with_name("extensions")
+                        .long("extensions")
+                        .short("e")
+                        .takes_value(true)
+                        .multiple(true)
+                        .help("File extensions to include in the search."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("functions")
+                .about("List all function definitions with their location and signature.")
+                .long_about(help::FUNCTIONS)
+                .arg(
+                    Arg::with_name("PATH")
+                        .help("Input directory or file.")
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("cpp")
+                        .short("X")
+                        .long("cpp")
+                        .help("Enable C++ mode."),
+                )
+                .arg(
+                    Arg::with_name("extensions")
+                        .long("extensions")
+                        .short("e")
+                        .takes_value(true)
+                        .multiple(true)
+                        .help("File extensions to include."),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Print one JSON object per function."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("try")
+                .about("Run a pattern against an inline code snippet and show its captures.")
+                .long_about(help::TRY)
+                .arg(
+                    Arg::with_name("PATTERN")
+                        .help("A weggli search pattern.")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("against")
+                        .long("against")
+                        .short("a")
+                        .takes_value(true)
+                        .value_name("CODE")
+                        .required(true)
+                        .help("Source code to search."),
+                )
+                .arg(
+                    Arg::with_name("cpp")
+                        .short("X")
+                        .long("cpp")
+                        .help("Enable C++ mode."),
+                ),
+        )
+        .get_matches_safe()
+        .unwrap_or_else(|e| match e.kind {
+            clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => e.exit(),
+            _ => {
+                eprintln!("{}", e.message);
+                std::process::exit(2)
+            }
+        });
+
+    // --color is a global option, so it can also be passed to subcommands.
+    let color = matches.subcommand().1.unwrap_or(&matches);
+    match (color.occurrences_of("color"), color.value_of("color")) {
+        (0, _) | (_, Some("auto")) => ColorChoice::Auto,
+        (_, Some("never")) => ColorChoice::Never,
+        _ => ColorChoice::Always,
+    }
+    .apply();
 
     let helper = |option_name| -> Vec<String> {
         if let Some(v) = matches.values_of(option_name) {
@@ -182,84 +1093,642 @@ pub fn parse_arguments() -> Args {
         _ => log::LevelFilter::Debug,
     };
 
-    let _ = SimpleLogger::init(level, Config::default());
-
-    let directory = Path::new(matches.value_of("PATH").unwrap_or("."));
-
-    let mut pattern = vec![matches.value_of("PATTERN").unwrap().to_string()];
-    if let Some(p) = matches.values_of("p") {
-        pattern.extend(p.map(|v| v.to_string()))
-    }
-
-    let regexes = helper("regex");
-
-    let path = if directory.is_absolute() || directory.to_string_lossy() == "-" {
-        directory.to_path_buf()
+    let logger: Box<dyn log::Log> = if matches.value_of("log-format") == Some("json") {
+        crate::events::init(level)
     } else {
-        std::env::current_dir().unwrap().join(directory)
+        // Results are written to stdout, so log messages must not end up there.
+        WriteLogger::new(level, Config::default(), std::io::stderr())
     };
+    let max_warnings = matches
+        .value_of("max-warnings")
+        .map(|v| validated(v, str::parse));
+    crate::warnings::init(logger, level, max_warnings);
 
-    let before = match matches.value_of("before") {
-        Some(v) => v.parse().unwrap_or(5),
-        None => 5,
+    let join_modes = |m: &clap::ArgMatches| -> HashMap<String, JoinMode> {
+        m.values_of("join")
+            .into_iter()
+            .flatten()
+            .map(|v| validated(v, parse_join))
+            .collect()
     };
 
-    let after = match matches.value_of("after") {
-        Some(v) => v.parse().unwrap_or(5),
-        None => 5,
-    };
+    if let Some(m) = matches.subcommand_matches("merge-results") {
+        return Command::MergeResults(
+            m.values_of_os("FILES")
+                .unwrap()
+                .map(PathBuf::from)
+                .collect(),
+            join_modes(m),
+        );
+    }
 
-    let limit = matches.occurrences_of("limit") > 0;
+    if let Some(m) = matches.subcommand_matches("index") {
+        let path = PathBuf::from(m.value_of_os("PATH").unwrap());
+        let dir = match m.value_of_os("index-dir") {
+            Some(d) => PathBuf::from(d),
+            None => path.join(crate::index::DEFAULT_DIR),
+        };
+        let extensions = match m.values_of("extensions") {
+            Some(e) => e.map(|v| v.to_string()).collect(),
+            None => default_extensions(true, true),
+        };
+        return Command::Index(path, dir, extensions);
+    }
+
+    if let Some(m) = matches.subcommand_matches("import-semgrep") {
+        return Command::ImportSemgrep(
+            PathBuf::from(m.value_of_os("FILE").unwrap()),
+            m.value_of_os("output").map(PathBuf::from),
+        );
+    }
+
+    if let Some(m) = matches.subcommand_matches("repl") {
+        let cpp = m.occurrences_of("cpp") > 0;
+        let extensions = match m.values_of("extensions") {
+            Some(e) => e.map(|v| v.to_string()).collect(),
+            None => default_extensions(cpp, false),
+        };
+        return Command::Repl(
+            PathBuf::from(m.value_of_os("PATH").unwrap()),
+            cpp,
+            extensions,
+        );
+    }
+
+    if let Some(m) = matches.subcommand_matches("functions") {
+        let cpp = m.occurrences_of("cpp") > 0;
+        let extensions = match m.values_of("extensions") {
+            Some(e) => e.map(|v| v.to_string()).collect(),
+            None => default_extensions(cpp, false),
+        };
+        return Command::Functions(
+            PathBuf::from(m.value_of_os("PATH").unwrap()),
+            cpp,
+            extensions,
+            m.occurrences_of("json") > 0,
+        );
+    }
+
+    if let Some(m) = matches.subcommand_matches("try") {
+        return Command::Try(
+            m.value_of("PATTERN").unwrap().to_string(),
+            m.value_of("against").unwrap().to_string(),
+            m.occurrences_of("cpp") > 0,
+        );
+    }
+
+    let rules = match matches.value_of_os("rules") {
+        Some(r) => rules::load_rules(Path::new(r)).unwrap_or_else(|e| {
+            eprintln!("Failed to load rules: {}", e);
+            std::process::exit(2)
+        }),
+        None => vec![],
+    };
+
+    let baseline = matches.value_of_os("baseline").map(|b| {
+        if matches.is_present("update-baseline") {
+            Baseline::update(Path::new(b))
+        } else {
+            Baseline::load(Path::new(b)).unwrap_or_else(|e| {
+                eprintln!("Failed to load baseline {}: {}", Path::new(b).display(), e);
+                std::process::exit(2)
+            })
+        }
+    });
+
+    // With --rules or --raw-tsquery, the only positional argument is the search path.
+    let raw_query = matches.value_of("raw-tsquery");
+    let (pattern, directory) = if let Some(sexp) = raw_query {
+        (vec![sexp.to_string()], matches.value_of("PATTERN"))
+    } else if rules.is_empty() {
+        let mut pattern = match matches.value_of("PATTERN") {
+            Some(p) => vec![p.to_string()],
+            None => {
+                eprintln!("error: The following required arguments were not provided:");
+                eprintln!("    <PATTERN>");
+                std::process::exit(2)
+            }
+        };
+        if let Some(p) = matches.values_of("p") {
+            pattern.extend(p.map(|v| v.to_string()))
+        }
+        (pattern, matches.value_of("PATH"))
+    } else {
+        let pattern = rules.iter().map(|r| r.pattern.clone()).collect();
+        (pattern, matches.value_of("PATTERN"))
+    };
+
+    let files_from = matches.value_of_os("files-from").map(PathBuf::from);
+    if directory == Some("-") && files_from.as_deref() == Some(Path::new("-")) {
+        eprintln!("PATH and --files-from can't both read from STDIN");
+        std::process::exit(2)
+    }
+
+    let regexes = helper("regex");
+
+    let string_lengths = helper("string-length")
+        .iter()
+        .map(|v| validated(v, str::parse))
+        .collect();
+    let num_constraints = helper("num-constraint")
+        .iter()
+        .map(|v| validated(v, str::parse))
+        .collect();
+    let variable_constraints = helper("constraint")
+        .iter()
+        .map(|v| validated(v, str::parse))
+        .collect();
+    let filters = helper("filter")
+        .iter()
+        .map(|v| validated(v, str::parse))
+        .collect();
+
+    let path = directory.map(Path::new).map(|directory| {
+        if directory.is_absolute() || directory.to_string_lossy() == "-" {
+            directory.to_path_buf()
+        } else {
+            std::env::current_dir().unwrap().join(directory)
+        }
+    });
+
+    let diff = matches.value_of("diff").map(|rev| {
+        let repo = match &path {
+            Some(p) if p.to_string_lossy() != "-" => p.clone(),
+            _ => std::env::current_dir().unwrap(),
+        };
+        Changes::since(&repo, rev).unwrap_or_else(|e| {
+            eprintln!("Failed to get changes since {}: {}", rev, e);
+            std::process::exit(2)
+        })
+    });
+    let changed_lines = matches.occurrences_of("changed-lines") > 0;
+
+    let before = match matches.value_of("before") {
+        Some(v) => v.parse().unwrap_or(5),
+        None => 5,
+    };
+
+    let after = match matches.value_of("after") {
+        Some(v) => v.parse().unwrap_or(5),
+        None => 5,
+    };
+
+    let context_statements = matches
+        .value_of("context-statements")
+        .map(|v| validated(v, str::parse));
+
+    let function_header = match matches.value_of("function-header") {
+        Some("signature") => FunctionHeader::Signature,
+        Some("name") => FunctionHeader::Name,
+        Some("none") => FunctionHeader::None,
+        _ => FunctionHeader::Line,
+    };
+
+    let (before, after) = if matches.occurrences_of("whole-function") > 0 {
+        (WHOLE_FUNCTION, WHOLE_FUNCTION)
+    } else if context_statements.is_some() {
+        // Statements replace the default context lines, unless they were set explicitly.
+        (
+            if matches.occurrences_of("before") > 0 {
+                before
+            } else {
+                0
+            },
+            if matches.occurrences_of("after") > 0 {
+                after
+            } else {
+                0
+            },
+        )
+    } else {
+        (before, after)
+    };
+
+    // Rules can set their own context, --pattern-context overrides both.
+    let mut contexts = vec![(before, after); pattern.len()];
+    for (c, rule) in contexts.iter_mut().zip(rules.iter()) {
+        *c = if rule.whole_function {
+            (WHOLE_FUNCTION, WHOLE_FUNCTION)
+        } else {
+            (rule.before.unwrap_or(c.0), rule.after.unwrap_or(c.1))
+        };
+    }
+    for v in helper("pattern-context") {
+        let (index, context) = validated(&v, parse_pattern_context);
+        match contexts.get_mut(index) {
+            Some(c) => *c = context,
+            None => {
+                eprintln!(
+                    "--pattern-context {}: there is no pattern with index {} ({} pattern(s))",
+                    v,
+                    index,
+                    pattern.len()
+                );
+                std::process::exit(2)
+            }
+        }
+    }
+
+    let limit = matches.occurrences_of("limit") > 0;
 
     let unique = matches.occurrences_of("unique") > 0;
+    let raw_values = matches.occurrences_of("raw-values") > 0;
+
+    let unique_by = helper("unique-by")
+        .into_iter()
+        .map(|v| {
+            if v.starts_with('$') {
+                v
+            } else {
+                "$".to_string() + &v
+            }
+        })
+        .collect();
 
     let cpp = matches.occurrences_of("cpp") > 0;
-    let force_color = matches.occurrences_of("color") > 0;
+    let auto_lang = matches.occurrences_of("auto-lang") > 0;
+    let fallback_cpp = matches.occurrences_of("fallback-cpp") > 0;
+    let dual_headers = matches.occurrences_of("dual-headers") > 0;
 
     let extensions = {
         let e = helper("extensions");
         if e.is_empty() {
-            if !cpp {
-                vec!["c".to_string(), "h".into()]
-            } else {
-                vec![
-                    "cc".to_string(),
-                    "cpp".into(),
-                    "h".into(),
-                    "cxx".into(),
-                    "hpp".into(),
-                ]
-            }
+            default_extensions(cpp, auto_lang)
         } else {
             e
         }
     };
 
+    let no_ignore = matches.occurrences_of("no-ignore") > 0;
     let exclude = helper("exclude");
     let include = helper("include");
 
     let force_query = matches.occurrences_of("force") > 0;
+    let no_normalize = matches.occurrences_of("no-normalize") > 0;
 
     let enable_line_numbers = matches.occurrences_of("line-numbers") > 0;
 
-    Args {
+    let invert_match = matches.occurrences_of("invert-match") > 0;
+
+    let number = |name| matches.value_of(name).map(|v| validated(v, str::parse));
+    let function_lines = (number("min-function-lines"), number("max-function-lines"));
+    let complexity = (number("min-complexity"), number("max-complexity"));
+    let sample = number("sample");
+    let threads = (
+        number("threads"),
+        number("parse-threads"),
+        number("query-threads"),
+    );
+
+    let dedup_snippets = matches.occurrences_of("dedup-snippets") > 0;
+    let quiet = matches.occurrences_of("quiet") > 0;
+    let list_variables = matches.occurrences_of("list-variables") > 0;
+    let variable = |v: &str| {
+        if v.starts_with('$') {
+            v.to_string()
+        } else {
+            "$".to_string() + v
+        }
+    };
+    let aggregate = matches.value_of("aggregate").map(variable);
+    let group_by = matches.value_of("group-by").map(variable);
+
+    let file_report = if matches.occurrences_of("count") > 0 {
+        Some(FileReport::Count)
+    } else if matches.occurrences_of("files-with-matches") > 0 {
+        Some(FileReport::WithMatches)
+    } else if matches.occurrences_of("files-without-match") > 0 {
+        Some(FileReport::WithoutMatch)
+    } else {
+        None
+    };
+
+    let shard = matches.value_of("shard").map(|v| validated(v, parse_shard));
+    let sample_files = matches
+        .value_of("sample-files")
+        .map(|v| validated(v, parse_file_sample));
+    let max_filesize = if matches.occurrences_of("force-large") > 0 {
+        None
+    } else {
+        let size = matches
+            .value_of("max-filesize")
+            .map(|v| validated(v, parse_size));
+        Some(size.unwrap_or(DEFAULT_MAX_FILESIZE))
+    };
+    let timeout_per_file = matches
+        .value_of("timeout-per-file")
+        .map(|v| validated(v, parse_timeout));
+    let save_results = matches.value_of_os("save-results").map(PathBuf::from);
+    let output = matches.value_of_os("output").map(PathBuf::from);
+
+    let join = join_modes(&matches);
+    let same_function = matches.occurrences_of("same-function") > 0;
+    let stream_join = if matches.occurrences_of("stream-join") > 0 {
+        Some(number("stream-join-limit").unwrap_or(DEFAULT_STREAM_JOIN_LIMIT))
+    } else {
+        None
+    };
+
+    let format = match matches.value_of("format") {
+        Some("json") => Format::Json,
+        Some("sarif") => Format::Sarif,
+        Some("targets") => Format::Targets,
+        Some("oneline") => Format::Oneline,
+        Some("csv") => Format::Csv,
+        Some("tsv") => Format::Tsv,
+        Some("github") => Format::Github,
+        Some("gitlab-codequality") => Format::GitlabCodequality,
+        _ if matches.occurrences_of("json") > 0 => Format::Json,
+        _ if output.is_some() => Format::Json,
+        _ => Format::Text,
+    };
+
+    if aggregate.is_some() && matches!(format, Format::Sarif | Format::GitlabCodequality) {
+        eprintln!("--aggregate can't be used with --format sarif or gitlab-codequality");
+        std::process::exit(2)
+    }
+    if group_by.is_some() && matches!(format, Format::Sarif | Format::GitlabCodequality) {
+        eprintln!("--group-by can't be used with --format sarif or gitlab-codequality");
+        std::process::exit(2)
+    }
+
+    let only_matching = matches.occurrences_of("only-matching") > 0;
+    let output_template = matches
+        .value_of("output-format")
+        .map(|v| validated(v, str::parse));
+    if only_matching && !matches!(format, Format::Text | Format::Oneline) {
+        eprintln!("--only-matching can only be used with --format text or oneline");
+        std::process::exit(2)
+    }
+
+    let blame = matches.occurrences_of("blame") > 0;
+    if blame && (output_template.is_some() || !matches!(format, Format::Json | Format::Sarif)) {
+        eprintln!("--blame can only be used with --format json or sarif");
+        std::process::exit(2)
+    }
+
+    let metrics = matches.occurrences_of("metrics") > 0;
+    let explain = matches.occurrences_of("explain-results") > 0;
+    let skip_broken_rules = matches.occurrences_of("skip-broken-rules") > 0;
+    let use_index = if matches.occurrences_of("use-index") > 0 {
+        match matches.value_of_os("use-index") {
+            Some(d) if !d.is_empty() => Some(PathBuf::from(d)),
+            _ => match &path {
+                Some(p) if p.to_string_lossy() != "-" => Some(p.join(crate::index::DEFAULT_DIR)),
+                _ => Some(PathBuf::from(crate::index::DEFAULT_DIR)),
+            },
+        }
+    } else {
+        None
+    };
+
+    // values were already checked by clap.
+    let anchor = matches
+        .value_of("anchor")
+        .map_or(Anchor::Function, |v| v.parse().unwrap());
+
+    let rewrite = matches.value_of("rewrite").map(|v| v.to_string());
+    let in_place = matches.occurrences_of("in-place") > 0;
+
+    Command::Search(Box::new(Args {
         path,
+        files_from,
         pattern,
-        before,
-        after,
+        contexts,
+        context_statements,
+        function_header,
         extensions,
+        no_ignore,
         regexes,
         limit,
         cpp,
+        auto_lang,
+        fallback_cpp,
+        dual_headers,
         unique,
-        force_color,
+        unique_by,
+        raw_values,
         force_query,
+        no_normalize,
         include,
         exclude,
         enable_line_numbers,
+        invert_match,
+        function_lines,
+        complexity,
+        sample,
+        dedup_snippets,
+        quiet,
+        list_variables,
+        aggregate,
+        group_by,
+        file_report,
+        shard,
+        sample_files,
+        max_filesize,
+        timeout_per_file,
+        threads,
+        save_results,
+        output,
+        join,
+        same_function,
+        stream_join,
+        format,
+        only_matching,
+        output_template,
+        blame,
+        columns: Vec::new(),
+        string_lengths,
+        num_constraints,
+        variable_constraints,
+        filters,
+        rules,
+        raw_query: raw_query.is_some(),
+        rewrite,
+        in_place,
+        anchor,
+        metrics,
+        explain,
+        use_index,
+        no_match_cache: matches.value_of_os("no-match-cache").map(PathBuf::from),
+        skip_broken_rules,
+        baseline,
+        diff,
+        changed_lines,
+    }))
+}
+
+/// File extensions searched when --extensions is not set.
+fn default_extensions(cpp: bool, auto_lang: bool) -> Vec<String> {
+    if auto_lang {
+        vec![
+            "c".to_string(),
+            "h".into(),
+            "cc".into(),
+            "cpp".into(),
+            "cxx".into(),
+            "hpp".into(),
+        ]
+    } else if !cpp {
+        vec!["c".to_string(), "h".into()]
+    } else {
+        vec![
+            "cc".to_string(),
+            "cpp".into(),
+            "h".into(),
+            "cxx".into(),
+            "hpp".into(),
+        ]
+    }
+}
+
+/// Parse the value of an argument with `parse`. Values are only parsed after clap ran the
+/// argument's validator, which uses the same function, so parsing can't fail.
+fn validated<T, E: std::fmt::Debug>(v: &str, parse: impl FnOnce(&str) -> Result<T, E>) -> T {
+    parse(v).expect("value was checked by the argument validator")
+}
+
+/// Parse a --join value of the form VAR=MODE.
+fn parse_join(v: &str) -> Result<(String, JoinMode), String> {
+    let (var, mode) = v
+        .split_once('=')
+        .ok_or_else(|| format!("'{}' is not of the form VAR=MODE", v))?;
+    let var = if var.starts_with('$') {
+        var.to_string()
+    } else {
+        "$".to_string() + var
+    };
+    Ok((var, mode.parse()?))
+}
+
+/// Parse a --shard value of the form K/N and return the 0-based shard index and count.
+fn parse_shard(v: &str) -> Result<(usize, usize), String> {
+    let err = || format!("'{}' is not of the form K/N with 1 <= K <= N", v);
+
+    let (k, n) = v.split_once('/').ok_or_else(err)?;
+    let k: usize = k.trim().parse().map_err(|_| err())?;
+    let n: usize = n.trim().parse().map_err(|_| err())?;
+    if k == 0 || k > n {
+        return Err(err());
+    }
+    Ok((k - 1, n))
+}
+
+/// Size of the random subset of files searched with --sample-files.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileSample {
+    Count(usize),
+    Percent(f64),
+}
+
+impl FileSample {
+    /// Number of files to sample out of `total`. A percentage always selects at
+    /// least one file.
+    pub fn size(self, total: usize) -> usize {
+        match self {
+            FileSample::Count(n) => n.min(total),
+            FileSample::Percent(p) => ((total as f64 * p / 100.0).ceil() as usize)
+                .max(1)
+                .min(total),
+        }
+    }
+}
+
+/// Parse a --sample-files value: a number of files such as 100, or a percentage such as 5%.
+fn parse_file_sample(v: &str) -> Result<FileSample, String> {
+    let err = || {
+        format!(
+            "'{}' is not a number of files or a percentage such as 5%",
+            v
+        )
+    };
+
+    match v.trim().strip_suffix('%') {
+        Some(p) => match p.trim().parse::<f64>() {
+            Ok(p) if p > 0.0 && p <= 100.0 => Ok(FileSample::Percent(p)),
+            _ => Err(err()),
+        },
+        None => match v.trim().parse::<usize>() {
+            Ok(n) if n > 0 => Ok(FileSample::Count(n)),
+            _ => Err(err()),
+        },
+    }
+}
+
+/// Options that print something else than results, so they can't be combined
+/// with --count, --files-with-matches and --files-without-match.
+const FILE_REPORT_CONFLICTS: [&str; 8] = [
+    "aggregate",
+    "group-by",
+    "list-variables",
+    "dedup-snippets",
+    "sample",
+    "invert-match",
+    "rewrite",
+    "save-results",
+];
+
+/// Results that can wait for compatible results with --stream-join, see --stream-join-limit.
+const DEFAULT_STREAM_JOIN_LIMIT: usize = 100_000;
+
+/// Files larger than this are skipped unless --max-filesize or --force-large is set.
+const DEFAULT_MAX_FILESIZE: u64 = 10 << 20;
+
+/// Parse a file size such as 512, 100K, 10M or 1G.
+fn parse_size(v: &str) -> Result<u64, String> {
+    let err = || format!("'{}' is not a size such as 500K or 10M", v);
+
+    let (number, shift) = match v.trim().to_ascii_uppercase() {
+        s if s.ends_with('K') => (s[..s.len() - 1].to_string(), 10),
+        s if s.ends_with('M') => (s[..s.len() - 1].to_string(), 20),
+        s if s.ends_with('G') => (s[..s.len() - 1].to_string(), 30),
+        s => (s, 0),
+    };
+    let number: u64 = number.trim().parse().map_err(|_| err())?;
+    number.checked_mul(1 << shift).ok_or_else(err)
+}
+
+/// Parse a --timeout-per-file value: a positive number of seconds.
+fn parse_timeout(v: &str) -> Result<Duration, String> {
+    match v.trim().parse::<f64>() {
+        Ok(secs) if secs > 0.0 && secs.is_finite() => Ok(Duration::from_secs_f64(secs)),
+        _ => Err(format!("'{}' is not a positive number of seconds", v)),
     }
 }
 
+fn is_number(v: String) -> Result<(), String> {
+    v.parse::<usize>()
+        .map(|_| ())
+        .map_err(|_| format!("'{}' is not a number", v))
+}
+
+/// Context size used to display the full function.
+const WHOLE_FUNCTION: usize = usize::MAX;
+
+/// Parse a --pattern-context value of the form INDEX=BEFORE,AFTER / INDEX=LINES / INDEX=function
+fn parse_pattern_context(v: &str) -> Result<(usize, (usize, usize)), String> {
+    let err = || format!("'{}' is not of the form INDEX=BEFORE,AFTER", v);
+
+    let (index, context) = v.split_once('=').ok_or_else(err)?;
+    let index = index.trim().parse().map_err(|_| err())?;
+
+    let context = match context.trim() {
+        "function" => (WHOLE_FUNCTION, WHOLE_FUNCTION),
+        c => match c.split_once(',') {
+            Some((b, a)) => (
+                b.trim().parse().map_err(|_| err())?,
+                a.trim().parse().map_err(|_| err())?,
+            ),
+            None => {
+                let lines = c.parse().map_err(|_| err())?;
+                (lines, lines)
+            }
+        },
+    };
+
+    Ok((index, context))
+}
+
 mod help {
     pub const ABOUT: &str = "\
  weggli is a semantic search tool for C and C++ codebases.
@@ -281,7 +1750,10 @@ mod help {
  {positionals}
  
  OPTIONS:
- {unified}";
+ {unified}
+
+ SUBCOMMANDS:
+ {subcommands}";
 
     pub const PATTERN: &str = "\
  A weggli search pattern. weggli's query language closely resembles
@@ -301,20 +1773,93 @@ mod help {
           optionally enforces that $x != $y != $z. The --regex option can
           enforce that the variable has to match (or not match) a
           regular expression.
+          Variables used as an array size ('char $buf[$n];') match any
+          size expression and also match number literals elsewhere in the
+          query. Number literals are compared by value, so
+          '{char $buf[$n]; memcpy($buf,_,$n);}' matches 'char b[0x10];
+          memcpy(b,src,16);'.
+
+ $_var    Local variables. Variables starting with $_ are local to the
+          block or not: query they are used in and are not unified with
+          variables of the same name outside of it. For example
+          '{$p = malloc($n); not: $_x = _; use($p);}' ignores allocations
+          that are followed by any assignment before the use.
+
+ $f~\"re\" Inline regex constraints. Same as --regex, but written next to
+          the variable: '$f~\"^mem\"(_,_,_);' only matches functions starting
+          with mem, '$f!~\"^mem\"' all others. Use \\\" for a quote inside the
+          regex. Inline constraints replace --regex constraints for the
+          same variable.
+
+ $n[>4096] Numeric range constraints. The variable only matches number
+          literals that satisfy the comparison (<, <=, >, >=, == or !=), see
+          --num-constraint. For example '{char $buf[$n[>4096]];}' finds
+          large stack buffers.
+
+ $f!{a,b} Excluded identifiers. The variable must not match any of the
+          listed names. For example '$f!{memcpy,memmove}(_,_,sizeof(_));'
+          finds calls to all other functions with a sizeof argument.
  
  _(..)    Subexpressions. The _(..) wildcard matches on arbitrary
           sub expressions. This can be helpful if you are looking for some
           operation involving a variable, but don't know more about it.
           For example, _(test) will match on expressions like test+10,
           buf[test->size] or f(g(&test));
+          With multiple arguments, all of them need to appear in the same
+          expression, or in the same statement if _(..) is used as a statement.
+          For example, memcpy(_, _, _($len, $off)); finds sizes combining both.
+
+ ...      Argument wildcards. Matches zero or more arguments of a call.
+          Once an argument list contains '...' (or '$...'), the other
+          arguments are matched at exact positions: 'memcpy($dst, ..., $len);'
+          only matches calls where $dst is the first and $len the last argument.
  
  not:     Negative sub queries. Only show results that do not match the
           following sub query. For example, '{not: $fv==NULL; not: $fv!=NULL *$v;}'
           would find pointer dereferences that are not preceded by a NULL check.
+          In initializer lists, not: excludes designated initializers in any
+          position: '{_ $ops = { .unlocked_ioctl = $f, not: .compat_ioctl = _ };}'
+          finds tables without a compat_ioctl handler. Designated
+          initializers match in any order.
+
+never:   Like not:, but the sub query must not match anywhere in the
+          enclosing function, independent of the order of statements. For
+          example '{$p = malloc(_); never: free($p);}' only finds functions
+          that never free the allocated memory.
 
 strict:   Enable stricter matching. This turns off statement unwrapping and greedy
           function name matching. For example 'strict: func();' will not match
           on 'if (func() == 1)..' or 'a->func()' anymore. 
+
+or:       Alternatives. A statement with an or: label is an alternative to the
+          previous statement, so the query matches if any of them is present.
+          For example '{$p = malloc(_); or: $p = calloc(_,_); use($p);}' finds
+          uses of memory allocated by either malloc or calloc.
+
+count(>2): Occurrence counts. The statement needs to match the given number
+          of times (<, <=, >, >=, == or !=) instead of at least once. Matches
+          are counted separately for each value of the statement's variables.
+          For example '{count(>=3): $f($x);}' finds functions that call the
+          same function with the same argument at least three times.
+
+show:     Highlight the complete statement in the output instead of only
+          its identifiers and keywords. For example
+          '{$p = malloc(_); show: if ($p) _;}' highlights the whole if statement.
+
+unused:   Only match calls whose return value is discarded. For example
+          'unused: $f(_);' will match on 'func(a);' but not on 'x = func(a);',
+          'if (func(a))..' or 'return func(a);'.
+
+/* .. */  Comment regex. A comment in front of the pattern is a regular
+          expression for a comment directly preceding the function (or
+          the --anchor node) of a match. For example
+          '/* TODO.*overflow */ {memcpy($d,_,_);}' finds functions with
+          such a comment that call memcpy. '// ..' comments work as well.
+
+asm(\"..\"): Inline assembly. The string is a regular expression for the
+          assembly template (asm, __asm__ and __asm statements). Qualifiers
+          and operands are ignored. For example '{asm(\"cr3\"); $f($x);}'
+          finds functions with inline assembly that accesses cr3.
  
  weggli automatically unwraps expression statements in the query source 
  to search for the inner expression instead. This means that the query `{func($x);}` 
@@ -323,6 +1868,11 @@ strict:   Enable stricter matching. This turns off statement unwrapping and gree
  Similarly, `void func($t $param)` will also match function definitions 
  with multiple parameters. 
  
+ Function declarations like `_ $func(char *, size_t $len);` are signature
+ queries: They match on prototypes (for example in header files) as well as
+ on the signature of function definitions. Wrap the declaration in a block
+ (`{_ $func(char *, size_t);}`) to only search for local declarations.
+ 
  Additional patterns can be specified using the --pattern (-p) option. This makes
  it possible to search across functions or type definitions.
  ";
@@ -336,6 +1886,105 @@ strict:   Enable stricter matching. This turns off statement unwrapping and gree
  When combining weggli with other tools or preprocessing steps, 
  files can also be specified via STDIN by setting the directory to '-' 
  and piping a list of filenames.
+ ";
+
+    pub const FILES_FROM: &str = "\
+ Search the files listed in FILE, one path per line, e.g. the output of
+ 'git ls-files' or a build manifest. With '-', the list is read from STDIN.
+ Empty lines are ignored, relative paths are resolved against the current
+ directory. The listed files are always searched, regardless of their
+ extension and of ignore files, but --include, --exclude and --diff apply.
+
+ PATH is optional with this option. If it's given as well, it is searched
+ in addition to the listed files:
+
+ git ls-files '*.c' | weggli --files-from - 'memcpy(_, _, _);' ./generated
+";
+
+    pub const PATTERN_CONTEXT: &str = "\
+ Override the number of context lines (see --before, --after and
+ --whole-function) for a single pattern. Patterns are identified by their
+ index: 0 is the main search pattern and 1, 2, .. refer to additional
+ patterns specified with --pattern (-p).
+
+ Examples:
+
+ Show two lines before and after results of the first -p pattern:
+ --pattern-context 1=2,2
+
+ Show the whole function for results of the main pattern:
+ --pattern-context 0=function
+ ";
+
+    pub const LOG_FORMAT: &str = "\
+ Format of log messages written to stderr. 'json' turns log messages
+ into JSON objects (one per line) and additionally emits progress events
+ for monitoring weggli at scale:
+
+ {\"type\":\"event\",\"event\":\"file_started\",\"path\":\"..\"}
+
+ Supported events are started, file_started, file_skipped, parse_error,
+ matches and finished.
+ ";
+
+    pub const MAX_WARNINGS: &str = "\
+ Show at most N distinct warnings. Repeated warnings are always shown
+ only once. How often warnings were repeated, and how many were not
+ shown because of this limit, is reported at the end of the search.
+ ";
+
+    pub const DIFF: &str = "\
+ Only search files that changed in the git working tree since the revision
+ REV (a commit, branch or tag), including staged changes and untracked
+ files. Use --changed-lines to additionally drop results that don't include
+ any of the added or modified lines. For example, to check a pull request:
+
+ weggli --rules rules/ --diff origin/main --changed-lines .
+ ";
+
+    pub const BASELINE: &str = "\
+ Don't report results that are recorded in the baseline FILE, so that only
+ new results are reported when weggli is introduced on an existing code base.
+ Create or replace the baseline with all current results using:
+
+ weggli --rules rules/ --baseline baseline.json --update-baseline .
+
+ Results are identified by their rule (or pattern index), path and matched
+ code, so they are still recognized if the code around them changes. If the
+ same code is recorded n times in a file, at most n of its results are
+ suppressed, so that new copies of it are still reported.
+
+ Single results can be suppressed with a comment in the code on the line
+ before them. Without IDs, all results on the next line are suppressed:
+
+ // weggli-ignore[rule-id, other-rule]
+ ";
+
+    pub const RAW_TSQUERY: &str = "\
+ Search with a raw tree-sitter query instead of a weggli PATTERN, for
+ node-level searches that patterns can't express. The only positional
+ argument is then the search path. Every capture @name binds the query
+ variable $name, which can be used with --regex, --filter and the output
+ formats. Multiple patterns in SEXP are alternatives, and the text
+ predicates #eq?, #not-eq?, #match? and #not-match? are supported:
+
+ --raw-tsquery '(call_expression function: (identifier) @f (#match? @f \"^str\"))'
+
+ Node kinds are those of the C grammar, or the C++ grammar with --cpp.
+ Use 'weggli try' with -v to see the parse tree of a snippet.
+ ";
+
+    pub const NO_IGNORE: &str = "\
+ Search all files, including those excluded by .gitignore, .ignore or
+ .weggliignore files. By default, weggli skips them like git would, also
+ outside of git repositories. .weggliignore files use the same syntax as
+ .gitignore and exclude files only from weggli searches, e.g. vendored code:
+
+ third_party/
+ *_generated.c
+
+ Hidden files and directories are skipped either way. Files passed
+ directly (or as a list on STDIN) are always searched.
  ";
 
     pub const REGEX: &str = "\
@@ -353,6 +2002,652 @@ strict:   Enable stricter matching. This turns off statement unwrapping and gree
  weggli -R 's!=^size$' 'memcpy(_,_,$s);' 
  ";
 
+    pub const STRING_LENGTH: &str = "\
+ Only report matches where a variable matches a string literal whose
+ length satisfies a comparison (<, <=, >, >=, == or !=). The length
+ is the number of bytes stored by the literal without the terminating
+ NUL byte, so escape sequences such as '\\n' or '\\x41' count as one byte.
+ Variables that match anything but a string literal never satisfy
+ the constraint.
+
+ The right hand side is either a number or another variable. Variables
+ can match a number literal (e.g. an array size) or a string literal,
+ which is compared by length.
+
+ Examples:
+
+ Find format strings longer than 100 bytes:
+ weggli --string-length 'fmt>100' 'printf($fmt);'
+
+ Find string copies that don't fit into the destination buffer:
+ weggli --string-length 'src>=$size' '{char $buf[$size]; strcpy($buf, $src);}'
+ ";
+
+    pub const NO_NORMALIZE: &str = "\
+ weggli normalizes patterns that don't parse or that are not a compound
+ statement: a missing ';' is added at the end ('memcpy(_,_,_)' becomes
+ 'memcpy(_,_,_);') and statements are wrapped into a compound statement
+ ('{memcpy(_,_,_);}'), which matches anywhere inside of a function.
+ The query that is actually run is logged with -v (as a 'normalized'
+ event with --log-format json).
+
+ With --no-normalize, patterns are compiled as written and patterns that
+ would need to be normalized are rejected.
+ ";
+
+    pub const CONSTRAINT: &str = "\
+ Only report matches where the values of two variables are equal ('$a==$b')
+ or different ('$a!=$b'). Values are compared like variables of the same
+ name, so comments and whitespace are ignored. Unlike --unique, which
+ requires all variables to have different values, this only applies to
+ the given pair. The option can be used multiple times.
+
+ Examples:
+
+ Find copies between different buffers, but allow the length to match either of them:
+ weggli --constraint '$dst!=$src' 'memcpy($dst, $src, $len);'
+ ";
+
+    pub const FILTER: &str = "\
+ Only report matches for which EXPR is true. EXPR is evaluated against the
+ values captured by the query variables (normalized unless --raw-values is
+ set) and can use:
+
+ $var, \"text\", 42       variable values, strings and number literals
+ len(x)                 the length of a value
+ x == y, x != y         equality, numbers are compared by value
+ x < y, <=, >, >=       numeric comparison (false for non-numbers)
+ eq(x, y)               same as x == y
+ match(x, \"regex\")      true if the regex matches the value
+ has($var)              true if the result binds the variable
+ and, or, not, ( )      combine conditions
+
+ A value on its own is true if it is not empty and not 0. Variables that
+ are not bound by a result are false in all comparisons. With multiple
+ patterns, each result is filtered on its own before the results are
+ joined. The option can be used multiple times, all filters need to match.
+
+ Examples:
+
+ weggli --filter 'len($buf) > 4 and not eq($src, $dst)' \\
+     'char $buf[_]; memcpy($dst, $src, _);' .
+ weggli --filter 'match($fn, \"^str\") or len($dst) < 3' '$fn($dst, _, _);' .
+";
+
+    pub const NUM_CONSTRAINT: &str = "\
+ Only report matches where the number literal matched by a variable
+ satisfies a comparison, such as '$size>=4096' or 'len<0x10'. Supported
+ operators are <, <=, >, >=, == and !=. Number literals are compared by
+ value, so hex, octal and binary literals and suffixes are supported.
+ A constrained variable only matches number literals (or any expression
+ in array sizes and case labels, which then has to be a number literal).
+
+ The constraint can also be written inline in the pattern: '$size[>4096]'.
+
+ Examples:
+
+ Find large stack buffers:
+ weggli --num-constraint 'n>4096' '{char $buf[$n];}'
+
+ Find memcpy calls with a huge constant length:
+ weggli 'memcpy(_, _, $len[>=0x10000]);'
+ ";
+
+    pub const AUTO_LANG: &str = "\
+ Pick the C or C++ grammar separately for each file instead of using
+ a single mode for the whole run. .c files are parsed as C, well known
+ C++ extensions (.cc, .cpp, .cxx, .hpp, ..) as C++. For headers and other
+ files, weggli looks for C++ only constructs such as '::', 'class' or 'template'.
+
+ Search patterns are compiled for both grammars. Patterns that are only
+ valid in one of them are only executed against files of that language.
+ By default, the extension sets of C and C++ mode are combined.
+ ";
+
+    pub const INVERT_MATCH: &str = "\
+ Print the name and location of all functions that do NOT contain a
+ match for the search pattern.
+
+ Additional patterns specified with --pattern (-p) act as a function filter:
+ Only functions that contain a match for all of them are listed.
+ For example, the following command lists functions that call
+ copy_from_user, but never call capable():
+
+ weggli --invert-match 'capable(_);' -p 'copy_from_user(_);' ./src
+ ";
+
+    pub const COMPLEXITY: &str = "\
+ Only report matches whose enclosing function has a cyclomatic
+ complexity in the given range.
+
+ The complexity is approximated as 1 + the number of if/for/while/do
+ statements, case labels, ternary operators, catch clauses and
+ short-circuiting && / || operators in the function.
+ ";
+
+    pub const DEDUP_SNIPPETS: &str = "\
+ Collapse matches whose matched code is identical (ignoring whitespace),
+ for example in copy-pasted or vendored code. Only the first match is
+ shown, followed by the locations of all duplicates.
+
+ Results are printed once the search has finished.
+ ";
+
+    pub const LIST_VARIABLES: &str = "\
+ Once the search has finished, print every query variable with the distinct
+ values it matched and the number of results for each value, for example
+
+ $func: memcpy (57), memmove (3)
+
+ This gives a quick overview of the called functions or passed arguments
+ without reading through all results. Values are listed in order of their
+ count and compared without comments and whitespace (see --raw-values).
+ The counts include all results, also with --sample or --dedup-snippets.
+
+ With --format json, the counts are printed as a final JSON object of the
+ form {\"variables\": {\"$func\": {\"memcpy\": 57, ..}}}.
+ With --format sarif, the list is printed to stderr.
+ ";
+
+    pub const AGGREGATE: &str = "\
+ Don't print matches. Instead, count how many matches bound each distinct
+ value of the variable VAR and print the values sorted by their count once
+ the search has finished. For example,
+
+ weggli --aggregate func '_ $buf[_]; $func($buf, _, $len);' ./src
+
+ shows which functions are most often called with a stack buffer.
+ Values are compared without comments and whitespace (see --raw-values).
+
+ With --format json, every value is printed as a JSON object with the
+ fields value and count.
+ ";
+
+    pub const GROUP_BY: &str = "\
+ Collect all matches and print them once the search has finished, in one
+ section per distinct value of the variable VAR. For example,
+
+ weggli --group-by func '_ $buf[_]; $func($buf, _, $len);' ./src
+
+ prints all memcpy calls together, then all strcpy calls and so on.
+ Sections with the most matches come first, matches that don't bind VAR
+ (e.g. of another pattern) last. Values are compared without comments
+ and whitespace (see --raw-values).
+
+ With --format json, every match gets a group field with the value.
+ Other formats (e.g. oneline or csv) print the matches in the same order,
+ but without section headers.
+ ";
+
+    pub const MAX_FILESIZE: &str = "\
+ Skip files larger than SIZE bytes, with a warning. SIZE can use the
+ suffixes K, M and G. Large machine-generated files (for example
+ amalgamated sources or embedded data) can take a long time to parse
+ and stall a worker thread. The default is 10M, use --force-large to
+ search files of any size.
+ ";
+
+    pub const TIMEOUT_PER_FILE: &str = "\
+ Give up on a file once parsing it, or running the queries on it, took
+ longer than SECS seconds (fractions like 0.5 are allowed). Results of the
+ query that timed out are not reported for the file. Pathological generated
+ files can otherwise stall the whole search for minutes. Skipped files are
+ listed after the search.
+";
+
+    pub const CONTEXT_STATEMENTS: &str = "\
+ Instead of raw context lines, print the N statements before and after
+ each statement that contains a captured node. The statements are taken
+ from the syntax tree, so a statement that is wrapped over several lines
+ or a macro invocation spanning a whole block counts as one. Only
+ statements of the same block are shown. Context lines (--before,
+ --after) default to 0 with this option, but can still be added.
+
+ Example:
+
+ weggli --context-statements 2 'memcpy(_, _, _);' src
+";
+
+    pub const FUNCTION_HEADER: &str = "\
+ Each match is printed below the first line of the function (or other
+ node, see --anchor) that contains it. MODE changes this header:
+
+ line        the first line of the function (default)
+ signature   the whole signature up to the function body, e.g. for
+             parameter lists or K&R declarations spanning multiple lines
+ name        only the name of the function
+ none        no header, only the matched lines and their context
+
+ Nodes that aren't function definitions always use the first line with
+ signature and name. A header that contains a matched node is printed
+ as it is.
+";
+
+    pub const THREADS: &str = "\
+ Limit the number of threads used for the search. --threads N sets the
+ size of the shared thread pool used for all parallel work, the default
+ is one thread per CPU. --parse-threads and --query-threads move parsing
+ files and running queries on them into pools of their own, for example
+ to give the (usually more expensive) queries more threads than parsing.
+ The total is then the sum of both (plus a few mostly idle threads).
+ 0 means one thread per CPU.
+";
+
+    pub const SHARD: &str = "\
+ Split a scan of a large corpus across N machines.
+
+ --shard K/N only searches the K-th of N partitions of the input files.
+ Files are assigned based on their path relative to the search directory,
+ so every machine needs to search the same directory layout.
+
+ With a single pattern, the output of all shards can simply be concatenated.
+ When using multiple patterns, matches need to be joined across all shards:
+ Use --save-results FILE to store the unfiltered results of each shard and
+ combine them with 'weggli merge-results FILE...'.
+
+ weggli --shard 1/2 --save-results shard1.json 'free($x);' -p 'use($x);' .
+ weggli --shard 2/2 --save-results shard2.json 'free($x);' -p 'use($x);' .
+ weggli merge-results shard1.json shard2.json
+ ";
+
+    pub const QUIET: &str = "\
+ Don't print any results and exit with status 0 as soon as the first match
+ is found (after all filters). Errors are still reported on stderr.
+
+ Like grep, weggli always exits with 0 if there were matches, 1 if there
+ were none (or no files to search), and 2 on errors, so --quiet is only
+ needed to skip the output and the rest of the search:
+
+ weggli -q 'strcpy(_, _);' src && echo 'strcpy is not allowed'
+";
+
+    pub const FILE_REPORT: &str = "\
+ Print files instead of results, like grep:
+
+ -c, --count                 path:N for each file with N matches
+ --files-with-matches        the path of each file with matches
+ -L, --files-without-match   the path of each searched file without matches
+
+ (-l is short for --limit.) Files are printed in order once the search is
+ done. With --format json, one object with the path (and count) is printed
+ per file. Matches are counted after all filters, and with multiple
+ patterns after joining their results. Files that were skipped (see
+ --max-filesize, --timeout-per-file) are never listed as files without
+ matches.
+";
+
+    pub const SAMPLE_FILES: &str = "\
+ Search a random subset of the input files, to quickly try out a new rule
+ on a huge corpus before committing to a full scan. The value is either a
+ number of files (--sample-files 200) or a percentage of them
+ (--sample-files 5%). A different sample is chosen on every run. The
+ sample is taken after --include, --exclude and --shard are applied.
+";
+
+    pub const JOIN: &str = "\
+ When searching with multiple patterns, a match is only reported if it can be
+ combined with a match for every other pattern. By default, a variable that is
+ used by two patterns needs to have the same value in both matches and is
+ ignored if it's only bound in one of them.
+
+ --join VAR=MODE changes this for a single variable:
+
+ equal     Values need to be equal if bound in both matches (default).
+ required  The variable needs to be bound in both matches with equal values.
+ ignore    The variable is not used for joining.
+ differ    Values need to be different if bound in both matches.
+ exact     Like equal, but comments and whitespace are not ignored.
+
+ The following command finds functions that call both lock() and unlock(),
+ but on different objects:
+
+ weggli --join x=differ '{lock($x);}' -p '{unlock($x);}' .
+ ";
+
+    pub const SAME_FUNCTION: &str = "\
+ With multiple patterns (-p), results of different patterns are only
+ combined if their enclosing functions are the same, in addition to
+ having compatible variable values. This finds functions that match all
+ patterns without writing a single compound pattern.
+
+ Example: functions that free a pointer and also use a variable of the
+ same name:
+
+ weggli --same-function 'free($p);' -p '$p->_;' .
+ ";
+
+    pub const STREAM_JOIN: &str = "\
+ With multiple patterns (-p), results are normally collected until the
+ search is done and joined afterwards. With --stream-join, a result is
+ reported as soon as every other pattern has a compatible result, so
+ results of large scans show up early. Instead of the parsed source
+ files, only the distinct variable values of all results and the results
+ that are still waiting for a compatible result are kept in memory.
+
+ At most --stream-join-limit results (default: 100000) wait for a
+ compatible result. Beyond that, the oldest waiting results are dropped
+ and the number of dropped results is reported at the end, which keeps
+ the memory use of runs with many unmatched results bounded.
+
+ Results are reported in the order they can be combined. With more than
+ two patterns, results can differ slightly from the default: A result is
+ kept if it can be combined with a result of every other pattern, even
+ if that result is itself dropped by a third pattern.
+ ";
+
+    pub const METRICS: &str = "\
+ Add simple metrics of the function enclosing each match to JSON and SARIF
+ results, so that findings can be prioritized by code complexity:
+
+ lines       Number of lines.
+ callees     Number of distinct functions that are called.
+ conditions  Number of conditions (if statements, loops and ?: expressions).
+ complexity  Cyclomatic complexity (see --min-complexity).
+
+ JSON results get a \"metrics\" object, SARIF results store them in
+ properties.metrics.
+ ";
+
+    pub const USE_INDEX: &str = "\
+ Use the index created with 'weggli index' to skip files without reading
+ them. The index stores the size, modification time, content hash and the
+ identifiers of every file. Files that don't contain the identifiers used
+ in any pattern are skipped, all other files (and files that changed or are
+ not part of the index) are searched as usual. Without a value, the index in
+ PATH/.weggli is used:
+
+ weggli index ~/linux
+ weggli --use-index '{kfree($x); not: $x = _; kfree($x);}' ~/linux
+ weggli --use-index=/tmp/linux-index 'memcpy(_, _, sizeof(_))' ~/linux
+
+ Running 'weggli index' again only re-reads files that were modified.
+ ";
+
+    pub const NO_MATCH_CACHE: &str = "\
+ Remember the files in which the search patterns matched nothing and skip
+ them without reading them in later runs with the same patterns, as long
+ as their size and modification time don't change. This makes repeated
+ runs of the same patterns or rule pack on a large code base much faster,
+ e.g. while triaging results:
+
+ weggli --no-match-cache ~/.cache/weggli --rules rules/ ~/src/project
+
+ The cache is specific to the compiled patterns (including regexes and
+ other constraints), the --cpp/--auto-lang mode and the weggli version.
+ Only files without matches are cached, results are not stored: files with
+ matches are always searched again, so the output is the same with and
+ without the cache. This is also why the option can't be combined with
+ --invert-match. DIR is created if it doesn't exist.
+";
+
+    pub const EXPLAIN_RESULTS: &str = "\
+ Helps to debug complex patterns by showing which sub-pattern produced each
+ captured node of a result. Sub-patterns are the statements of a compound
+ pattern ({..}) or the whole pattern if it is a single statement or
+ expression. In text mode, the captures are listed after each result:
+
+ explanation:
+     12: $buf = dst                      from char $buf[_];
+     15: memcpy                          from memcpy($buf, _, _);
+
+ JSON results get an \"explanation\" array (SARIF results store it in
+ properties.explanation) with the offsets, line/column, variable, value and
+ sub-pattern of every capture.
+ ";
+
+    pub const ANCHOR: &str = "\
+ Select the node that matches of a compound pattern ({..}) are anchored to.
+ All statements of the pattern have to match inside the anchor, which is
+ also the code that is printed with --whole-function.
+
+ function  The enclosing function definition (default). Patterns for a
+           declaration with an initializer list also match global
+           declarations, which are anchored to themselves. This finds
+           static tables such as
+           'static const struct file_operations $o = { .read = $f };'
+ class     The enclosing class, struct or union. Matches in functions
+           that are defined outside of a class body are not reported.
+ file      The whole file. Statements are also matched at global scope,
+           for example '{static char $buf[_];}' finds global buffers.
+ compound  The innermost {..} block that contains the match.
+
+ Patterns that are not a compound statement, such as function or struct
+ definitions, are always anchored to the matched node.
+ ";
+
+    pub const REWRITE: &str = "\
+ Replace the code matched by PATTERN with TEMPLATE. Variables ($x) in
+ TEMPLATE are substituted with the code they matched. The pattern needs
+ to consist of a single statement or expression, which is the code that
+ gets replaced.
+
+ By default, the changes are printed as a unified diff. Use --in-place
+ to modify the files instead. Overlapping matches are only rewritten
+ once, so the outermost match wins.
+
+ Example:
+
+ weggli --rewrite 'memcpy_s($dst, $len, $src, $len)' 'memcpy($dst, $src, $len);' .
+ ";
+
+    pub const RULES: &str = "\
+ Load rules from a YAML (.yml, .yaml) or TOML (.toml) file, or from all
+ rule files in a directory, and run them in a single scan. PATTERN is
+ omitted in this mode, so the only positional argument is the PATH to
+ search. Every rule is an independent query and is reported with its ID.
+
+ A rule file contains a single rule with the following fields:
+
+ id           Unique rule ID (required).
+ pattern      weggli search pattern (required).
+ severity     error, warning (default) or note.
+ description  Short description of the issue.
+ regex        List of regex constraints (var=regex, see --regex).
+ before       Lines to print before a result (default: --before).
+ after        Lines to print after a result (default: --after).
+ whole_function
+              Print the whole function for each result (true/false).
+
+ --pattern-context overrides the context of a rule by its index.
+
+ Example (unchecked-malloc.yml):
+
+ id: unchecked-malloc
+ severity: warning
+ description: Result of malloc is used without a NULL check.
+ pattern: '{ $p = malloc(_); not: if (<+$p+>) _; $p[_] = _; }'
+
+ Run it with:
+
+ weggli --rules rules/ ./src
+
+ By default, weggli exits if the pattern or regex constraints of a rule are
+ invalid. With --skip-broken-rules, these rules are skipped and reported
+ after the search, so a rule pack written for another weggli version can
+ still be used.
+ ";
+
+    pub const IMPORT_SEMGREP: &str = "\
+ Convert the C and C++ rules in a Semgrep rule file into weggli rule files
+ that can be used with --rules. Rules that can't be expressed in weggli's
+ query language are skipped and listed with the reason.
+
+ Supported are rules with a single pattern, or with patterns consisting of
+ one pattern, pattern-not and metavariable-regex entries. Patterns can use
+ metavariables ($X), '...' between statements, at the end of argument lists,
+ as if/while conditions and bodies, and deep expressions (<... $X ...>).
+ pattern-not is only supported if the negated pattern is the pattern with
+ one additional statement, which is translated into a not: statement.
+
+ weggli matches calls with additional arguments, so translated patterns can
+ be less strict than the original rule.
+ ";
+
+    pub const TRY: &str = "\
+ Run PATTERN against the code passed with --against instead of searching
+ files, and print the results together with a table of all captured nodes:
+ their position, the variable they are bound to, their node kind and text.
+ This is meant for learning the query language and debugging patterns.
+
+ Example:
+
+ weggli try 'memcpy($dst, _, $len);' --against 'int f() { memcpy(a, b, c); }'
+ ";
+
+    pub const FUNCTIONS: &str = "\
+ List every function definition below PATH, sorted by file and line:
+
+ path:first-last: signature
+
+ The signature is everything before the function body, with whitespace
+ collapsed. With --json, one object per function is printed instead, with
+ the same fields as results in --format targets plus the signature.
+
+ Example:
+
+ weggli functions --json src > functions.jsonl
+";
+
+    pub const FORMAT: &str = "\
+ Output format for results:
+
+ text   Human readable output (default).
+ json   One JSON object per result, see --json.
+ sarif  A single SARIF 2.1.0 log, written once the search is done.
+        Each pattern is reported as a rule (weggli/pattern-0 for the
+        main pattern, weggli/pattern-1.. for -p patterns). Results
+        contain the location and source lines of the matched code.
+        The report can be uploaded to GitHub code scanning.
+ targets
+        The function enclosing each result as a JSON object with the
+        fields path, query, function (the name, or null if the result
+        is not inside a function definition), start and end (byte
+        offsets of the function), line and end_line. This is meant
+        as input for fuzzing harness generators and coverage tools.
+        Use --limit to report every function only once.
+ oneline
+        path:line:col: code with the source line of every matched
+        statement, like grep and ripgrep. Meant for editor quickfix
+        lists and other line oriented tools. See --only-matching.
+ csv    A header row followed by one row per result with the columns
+        path, line and the value of every query variable (sorted by
+        name, empty if a result doesn't bind it). Values are
+        normalized unless --raw-values is set. Meant for importing
+        results into a spreadsheet.
+ tsv    Same as csv, but tab separated. Tabs and line breaks in
+        values are replaced with spaces.
+ github
+        GitHub Actions workflow commands (::warning file=..,line=..::)
+        that show results as annotations of pull requests. Results of
+        rules use the severity of the rule (error, warning or notice).
+ gitlab-codequality
+        A GitLab Code Quality report (a JSON array of issues), written
+        once the search is done. Upload it as a codequality report
+        artifact to show results in merge requests.
+ ";
+
+    pub const OUTPUT_FORMAT: &str = "\
+ Print every result on its own line by filling in TEMPLATE instead of
+ using --format. Fields in braces are replaced with values of the result:
+
+ {file}      path of the file
+ {line}      line of the result (the start of the enclosing function)
+ {column}    column of the result
+ {function}  name of the enclosing function
+ {rule}      id of the rule that produced the result (--rules)
+ {snippet}   the matched lines, with whitespace collapsed
+ {$var}      value of a query variable (normalized unless --raw-values is set)
+
+ Fields without a value, such as variables of another pattern, are empty.
+ Use {{ and }} for literal braces and \\t and \\n for tabs and line breaks:
+
+ weggli --output-format '{file}:{line}\\t{$fn}\\t{$size}' \\
+     '$fn(_, _, $size);' src
+";
+
+    pub const ONLY_MATCHING: &str = "\
+ Only print the matched statements instead of the enclosing function,
+ e.g. the whole call for 'memcpy($dst, $src, $len);'. Matches in the
+ condition of an if or a loop print the whole statement.
+
+ With --format oneline, every matched statement is printed on a line of
+ its own (path:line:col: code), with its whitespace collapsed.
+";
+
+    pub const BLAME: &str = "\
+ Look up the last commit that changed the matched lines of each result
+ with git blame and add it to the result: the commit id, author, email
+ and date (in UTC) with --format json, or the same fields in the
+ properties of SARIF results. Uncommitted changes are reported with a
+ null commit, files that are not tracked by git with a null blame.
+
+ The search gets much slower for results in files with a long history,
+ so this is best combined with a narrow pattern or --rules.
+";
+
+    pub const OUTPUT: &str = "\
+ Write results to FILE instead of stdout. Results are written as soon
+ as they are found, using --format json unless another format is
+ selected.
+
+ If FILE ends in .zst, the output is compressed with zstd. It is
+ written as a sequence of independent frames, so the results found so
+ far can still be decompressed if the search is interrupted.
+
+ Example:
+
+ weggli --output results.json.zst 'memcpy(_, _, $n);' ./src
+ zstd -dc results.json.zst | jq .path
+ ";
+
+    pub const JSON: &str = "\
+ Same as --format json.
+ Print every result as a JSON object on a separate line instead of
+ human readable text. Each object contains the following fields:
+
+ path      Path of the source file.
+ query     Index of the pattern (0 for the main pattern, 1.. for -p).
+ line      Line of the first line of the match (1-based).
+ column    Column of the start of the match (1-based, in bytes).
+ start     Start offset of the match (usually the enclosing function).
+ end       End offset of the match.
+ captures  Start/end offsets and line/column of all captured nodes.
+ snippet   Source lines of the matched code.
+ vars      Values of all query variables.
+ metrics   Metrics of the enclosing function (only with --metrics).
+ explanation
+           Sub-pattern of each capture (only with --explain-results).
+
+ With --invert-match, objects only contain path, line and function.
+ ";
+
+    pub const COLOR: &str = "\
+ When to use colors. With auto (the default), colors are used if the output
+ goes to a terminal. Set NO_COLOR or CLICOLOR=0 to disable them and
+ CLICOLOR_FORCE=1 to enable them even if the output is redirected.
+ -C or --color without a value always enables colors.
+ ";
+
+    pub const FALLBACK_CPP: &str = "\
+ Don't exit if a pattern is invalid in C mode, but valid in C++ mode.
+ Instead, the pattern is compiled for the C++ grammar and the searched
+ files are additionally parsed as C++ for this pattern. All other
+ patterns still use C mode.
+ ";
+
+    pub const DUAL_HEADERS: &str = "\
+ Header files (.h) are shared between C and C++ code, so whichever mode
+ is used, some of them are parsed with the wrong grammar and matches in
+ them can be missed. With this option, .h files are searched in both C
+ and C++ mode and results found in both are only reported once. All other
+ files are still searched in the selected mode (or the detected one with
+ --auto-lang).
+
+ Patterns are compiled for both grammars, but need to be valid in the
+ selected mode. Patterns that are only valid in the other mode are only
+ run on headers.
+";
+
     pub const UNIQUE: &str = "\
  Enforce uniqueness of variable matches.
  By default, two variables such as $a and $b can match on identical values.
@@ -368,5 +2663,27 @@ strict:   Enable stricter matching. This turns off statement unwrapping and gree
  memcpy(buf, src, size);
  
  Using the unique flag would filter out the first match as $a==$b.
+ Comments and whitespace in the values are ignored (see --raw-values).
+ ";
+
+    pub const RAW_VALUES: &str = "\
+ By default, comments and whitespace are removed from captured values before
+ they are compared for --unique, --unique-by and when joining the results of
+ multiple patterns, so 'buf /* dst */' and 'buf' or 's -> len' and 's->len'
+ are the same value. With --raw-values, values are compared as written.
+ Number literals are still compared by value.
+ The values of a variable within a single pattern are always compared
+ without comments and whitespace.
+ ";
+
+    pub const UNIQUE_BY: &str = "\
+ Only show the first match for each distinct value of a variable.
+ This is useful to condense inventory-style queries. For example,
+
+ weggli --unique-by func '$func(_, _, $len);' ./src
+
+ shows a single example call site for each called function.
+ If the option is given multiple times, matches are deduplicated based on
+ the combination of all variable values.
  ";
 }
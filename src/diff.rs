@@ -0,0 +1,133 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Incremental searches (--diff): the files and lines that changed in the
+//! working tree since a git revision, looked up with libgit2.
+
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+use git2::{DiffLineType, DiffOptions, Repository};
+
+pub struct Changes {
+    // Canonical path -> added or modified lines (1-based) in the working tree version
+    files: HashMap<PathBuf, Vec<RangeInclusive<usize>>>,
+}
+
+impl Changes {
+    /// Find all changes between `rev` and the working tree of the repository
+    /// containing `path`, including staged changes and untracked files.
+    pub fn since(path: &Path, rev: &str) -> Result<Changes, String> {
+        let repo = Repository::discover(path).map_err(|e| e.message().to_string())?;
+        let workdir = repo
+            .workdir()
+            .ok_or("bare repositories can't be searched")?
+            .canonicalize()
+            .map_err(|e| e.to_string())?;
+        let tree = repo
+            .revparse_single(rev)
+            .and_then(|o| o.peel_to_tree())
+            .map_err(|e| format!("{}: {}", rev, e.message()))?;
+
+        let mut opts = DiffOptions::new();
+        opts.include_untracked(true)
+            .recurse_untracked_dirs(true)
+            .show_untracked_content(true)
+            .context_lines(0);
+        let diff = repo
+            .diff_tree_to_workdir_with_index(Some(&tree), Some(&mut opts))
+            .map_err(|e| e.message().to_string())?;
+
+        // Deleted files are skipped, changed files without added lines are kept
+        let mut lines: HashMap<PathBuf, Vec<usize>> = diff
+            .deltas()
+            .filter(|d| d.new_file().exists())
+            .filter_map(|d| d.new_file().path().map(|p| (workdir.join(p), Vec::new())))
+            .collect();
+        diff.foreach(
+            &mut |_, _| true,
+            None,
+            None,
+            Some(&mut |delta, _, line| {
+                if let (DiffLineType::Addition, Some(n), Some(p)) = (
+                    line.origin_value(),
+                    line.new_lineno(),
+                    delta.new_file().path(),
+                ) {
+                    lines.entry(workdir.join(p)).or_default().push(n as usize);
+                }
+                true
+            }),
+        )
+        .map_err(|e| e.message().to_string())?;
+
+        let files = lines
+            .into_iter()
+            .map(|(path, mut lines)| {
+                lines.sort_unstable();
+                let mut ranges: Vec<RangeInclusive<usize>> = Vec::new();
+                for n in lines {
+                    match ranges.last_mut() {
+                        Some(r) if *r.end() + 1 >= n => *r = *r.start()..=n,
+                        _ => ranges.push(n..=n),
+                    }
+                }
+                (path, ranges)
+            })
+            .collect();
+        Ok(Changes { files })
+    }
+
+    /// Returns the changed files inside `root` with one of the `extensions`,
+    /// as paths relative to `root` (like the files found by walking `root`).
+    pub fn files_in(&self, root: &Path, extensions: &[String]) -> Vec<PathBuf> {
+        let canonical = match root.canonicalize() {
+            Ok(p) => p,
+            Err(_) => return Vec::new(),
+        };
+        let mut files: Vec<PathBuf> = self
+            .files
+            .keys()
+            .filter(|p| {
+                p.extension()
+                    .and_then(|e| e.to_str())
+                    .map_or(false, |e| extensions.iter().any(|x| x == e))
+            })
+            .filter_map(|p| p.strip_prefix(&canonical).ok())
+            .map(|p| {
+                if p.as_os_str().is_empty() {
+                    root.to_path_buf()
+                } else {
+                    root.join(p)
+                }
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    /// Returns true if `path` changed.
+    pub fn contains(&self, path: &Path) -> bool {
+        self.lines(path).is_some()
+    }
+
+    /// Returns the changed lines of `path`, or None if it didn't change.
+    pub fn lines(&self, path: &Path) -> Option<&[RangeInclusive<usize>]> {
+        let path = path.canonicalize().ok()?;
+        self.files.get(&path).map(Vec::as_slice)
+    }
+}
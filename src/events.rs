@@ -0,0 +1,76 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Machine readable log output (--log-format json).
+//! Log messages and progress events are written to stderr as one JSON object per line.
+
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+struct JsonLogger {
+    level: LevelFilter,
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "{}",
+                json!({
+                    "type": "log",
+                    "level": record.level().to_string(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                })
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Enable progress events and return the JSON logger.
+pub fn init(level: LevelFilter) -> Box<dyn Log> {
+    ENABLED.store(true, Ordering::Relaxed);
+    Box::new(JsonLogger { level })
+}
+
+/// Returns true if progress events should be emitted.
+/// Callers should check this before building an event to keep the
+/// hot path cheap when JSON logging is disabled.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Emit the progress event `event` with additional `fields`.
+pub fn emit(event: &str, fields: Value) {
+    if !enabled() {
+        return;
+    }
+
+    let mut v = json!({"type": "event", "event": event});
+    if let (Some(v), Value::Object(fields)) = (v.as_object_mut(), fields) {
+        v.extend(fields);
+    }
+    eprintln!("{}", v);
+}
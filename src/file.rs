@@ -0,0 +1,219 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Loading and parsing of the searched files, shared by every search over files
+//! so that they agree on which files are skipped and how they are parsed.
+//! Larger files are memory mapped instead of being read, so files that are skipped
+//! by the identifier prefilter are never copied. Only files that are parsed are
+//! turned into text.
+
+use memmap2::Mmap;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use thread_local::ThreadLocal;
+use tree_sitter::{Parser, Tree};
+
+use crate::search::long_path;
+
+// Reading small files is cheaper than setting up a mapping.
+const MMAP_THRESHOLD: u64 = 64 * 1024;
+
+/// The raw contents of a file.
+pub(crate) enum Contents {
+    Mapped(Mmap),
+    Read(Vec<u8>),
+}
+
+impl Contents {
+    pub fn open(path: &Path) -> io::Result<Contents> {
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        if size >= MMAP_THRESHOLD {
+            // Safety: The mapping is read-only and only accessed as bytes, the text
+            // is copied out of it (see into_source). Truncating a file while it is
+            // mapped makes accessing the missing pages raise SIGBUS, a risk that we
+            // only take for files that are large enough to benefit from the mapping.
+            if let Ok(mmap) = unsafe { Mmap::map(&file) } {
+                return Ok(Contents::Mapped(mmap));
+            }
+        }
+        let mut buf = Vec::with_capacity(size as usize);
+        file.read_to_end(&mut buf)?;
+        Ok(Contents::Read(buf))
+    }
+
+    /// Returns the contents as text. Invalid UTF-8 sequences are replaced.
+    /// Mapped contents are always copied, read contents only if they are not
+    /// valid UTF-8.
+    pub fn into_source(self) -> String {
+        match self {
+            Contents::Mapped(m) => String::from_utf8_lossy(&m).into_owned(),
+            Contents::Read(v) => match String::from_utf8(v) {
+                Ok(s) => s,
+                Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+            },
+        }
+    }
+}
+
+impl Deref for Contents {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Contents::Mapped(m) => m,
+            Contents::Read(v) => v,
+        }
+    }
+}
+
+/// A file parsed by `Loader`.
+pub struct ParsedFile {
+    pub source: Arc<String>,
+    pub tree: Tree,
+    /// The file was parsed with the C++ grammar.
+    pub cpp: bool,
+}
+
+/// Why `Loader` didn't parse a file, or didn't parse it with one of its grammars.
+#[derive(Debug)]
+pub enum Skipped {
+    /// The file is larger than the maximum file size, in bytes.
+    TooLarge(u64),
+    /// The file couldn't be read.
+    Unreadable(io::Error),
+    /// No grammar was selected for the file, usually because it doesn't contain
+    /// the identifiers of any query.
+    NoPotentialMatch,
+    /// Parsing took longer than the timeout.
+    Timeout,
+}
+
+impl std::fmt::Display for Skipped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Skipped::TooLarge(size) => write!(f, "file with {} bytes is too large", size),
+            Skipped::Unreadable(e) => write!(f, "{}", e),
+            Skipped::NoPotentialMatch => write!(f, "no potential match"),
+            Skipped::Timeout => write!(f, "parsing timed out"),
+        }
+    }
+}
+
+/// Reads and parses files, from multiple threads.
+#[derive(Default)]
+pub struct Loader {
+    max_filesize: Option<u64>,
+    timeout: Option<Duration>,
+    // One parser per thread and language, created when the first file in the language is parsed.
+    parsers: ThreadLocal<RefCell<HashMap<&'static str, Parser>>>,
+}
+
+impl Loader {
+    /// Skip files larger than `max_filesize` bytes without reading them.
+    pub fn with_max_filesize(mut self, max_filesize: Option<u64>) -> Loader {
+        self.max_filesize = max_filesize;
+        self
+    }
+
+    /// Give up on files that take longer than `timeout` to parse.
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Loader {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Read the file at `path` and parse it with every grammar (false for C, true for C++)
+    /// that `languages` returns for its raw contents. Files without a grammar are skipped
+    /// before they are converted to text. Returns the parsed file for each grammar, or the
+    /// reason why the file or one of its grammars was skipped.
+    pub fn load(
+        &self,
+        path: &Path,
+        languages: impl FnOnce(&[u8]) -> Vec<bool>,
+    ) -> Vec<Result<ParsedFile, Skipped>> {
+        let path = long_path(path);
+        if let Some(max) = self.max_filesize {
+            let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            if size > max {
+                return vec![Err(Skipped::TooLarge(size))];
+            }
+        }
+
+        let contents = match Contents::open(&path) {
+            Ok(c) => c,
+            Err(e) => return vec![Err(Skipped::Unreadable(e))],
+        };
+        let languages = languages(&contents);
+        if languages.is_empty() {
+            return vec![Err(Skipped::NoPotentialMatch)];
+        }
+        self.parse_source(Arc::new(contents.into_source()), &languages)
+    }
+
+    /// Like `load`, for the `contents` of a file that is already in memory.
+    pub fn parse(
+        &self,
+        contents: &[u8],
+        languages: impl FnOnce(&[u8]) -> Vec<bool>,
+    ) -> Vec<Result<ParsedFile, Skipped>> {
+        let languages = languages(contents);
+        if languages.is_empty() {
+            return vec![Err(Skipped::NoPotentialMatch)];
+        }
+        let source = String::from_utf8_lossy(contents).into_owned();
+        self.parse_source(Arc::new(source), &languages)
+    }
+
+    fn parse_source(
+        &self,
+        source: Arc<String>,
+        languages: &[bool],
+    ) -> Vec<Result<ParsedFile, Skipped>> {
+        let mut parsers = self.parsers.get_or(Default::default).borrow_mut();
+        languages
+            .iter()
+            .map(|&cpp| {
+                let language = crate::language::for_cpp(cpp);
+                let parser = parsers.entry(language.name()).or_insert_with(|| {
+                    let mut parser = language.parser();
+                    if let Some(timeout) = self.timeout {
+                        parser.set_timeout_micros(timeout.as_micros() as u64);
+                    }
+                    parser
+                });
+                match language.try_parse_with(parser, &source) {
+                    Some(tree) => Ok(ParsedFile {
+                        source: source.clone(),
+                        tree,
+                        cpp,
+                    }),
+                    None => {
+                        // Otherwise the parser would continue with this file next time.
+                        parser.reset();
+                        Err(Skipped::Timeout)
+                    }
+                }
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,429 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Post-match filters (--filter): boolean expressions over the captured values
+//! of a result, such as `len($buf) > 4 and not eq($src, $dst)`.
+//!
+//! expr  := and ('or' and)*
+//! and   := unary ('and' unary)*
+//! unary := 'not' unary | '(' expr ')' | term (op term)? | eq(term, term)
+//!        | match(term, "regex") | has($var)
+//! term  := $var | "string" | number | len(term)
+
+use regex::Regex;
+use std::borrow::Cow;
+use weggli::Comparison;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Variable(String),
+    Text(String),
+    Number(i128),
+    Ident(String),
+    Op(Comparison),
+    LParen,
+    RParen,
+    Comma,
+}
+
+#[derive(Debug, Clone)]
+enum Term {
+    Variable(String),
+    Text(String),
+    Number(i128),
+    Len(Box<Term>),
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Term, Comparison, Term),
+    Match(Term, Regex),
+    Has(String),
+    Value(Term),
+}
+
+/// The value of a term for a single result.
+enum Value<'a> {
+    Missing,
+    Text(Cow<'a, str>),
+    Number(i128),
+}
+
+impl Value<'_> {
+    fn number(&self) -> Option<i128> {
+        match self {
+            Value::Missing => None,
+            Value::Text(t) => weggli::parse_number_literal(t),
+            Value::Number(n) => Some(*n),
+        }
+    }
+
+    /// Values that are used as a condition: Missing variables, empty strings
+    /// and zero are false.
+    fn truthy(&self) -> bool {
+        match self {
+            Value::Missing => false,
+            Value::Text(t) => !t.is_empty(),
+            Value::Number(n) => *n != 0,
+        }
+    }
+}
+
+/// A parsed --filter expression.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    expr: Expr,
+}
+
+impl Filter {
+    /// Evaluate the filter. `value` returns the (normalized) value of a
+    /// variable or None if the result doesn't bind it.
+    pub fn check<'a>(&self, value: &dyn Fn(&str) -> Option<Cow<'a, str>>) -> bool {
+        self.expr.eval(value)
+    }
+
+    /// All variables used in the filter.
+    pub fn variables(&self) -> Vec<&str> {
+        let mut variables = Vec::new();
+        self.expr.variables(&mut variables);
+        variables
+    }
+}
+
+impl std::str::FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |msg: String| format!("invalid filter '{}': {}", s, msg);
+        let mut parser = Parser {
+            tokens: tokenize(s).map_err(err)?,
+            pos: 0,
+        };
+        let expr = parser.expr().map_err(err)?;
+        if let Some(t) = parser.peek() {
+            return Err(err(format!("unexpected {}", describe(t))));
+        }
+        Ok(Filter { expr })
+    }
+}
+
+impl Term {
+    fn eval<'a>(&self, value: &dyn Fn(&str) -> Option<Cow<'a, str>>) -> Value<'a> {
+        match self {
+            Term::Variable(v) => value(v).map_or(Value::Missing, Value::Text),
+            Term::Text(t) => Value::Text(Cow::Owned(t.clone())),
+            Term::Number(n) => Value::Number(*n),
+            Term::Len(t) => match t.eval(value) {
+                Value::Missing => Value::Missing,
+                Value::Text(t) => Value::Number(t.chars().count() as i128),
+                Value::Number(n) => Value::Number(n.to_string().len() as i128),
+            },
+        }
+    }
+
+    fn variables<'a>(&'a self, variables: &mut Vec<&'a str>) {
+        match self {
+            Term::Variable(v) => variables.push(v),
+            Term::Len(t) => t.variables(variables),
+            Term::Text(_) | Term::Number(_) => (),
+        }
+    }
+}
+
+impl Expr {
+    fn eval<'a>(&self, value: &dyn Fn(&str) -> Option<Cow<'a, str>>) -> bool {
+        match self {
+            Expr::Or(a, b) => a.eval(value) || b.eval(value),
+            Expr::And(a, b) => a.eval(value) && b.eval(value),
+            Expr::Not(e) => !e.eval(value),
+            Expr::Compare(a, op, b) => {
+                let (a, b) = (a.eval(value), b.eval(value));
+                match (a.number(), b.number()) {
+                    (Some(a), Some(b)) => op.compare(a, b),
+                    _ => match (a, b, op) {
+                        (Value::Missing, _, _) | (_, Value::Missing, _) => false,
+                        (a, b, Comparison::Equal) => text(&a) == text(&b),
+                        (a, b, Comparison::NotEqual) => text(&a) != text(&b),
+                        // Only numbers are ordered.
+                        _ => false,
+                    },
+                }
+            }
+            Expr::Match(t, regex) => match t.eval(value) {
+                Value::Missing => false,
+                v => regex.is_match(&text(&v)),
+            },
+            Expr::Has(v) => value(v).is_some(),
+            Expr::Value(t) => t.eval(value).truthy(),
+        }
+    }
+
+    fn variables<'a>(&'a self, variables: &mut Vec<&'a str>) {
+        match self {
+            Expr::Or(a, b) | Expr::And(a, b) => {
+                a.variables(variables);
+                b.variables(variables);
+            }
+            Expr::Not(e) => e.variables(variables),
+            Expr::Compare(a, _, b) => {
+                a.variables(variables);
+                b.variables(variables);
+            }
+            Expr::Match(t, _) | Expr::Value(t) => t.variables(variables),
+            Expr::Has(v) => variables.push(v),
+        }
+    }
+}
+
+fn text<'a>(v: &'a Value) -> Cow<'a, str> {
+    match v {
+        Value::Missing => Cow::Borrowed(""),
+        Value::Text(t) => Cow::Borrowed(t),
+        Value::Number(n) => Cow::Owned(n.to_string()),
+    }
+}
+
+fn describe(t: &Token) -> String {
+    match t {
+        Token::Variable(v) => format!("'{}'", v),
+        Token::Text(t) => format!("\"{}\"", t),
+        Token::Number(n) => format!("'{}'", n),
+        Token::Ident(i) => format!("'{}'", i),
+        Token::Op(_) => "comparison".to_string(),
+        Token::LParen => "'('".to_string(),
+        Token::RParen => "')'".to_string(),
+        Token::Comma => "','".to_string(),
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.char_indices().peekable();
+    let word_end = |start: usize| {
+        s[start..]
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '\''))
+            .map_or(s.len(), |i| start + i)
+    };
+
+    while let Some(&(i, c)) = chars.peek() {
+        let end = match c {
+            c if c.is_whitespace() => i + 1,
+            '(' | ')' | ',' => {
+                tokens.push(match c {
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    _ => Token::Comma,
+                });
+                i + 1
+            }
+            '<' | '>' | '=' | '!' => {
+                let (op, len) = match &s[i..] {
+                    o if o.starts_with("<=") => (Comparison::LessEqual, 2),
+                    o if o.starts_with(">=") => (Comparison::GreaterEqual, 2),
+                    o if o.starts_with("==") => (Comparison::Equal, 2),
+                    o if o.starts_with("!=") => (Comparison::NotEqual, 2),
+                    o if o.starts_with('<') => (Comparison::Less, 1),
+                    o if o.starts_with('>') => (Comparison::Greater, 1),
+                    _ => return Err(format!("unknown operator at '{}'", &s[i..])),
+                };
+                tokens.push(Token::Op(op));
+                i + len
+            }
+            '"' => {
+                let mut text = String::new();
+                let mut escaped = false;
+                let mut end = None;
+                for (j, c) in s[i + 1..].char_indices() {
+                    match c {
+                        _ if escaped => {
+                            text.push(c);
+                            escaped = false;
+                        }
+                        '\\' => escaped = true,
+                        '"' => {
+                            end = Some(i + 1 + j + 1);
+                            break;
+                        }
+                        _ => text.push(c),
+                    }
+                }
+                tokens.push(Token::Text(text));
+                end.ok_or_else(|| "unterminated string".to_string())?
+            }
+            '$' => {
+                let end = word_end(i + 1);
+                if end == i + 1 {
+                    return Err("'$' without a variable name".to_string());
+                }
+                tokens.push(Token::Variable(s[i..end].to_string()));
+                end
+            }
+            '-' | '0'..='9' => {
+                let end = word_end(i + 1);
+                let n = weggli::parse_number_literal(&s[i..end])
+                    .ok_or_else(|| format!("'{}' is not a number", &s[i..end]))?;
+                tokens.push(Token::Number(n));
+                end
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let end = word_end(i);
+                tokens.push(Token::Ident(s[i..end].to_string()));
+                end
+            }
+            c => return Err(format!("unexpected character '{}'", c)),
+        };
+        while chars.peek().map_or(false, |&(j, _)| j < end) {
+            chars.next();
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        if self.peek() == Some(&Token::Ident(keyword.to_string())) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), String> {
+        match self.next() {
+            Some(t) if t == token => Ok(()),
+            Some(t) => Err(format!(
+                "expected {}, found {}",
+                describe(&token),
+                describe(&t)
+            )),
+            None => Err(format!("expected {}", describe(&token))),
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        let mut left = self.and()?;
+        while self.keyword("or") {
+            left = Expr::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut left = self.unary()?;
+        while self.keyword("and") {
+            left = Expr::And(Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.keyword("not") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.peek() == Some(&Token::LParen) {
+            self.pos += 1;
+            let e = self.expr()?;
+            self.expect(Token::RParen)?;
+            return Ok(e);
+        }
+
+        if let Some(Token::Ident(name)) = self.peek() {
+            let name = name.clone();
+            match name.as_str() {
+                "eq" => {
+                    self.pos += 1;
+                    self.expect(Token::LParen)?;
+                    let a = self.term()?;
+                    self.expect(Token::Comma)?;
+                    let b = self.term()?;
+                    self.expect(Token::RParen)?;
+                    return Ok(Expr::Compare(a, Comparison::Equal, b));
+                }
+                "match" => {
+                    self.pos += 1;
+                    self.expect(Token::LParen)?;
+                    let t = self.term()?;
+                    self.expect(Token::Comma)?;
+                    let regex = match self.next() {
+                        Some(Token::Text(r)) => {
+                            Regex::new(&r).map_err(|e| format!("invalid regex: {}", e))?
+                        }
+                        _ => return Err("the second argument of match() must be a string".into()),
+                    };
+                    self.expect(Token::RParen)?;
+                    return Ok(Expr::Match(t, regex));
+                }
+                "has" => {
+                    self.pos += 1;
+                    self.expect(Token::LParen)?;
+                    let v = match self.next() {
+                        Some(Token::Variable(v)) => v,
+                        _ => return Err("the argument of has() must be a variable".into()),
+                    };
+                    self.expect(Token::RParen)?;
+                    return Ok(Expr::Has(v));
+                }
+                _ => (),
+            }
+        }
+
+        let left = self.term()?;
+        match self.peek() {
+            Some(Token::Op(op)) => {
+                let op = *op;
+                self.pos += 1;
+                Ok(Expr::Compare(left, op, self.term()?))
+            }
+            _ => Ok(Expr::Value(left)),
+        }
+    }
+
+    fn term(&mut self) -> Result<Term, String> {
+        match self.next() {
+            Some(Token::Variable(v)) => Ok(Term::Variable(v)),
+            Some(Token::Text(t)) => Ok(Term::Text(t)),
+            Some(Token::Number(n)) => Ok(Term::Number(n)),
+            Some(Token::Ident(f)) if f == "len" => {
+                self.expect(Token::LParen)?;
+                let t = self.term()?;
+                self.expect(Token::RParen)?;
+                Ok(Term::Len(Box::new(t)))
+            }
+            Some(Token::Ident(f)) => Err(format!("unknown function '{}'", f)),
+            Some(t) => Err(format!("unexpected {}", describe(&t))),
+            None => Err("unexpected end of the expression".to_string()),
+        }
+    }
+}
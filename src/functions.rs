@@ -0,0 +1,127 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Inventory of the function definitions in a code base (weggli functions).
+
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use rayon::prelude::*;
+use serde::Serialize;
+use tree_sitter::Node;
+
+use crate::search::{find_files, SearchOptions};
+
+/// A function definition in a file on disk.
+#[derive(Clone, Debug, Serialize)]
+pub struct FunctionDefinition {
+    pub path: PathBuf,
+    /// Name of the function, including qualifiers such as `Class::`. None if
+    /// the declarator couldn't be parsed.
+    pub name: Option<String>,
+    /// Everything before the body, with whitespace collapsed.
+    pub signature: String,
+    /// First and last line (1-based).
+    pub lines: Range<usize>,
+    /// Byte range of the definition.
+    pub range: Range<usize>,
+}
+
+/// Pass every function definition in the files in `paths` to `callback`.
+/// Files are found and parsed like in `search` (`options.join` is not used),
+/// so `callback` is called from multiple threads and definitions are only
+/// ordered within a file.
+pub fn functions<I, P, F>(paths: I, options: &SearchOptions, callback: F)
+where
+    I: IntoIterator<Item = P>,
+    P: AsRef<Path>,
+    F: Fn(FunctionDefinition) + Sync,
+{
+    let loader = options.loader();
+
+    find_files(paths, options).par_bridge().for_each(|path| {
+        let parsed = loader.load(&path, |contents| vec![options.is_cpp(&path, contents)]);
+        for file in parsed {
+            let file = match file {
+                Ok(file) => file,
+                Err(e) => {
+                    warn!("{}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let source = &file.source;
+
+            for f in definitions(file.tree.root_node()) {
+                callback(FunctionDefinition {
+                    path: path.clone(),
+                    name: name(f, source).map(|s| s.to_string()),
+                    signature: signature(f, source),
+                    lines: f.start_position().row + 1..f.end_position().row + 1,
+                    range: f.byte_range(),
+                });
+            }
+        }
+    });
+}
+
+/// Returns all function_definition nodes below `root` in source order.
+pub fn definitions(root: Node) -> Vec<Node> {
+    let mut result = Vec::new();
+    let mut cursor = root.walk();
+
+    loop {
+        if cursor.node().kind() == "function_definition" {
+            result.push(cursor.node());
+        }
+
+        if cursor.goto_first_child() {
+            continue;
+        }
+
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                return result;
+            }
+        }
+    }
+}
+
+/// Extract the name of the function defined by `f`.
+/// The name is nested in (potentially multiple) pointer or reference declarators.
+pub fn name<'a>(f: Node, source: &'a str) -> Option<&'a str> {
+    let mut declarator = f.child_by_field_name("declarator");
+    while let Some(d) = declarator {
+        if d.kind() == "function_declarator" {
+            return d
+                .child_by_field_name("declarator")
+                .map(|n| &source[n.byte_range()]);
+        }
+        declarator = d.child_by_field_name("declarator");
+    }
+    None
+}
+
+/// Returns the source of `f` up to its body, with all whitespace (including
+/// line breaks of wrapped parameter lists) collapsed into single spaces.
+pub fn signature(f: Node, source: &str) -> String {
+    let end = f
+        .child_by_field_name("body")
+        .map_or(f.end_byte(), |b| b.start_byte());
+    source[f.start_byte()..end]
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,234 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! On-disk index of the identifiers used in each file (weggli index, --use-index).
+//! Searches that use the index can skip files that don't contain the identifiers
+//! of any pattern without reading them.
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::hash::Hasher;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Index directory used if none is given, relative to the indexed directory.
+/// Hidden directories are never searched, so the index doesn't index itself.
+pub const DEFAULT_DIR: &str = ".weggli";
+
+const FILE_NAME: &str = "index.jsonl";
+
+/// Version of the index file format.
+const FORMAT_VERSION: u64 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u64,
+    // canonical path of the indexed directory
+    root: PathBuf,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Entry {
+    // path relative to the indexed directory
+    path: PathBuf,
+    size: u64,
+    // modification time in nanoseconds since the epoch
+    mtime: u64,
+    // hash of the file content
+    hash: String,
+    // sorted list of all words in the file
+    identifiers: Vec<String>,
+}
+
+pub struct Index {
+    root: PathBuf,
+    entries: HashMap<PathBuf, Entry>,
+}
+
+impl Index {
+    /// Load the index stored in `dir`.
+    pub fn load(dir: &Path) -> Result<Index, String> {
+        let path = dir.join(FILE_NAME);
+        let file = fs::File::open(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut lines = BufReader::new(file).lines();
+
+        let invalid = |e: &dyn std::fmt::Display| format!("{}: {}", path.display(), e);
+        let header: Header = match lines.next() {
+            Some(line) => {
+                serde_json::from_str(&line.map_err(|e| invalid(&e))?).map_err(|e| invalid(&e))?
+            }
+            None => return Err(invalid(&"empty index")),
+        };
+        if header.version != FORMAT_VERSION {
+            return Err(invalid(&format!(
+                "unsupported index version {}, run weggli index again",
+                header.version
+            )));
+        }
+
+        let mut entries = HashMap::new();
+        for line in lines {
+            let entry: Entry =
+                serde_json::from_str(&line.map_err(|e| invalid(&e))?).map_err(|e| invalid(&e))?;
+            entries.insert(entry.path.clone(), entry);
+        }
+
+        Ok(Index {
+            root: header.root,
+            entries,
+        })
+    }
+
+    /// Number of indexed files.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns the sorted identifiers of the file at `path`, or None if the file
+    /// is not part of the index or has been modified since it was indexed.
+    pub fn identifiers(&self, path: &Path) -> Option<&[String]> {
+        let entry = match path.strip_prefix(&self.root) {
+            Ok(relative) => self.entries.get(relative),
+            // The file might have been found through a symlink.
+            Err(_) => {
+                let path = fs::canonicalize(path).ok()?;
+                self.entries.get(path.strip_prefix(&self.root).ok()?)
+            }
+        }?;
+
+        let (size, mtime) = stat(path)?;
+        if entry.size != size || entry.mtime != mtime {
+            return None;
+        }
+        Some(&entry.identifiers)
+    }
+}
+
+/// Returns false if a file with the sorted `identifiers` can't contain `needle`.
+/// Only words can be looked up, everything else needs to be checked in the file.
+pub fn could_contain(identifiers: &[String], needle: &str) -> bool {
+    !is_word(needle)
+        || identifiers
+            .binary_search_by(|i| i.as_str().cmp(needle))
+            .is_ok()
+}
+
+fn is_word(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+/// Size and modification time of the file at `path`.
+pub fn stat(path: &Path) -> Option<(u64, u64)> {
+    let metadata = fs::metadata(weggli::long_path(path)).ok()?;
+    let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((metadata.len(), mtime.as_nanos() as u64))
+}
+
+fn content_hash(content: &[u8]) -> String {
+    let mut hasher = rustc_hash::FxHasher::default();
+    hasher.write(content);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Returns the sorted list of words in `content`. Query identifiers are
+/// compared with the full text of a node, so looking them up in the words of
+/// a file never skips a file that could match.
+fn words(content: &[u8]) -> Vec<String> {
+    let words: BTreeSet<&[u8]> = content
+        .split(|b| !(b.is_ascii_alphanumeric() || *b == b'_'))
+        .filter(|w| !w.is_empty())
+        .collect();
+    words
+        .into_iter()
+        .map(|w| String::from_utf8_lossy(w).to_string())
+        .collect()
+}
+
+/// Index all files with the given `extensions` below `path` and store the index in
+/// `dir`. Entries of an existing index are reused for unchanged files.
+pub fn build(path: &Path, dir: &Path, extensions: Vec<String>) -> Result<(), String> {
+    let root = fs::canonicalize(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let previous = Index::load(dir).ok().filter(|i| i.root == root);
+    let previous = previous.as_ref();
+
+    let files: Vec<PathBuf> = weggli::iter_files(&root, extensions).collect();
+
+    let mut entries: Vec<(Entry, bool)> = files
+        .par_iter()
+        .filter_map(|file| {
+            let relative = file.strip_prefix(&root).ok()?.to_path_buf();
+            let (size, mtime) = stat(file)?;
+            let old = previous.and_then(|p| p.entries.get(&relative));
+
+            if let Some(old) = old.filter(|o| o.size == size && o.mtime == mtime) {
+                return Some((old.clone(), true));
+            }
+
+            let content = match fs::read(weggli::long_path(file)) {
+                Ok(c) => c,
+                Err(e) => {
+                    warn!("{}: {}", file.display(), e);
+                    return None;
+                }
+            };
+            let hash = content_hash(&content);
+            let identifiers = match old.filter(|o| o.hash == hash) {
+                Some(old) => old.identifiers.clone(),
+                None => words(&content),
+            };
+
+            Some((
+                Entry {
+                    path: relative,
+                    size,
+                    mtime,
+                    hash,
+                    identifiers,
+                },
+                false,
+            ))
+        })
+        .collect();
+    entries.sort_by(|a, b| a.0.path.cmp(&b.0.path));
+
+    fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let path = dir.join(FILE_NAME);
+    let write = || -> std::io::Result<()> {
+        let mut w = BufWriter::new(fs::File::create(&path)?);
+        let header = Header {
+            version: FORMAT_VERSION,
+            root: root.clone(),
+        };
+        writeln!(w, "{}", serde_json::to_string(&header)?)?;
+        for (entry, _) in &entries {
+            writeln!(w, "{}", serde_json::to_string(entry)?)?;
+        }
+        w.flush()
+    };
+    write().map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    let unchanged = entries.iter().filter(|(_, unchanged)| *unchanged).count();
+    eprintln!(
+        "Indexed {} files ({} unchanged) in {}",
+        entries.len(),
+        unchanged,
+        dir.display()
+    );
+    Ok(())
+}
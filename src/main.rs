@@ -19,53 +19,114 @@ extern crate clap;
 extern crate log;
 extern crate rayon;
 extern crate simplelog;
-extern crate walkdir;
 
 use colored::Colorize;
-use rayon::iter::ParallelBridge;
+use memchr::memmem;
 use rayon::prelude::*;
 use regex::Regex;
+use serde_json::json;
 use std::cell::RefCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{Receiver, Sender};
-use std::sync::{mpsc, Arc};
+use std::sync::{mpsc, Arc, Mutex};
 use std::{collections::HashMap, path::Path};
 use std::{collections::HashSet, fs};
 use std::{io::prelude::*, path::PathBuf};
-use thread_local::ThreadLocal;
 use tree_sitter::Tree;
-use walkdir::WalkDir;
-use weggli::RegexMap;
+use weggli::{QueryOptions, RegexMap, Uniqueness};
 
-use weggli::parse_search_pattern;
 use weggli::query::QueryTree;
-use weggli::result::QueryResult;
+use weggli::result::{Header, QueryResult};
+use weggli::{parse_raw_query, parse_search_pattern, QueryError};
 
+mod blame;
 mod cli;
+mod diff;
+mod events;
+mod filter;
+mod index;
+mod metrics;
+mod no_match_cache;
+mod output;
+mod repl;
+mod rewrite;
+mod rules;
+mod semgrep;
+mod shard;
+mod snippet;
+mod stream;
+mod suppress;
+mod template;
+mod warnings;
 
 fn main() {
     reset_signal_pipe_handler();
 
-    let args = cli::parse_arguments();
+    let args = match cli::parse_arguments() {
+        cli::Command::Search(args) => *args,
+        cli::Command::MergeResults(files, join) => {
+            shard::merge_results(&files, &join, &output::Output::new(None, false));
+            return;
+        }
+        cli::Command::Index(path, dir, extensions) => {
+            if let Err(e) = index::build(&path, &dir, extensions) {
+                eprintln!("Failed to create index: {}", e);
+                std::process::exit(2)
+            }
+            return;
+        }
+        cli::Command::ImportSemgrep(file, output) => {
+            if let Err(e) = semgrep::run(&file, output.as_deref()) {
+                eprintln!("Failed to import rules: {}", e);
+                std::process::exit(2)
+            }
+            return;
+        }
+        cli::Command::Repl(path, cpp, extensions) => {
+            repl::run(&path, cpp, extensions);
+            return;
+        }
+        cli::Command::Try(pattern, source, cpp) => {
+            snippet::run(&pattern, &source, cpp);
+            return;
+        }
+        cli::Command::Functions(path, cpp, extensions, json) => {
+            list_functions(&path, cpp, extensions, json);
+            return;
+        }
+    };
+
+    if let Some(threads) = args.threads.0 {
+        if let Err(e) = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+        {
+            warn!("Can't limit the number of threads: {}", e);
+        }
+    }
 
-    if args.force_color {
-        colored::control::set_override(true)
+    // Saved results are rendered again by merge-results.
+    if args.save_results.is_some() || args.output.is_some() {
+        colored::control::set_override(false)
     }
 
     // Keep track of all variables used in the input pattern(s)
     let mut variables = HashSet::new();
 
+    // --unique is enforced by the query engine, using the same comparison as --unique-by.
+    let uniqueness = match (args.unique, args.raw_values) {
+        (false, _) => Uniqueness::Any,
+        (true, false) => Uniqueness::Distinct,
+        (true, true) => Uniqueness::RawDistinct,
+    };
+
     // Validate all regular expressions
-    let regex_constraints = process_regexes(&args.regexes).unwrap_or_else(|e| {
-        let msg = match e {
-            RegexError::InvalidArg(s) => format!(
-                "'{}' is not a valid argument of the form var=regex",
-                s.red()
-            ),
-            RegexError::InvalidRegex(s) => format!("Regex error {}", s),
-        };
-        eprintln!("{}", msg);
-        std::process::exit(1)
-    });
+    let options = QueryOptions::from(validate_regexes(&args.regexes))
+        .with_length_constraints(args.string_lengths.clone())
+        .with_number_constraints(args.num_constraints.clone())
+        .with_variable_constraints(args.variable_constraints.clone())
+        .with_anchor(args.anchor)
+        .with_uniqueness(uniqueness);
 
     // Normalize all patterns and translate them into QueryTrees
     // We also extract the identifiers at this point
@@ -73,47 +134,307 @@ fn main() {
     // Invalid patterns trigger a process exit in validate_query so
     // after this point we now that all patterns are valid.
     // The loop also fills the `variables` set with used variable names.
+    // In --auto-lang and --dual-headers mode, every pattern is compiled for both grammars.
+    let languages = if args.auto_lang || args.dual_headers {
+        vec![false, true]
+    } else {
+        vec![args.cpp]
+    };
+
+    // Rules that failed to build with --skip-broken-rules: (rule id, error message)
+    let mut broken_rules: Vec<(String, String)> = Vec::new();
+
     let work: Vec<WorkItem> = args
         .pattern
         .iter()
-        .map(|pattern| {
-            match parse_search_pattern(
-                pattern,
-                args.cpp,
-                args.force_query,
-                Some(regex_constraints.clone()),
-            ) {
-                Ok(qt) => {
-                    let identifiers = qt.identifiers();
+        .zip(args.contexts.iter())
+        .enumerate()
+        .map(|(i, (pattern, &(before, after)))| {
+            let rule = args.rules.get(i).cloned();
+            let mut item = WorkItem {
+                c: None,
+                cpp: None,
+                header_only: None,
+                identifiers: Vec::new(),
+                finders: Vec::new(),
+                before,
+                after,
+                rule,
+            };
+            let mut error = None;
+
+            // Rules can add their own regex constraints to the ones passed with -R.
+            // With --skip-broken-rules, rules that fail to build are reported after the
+            // search and don't get a query, so they never match.
+            let options = match &item.rule {
+                Some(rule) if !rule.regex.is_empty() => {
+                    let regexes: Vec<String> = args
+                        .regexes
+                        .iter()
+                        .chain(rule.regex.iter())
+                        .cloned()
+                        .collect();
+                    match process_regexes(&regexes) {
+                        Ok(r) => options.clone().with_regexes(r),
+                        Err(e) if args.skip_broken_rules => {
+                            broken_rules.push((rule.id.clone(), regex_error_message(e)));
+                            return item;
+                        }
+                        Err(e) => {
+                            eprintln!("{}", regex_error_message(e));
+                            std::process::exit(2)
+                        }
+                    }
+                }
+                _ => options.clone(),
+            };
+            let options = if args.no_normalize {
+                options.without_normalization()
+            } else {
+                options
+            };
+
+            for &is_cpp in &languages {
+                match compile_pattern(&args, pattern, is_cpp, options.clone()) {
+                    Ok(qt) => {
+                        variables.extend(qt.variables());
+                        if is_cpp {
+                            item.cpp = Some(qt);
+                        } else {
+                            item.c = Some(qt);
+                        }
+                    }
+                    Err(qe) => {
+                        error.get_or_insert(qe);
+                    }
+                }
+            }
+
+            // With --dual-headers, the query for the other grammar is only run on
+            // headers. The pattern still needs to be valid in the selected mode.
+            if args.dual_headers && !args.auto_lang {
+                if item.query(args.cpp).is_none() {
+                    item.c = None;
+                    item.cpp = None;
+                } else if item.query(!args.cpp).is_some() {
+                    item.header_only = Some(!args.cpp);
+                }
+            }
+
+            if item.c.is_none() && item.cpp.is_none() && args.fallback_cpp {
+                if let Ok(qt) = compile_pattern(&args, pattern, true, options.clone()) {
+                    warn!(
+                        "'{}' is only valid in C++ mode. Falling back to the C++ grammar.",
+                        pattern
+                    );
                     variables.extend(qt.variables());
-                    WorkItem { qt, identifiers }
+                    item.cpp = Some(qt);
                 }
-                Err(qe) => {
-                    eprintln!("{}", qe.message);
+            }
+
+            if let Some(qt) = item.c.as_ref().or(item.cpp.as_ref()) {
+                report_normalization(pattern, qt);
+            }
+
+            // Only use identifiers that are shared by all compiled queries,
+            // otherwise the file filter could skip valid matches.
+            item.identifiers = match (&item.c, &item.cpp) {
+                (Some(c), Some(cpp)) => {
+                    let cpp_identifiers = cpp.identifiers();
+                    c.identifiers()
+                        .into_iter()
+                        .filter(|i| cpp_identifiers.contains(i))
+                        .collect()
+                }
+                (Some(qt), None) | (None, Some(qt)) => qt.identifiers(),
+                (None, None) => {
+                    if let (Some(rule), true) = (&item.rule, args.skip_broken_rules) {
+                        broken_rules.push((rule.id.clone(), error.unwrap().message));
+                        return item;
+                    }
+                    eprintln!("{}", error.unwrap().message);
                     if !args.cpp
-                        && parse_search_pattern(
-                            pattern,
-                            true,
-                            args.force_query,
-                            Some(regex_constraints.clone()),
-                        )
-                        .is_ok()
+                        && !args.auto_lang
+                        && compile_pattern(&args, pattern, true, options.clone()).is_ok()
                     {
-                        eprintln!("{} This query is valid in C++ mode (-X)", "Note:".bold());
+                        eprintln!(
+                            "{} This query is valid in C++ mode (-X, --fallback-cpp)",
+                            "Note:".bold()
+                        );
+                    }
+                    std::process::exit(2);
+                }
+            };
+            item.finders = item
+                .identifiers
+                .iter()
+                .map(|i| memmem::Finder::new(i).into_owned())
+                .collect();
+
+            if let Some(rule) = &item.rule {
+                let rule_variables: HashSet<String> = item
+                    .c
+                    .iter()
+                    .chain(item.cpp.iter())
+                    .flat_map(|qt| qt.variables())
+                    .collect();
+                for v in validate_regexes(&rule.regex).variables() {
+                    if !rule_variables.contains(v) {
+                        if args.skip_broken_rules {
+                            let message = format!("'{}' is not a valid query variable", v);
+                            broken_rules.push((rule.id.clone(), message));
+                            item.c = None;
+                            item.cpp = None;
+                            break;
+                        }
+                        eprintln!(
+                            "rule {}: '{}' is not a valid query variable",
+                            rule.id,
+                            v.red()
+                        );
+                        std::process::exit(2)
                     }
-                    std::process::exit(1);
                 }
             }
+            item
         })
         .collect();
 
-    for v in regex_constraints.variables() {
+    for v in options
+        .regexes()
+        .variables()
+        .chain(args.unique_by.iter())
+        .chain(args.aggregate.iter())
+        .chain(args.group_by.iter())
+        .chain(args.join.keys())
+        .chain(args.string_lengths.iter().flat_map(|c| match &c.bound {
+            weggli::Bound::Variable(v) => vec![&c.variable, v],
+            weggli::Bound::Constant(_) => vec![&c.variable],
+        }))
+        .chain(args.num_constraints.iter().map(|c| &c.variable))
+        .chain(
+            args.variable_constraints
+                .iter()
+                .flat_map(|c| [&c.left, &c.right]),
+        )
+        .map(|v| v.as_str())
+        .chain(args.filters.iter().flat_map(|f| f.variables()))
+        .chain(args.output_template.iter().flat_map(|t| t.variables()))
+    {
         if !variables.contains(v) {
             eprintln!("'{}' is not a valid query variable", v.red());
-            std::process::exit(1)
+            std::process::exit(2)
+        }
+    }
+
+    // Regex constraints on valid variables that still can't have an effect.
+    let queries = || work.iter().flat_map(|w| w.c.iter().chain(w.cpp.iter()));
+    let result_variables: HashSet<String> =
+        queries().flat_map(|qt| qt.result_variables()).collect();
+    let number_variables: HashSet<String> =
+        queries().flat_map(|qt| qt.number_variables()).collect();
+    for v in options.regexes().variables() {
+        if !result_variables.contains(v) {
+            warn!(
+                "'{}' only appears in negative sub queries (not:, never:). Its regex \
+                 constraint only limits what they exclude, it can't filter results.",
+                v
+            );
+        }
+        if let Some((false, regex)) = options.regexes().get(v) {
+            if number_variables.contains(v) && !weggli::regex_can_match_number(regex.as_str()) {
+                warn!(
+                    "'{}' has a numeric constraint and only matches number literals, \
+                     but the regex '{}' can never match a number.",
+                    v,
+                    regex.as_str()
+                );
+            }
+        }
+    }
+
+    // With --raw-values, variables are joined on their exact text unless --join says otherwise.
+    let mut args = args;
+    // --invert-match prints functions without results, which have no values.
+    if matches!(args.format, cli::Format::Csv | cli::Format::Tsv) && !args.invert_match {
+        args.columns = variables.iter().cloned().collect();
+        args.columns.sort();
+    }
+    if args.raw_values {
+        for v in &variables {
+            args.join
+                .entry(v.clone())
+                .or_insert(weggli::result::JoinMode::Exact);
         }
     }
 
+    let rewriter = args.rewrite.as_ref().map(|template| {
+        let rewriter =
+            rewrite::Rewriter::new(template, &args.pattern[0], args.cpp).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(2)
+            });
+        for v in rewriter.variables() {
+            if !variables.contains(&v) {
+                eprintln!("'{}' is not a valid query variable", v.red());
+                std::process::exit(2)
+            }
+        }
+        rewriter
+    });
+
+    let unique_by = UniqueBy {
+        variables: args.unique_by.clone(),
+        seen: Mutex::new(HashSet::new()),
+        raw: args.raw_values,
+        header_results: Mutex::new(HashSet::new()),
+    };
+
+    let mut output = output::Output::new(args.sample, args.dedup_snippets);
+    if args.quiet {
+        output = output.with_quiet();
+    }
+    if let Some(report) = args.file_report {
+        output = output.with_file_report(report, args.format == cli::Format::Json);
+    } else if args.format == cli::Format::GitlabCodequality {
+        output = output.with_codequality();
+    } else if args.format == cli::Format::Sarif {
+        let rules = work
+            .iter()
+            .zip(args.pattern.iter())
+            .enumerate()
+            .map(|(i, (w, pattern))| {
+                let description = w.rule.as_ref().and_then(|r| r.description.as_deref());
+                output::sarif_rule(&w.id(i), pattern, description)
+            })
+            .collect();
+        output = output.with_sarif(rules);
+    }
+    if args.list_variables {
+        output = output.with_variable_report(args.format.is_json_lines());
+    }
+    if let Some(var) = &args.aggregate {
+        output = output.with_aggregate(var.clone(), args.format.is_json_lines());
+    }
+    if let Some(var) = &args.group_by {
+        output = output.with_group_by(var.clone());
+    }
+    if let Some(path) = &args.output {
+        output = output.with_file(path).unwrap_or_else(|e| {
+            eprintln!("Failed to create {}: {}", path.display(), e);
+            std::process::exit(2)
+        });
+    }
+    if matches!(args.format, cli::Format::Csv | cli::Format::Tsv)
+        && !args.quiet
+        && args.file_report.is_none()
+        && args.aggregate.is_none()
+    {
+        let mut header = vec!["path", "line"];
+        header.extend(args.columns.iter().map(String::as_str));
+        output.header(&output::table_row(&header, args.format == cli::Format::Tsv));
+    }
+
     // Verify that the --include and --exclude regexes are valid.
     let helper_regex = |v: &[String]| -> Vec<Regex> {
         v.iter()
@@ -123,7 +444,7 @@ fn main() {
                     Ok(regex) => regex,
                     Err(e) => {
                         eprintln!("Regex error {}", e);
-                        std::process::exit(1)
+                        std::process::exit(2)
                     }
                 }
             })
@@ -133,65 +454,332 @@ fn main() {
     let exclude_re = helper_regex(&args.exclude);
     let include_re = helper_regex(&args.include);
 
-    // Collect and filter our input file set.
-    let mut files: Vec<PathBuf> = if args.path.to_string_lossy() == "-" {
-        std::io::stdin()
-            .lock()
-            .lines()
-            .filter_map(|l| l.ok())
-            .map(|s| Path::new(&s).to_path_buf())
-            .collect()
+    // Collect and filter our input file set: the files listed with --files-from or on
+    // STDIN (PATH '-') and the files in PATH. Directories are walked in parallel.
+    let stdin = Path::new("-");
+    let list = match (&args.files_from, &args.path) {
+        (Some(list), _) => Some(list.as_path()),
+        (None, Some(path)) if path == stdin => Some(stdin),
+        _ => None,
+    };
+    let listed: Vec<PathBuf> = list
+        .map(|list| {
+            read_file_list(list).unwrap_or_else(|e| {
+                eprintln!("Failed to read file list {}: {}", list.display(), e);
+                std::process::exit(2)
+            })
+        })
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|f| {
+            args.diff
+                .as_ref()
+                .map_or(true, |changes| changes.contains(f))
+        })
+        .collect();
+
+    let found: Box<dyn Iterator<Item = PathBuf> + Send> = match (&args.path, &args.diff) {
+        (Some(path), _) if path == stdin => Box::new(std::iter::empty()),
+        (Some(path), Some(changes)) => {
+            Box::new(changes.files_in(path, &args.extensions).into_iter())
+        }
+        (Some(path), None) => Box::new(weggli::par_iter_files(
+            path,
+            args.extensions.clone(),
+            !args.no_ignore,
+        )),
+        (None, _) => Box::new(std::iter::empty()),
+    };
+    let files = listed.into_iter().chain(found);
+
+    // Filter files based on include and exclude regexes
+    let files = files.filter(|f| {
+        if exclude_re.is_empty() && include_re.is_empty() {
+            return true;
+        }
+        let f = regex_path(f);
+        if exclude_re.iter().any(|r| r.is_match(&f)) {
+            return false;
+        }
+        if include_re.is_empty() {
+            return true;
+        }
+        include_re.iter().any(|r| r.is_match(&f))
+    });
+    // Number of files before --shard picks its partition. A shard can be assigned no
+    // files at all, which is not an error as long as there are files to distribute.
+    let found = AtomicUsize::new(0);
+    let files = files.inspect(|_| {
+        found.fetch_add(1, Ordering::Relaxed);
+    });
+    let root = args.path.clone().unwrap_or_default();
+    let files = files.filter(|f| {
+        args.shard
+            .map_or(true, |shard| shard::in_shard(f, &root, shard))
+    });
+
+    // Files are searched as soon as they are found, unless the full list is needed
+    // up front (to sample it, to report files without matches or for --events).
+    let stream = list.is_none()
+        && args.diff.is_none()
+        && args.sample_files.is_none()
+        && args.file_report != Some(output::FileReport::WithoutMatch)
+        && !events::enabled();
+
+    let files: Box<dyn Iterator<Item = PathBuf> + Send> = if stream {
+        info!("parsing files as they are found");
+        Box::new(files)
     } else {
-        iter_files(&args.path, args.extensions.clone())
-            .map(|d| d.into_path())
-            .collect()
+        let mut files: Vec<PathBuf> = files.collect();
+
+        if let Some(sample) = args.sample_files {
+            let total = files.len();
+            fastrand::shuffle(&mut files);
+            files.truncate(sample.size(total));
+            files.sort();
+            info!("sampled {} of {} files", files.len(), total);
+        }
+
+        info!("parsing {} files", files.len());
+        // Nothing changed since the --diff revision, so there can't be new results
+        if files.is_empty() && args.diff.is_some() {
+            info!("no changed files");
+            std::process::exit(1)
+        }
+        if found.load(Ordering::Relaxed) == 0 {
+            no_files()
+        }
+
+        events::emit("started", json!({ "files": files.len() }));
+
+        if args.file_report == Some(output::FileReport::WithoutMatch) {
+            output.set_searched_files(files.iter().map(|f| f.display().to_string()).collect());
+        }
+        Box::new(files.into_iter())
     };
 
-    if !exclude_re.is_empty() || !include_re.is_empty() {
-        // Filter files based on include and exclude regexes
-        files.retain(|f| {
-            if exclude_re.iter().any(|r| r.is_match(&f.to_string_lossy())) {
-                return false;
+    // Number of files passed to the parse workers, checked after a streamed walk.
+    let parsed = AtomicUsize::new(0);
+    let files = files.inspect(|_| {
+        parsed.fetch_add(1, Ordering::Relaxed);
+    });
+
+    let index = args
+        .use_index
+        .as_ref()
+        .and_then(|dir| match index::Index::load(dir) {
+            Ok(index) => {
+                info!("loaded index with {} files", index.len());
+                Some(index)
             }
-            if include_re.is_empty() {
-                return true;
+            Err(e) => {
+                warn!("Not using index: {}", e);
+                None
             }
-            include_re.iter().any(|r| r.is_match(&f.to_string_lossy()))
         });
-    }
 
-    info!("parsing {} files", files.len());
-    if files.is_empty() {
-        eprintln!("{}", String::from("No files to parse. Exiting...").red());
-        std::process::exit(1)
-    }
+    let cache = args.no_match_cache.as_ref().map(|dir| {
+        no_match_cache::NoMatchCache::open(dir, cache_key(&work, &args)).unwrap_or_else(|e| {
+            eprintln!("Failed to open the no-match cache: {}", e);
+            std::process::exit(2)
+        })
+    });
 
-    // The main parallelized work pipeline
-    rayon::scope(|s| {
+    // --parse-threads and --query-threads give their stage a thread pool of its own.
+    let pool = |threads: Option<usize>, stage: &'static str| {
+        threads.map(|n| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(n)
+                .thread_name(move |i| format!("weggli-{}-{}", stage, i))
+                .build()
+                .unwrap_or_else(|e| {
+                    eprintln!("Failed to create thread pool: {}", e);
+                    std::process::exit(2)
+                })
+        })
+    };
+    let parse_pool = pool(args.threads.1, "parse");
+    let query_pool = pool(args.threads.2, "query");
+
+    // The main parallelized work pipeline. The workers only coordinate their stage,
+    // the actual work runs in the rayon thread pools.
+    std::thread::scope(|s| {
         // spin up channels for worker communication
         let (ast_tx, ast_rx) = mpsc::channel();
         let (results_tx, results_rx) = mpsc::channel();
 
         // avoid lifetime issues
-        let cpp = args.cpp;
+        let a = &args;
         let w = &work;
-        let before = args.before;
-        let after = args.after;
-        let enable_line_numbers = args.enable_line_numbers;
+        let u = &unique_by;
+        let o = &output;
+        let r = rewriter.as_ref();
+        let i = index.as_ref();
+        let c = cache.as_ref();
+        let (pp, qp) = (parse_pool.as_ref(), query_pool.as_ref());
 
         // Spawn worker to iterate through files, parse potential matches and forward ASTs
-        s.spawn(move |_| parse_files_worker(files, ast_tx, w, cpp));
+        s.spawn(move || match pp {
+            Some(pool) => pool.install(|| parse_files_worker(files, ast_tx, w, a, i, c, o)),
+            None => parse_files_worker(files, ast_tx, w, a, i, c, o),
+        });
 
         // Run search queries on ASTs and apply CLI constraints
         // on the results. For single query executions, we can
         // directly print any remaining matches. For multi
         // query runs we forward them to our next worker function
-        s.spawn(move |_| execute_queries_worker(ast_rx, results_tx, w, &args));
+        s.spawn(move || execute_queries_worker(ast_rx, results_tx, w, a, u, o, r, c, qp));
 
-        if w.len() > 1 {
-            s.spawn(move |_| multi_query_worker(results_rx, w.len(), before, after, enable_line_numbers));
+        if (w.len() > 1 || a.save_results.is_some()) && !a.invert_match && a.rules.is_empty() {
+            s.spawn(move || multi_query_worker(results_rx, w, a, u, o));
         }
     });
+
+    if found.load(Ordering::Relaxed) == 0 {
+        no_files()
+    }
+    if parsed.load(Ordering::Relaxed) == 0 {
+        info!("no files in this shard (--shard)");
+    }
+
+    if let Some(cache) = &cache {
+        info!(
+            "skipped {} unchanged files without matches (--no-match-cache)",
+            cache.hits()
+        );
+        if let Err(e) = cache.finish() {
+            warn!("Failed to update the no-match cache: {}", e);
+        }
+    }
+
+    output.finish();
+
+    if let Some(Err(e)) = args.baseline.as_ref().map(|b| b.finish()) {
+        eprintln!("Failed to write baseline: {}", e);
+        std::process::exit(2)
+    }
+
+    if !broken_rules.is_empty() {
+        eprintln!(
+            "{}",
+            format!("Skipped {} broken rule(s):", broken_rules.len()).yellow()
+        );
+        for (id, message) in &broken_rules {
+            eprintln!("  {}: {}", id.bold(), message);
+        }
+    }
+
+    if let Some(rewriter) = rewriter {
+        if rewriter.finish(args.in_place) > 0 {
+            output.mark_found();
+        }
+    }
+
+    warnings::summary();
+    events::emit("finished", json!({}));
+
+    // Like grep: 0 if there were results, 1 if there were none (errors exit with 2)
+    if !output.found() {
+        std::process::exit(1)
+    }
+}
+
+/// Returns `path` in the form that --include and --exclude regexes are matched against.
+/// Windows paths use forward slashes, so that the same regexes work on all platforms.
+fn regex_path(path: &Path) -> String {
+    let s = path.to_string_lossy();
+    if cfg!(windows) {
+        s.replace('\\', "/")
+    } else {
+        s.into_owned()
+    }
+}
+
+/// Identifies the compiled queries of a run for --no-match-cache. Files without results are only
+/// valid for the same queries, grammars and version of weggli.
+fn cache_key(work: &[WorkItem], args: &cli::Args) -> u64 {
+    let flags = format!(
+        "{} {} {} {}",
+        args.cpp, args.auto_lang, args.fallback_cpp, args.dual_headers
+    );
+    let mut parts = vec![env!("CARGO_PKG_VERSION").to_string(), flags];
+    for w in work {
+        for qt in [&w.c, &w.cpp] {
+            parts.push(serde_json::to_string(qt).unwrap_or_default());
+        }
+        parts.push(format!("{:?}", w.header_only));
+    }
+    let parts: Vec<&str> = parts.iter().map(String::as_str).collect();
+    output::fnv1a(&parts)
+}
+
+/// Read a list of files, one per line, from `path` or from STDIN if `path` is '-'.
+/// Empty lines are skipped.
+fn read_file_list(path: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let content = if path == Path::new("-") {
+        let mut content = Vec::new();
+        std::io::stdin().lock().read_to_end(&mut content)?;
+        content
+    } else {
+        fs::read(path)?
+    };
+    Ok(content
+        .split(|&b| b == b'\n')
+        .map(|l| {
+            let end = l.iter().rposition(|b| !b.is_ascii_whitespace());
+            &l[..end.map_or(0, |i| i + 1)]
+        })
+        .filter(|l| !l.is_empty())
+        .filter_map(path_from_bytes)
+        .collect())
+}
+
+/// Paths aren't necessarily valid UTF-8, so lines of a file list are used as they are.
+#[cfg(unix)]
+fn path_from_bytes(line: &[u8]) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(PathBuf::from(std::ffi::OsStr::from_bytes(line)))
+}
+
+/// Other platforms can't build a path from arbitrary bytes, so lines that are not
+/// valid UTF-8 are skipped.
+#[cfg(not(unix))]
+fn path_from_bytes(line: &[u8]) -> Option<PathBuf> {
+    match std::str::from_utf8(line) {
+        Ok(line) => Some(PathBuf::from(line)),
+        Err(_) => {
+            warn!(
+                "skipping {} in the file list: not valid UTF-8",
+                String::from_utf8_lossy(line)
+            );
+            None
+        }
+    }
+}
+
+/// Exit with a message because no files were found. Like a search of no files with grep,
+/// this is not an error: there are no matches, so the exit status is 1.
+fn no_files() -> ! {
+    eprintln!("{}", String::from("No files to parse. Exiting...").red());
+    std::process::exit(1)
+}
+
+/// Compile all regex constraints or exit with an error message.
+fn validate_regexes(regexes: &[String]) -> RegexMap {
+    process_regexes(regexes).unwrap_or_else(|e| {
+        eprintln!("{}", regex_error_message(e));
+        std::process::exit(2)
+    })
+}
+
+fn regex_error_message(e: RegexError) -> String {
+    match e {
+        RegexError::InvalidArg(s) => format!(
+            "'{}' is not a valid argument of the form var=regex",
+            s.red()
+        ),
+        RegexError::InvalidRegex(s) => format!("Regex error {}", s),
+    }
 }
 
 enum RegexError {
@@ -232,230 +820,1463 @@ fn process_regexes(regexes: &[String]) -> Result<RegexMap, RegexError> {
     Ok(RegexMap::new(result))
 }
 
-/// Recursively iterate through all files under `path` that match an ending listed in `extensions`
-fn iter_files(path: &Path, extensions: Vec<String>) -> impl Iterator<Item = walkdir::DirEntry> {
-    let is_hidden = |entry: &walkdir::DirEntry| {
-        entry
-            .file_name()
-            .to_str()
-            .map(|s| s.starts_with('.'))
-            .unwrap_or(false)
-    };
+struct WorkItem {
+    // Query compiled for the C grammar. None if the pattern is invalid in C mode
+    // or C mode is not enabled.
+    c: Option<QueryTree>,
+    // Query compiled for the C++ grammar.
+    cpp: Option<QueryTree>,
+    // The grammar (is_cpp) whose query is only run on headers (--dual-headers)
+    header_only: Option<bool>,
+    identifiers: Vec<String>,
+    // Precompiled substring searchers for `identifiers`
+    finders: Vec<memmem::Finder<'static>>,
+    // Number of context lines to display before and after each match
+    before: usize,
+    after: usize,
+    // The rule this query belongs to (--rules)
+    rule: Option<rules::Rule>,
+}
 
-    WalkDir::new(path)
-        .into_iter()
-        .filter_entry(move |e| !is_hidden(e))
-        .filter_map(|e| e.ok())
-        .filter(move |entry| {
-            if entry.file_type().is_dir() {
-                return false;
-            }
+impl WorkItem {
+    /// Returns the ID used to report results of this query, which has index `query_index`.
+    fn id(&self, query_index: usize) -> String {
+        match &self.rule {
+            Some(rule) => rule.id.clone(),
+            None => output::sarif_rule_id(query_index),
+        }
+    }
 
-            let path = entry.path();
+    /// Returns the query that should be executed against C (or C++) sources.
+    fn query(&self, is_cpp: bool) -> Option<&QueryTree> {
+        if is_cpp {
+            self.cpp.as_ref()
+        } else {
+            self.c.as_ref()
+        }
+    }
 
-            match path.extension() {
-                None => return false,
-                Some(ext) => {
-                    let s = ext.to_str().unwrap_or_default();
-                    if !extensions.contains(&s.to_string()) {
-                        return false;
-                    }
-                }
-            }
-            true
-        })
+    /// Like `query`, but without queries that are only run on header files
+    /// unless `is_header` is set.
+    fn query_for(&self, is_cpp: bool, is_header: bool) -> Option<&QueryTree> {
+        if self.header_only == Some(is_cpp) && !is_header {
+            return None;
+        }
+        self.query(is_cpp)
+    }
 }
-struct WorkItem {
-    qt: QueryTree,
-    identifiers: Vec<String>,
+
+/// Returns true for header files that are searched in both modes with --dual-headers.
+fn is_header(path: &Path) -> bool {
+    path.extension().map_or(false, |e| e == "h")
 }
 
 /// Iterate over all paths in `files`, parse files that might contain a match for any of the queries
 /// in `work` and send them to the next worker using `sender`.
+/// If --auto-lang is set, the language of each file is detected using `weggli::is_cpp_file`,
+/// otherwise all files are parsed according to --cpp.
+/// With --use-index, files that can't match according to `index` are skipped without reading them.
+/// With --no-match-cache, unchanged files without results in an earlier run are skipped as well.
+/// Files larger than --max-filesize are skipped with a warning, files that take longer
+/// than --timeout-per-file to parse are skipped. Both are reported by `output` at the end.
 fn parse_files_worker(
-    files: Vec<PathBuf>,
-    sender: Sender<(Arc<String>, Tree, String)>,
+    files: impl Iterator<Item = PathBuf> + Send,
+    sender: Sender<(weggli::ParsedFile, String)>,
     work: &[WorkItem],
-    is_cpp: bool,
+    args: &cli::Args,
+    index: Option<&index::Index>,
+    cache: Option<&no_match_cache::NoMatchCache>,
+    output: &output::Output,
 ) {
-    let tl = ThreadLocal::new();
-
-    files
-        .into_par_iter()
-        .for_each_with(sender, move |sender, path| {
-            let maybe_parse = |path| {
-                let c = match fs::read(path) {
-                    Ok(content) => content,
-                    Err(_) => return None,
-                };
+    let loader = weggli::Loader::default()
+        .with_max_filesize(args.max_filesize)
+        .with_timeout(args.timeout_per_file);
 
-                let source = String::from_utf8_lossy(&c);
+    // Languages we need to parse files in. Normally this is only the configured
+    // mode, but --fallback-cpp can add C++ queries to a C mode run.
+    let languages: Vec<bool> = [false, true]
+        .iter()
+        .copied()
+        .filter(|&cpp| work.iter().any(|w| w.query(cpp).is_some()))
+        .collect();
 
-                let potential_match = work.iter().any(|WorkItem { qt: _, identifiers }| {
-                    identifiers.iter().all(|i| source.find(i).is_some())
-                });
+    files.par_bridge().for_each_with(sender, |sender, path| {
+        if events::enabled() {
+            events::emit("file_started", json!({ "path": path }));
+        }
 
-                if !potential_match {
-                    None
-                } else {
-                    let mut parser = tl
-                        .get_or(|| RefCell::new(weggli::get_parser(is_cpp)))
-                        .borrow_mut();
-                    let tree = parser.parse(&source.as_bytes(), None).unwrap();
-                    Some((tree, source.to_string()))
+        if let Some(cache) = cache {
+            if cache.skip(&path) {
+                if events::enabled() {
+                    events::emit(
+                        "file_skipped",
+                        json!({ "path": path, "reason": "no match (cache)" }),
+                    );
                 }
+                return;
+            }
+            cache.visit(&path);
+        }
+        // The file is not cached if it isn't searched completely.
+        let incomplete = || {
+            if let Some(cache) = cache {
+                cache.found(&path.display().to_string());
+            }
+        };
+
+        // Skip files that can't match based on the identifiers stored in the index.
+        if let Some(identifiers) = index.and_then(|i| i.identifiers(&path)) {
+            let could_match = |w: &WorkItem| {
+                w.identifiers
+                    .iter()
+                    .all(|i| index::could_contain(identifiers, i))
             };
-            if let Some((source_tree, source)) = maybe_parse(&path) {
-                sender
-                    .send((
-                        std::sync::Arc::new(source),
-                        source_tree,
-                        path.display().to_string(),
-                    ))
-                    .unwrap();
+            let potential_match = if args.invert_match {
+                work.iter().skip(1).all(could_match)
+            } else {
+                work.iter().any(could_match)
+            };
+            if !potential_match {
+                if events::enabled() {
+                    events::emit(
+                        "file_skipped",
+                        json!({ "path": path, "reason": "no potential match (index)" }),
+                    );
+                }
+                return;
             }
-        });
+        }
+
+        let header = args.dual_headers && is_header(&path);
+
+        // Check the raw file content so we can skip the
+        // UTF-8 conversion for files that can't match.
+        let file_languages = |c: &[u8]| {
+            let file_languages = if header {
+                vec![false, true]
+            } else if args.auto_lang {
+                vec![weggli::is_cpp_file(&path, c)]
+            } else {
+                languages.clone()
+            };
+
+            file_languages
+                .into_iter()
+                .filter(|&cpp| {
+                    let could_match = |w: &WorkItem| {
+                        w.query_for(cpp, header).is_some()
+                            && w.finders.iter().all(|f| f.find(c).is_some())
+                    };
+
+                    // With --invert-match, files without a match for the first pattern are
+                    // interesting as well, so we only check the additional patterns.
+                    if args.invert_match {
+                        work[0].query_for(cpp, header).is_some()
+                            && work.iter().skip(1).all(could_match)
+                    } else {
+                        work.iter().any(could_match)
+                    }
+                })
+                .collect()
+        };
+
+        for parsed in loader.load(&path, file_languages) {
+            let reason = match parsed {
+                Ok(file) => {
+                    if events::enabled() && file.tree.root_node().has_error() {
+                        events::emit("parse_error", json!({ "path": path }));
+                    }
+                    sender.send((file, path.display().to_string())).unwrap();
+                    continue;
+                }
+                Err(weggli::Skipped::NoPotentialMatch) => "no potential match".to_string(),
+                Err(weggli::Skipped::TooLarge(size)) => {
+                    warn!(
+                        "{}: skipping file with {} bytes (see --max-filesize, --force-large)",
+                        path.display(),
+                        size
+                    );
+                    output.skip(&path, format!("{} bytes (--max-filesize)", size));
+                    incomplete();
+                    "file too large".to_string()
+                }
+                Err(weggli::Skipped::Unreadable(e)) => {
+                    incomplete();
+                    e.to_string()
+                }
+                Err(weggli::Skipped::Timeout) => {
+                    output.skip(&path, "parsing timed out (--timeout-per-file)".to_string());
+                    incomplete();
+                    "parse timeout".to_string()
+                }
+            };
+            if events::enabled() {
+                events::emit("file_skipped", json!({ "path": path, "reason": reason }));
+            }
+        }
+    });
 }
 
 struct ResultsCtx {
     query_index: usize,
     path: String,
-    source: std::sync::Arc<String>,
+    source: Arc<String>,
     result: weggli::result::QueryResult,
+    details: Details,
 }
 
-/// Fetches parsed ASTs from `receiver`, runs all queries in `work` on them and
-/// filters the results based on the provided regex `constraints` and --unique --limit switches.
-/// For single query runs, the remaining results are directly printed. Otherwise they get forwarded
-/// to `multi_query_worker` through the `results_tx` channel.
-fn execute_queries_worker(
-    receiver: Receiver<(Arc<String>, Tree, String)>,
-    results_tx: Sender<ResultsCtx>,
-    work: &[WorkItem],
+/// Compile `pattern`, which is a raw tree-sitter query with --raw-tsquery.
+fn compile_pattern(
     args: &cli::Args,
-) {
-    receiver.into_iter().par_bridge().for_each_with(
-        results_tx,
-        |results_tx, (source, tree, path)| {
-            // For each query
-            work.iter()
-                .enumerate()
-                .for_each(|(i, WorkItem { qt, identifiers: _ })| {
-                    // Run query
-                    let matches = qt.matches(tree.root_node(), &source);
-
-                    if matches.is_empty() {
-                        return;
-                    }
-
-                    // Enforce --unique
-                    let check_unique = |m: &QueryResult| {
-                        if args.unique {
-                            let mut seen = HashSet::new();
-                            m.vars
-                                .keys()
-                                .map(|k| m.value(k, &source).unwrap())
-                                .all(|x| seen.insert(x))
-                        } else {
-                            true
-                        }
-                    };
+    pattern: &str,
+    is_cpp: bool,
+    options: QueryOptions,
+) -> Result<QueryTree, QueryError> {
+    if args.raw_query {
+        parse_raw_query(pattern, is_cpp, Some(options))
+    } else {
+        parse_search_pattern(pattern, is_cpp, args.force_query, Some(options))
+    }
+}
 
-                    let mut skip_set = HashSet::new();
+/// Tell the user about normalizations that changed `pattern` before it was compiled
+/// into `qt`, so it is clear what query is actually run (with -v or as an event
+/// with --log-format json). They can be disabled with --no-normalize.
+fn report_normalization(pattern: &str, qt: &QueryTree) {
+    if qt.normalizations().is_empty() {
+        return;
+    }
 
-                    // Enforce --limit
-                    let check_limit = |m: &QueryResult| {
-                        if args.limit {
-                            skip_set.insert(m.start_offset())
-                        } else {
-                            true
-                        }
-                    };
+    let normalizations: Vec<String> = qt.normalizations().iter().map(|n| n.to_string()).collect();
+    if events::enabled() {
+        events::emit(
+            "normalized",
+            json!({ "pattern": pattern, "query": qt.pattern(), "normalizations": normalizations }),
+        );
+    } else {
+        info!(
+            "query normalized to '{}' ({}). Use --no-normalize to disable this.",
+            qt.pattern(),
+            normalizations.join(", ")
+        );
+    }
+}
 
-                    // Print match or forward it if we are in a multi query context
-                    let process_match = |m: QueryResult| {
-                        // single query
-                        if work.len() == 1 {
-                            let line = source[..m.start_offset()].matches('\n').count() + 1;
-                            println!(
-                                "{}:{}\n{}",
-                                path.clone().bold(),
-                                line,
-                                m.display(&source, args.before, args.after, args.enable_line_numbers)
-                            );
-                        } else {
-                            results_tx
-                                .send(ResultsCtx {
-                                    query_index: i,
-                                    result: m,
-                                    path: path.clone(),
-                                    source: source.clone(),
-                                })
-                                .unwrap();
-                        }
-                    };
+/// Optional information about a result that needs the parse tree or the query,
+/// so it is computed before the result is printed or forwarded.
+#[derive(Default)]
+struct Details {
+    // metrics of the enclosing function (--metrics)
+    metrics: Option<serde_json::Value>,
+    // sub-pattern that produced each capture (--explain-results)
+    explanation: Option<Vec<Attribution>>,
+    // function definition enclosing the result (--format targets)
+    function: Option<Function>,
+    // ranges of the statements around the matched statements (--context-statements)
+    context: Vec<std::ops::Range<usize>>,
+    // ranges of the matched statements (--only-matching, --format oneline)
+    statements: Vec<std::ops::Range<usize>>,
+    // last commit that changed the matched lines (--blame)
+    blame: Option<serde_json::Value>,
+    // start of the outermost node shown above the matched lines (--function-header)
+    header: Header,
+}
 
-                    matches
-                        .into_iter()
-                        .filter(check_unique)
-                        .filter(check_limit)
-                        .for_each(process_match);
+/// Returns how the start of the outermost node of `m` is displayed (--function-header).
+/// The signature and name are only available for function definitions.
+fn function_header(
+    root: tree_sitter::Node,
+    m: &QueryResult,
+    source: &str,
+    mode: cli::FunctionHeader,
+) -> Header {
+    let node = || {
+        Some(metrics::enclosing_node(root, &m.function_range()))
+            .filter(|n| n.kind() == "function_definition")
+    };
+    match mode {
+        cli::FunctionHeader::Line => Header::FirstLine,
+        cli::FunctionHeader::None => Header::Omit,
+        cli::FunctionHeader::Signature => node()
+            .and_then(|n| n.child_by_field_name("body"))
+            .map_or(Header::FirstLine, |body| {
+                Header::Until(source[..body.start_byte()].trim_end().len())
+            }),
+        cli::FunctionHeader::Name => node()
+            .and_then(|n| weggli::functions::name(n, source))
+            .map_or(Header::FirstLine, |name| Header::Text(name.to_string())),
+    }
+}
+
+/// Name and byte range of a function definition.
+struct Function {
+    name: Option<String>,
+    range: std::ops::Range<usize>,
+}
+
+impl Function {
+    /// Returns the innermost function definition containing the captured nodes of `m`.
+    /// The outermost node is not used, as it is not a function with --anchor file/class.
+    fn enclosing(root: tree_sitter::Node, m: &QueryResult, source: &str) -> Option<Function> {
+        let outer = m.function_range();
+        let ranges = m.captures.iter().map(|c| &c.range).filter(|r| **r != outer);
+        let range = match (
+            ranges.clone().map(|r| r.start).min(),
+            ranges.map(|r| r.end).max(),
+        ) {
+            (Some(start), Some(end)) => start..end,
+            _ => outer,
+        };
+
+        let mut node = Some(metrics::enclosing_node(root, &range));
+        while let Some(n) = node {
+            if n.kind() == "function_definition" {
+                return Some(Function {
+                    name: weggli::functions::name(n, source).map(|s| s.to_string()),
+                    range: n.byte_range(),
                 });
+            }
+            node = n.parent();
+        }
+        None
+    }
+
+    fn to_json(&self, path: &str, source: &str) -> serde_json::Value {
+        json!({
+            "path": path,
+            "function": self.name,
+            "start": self.range.start,
+            "end": self.range.end,
+            "line": output::line_column(source, self.range.start).0,
+            "end_line": output::line_column(source, self.range.end).0,
+        })
+    }
+}
+
+/// Returns one range for each statement containing a captured node of `m`, extended
+/// to `n` sibling statements before and after it. Statements are the named children
+/// of a `block_kind` node, comments are not counted.
+fn statement_context(
+    root: tree_sitter::Node,
+    m: &QueryResult,
+    block_kind: &str,
+    n: usize,
+) -> Vec<std::ops::Range<usize>> {
+    let outer = m.function_range();
+    let mut ranges: Vec<_> = m
+        .captures
+        .iter()
+        .map(|c| &c.range)
+        .filter(|r| **r != outer)
+        .collect();
+    if ranges.is_empty() {
+        ranges.push(&outer);
+    }
+
+    fn sibling(node: tree_sitter::Node, next: bool) -> Option<tree_sitter::Node> {
+        let mut s = node;
+        loop {
+            s = if next {
+                s.next_named_sibling()?
+            } else {
+                s.prev_named_sibling()?
+            };
+            if s.kind() != "comment" {
+                return Some(s);
+            }
+        }
+    }
+
+    let mut context = Vec::new();
+    for r in ranges {
+        let mut node = Some(metrics::enclosing_node(root, r));
+        while let Some(n) = node {
+            if n.parent().map_or(false, |p| p.kind() == block_kind) {
+                break;
+            }
+            node = n.parent();
+        }
+        let statement = match node {
+            Some(s) => s,
+            None => continue,
+        };
+
+        let (mut first, mut last) = (statement, statement);
+        for _ in 0..n {
+            match sibling(first, false) {
+                Some(s) => first = s,
+                None => break,
+            }
+        }
+        for _ in 0..n {
+            match sibling(last, true) {
+                Some(s) => last = s,
+                None => break,
+            }
+        }
+        context.push(first.start_byte()..last.end_byte());
+    }
+    context
+}
+
+/// Returns the ranges of the statements or declarations containing the captured
+/// nodes of `m` in source order, without statements nested in another one.
+/// Captures outside of statements are reported as the whole match.
+fn matched_statements(
+    root: tree_sitter::Node,
+    m: &QueryResult,
+    block_kind: &str,
+) -> Vec<std::ops::Range<usize>> {
+    let outer = m.function_range();
+    let mut ranges: Vec<_> = m
+        .captures
+        .iter()
+        .filter(|c| c.range != outer)
+        .map(|c| {
+            let mut node = Some(metrics::enclosing_node(root, &c.range));
+            while let Some(n) = node {
+                if n.kind().ends_with("statement")
+                    || n.kind().ends_with("declaration")
+                    || n.parent().map_or(false, |p| p.kind() == block_kind)
+                {
+                    return n.byte_range();
+                }
+                node = n.parent();
+            }
+            outer.clone()
+        })
+        .collect();
+    if ranges.is_empty() {
+        ranges.push(outer);
+    }
+    ranges.sort_by_key(|r| (r.start, std::cmp::Reverse(r.end)));
+
+    let mut statements: Vec<std::ops::Range<usize>> = Vec::new();
+    for r in ranges {
+        if statements.last().map_or(true, |s| r.end > s.end) {
+            statements.push(r);
+        }
+    }
+    statements
+}
+
+/// Describes which sub-pattern of a query produced a capture.
+struct Attribution {
+    range: std::ops::Range<usize>,
+    variable: Option<String>,
+    query_id: usize,
+    clause: String,
+}
+
+impl Attribution {
+    fn to_json(&self, source: &str) -> serde_json::Value {
+        let (line, column) = output::line_column(source, self.range.start);
+        json!({
+            "start": self.range.start,
+            "end": self.range.end,
+            "line": line,
+            "column": column,
+            "variable": self.variable,
+            "value": &source[self.range.clone()],
+            "query_id": self.query_id,
+            "clause": self.clause,
+        })
+    }
+}
+
+/// Attribute all captures of `m` (except the one for the enclosing function) to the
+/// sub-pattern of `qt` that produced them.
+fn explain(qt: &QueryTree, m: &QueryResult) -> Vec<Attribution> {
+    let function = m.function_range();
+
+    let mut result: Vec<Attribution> = Vec::new();
+    for c in &m.captures {
+        if c.range == function {
+            continue;
+        }
+        let clause = match qt.clause(c.query_id, c.clause) {
+            Some(clause) => clause.split_whitespace().collect::<Vec<_>>().join(" "),
+            None => continue,
+        };
+        let a = Attribution {
+            range: c.range.clone(),
+            variable: qt
+                .capture_variable(c.query_id, c.capture_idx)
+                .map(|v| v.to_string()),
+            query_id: c.query_id,
+            clause,
+        };
+        // The same node can be captured more than once by a sub-pattern.
+        if !result
+            .iter()
+            .any(|r| r.range == a.range && r.clause == a.clause)
+        {
+            result.push(a);
+        }
+    }
+    result.sort_by_key(|a| a.range.start);
+    result
+}
+
+/// Text representation of --explain-results, printed after each result.
+fn explanation_text(source: &str, explanation: &[Attribution]) -> String {
+    let mut s = format!("{}\n", "explanation:".bold());
+    for a in explanation {
+        let (line, _) = output::line_column(source, a.range.start);
+        let value = &source[a.range.clone()];
+        let value = match value.find('\n') {
+            Some(i) => format!("{}...", &value[..i]),
+            None => value.to_string(),
+        };
+        let capture = match &a.variable {
+            Some(v) => format!("{} = {}", v, value),
+            None => value,
+        };
+        s += &format!("  {:>5}: {:<30} from {}\n", line, capture, a.clause.cyan());
+    }
+    s
+}
+
+/// Implements --unique-by: Only the first result for each distinct value (or
+/// combination of values) of `variables` is displayed.
+/// Values are compared without comments and whitespace unless `raw` (--raw-values) is set.
+/// It also drops results in headers that were found by both grammars (--dual-headers).
+struct UniqueBy {
+    variables: Vec<String>,
+    seen: Mutex<HashSet<Vec<String>>>,
+    raw: bool,
+    // (query index, path, matched range) of the results in headers
+    header_results: Mutex<HashSet<(usize, String, std::ops::Range<usize>)>>,
+}
+
+impl UniqueBy {
+    fn normalize<'a>(&self, value: &'a str) -> std::borrow::Cow<'a, str> {
+        if self.raw {
+            value.into()
+        } else {
+            weggli::normalize_value(value)
+        }
+    }
+
+    fn check(&self, m: &QueryResult, source: &str) -> bool {
+        self.insert(self.key(m, source))
+    }
+
+    /// Returns the values of the --unique-by variables in `m`, or None if it
+    /// doesn't bind any of them.
+    fn key(&self, m: &QueryResult, source: &str) -> Option<Vec<String>> {
+        let values: Vec<Option<&str>> = self.variables.iter().map(|v| m.value(v, source)).collect();
+
+        // Results that don't bind any of the variables can't be deduplicated
+        if values.iter().all(Option::is_none) {
+            return None;
+        }
+
+        let key = values
+            .into_iter()
+            .map(|v| self.normalize(v.unwrap_or_default()).to_string())
+            .collect();
+        Some(key)
+    }
+
+    /// Returns false if the result of query `query_index` at `range` in the
+    /// header `path` was already found with the other grammar.
+    fn check_header(&self, query_index: usize, path: &str, range: std::ops::Range<usize>) -> bool {
+        self.header_results
+            .lock()
+            .unwrap()
+            .insert((query_index, path.to_string(), range))
+    }
+
+    /// Returns false if a result with the same `key` was already seen.
+    fn insert(&self, key: Option<Vec<String>>) -> bool {
+        match key {
+            Some(key) => self.seen.lock().unwrap().insert(key),
+            None => true,
+        }
+    }
+}
+
+/// Fetches parsed ASTs from `receiver`, runs all queries in `work` on them and
+/// filters the results based on the provided regex `constraints` and --unique --limit switches.
+/// --unique-by is enforced using the shared `unique_by` filter.
+/// For single query runs, the remaining results are directly printed (or passed to `rewriter`
+/// with --rewrite). Otherwise they get forwarded to `multi_query_worker` through the
+/// `results_tx` channel.
+#[allow(clippy::too_many_arguments)]
+fn execute_queries_worker<'a>(
+    receiver: Receiver<(weggli::ParsedFile, String)>,
+    results_tx: Sender<ResultsCtx>,
+    work: &'a [WorkItem],
+    args: &'a cli::Args,
+    unique_by: &'a UniqueBy,
+    output: &'a output::Output,
+    rewriter: Option<&'a rewrite::Rewriter>,
+    cache: Option<&'a no_match_cache::NoMatchCache>,
+    pool: Option<&rayon::ThreadPool>,
+) {
+    // Files are received on this thread and only the queries run in the pool. A pool
+    // thread waiting for the next file could block the parse workers sharing the pool.
+    let receive = |s: &rayon::Scope<'a>| {
+        for parsed in receiver {
+            let results_tx = results_tx.clone();
+            s.spawn(move |_| {
+                execute_queries(
+                    parsed,
+                    &results_tx,
+                    work,
+                    args,
+                    unique_by,
+                    output,
+                    rewriter,
+                    cache,
+                )
+            });
+        }
+    };
+    match pool {
+        Some(pool) => pool.in_place_scope(receive),
+        None => rayon::in_place_scope(receive),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn execute_queries(
+    (weggli::ParsedFile { source, tree, cpp }, path): (weggli::ParsedFile, String),
+    results_tx: &Sender<ResultsCtx>,
+    work: &[WorkItem],
+    args: &cli::Args,
+    unique_by: &UniqueBy,
+    output: &output::Output,
+    rewriter: Option<&rewrite::Rewriter>,
+    cache: Option<&no_match_cache::NoMatchCache>,
+) {
+    if args.invert_match {
+        print_unmatched_functions(&source, &tree, &path, cpp, work, output, args.format);
+        return;
+    }
+
+    // All queries on the file share the --timeout-per-file budget.
+    let deadline = args.timeout_per_file.map(|t| std::time::Instant::now() + t);
+    let mut timed_out = false;
+
+    // --dual-headers: headers are searched with both grammars
+    let header = args.dual_headers && is_header(Path::new(&path));
+
+    // Results can be suppressed with weggli-ignore comments
+    let comments = suppress::Comments::parse(&source);
+
+    // --changed-lines: lines changed since the --diff revision
+    let changed_lines = match &args.diff {
+        Some(changes) if args.changed_lines => Some(changes.lines(Path::new(&path)).unwrap_or(&[])),
+        _ => None,
+    };
+
+    // --blame: the file is only blamed once it has a result
+    let file_blame: RefCell<Option<Option<blame::FileBlame>>> = RefCell::new(None);
+
+    // For each query
+    work.iter().enumerate().for_each(|(i, w)| {
+        let qt = match w.query_for(cpp, header) {
+            Some(qt) => qt,
+            None => return,
+        };
+
+        // Run query
+        let matches = match deadline {
+            Some(deadline) => match qt.matches_until(tree.root_node(), &source, deadline) {
+                Some(matches) => matches,
+                None => {
+                    timed_out = true;
+                    return;
+                }
+            },
+            None => qt.matches(tree.root_node(), &source),
+        };
+
+        if events::enabled() {
+            events::emit(
+                "matches",
+                json!({ "path": path, "query": i, "count": matches.len() }),
+            );
+        }
+
+        if matches.is_empty() {
+            return;
+        }
+        if let Some(cache) = cache {
+            cache.found(&path);
+        }
+
+        // Results in headers are reported once, even if both grammars find them
+        let check_header =
+            |m: &QueryResult| !header || unique_by.check_header(i, &path, m.matched_range(&source));
+
+        let mut skip_set = HashSet::new();
+
+        // Enforce --limit
+        let check_limit = |m: &QueryResult| {
+            if args.limit {
+                skip_set.insert(m.start_offset())
+            } else {
+                true
+            }
+        };
+
+        // Enforce --filter
+        let check_filters = |m: &QueryResult| {
+            args.filters
+                .iter()
+                .all(|f| f.check(&|v: &str| m.value(v, &source).map(|v| unique_by.normalize(v))))
+        };
+
+        // Enforce --min/max-function-lines and --min/max-complexity
+        let check_metrics = |m: &QueryResult| {
+            if args.function_lines == (None, None) && args.complexity == (None, None) {
+                return true;
+            }
+            let f = metrics::enclosing_node(tree.root_node(), &m.function_range());
+            in_range(metrics::line_count(f), args.function_lines)
+                && (args.complexity == (None, None)
+                    || in_range(metrics::cyclomatic_complexity(f), args.complexity))
+        };
+
+        // Drop results suppressed by a weggli-ignore comment or the --baseline
+        let check_suppressed = |m: &QueryResult| {
+            let line = output::line_column(&source, m.start_offset()).0;
+            let range = m.matched_range(&source);
+            let first = output::line_column(&source, range.start).0;
+            let last = output::line_column(&source, range.end.saturating_sub(1)).0;
+            let id = w.id(i);
+            if comments.suppresses(&id, first..=last.max(first))
+                || comments.suppresses(&id, line..=line)
+            {
+                return false;
+            }
+            args.baseline.as_ref().map_or(true, |b| {
+                let (uri, snippet) = (sarif_uri(&path), m.snippet(&source));
+                if b.is_recording() {
+                    b.record(&id, &uri, line, &snippet);
+                    return true;
+                }
+                !b.suppresses(&id, &uri, &snippet)
+            })
+        };
+
+        // Enforce --changed-lines
+        let check_changed = |m: &QueryResult| {
+            let changed_lines = match changed_lines {
+                Some(lines) => lines,
+                None => return true,
+            };
+            let range = m.matched_range(&source);
+            let first = output::line_column(&source, range.start).0;
+            let last = output::line_column(&source, range.end.saturating_sub(1)).0;
+            changed_lines
+                .iter()
+                .any(|r| *r.start() <= last && first <= *r.end())
+        };
+
+        // Compute --metrics for the node enclosing the match and --explain-results
+        let details = |m: &QueryResult| Details {
+            metrics: if args.metrics {
+                let f = metrics::enclosing_node(tree.root_node(), &m.function_range());
+                Some(metrics::metrics(f, &source))
+            } else {
+                None
+            },
+            explanation: if args.explain {
+                Some(explain(qt, m))
+            } else {
+                None
+            },
+            function: if args.format == cli::Format::Targets
+                || args
+                    .output_template
+                    .as_ref()
+                    .map_or(false, |t| t.uses_function())
+            {
+                Function::enclosing(tree.root_node(), m, &source)
+            } else {
+                None
+            },
+            context: match args.context_statements {
+                Some(n) => {
+                    let block_kind = weggli::language::for_cpp(cpp).block_kind();
+                    statement_context(tree.root_node(), m, block_kind, n)
+                }
+                None => Vec::new(),
+            },
+            statements: if args.only_matching || args.format == cli::Format::Oneline {
+                let block_kind = weggli::language::for_cpp(cpp).block_kind();
+                matched_statements(tree.root_node(), m, block_kind)
+            } else {
+                Vec::new()
+            },
+            blame: if args.blame {
+                let range = m.matched_range(&source);
+                let first = output::line_column(&source, range.start).0;
+                let last = output::line_column(&source, range.end.saturating_sub(1)).0;
+                let mut file_blame = file_blame.borrow_mut();
+                let file_blame = file_blame
+                    .get_or_insert_with(|| blame::FileBlame::new(Path::new(&path), &source));
+                Some(file_blame.as_ref().map_or(serde_json::Value::Null, |b| {
+                    b.last_change(first..=last.max(first))
+                }))
+            } else {
+                None
+            },
+            header: function_header(tree.root_node(), m, &source, args.function_header),
+        };
+
+        // Print match or forward it if we are in a multi query context
+        let process_match = |m: QueryResult| {
+            // single query, or independent queries loaded with --rules
+            if (work.len() == 1 || !args.rules.is_empty()) && args.save_results.is_none() {
+                if !unique_by.check(&m, &source) {
+                    return;
+                }
+                match rewriter {
+                    Some(r) => r.add(&path, &source, tree.root_node(), &m),
+                    None => {
+                        output.print_match(finding(args, w, i, &path, &source, &m, details(&m)))
+                    }
+                }
+            } else {
+                results_tx
+                    .send(ResultsCtx {
+                        query_index: i,
+                        details: details(&m),
+                        result: m,
+                        path: path.clone(),
+                        source: source.clone(),
+                    })
+                    .unwrap();
+            }
+        };
+
+        matches
+            .into_iter()
+            .filter(check_filters)
+            .filter(check_metrics)
+            .filter(check_header)
+            .filter(check_changed)
+            .filter(check_suppressed)
+            .filter(check_limit)
+            .for_each(process_match);
+    });
+
+    if timed_out {
+        if let Some(cache) = cache {
+            cache.found(&path);
+        }
+        output.skip(
+            Path::new(&path),
+            "queries timed out (--timeout-per-file)".to_string(),
+        );
+        if events::enabled() {
+            events::emit(
+                "file_skipped",
+                json!({ "path": path, "reason": "query timeout" }),
+            );
+        }
+    }
+}
+
+fn in_range(value: usize, (min, max): (Option<usize>, Option<usize>)) -> bool {
+    min.map_or(true, |min| value >= min) && max.map_or(true, |max| value <= max)
+}
+
+/// Format the result `m` of the query `work` for printing.
+fn finding(
+    args: &cli::Args,
+    work: &WorkItem,
+    query_index: usize,
+    path: &str,
+    source: &str,
+    m: &QueryResult,
+    details: Details,
+) -> output::Finding {
+    let (line, column) = output::line_column(source, m.start_offset());
+
+    let body = match &args.output_template {
+        Some(template) => {
+            let fields = template::Fields {
+                file: path,
+                line,
+                column,
+                function: details.function.as_ref().and_then(|f| f.name.as_deref()),
+                rule: work.rule.as_ref().map(|r| r.id.as_str()),
+                snippet: &m.snippet(source),
+            };
+            output::Body::Lines(template.render(&fields, &|v| {
+                m.value(v, source).map(|value| {
+                    if args.raw_values {
+                        value.into()
+                    } else {
+                        weggli::normalize_value(value)
+                    }
+                })
+            }))
+        }
+        None => match args.format {
+            cli::Format::Json => json_result(work, query_index, path, source, m, details),
+            cli::Format::Sarif => sarif_result(work, query_index, path, source, m, details),
+            cli::Format::Github => {
+                let range = m.matched_range(source);
+                let (severity, message) = result_message(work, query_index);
+                let level = match severity {
+                    rules::Severity::Error => "error",
+                    rules::Severity::Warning => "warning",
+                    rules::Severity::Note => "notice",
+                };
+                let lines = (
+                    output::line_column(source, range.start).0,
+                    output::line_column(source, range.end).0,
+                );
+                output::Body::Lines(output::github_annotation(
+                    level,
+                    &sarif_uri(path),
+                    lines,
+                    &work.id(query_index),
+                    &message,
+                ))
+            }
+            cli::Format::GitlabCodequality => {
+                let range = m.matched_range(source);
+                let (severity, message) = result_message(work, query_index);
+                let severity = match severity {
+                    rules::Severity::Error => "major",
+                    rules::Severity::Warning => "minor",
+                    rules::Severity::Note => "info",
+                };
+                output::Body::Codequality(output::codequality_issue(
+                    &work.id(query_index),
+                    severity,
+                    &message,
+                    &sarif_uri(path),
+                    output::line_column(source, range.start).0,
+                    &m.snippet(source),
+                ))
+            }
+            cli::Format::Targets => {
+                // Results outside of functions are reported with the range of the match.
+                let function = details.function.unwrap_or(Function {
+                    name: None,
+                    range: m.function_range(),
+                });
+                let mut target = function.to_json(path, source);
+                target["query"] = json!(query_index);
+                output::Body::Json(target)
+            }
+            cli::Format::Oneline => output::Body::Lines(oneline(
+                path,
+                source,
+                &details.statements,
+                args.only_matching,
+            )),
+            cli::Format::Csv | cli::Format::Tsv => {
+                let mut row = vec![path.to_string(), line.to_string()];
+                row.extend(args.columns.iter().map(|v| {
+                    m.value(v, source).map_or(String::new(), |value| {
+                        if args.raw_values {
+                            value.to_string()
+                        } else {
+                            weggli::normalize_value(value).into_owned()
+                        }
+                    })
+                }));
+                output::Body::Lines(output::table_row(&row, args.format == cli::Format::Tsv))
+            }
+            cli::Format::Text if args.only_matching => {
+                let text: Vec<&str> = details
+                    .statements
+                    .iter()
+                    .map(|r| &source[r.clone()])
+                    .collect();
+                output::Body::Text(text.join("\n") + "\n")
+            }
+            cli::Format::Text => {
+                let mut text = m.display_with_header(
+                    source,
+                    &details.context,
+                    &details.header,
+                    work.before,
+                    work.after,
+                    args.enable_line_numbers,
+                );
+                if let Some(explanation) = &details.explanation {
+                    text += &explanation_text(source, explanation);
+                }
+                match &work.rule {
+                    Some(rule) => output::Body::Text(format!("{}\n{}", rule_header(rule), text)),
+                    None => output::Body::Text(text),
+                }
+            }
         },
-    );
+    };
+
+    // --list-variables, --aggregate and --group-by use normalized values, unless --raw-values
+    // is set.
+    let variables = if args.list_variables || args.aggregate.is_some() || args.group_by.is_some() {
+        m.vars
+            .keys()
+            .map(|k| {
+                let value = m.value(k, source).unwrap();
+                let value = if args.raw_values {
+                    value.to_string()
+                } else {
+                    weggli::normalize_value(value).into_owned()
+                };
+                (k.clone(), value)
+            })
+            .collect()
+    } else {
+        vec![]
+    };
+
+    output::Finding {
+        location: format!("{}:{}", path, line),
+        snippet: m.snippet(source),
+        variables,
+        body,
+    }
+}
+
+/// Formats the matched `statements` as path:line:col: code lines (--format oneline).
+/// Prints the first line of every statement (once for statements on the same line),
+/// or every whole statement with `only_matching`.
+fn oneline(
+    path: &str,
+    source: &str,
+    statements: &[std::ops::Range<usize>],
+    only_matching: bool,
+) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    let mut last_line = 0;
+    for r in statements.iter().cloned() {
+        let (line, column) = output::line_column(source, r.start);
+        let code = if only_matching {
+            source[r].split_whitespace().collect::<Vec<_>>().join(" ")
+        } else if line != last_line {
+            let start = r.start + 1 - column;
+            let end = source[start..]
+                .find('\n')
+                .map_or(source.len(), |i| start + i);
+            source[start..end].trim().to_string()
+        } else {
+            continue;
+        };
+        last_line = line;
+        lines.push(format!("{}:{}:{}: {}", path.bold(), line, column, code));
+    }
+    lines.join("\n")
+}
+
+/// Returns a header line such as "warning[id]: description" for results of `rule`.
+fn rule_header(rule: &rules::Rule) -> String {
+    let severity = match rule.severity {
+        rules::Severity::Error => "error".red(),
+        rules::Severity::Warning => "warning".yellow(),
+        rules::Severity::Note => "note".cyan(),
+    };
+    match &rule.description {
+        Some(d) => format!("{}[{}]: {}", severity.bold(), rule.id, d),
+        None => format!("{}[{}]", severity.bold(), rule.id),
+    }
+}
+
+fn json_result(
+    work: &WorkItem,
+    query_index: usize,
+    path: &str,
+    source: &str,
+    m: &QueryResult,
+    details: Details,
+) -> output::Body {
+    let (line, column) = output::line_column(source, m.start_offset());
+    let range = m.function_range();
+    let captures: Vec<_> = m
+        .captures
+        .iter()
+        .map(|c| {
+            let (line, column) = output::line_column(source, c.range.start);
+            json!({ "start": c.range.start, "end": c.range.end, "line": line, "column": column })
+        })
+        .collect();
+    let vars: HashMap<&str, &str> = m
+        .vars
+        .keys()
+        .map(|k| (k.as_str(), m.value(k, source).unwrap()))
+        .collect();
+
+    let mut result = json!({
+        "path": path,
+        "query": query_index,
+        "line": line,
+        "column": column,
+        "start": range.start,
+        "end": range.end,
+        "captures": captures,
+        "snippet": m.matched_lines(source),
+        "vars": vars,
+    });
+    if let Some(rule) = &work.rule {
+        result["rule"] = json!(rule.id);
+        result["severity"] = json!(rule.severity.to_string());
+    }
+    if let Some(metrics) = details.metrics {
+        result["metrics"] = metrics;
+    }
+    if let Some(explanation) = details.explanation {
+        result["explanation"] = explanation
+            .iter()
+            .map(|a| a.to_json(source))
+            .collect::<Vec<_>>()
+            .into();
+    }
+    if let Some(blame) = details.blame {
+        result["blame"] = blame;
+    }
+    output::Body::Json(result)
+}
+
+fn sarif_result(
+    work: &WorkItem,
+    query_index: usize,
+    path: &str,
+    source: &str,
+    m: &QueryResult,
+    details: Details,
+) -> output::Body {
+    let range = m.matched_range(source);
+    let (start_line, start_column) = output::line_column(source, range.start);
+    let (end_line, end_column) = output::line_column(source, range.end);
+
+    let (level, message) = result_message(work, query_index);
+
+    let mut result = json!({
+        "ruleId": work.id(query_index),
+        "ruleIndex": query_index,
+        "level": level.to_string(),
+        "message": { "text": message },
+        "locations": [{
+            "physicalLocation": {
+                "artifactLocation": { "uri": sarif_uri(path) },
+                "region": {
+                    "startLine": start_line,
+                    "startColumn": start_column,
+                    "endLine": end_line,
+                    "endColumn": end_column,
+                    "snippet": { "text": &source[range] },
+                }
+            }
+        }]
+    });
+    if let Some(metrics) = details.metrics {
+        result["properties"]["metrics"] = metrics;
+    }
+    if let Some(explanation) = details.explanation {
+        result["properties"]["explanation"] = explanation
+            .iter()
+            .map(|a| a.to_json(source))
+            .collect::<Vec<_>>()
+            .into();
+    }
+    if let Some(blame) = details.blame {
+        result["properties"]["blame"] = blame;
+    }
+    output::Body::Sarif(result)
+}
+
+/// Severity and message of results of the query `work` for code scanning reports:
+/// the severity and description of its rule, or a warning for plain patterns.
+fn result_message(work: &WorkItem, query_index: usize) -> (rules::Severity, String) {
+    match &work.rule {
+        Some(rule) => (
+            rule.severity,
+            rule.description
+                .clone()
+                .unwrap_or_else(|| format!("Match for rule {}", rule.id)),
+        ),
+        None => (
+            rules::Severity::Warning,
+            format!("Match for pattern {}", query_index),
+        ),
+    }
+}
+
+/// SARIF consumers such as GitHub code scanning expect paths relative to the
+/// repository root, so paths below the working directory are made relative.
+fn sarif_uri(path: &str) -> String {
+    let relative = std::env::current_dir().ok().and_then(|cwd| {
+        Path::new(path)
+            .strip_prefix(cwd)
+            .ok()
+            .map(Path::to_path_buf)
+    });
+    match relative {
+        Some(p) => p.to_string_lossy().replace('\\', "/"),
+        None => path.to_string(),
+    }
+}
+
+/// Implements --invert-match: Print all functions in `tree` that do not contain a match for the
+/// first query in `work`, but contain matches for all additional queries.
+fn print_unmatched_functions(
+    source: &str,
+    tree: &Tree,
+    path: &str,
+    is_cpp: bool,
+    work: &[WorkItem],
+    output: &output::Output,
+    format: cli::Format,
+) {
+    let results: Vec<Vec<QueryResult>> = work
+        .iter()
+        .map(|w| match w.query(is_cpp) {
+            Some(qt) => qt.matches(tree.root_node(), source),
+            None => vec![],
+        })
+        .collect();
+
+    for f in weggli::functions::definitions(tree.root_node()) {
+        let contains_match =
+            |r: &Vec<QueryResult>| r.iter().any(|m| f.byte_range().contains(&m.start_offset()));
+
+        if contains_match(&results[0]) || !results[1..].iter().all(contains_match) {
+            continue;
+        }
+
+        let line = f.start_position().row + 1;
+        let name = weggli::functions::name(f, source).unwrap_or("<unknown>");
+        match format {
+            cli::Format::Json => {
+                output.print(json!({ "path": path, "line": line, "function": name }).to_string())
+            }
+            cli::Format::Targets => {
+                let function = Function {
+                    name: weggli::functions::name(f, source).map(|s| s.to_string()),
+                    range: f.byte_range(),
+                };
+                output.print(function.to_json(path, source).to_string())
+            }
+            cli::Format::Sarif => output.print_match(output::Finding {
+                location: format!("{}:{}", path, line),
+                snippet: String::new(),
+                variables: vec![],
+                body: output::Body::Sarif(json!({
+                    "ruleId": output::sarif_rule_id(0),
+                    "ruleIndex": 0,
+                    "level": "note",
+                    "message": { "text": format!("{} does not contain a match", name) },
+                    "locations": [{
+                        "physicalLocation": {
+                            "artifactLocation": { "uri": sarif_uri(path) },
+                            "region": { "startLine": line }
+                        }
+                    }]
+                })),
+            }),
+            cli::Format::Github => output.print(output::github_annotation(
+                "notice",
+                &sarif_uri(path),
+                (line, line),
+                &output::sarif_rule_id(0),
+                &format!("{} does not contain a match", name),
+            )),
+            cli::Format::GitlabCodequality => output.print_match(output::Finding {
+                location: format!("{}:{}", path, line),
+                snippet: String::new(),
+                variables: vec![],
+                body: output::Body::Codequality(output::codequality_issue(
+                    &output::sarif_rule_id(0),
+                    "info",
+                    &format!("{} does not contain a match", name),
+                    &sarif_uri(path),
+                    line,
+                    name,
+                )),
+            }),
+            cli::Format::Text => output.print(format!("{}:{} {}", path.bold(), line, name)),
+            cli::Format::Oneline => output.print(format!(
+                "{}:{}:{}: {}",
+                path.bold(),
+                line,
+                f.start_position().column + 1,
+                name
+            )),
+            cli::Format::Csv | cli::Format::Tsv => {
+                let row = [path.to_string(), line.to_string()];
+                output.print(output::table_row(&row, format == cli::Format::Tsv))
+            }
+        }
+    }
+}
+
+/// Print all function definitions below `path` (weggli functions).
+fn list_functions(path: &Path, cpp: bool, extensions: Vec<String>, json: bool) {
+    let options = weggli::SearchOptions {
+        cpp,
+        extensions,
+        ..Default::default()
+    };
+    let definitions = Mutex::new(Vec::new());
+    weggli::functions(std::iter::once(path), &options, |f| {
+        definitions.lock().unwrap().push(f)
+    });
+
+    let mut definitions = definitions.into_inner().unwrap();
+    definitions.sort_by(|a, b| (&a.path, a.range.start).cmp(&(&b.path, b.range.start)));
+
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    for f in definitions {
+        let line = if json {
+            json!({
+                "path": f.path,
+                "function": f.name,
+                "signature": f.signature,
+                "start": f.range.start,
+                "end": f.range.end,
+                "line": f.lines.start,
+                "end_line": f.lines.end,
+            })
+            .to_string()
+        } else {
+            format!(
+                "{}:{}-{}: {}",
+                f.path.display(),
+                f.lines.start,
+                f.lines.end,
+                f.signature
+            )
+        };
+        if writeln!(out, "{}", line).is_err() {
+            return;
+        }
+    }
 }
 
 /// For multi query runs, we collect all independent results first and filter
 /// them to make sure that variable assignments are valid for all queries.
 fn multi_query_worker(
     results_rx: Receiver<ResultsCtx>,
-    num_queries: usize,
-    before: usize,
-    after: usize,
-    enable_line_numbers: bool
+    work: &[WorkItem],
+    args: &cli::Args,
+    unique_by: &UniqueBy,
+    output: &output::Output,
 ) {
-    let mut query_results = Vec::with_capacity(num_queries);
-    for _ in 0..num_queries {
+    let mut query_results = Vec::with_capacity(work.len());
+    for _ in 0..work.len() {
         query_results.push(Vec::new());
     }
 
+    if let Some(limit) = args.stream_join {
+        let mut join = stream::StreamJoin::new(work.len(), &args.join, limit);
+        for ctx in results_rx {
+            let path = Some(ctx.path.as_str()).filter(|_| args.same_function);
+            let key = stream::JoinKey::new(&ctx.result, &ctx.source, path, &args.join);
+            let unique_key = unique_by.key(&ctx.result, &ctx.source);
+            let i = ctx.query_index;
+            let f = finding(
+                args,
+                &work[i],
+                i,
+                &ctx.path,
+                &ctx.source,
+                &ctx.result,
+                ctx.details,
+            );
+            for (unique_key, f) in join.add(i, key, (unique_key, f)) {
+                if unique_by.insert(unique_key) {
+                    output.print_match(f);
+                }
+            }
+        }
+        info!(
+            "{} results without compatible results for all patterns",
+            join.pending()
+        );
+        if join.dropped() > 0 {
+            eprintln!(
+                "{}",
+                format!(
+                    "Dropped {} result(s) that waited for compatible results (see --stream-join-limit)",
+                    join.dropped()
+                )
+                .yellow()
+            );
+        }
+        return;
+    }
+
     // collect all results
     for ctx in results_rx {
         query_results[ctx.query_index].push(ctx);
     }
 
-    // filter results.
-    // We now have a list of results for each query in query_results, but we still need to ensure
-    // that we only show results for query A that can be combined with at least one result in query B
-    // (and C and D).
-    // TODO: The runtime of this approach is pretty terrible, think about improving it.
-    let filter = |x: &mut Vec<ResultsCtx>, y: &mut Vec<ResultsCtx>| {
-        x.retain(|r| {
-            y.iter()
-                .any(|f| r.result.chainable(&r.source, &f.result, &f.source))
-        })
-    };
-
-    for i in 0..query_results.len() {
-        let (part1, part2) = query_results.split_at_mut(i + 1);
-        let a = part1.last_mut().unwrap();
-        for b in part2 {
-            filter(a, b);
-            filter(b, a);
+    if let Some(path) = &args.save_results {
+        let saved: Vec<shard::SavedResult> = query_results
+            .into_iter()
+            .zip(work)
+            .flat_map(|(rv, w)| {
+                rv.into_iter().map(move |r| {
+                    let line = r.source[..r.result.start_offset()].matches('\n').count() + 1;
+                    shard::SavedResult {
+                        query_index: r.query_index,
+                        location: format!("{}:{}", r.path, line),
+                        display: r.result.display_with_header(
+                            &r.source,
+                            &r.details.context,
+                            &r.details.header,
+                            w.before,
+                            w.after,
+                            args.enable_line_numbers,
+                        ),
+                        snippet: r.result.snippet(&r.source),
+                        vars: r
+                            .result
+                            .vars
+                            .keys()
+                            .map(|k| (k.clone(), r.result.value(k, &r.source).unwrap().to_string()))
+                            .collect(),
+                    }
+                })
+            })
+            .collect();
+        if !saved.is_empty() {
+            output.mark_found();
         }
+        shard::save_results(path, &args.pattern, &saved);
+        return;
     }
 
+    weggli::vfs::join(
+        &mut query_results,
+        |r| r.result.join_keys(&r.source, &args.join),
+        |a, b| {
+            (!args.same_function || (a.path == b.path && a.result.same_function(&b.result)))
+                && a.result
+                    .chainable_with(&a.source, &b.result, &b.source, &args.join)
+        },
+    );
+
     // Print remaining results
-    query_results.into_iter().for_each(|rv| {
-        rv.into_iter().for_each(|r| {
-            let line = r.source[..r.result.start_offset()].matches('\n').count() + 1;
-            println!(
-                "{}:{}\n{}",
-                r.path.bold(),
-                line,
-                r.result.display(&r.source, before, after, enable_line_numbers)
-            );
-        })
+    query_results.into_iter().zip(work).for_each(|(rv, w)| {
+        rv.into_iter()
+            .filter(|r| unique_by.check(&r.result, &r.source))
+            .for_each(|r| {
+                output.print_match(finding(
+                    args,
+                    w,
+                    r.query_index,
+                    &r.path,
+                    &r.source,
+                    &r.result,
+                    r.details,
+                ));
+            })
     });
 }
 
@@ -0,0 +1,125 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Simple structural metrics for the function (or other node) enclosing a match.
+
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use tree_sitter::Node;
+
+/// Node kinds that add a branch to the control flow graph.
+const DECISION_KINDS: &[&str] = &[
+    "if_statement",
+    "for_statement",
+    "for_range_loop",
+    "while_statement",
+    "do_statement",
+    "case_statement",
+    "conditional_expression",
+    "catch_clause",
+];
+
+/// Node kinds with a condition.
+const CONDITION_KINDS: &[&str] = &[
+    "if_statement",
+    "for_statement",
+    "while_statement",
+    "do_statement",
+    "conditional_expression",
+];
+
+/// Returns the node spanning exactly `range`, falling back to the smallest node
+/// containing it.
+pub fn enclosing_node<'a>(root: Node<'a>, range: &std::ops::Range<usize>) -> Node<'a> {
+    root.descendant_for_byte_range(range.start, range.end)
+        .unwrap_or(root)
+}
+
+/// Number of source lines spanned by `node`.
+pub fn line_count(node: Node) -> usize {
+    node.end_position().row - node.start_position().row + 1
+}
+
+/// A crude approximation of McCabe's cyclomatic complexity:
+/// 1 + the number of branching statements and short-circuiting operators.
+pub fn cyclomatic_complexity(node: Node) -> usize {
+    let mut complexity = 1;
+    visit(node, |n| {
+        if DECISION_KINDS.contains(&n.kind()) {
+            complexity += 1;
+        } else if n.kind() == "binary_expression" {
+            if let Some(op) = n.child_by_field_name("operator") {
+                if op.kind() == "&&" || op.kind() == "||" {
+                    complexity += 1;
+                }
+            }
+        }
+    });
+    complexity
+}
+
+/// Number of distinct functions called in `node`.
+pub fn callee_count(node: Node, source: &str) -> usize {
+    let mut callees = HashSet::new();
+    visit(node, |n| {
+        if n.kind() == "call_expression" {
+            if let Some(f) = n.child_by_field_name("function") {
+                callees.insert(&source[f.byte_range()]);
+            }
+        }
+    });
+    callees.len()
+}
+
+/// Number of conditions (of if statements, loops and ?: expressions) in `node`.
+pub fn condition_count(node: Node) -> usize {
+    let mut conditions = 0;
+    visit(node, |n| {
+        if CONDITION_KINDS.contains(&n.kind()) && n.child_by_field_name("condition").is_some() {
+            conditions += 1;
+        }
+    });
+    conditions
+}
+
+/// Metrics of the function enclosing a match, see --metrics.
+pub fn metrics(node: Node, source: &str) -> Value {
+    json!({
+        "lines": line_count(node),
+        "callees": callee_count(node, source),
+        "conditions": condition_count(node),
+        "complexity": cyclomatic_complexity(node),
+    })
+}
+
+/// Call `f` for `node` and all its descendants.
+fn visit<'a>(node: Node<'a>, mut f: impl FnMut(Node<'a>)) {
+    let mut cursor = node.walk();
+
+    loop {
+        f(cursor.node());
+
+        if cursor.goto_first_child() {
+            continue;
+        }
+
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() || cursor.node() == node {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,208 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Negative cache of files without matches across runs (--no-match-cache).
+//! For every set of queries, the cache stores the files in which they found
+//! nothing, either because of the identifier prefilter or because the queries
+//! didn't match. Unchanged files are skipped without reading them the next
+//! time the same queries are run. Results are not stored: files with matches
+//! are always searched, so the output doesn't depend on the cache.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::index;
+
+/// Version of the cache file format.
+const FORMAT_VERSION: u64 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    // canonical path of a file without results
+    path: PathBuf,
+    size: u64,
+    // modification time in nanoseconds since the epoch
+    mtime: u64,
+}
+
+/// A file that was searched in this run.
+struct Visit {
+    // canonical path, size and mtime before the file was read
+    path: PathBuf,
+    stamp: (u64, u64),
+    // false once the file had a result or couldn't be searched completely
+    empty: bool,
+}
+
+pub struct NoMatchCache {
+    // file of the cached queries in the cache directory
+    path: PathBuf,
+    // canonical path -> (size, mtime) of the files without results
+    known: HashMap<PathBuf, (u64, u64)>,
+    // files searched in this run, by the path they were found with
+    visited: Mutex<HashMap<String, Visit>>,
+    hits: AtomicUsize,
+}
+
+impl NoMatchCache {
+    /// Open the cache for the queries identified by `key` in `dir`. The directory
+    /// is created if it doesn't exist yet, an unreadable cache file is ignored.
+    pub fn open(dir: &Path, key: u64) -> Result<NoMatchCache, String> {
+        fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        let path = dir.join(format!("no-match-{:016x}.jsonl", key));
+        let known = match load(&path) {
+            Ok(known) => known,
+            Err(e) => {
+                if path.exists() {
+                    warn!("{}: {}, the cache is rebuilt", path.display(), e);
+                }
+                HashMap::new()
+            }
+        };
+        Ok(NoMatchCache {
+            path,
+            known,
+            visited: Mutex::new(HashMap::new()),
+            hits: AtomicUsize::new(0),
+        })
+    }
+
+    /// Returns true if `path` had no results the last time and hasn't changed since.
+    pub fn skip(&self, path: &Path) -> bool {
+        let canonical = match fs::canonicalize(path) {
+            Ok(p) => p,
+            Err(_) => return false,
+        };
+        let known = match self.known.get(&canonical) {
+            Some(&known) => known,
+            None => return false,
+        };
+        if index::stat(path) != Some(known) {
+            return false;
+        }
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        self.visited.lock().unwrap().insert(
+            path.display().to_string(),
+            Visit {
+                path: canonical,
+                stamp: known,
+                empty: true,
+            },
+        );
+        true
+    }
+
+    /// Record that `path` is about to be searched. It is cached as a file without
+    /// results unless `found` is called for it.
+    pub fn visit(&self, path: &Path) {
+        // The file is stat'ed before it is read, so changes during the search
+        // invalidate the entry.
+        let stamp = index::stat(path);
+        let visit = match (fs::canonicalize(path), stamp) {
+            (Ok(canonical), Some(stamp)) => Visit {
+                path: canonical,
+                stamp,
+                empty: true,
+            },
+            _ => return,
+        };
+        self.visited
+            .lock()
+            .unwrap()
+            .insert(path.display().to_string(), visit);
+    }
+
+    /// Record that `path` had a result or couldn't be searched completely.
+    pub fn found(&self, path: &str) {
+        if let Some(visit) = self.visited.lock().unwrap().get_mut(path) {
+            visit.empty = false;
+        }
+    }
+
+    /// Number of files skipped because of the cache.
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Write the updated cache. Files that were not searched in this run keep
+    /// their entries.
+    pub fn finish(&self) -> Result<(), String> {
+        let mut known = self.known.clone();
+        for visit in self.visited.lock().unwrap().values() {
+            if visit.empty {
+                known.insert(visit.path.clone(), visit.stamp);
+            } else {
+                known.remove(&visit.path);
+            }
+        }
+        let mut entries: Vec<Entry> = known
+            .into_iter()
+            .map(|(path, (size, mtime))| Entry { path, size, mtime })
+            .collect();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        // Concurrent runs with the same queries replace the file as a whole.
+        let tmp = self
+            .path
+            .with_extension(format!("tmp{}", std::process::id()));
+        let write = || -> std::io::Result<()> {
+            let mut w = BufWriter::new(fs::File::create(&tmp)?);
+            let header = Header {
+                version: FORMAT_VERSION,
+            };
+            writeln!(w, "{}", serde_json::to_string(&header)?)?;
+            for entry in &entries {
+                writeln!(w, "{}", serde_json::to_string(entry)?)?;
+            }
+            w.flush()?;
+            fs::rename(&tmp, &self.path)
+        };
+        write().map_err(|e| format!("{}: {}", self.path.display(), e))
+    }
+}
+
+fn load(path: &Path) -> Result<HashMap<PathBuf, (u64, u64)>, String> {
+    let file = fs::File::open(path).map_err(|e| e.to_string())?;
+    let mut lines = BufReader::new(file).lines();
+
+    let header: Header = match lines.next() {
+        Some(line) => {
+            serde_json::from_str(&line.map_err(|e| e.to_string())?).map_err(|e| e.to_string())?
+        }
+        None => return Err("empty cache file".to_string()),
+    };
+    if header.version != FORMAT_VERSION {
+        return Err(format!("unsupported cache version {}", header.version));
+    }
+
+    let mut known = HashMap::new();
+    for line in lines {
+        let entry: Entry =
+            serde_json::from_str(&line.map_err(|e| e.to_string())?).map_err(|e| e.to_string())?;
+        known.insert(entry.path, (entry.size, entry.mtime));
+    }
+    Ok(known)
+}
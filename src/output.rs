@@ -0,0 +1,831 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Final output stage for query results.
+
+use colored::Colorize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use weggli::result::Position;
+
+/// A single formatted query result.
+pub struct Finding {
+    // path:line of the result
+    pub location: String,
+    // normalized matched code, used by --dedup-snippets
+    pub snippet: String,
+    // (variable, value) pairs of the result, used by --list-variables
+    pub variables: Vec<(String, String)>,
+    pub body: Body,
+}
+
+pub enum Body {
+    // Human readable output, printed below the location
+    Text(String),
+    // Lines that already contain the location (--format oneline)
+    Lines(String),
+    // A single JSON object (--format json)
+    Json(Value),
+    // A SARIF result object (--format sarif)
+    Sarif(Value),
+    // A GitLab Code Quality issue (--format gitlab-codequality)
+    Codequality(Value),
+}
+
+impl Finding {
+    // `location` is always path:line
+    fn path(&self) -> &str {
+        self.location
+            .rsplit_once(':')
+            .map_or(&self.location, |(path, _)| path)
+    }
+
+    fn line(&self) -> usize {
+        self.location
+            .rsplit_once(':')
+            .map_or(0, |(_, line)| line.parse().unwrap_or(0))
+    }
+}
+
+/// Report files instead of results (grep's -c, -l and -L).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileReport {
+    /// The number of results in each file with results (--count).
+    Count,
+    /// The files with results (--files-with-matches).
+    WithMatches,
+    /// The searched files without results (--files-without-match).
+    WithoutMatch,
+}
+
+/// A formatted result that is ready to be written.
+enum Item {
+    Line(String),
+    Sarif(Value),
+    Codequality(Value),
+}
+
+/// Destination for formatted query results.
+///
+/// By default results are printed as soon as they are found. With --sample N,
+/// only a uniformly distributed sample of N results is kept (reservoir sampling).
+/// With --dedup-snippets, results with identical matched code are grouped and
+/// reported once. In both cases the output is printed once the search is done.
+/// SARIF results and GitLab Code Quality issues are always collected and written
+/// as a single report by `finish`.
+/// With --list-variables, the values of all results are counted and `finish` prints
+/// them after the results. With --aggregate, only the values of a single variable are
+/// counted and `finish` prints them instead of the results.
+/// With --group-by, results are collected and `finish` prints them in sections per
+/// value of a variable.
+/// With --count, --files-with-matches or --files-without-match, only the number
+/// of results per file is kept and `finish` prints the files instead of the results.
+/// With --output, everything is written to a file instead of stdout.
+/// With --quiet, nothing is printed and the process exits on the first result.
+pub struct Output {
+    sink: Mutex<Sink>,
+    sample: Option<Mutex<Reservoir<Item>>>,
+    dedup: Option<Mutex<Groups>>,
+    sample_size: Option<usize>,
+    sarif: Option<Sarif>,
+    codequality: Option<Mutex<Vec<Value>>>,
+    variables: Option<Variables>,
+    groups: Option<Grouped>,
+    files: Option<Files>,
+    // Files that were not (completely) searched: (path, reason), reported in `finish`.
+    skipped: Mutex<Vec<(String, String)>>,
+    // Set once the first result is printed, for the exit status.
+    found: AtomicBool,
+    quiet: bool,
+}
+
+struct Variables {
+    json: bool,
+    // Only count this variable and don't print results (--aggregate)
+    aggregate: Option<String>,
+    // variable -> value -> number of results
+    counts: Mutex<HashMap<String, HashMap<String, usize>>>,
+}
+
+impl Variables {
+    /// Returns the values of each variable in order of their count (and value).
+    fn sorted(&self) -> Vec<(String, Vec<(String, usize)>)> {
+        let counts = std::mem::take(&mut *self.counts.lock().unwrap());
+        let mut variables: Vec<_> = counts
+            .into_iter()
+            .map(|(var, values)| {
+                let mut values: Vec<_> = values.into_iter().collect();
+                values.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+                (var, values)
+            })
+            .collect();
+        variables.sort();
+        variables
+    }
+}
+
+/// Results grouped by the value of a variable (--group-by).
+struct Grouped {
+    variable: String,
+    // value -> results, None for results that don't bind the variable
+    results: Mutex<HashMap<Option<String>, Vec<Finding>>>,
+}
+
+impl Grouped {
+    /// Returns the groups with the most results first. Results without a value
+    /// come last, results within a group are ordered by location.
+    fn sorted(&self) -> Vec<(Option<String>, Vec<Finding>)> {
+        let results = std::mem::take(&mut *self.results.lock().unwrap());
+        let mut groups: Vec<_> = results.into_iter().collect();
+        for (_, findings) in groups.iter_mut() {
+            findings.sort_by(|a, b| (a.path(), a.line()).cmp(&(b.path(), b.line())));
+        }
+        groups.sort_by(|a, b| {
+            (a.0.is_none(), b.1.len(), &a.0).cmp(&(b.0.is_none(), a.1.len(), &b.0))
+        });
+        groups
+    }
+}
+
+struct Files {
+    report: FileReport,
+    json: bool,
+    // path -> number of results
+    counts: Mutex<HashMap<String, usize>>,
+    // all searched files, only needed for FileReport::WithoutMatch
+    searched: Vec<String>,
+}
+
+/// Number of lines written to a single zstd frame.
+const FRAME_LINES: usize = 1000;
+
+/// Destination of all output lines.
+enum Sink {
+    Stdout,
+    File(BufWriter<File>),
+    // Files ending in .zst are compressed. The output is split into independent
+    // frames of FRAME_LINES lines, so all completed frames can still be
+    // decompressed if weggli is killed during the search.
+    Zstd {
+        encoder: Option<zstd::Encoder<'static, File>>,
+        lines: usize,
+    },
+}
+
+impl Sink {
+    fn create(path: &Path) -> std::io::Result<Sink> {
+        let file = File::create(path)?;
+        if path.extension().map_or(false, |e| e == "zst") {
+            Ok(Sink::Zstd {
+                encoder: Some(zstd::Encoder::new(file, 0)?),
+                lines: 0,
+            })
+        } else {
+            Ok(Sink::File(BufWriter::new(file)))
+        }
+    }
+
+    fn line(&mut self, line: &str) -> std::io::Result<()> {
+        match self {
+            Sink::Stdout => {
+                println!("{}", line);
+                Ok(())
+            }
+            Sink::File(w) => writeln!(w, "{}", line),
+            Sink::Zstd { encoder, lines } => {
+                writeln!(encoder.as_mut().unwrap(), "{}", line)?;
+                *lines += 1;
+                if *lines == FRAME_LINES {
+                    let file = encoder.take().unwrap().finish()?;
+                    *encoder = Some(zstd::Encoder::new(file, 0)?);
+                    *lines = 0;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        match self {
+            Sink::Stdout => Ok(()),
+            Sink::File(w) => w.flush(),
+            Sink::Zstd { encoder, .. } => match encoder.take() {
+                Some(e) => e.finish()?.flush(),
+                None => Ok(()),
+            },
+        }
+    }
+}
+
+struct Sarif {
+    rules: Vec<Value>,
+    results: Mutex<Vec<Value>>,
+}
+
+struct Reservoir<T> {
+    size: usize,
+    seen: usize,
+    items: Vec<T>,
+    rng: fastrand::Rng,
+}
+
+impl<T> Reservoir<T> {
+    fn new(size: usize) -> Reservoir<T> {
+        Reservoir {
+            size,
+            seen: 0,
+            items: Vec::with_capacity(size),
+            rng: fastrand::Rng::new(),
+        }
+    }
+
+    fn add(&mut self, item: T) {
+        self.seen += 1;
+        if self.items.len() < self.size {
+            self.items.push(item);
+        } else {
+            let i = self.rng.usize(..self.seen);
+            if i < self.size {
+                self.items[i] = item;
+            }
+        }
+    }
+}
+
+/// Results grouped by their normalized snippet, in order of first appearance.
+#[derive(Default)]
+struct Groups {
+    index: HashMap<String, usize>,
+    groups: Vec<Group>,
+}
+
+struct Group {
+    // The first result in this group
+    representative: Finding,
+    // Locations (path:line) of all other results
+    duplicates: Vec<String>,
+}
+
+impl Group {
+    fn into_item(self) -> Item {
+        match self.representative.body {
+            Body::Text(text) => {
+                let mut s = format!("{}\n{}", self.representative.location.bold(), text);
+                if !self.duplicates.is_empty() {
+                    s += &format!(
+                        "{}\n",
+                        format!(
+                            "identical code in {} more location(s):",
+                            self.duplicates.len()
+                        )
+                        .bold()
+                    );
+                    for d in &self.duplicates {
+                        s += &format!("  {}\n", d);
+                    }
+                }
+                Item::Line(s)
+            }
+            // Duplicates are not listed, to keep one line per match
+            Body::Lines(lines) => Item::Line(lines),
+            Body::Json(mut value) => {
+                value["duplicates"] = json!(self.duplicates);
+                Item::Line(value.to_string())
+            }
+            Body::Sarif(mut value) => {
+                value["properties"] = json!({ "duplicates": self.duplicates });
+                Item::Sarif(value)
+            }
+            Body::Codequality(value) => Item::Codequality(value),
+        }
+    }
+}
+
+impl Output {
+    pub fn new(sample: Option<usize>, dedup: bool) -> Output {
+        Output {
+            sink: Mutex::new(Sink::Stdout),
+            sample: sample
+                .filter(|_| !dedup)
+                .map(|s| Mutex::new(Reservoir::new(s))),
+            dedup: if dedup {
+                Some(Mutex::new(Groups::default()))
+            } else {
+                None
+            },
+            sample_size: sample,
+            sarif: None,
+            codequality: None,
+            variables: None,
+            groups: None,
+            files: None,
+            skipped: Mutex::new(Vec::new()),
+            found: AtomicBool::new(false),
+            quiet: false,
+        }
+    }
+
+    /// Write all output to `path` instead of stdout (--output). The file is
+    /// compressed with zstd if its name ends in .zst.
+    pub fn with_file(mut self, path: &Path) -> std::io::Result<Output> {
+        self.sink = Mutex::new(Sink::create(path)?);
+        Ok(self)
+    }
+
+    /// Exit with status 0 on the first result instead of printing it (--quiet).
+    pub fn with_quiet(mut self) -> Output {
+        self.quiet = true;
+        self
+    }
+
+    /// Returns true if any result was printed.
+    pub fn found(&self) -> bool {
+        self.found.load(Ordering::Relaxed)
+    }
+
+    /// Record a result that isn't printed by `Output` (e.g. --save-results).
+    /// Exits with status 0 in quiet mode.
+    pub fn mark_found(&self) {
+        if self.quiet {
+            std::process::exit(0)
+        }
+        self.found.store(true, Ordering::Relaxed);
+    }
+
+    /// Count the values of all query variables and print them in `finish`
+    /// (--list-variables). With `json`, the counts are printed as a JSON object.
+    pub fn with_variable_report(mut self, json: bool) -> Output {
+        self.variables = Some(Variables {
+            json,
+            aggregate: None,
+            counts: Mutex::new(HashMap::new()),
+        });
+        self
+    }
+
+    /// Count the values of `variable` instead of printing results and print them
+    /// as a histogram in `finish` (--aggregate).
+    pub fn with_aggregate(mut self, variable: String, json: bool) -> Output {
+        self.variables = Some(Variables {
+            json,
+            aggregate: Some(variable),
+            counts: Mutex::new(HashMap::new()),
+        });
+        self
+    }
+
+    /// Collect all results and print them in `finish`, grouped by the value
+    /// of `variable` (--group-by).
+    pub fn with_group_by(mut self, variable: String) -> Output {
+        self.groups = Some(Grouped {
+            variable,
+            results: Mutex::new(HashMap::new()),
+        });
+        self
+    }
+
+    /// Count the results in each file and print the files in `finish` instead of
+    /// the results. With `json`, one JSON object is printed per file.
+    pub fn with_file_report(mut self, report: FileReport, json: bool) -> Output {
+        self.files = Some(Files {
+            report,
+            json,
+            counts: Mutex::new(HashMap::new()),
+            searched: Vec::new(),
+        });
+        self
+    }
+
+    /// Set the files that are searched, FileReport::WithoutMatch lists those
+    /// without results.
+    pub fn set_searched_files(&mut self, paths: Vec<String>) {
+        if let Some(files) = &mut self.files {
+            files.searched = paths;
+        }
+    }
+
+    /// Collect results into a SARIF report. `rules` contains one rule descriptor
+    /// (see `sarif_rule`) per query.
+    pub fn with_sarif(mut self, rules: Vec<Value>) -> Output {
+        self.sarif = Some(Sarif {
+            rules,
+            results: Mutex::new(vec![]),
+        });
+        self
+    }
+
+    /// Collect results into a GitLab Code Quality report.
+    pub fn with_codequality(mut self) -> Output {
+        self.codequality = Some(Mutex::new(vec![]));
+        self
+    }
+
+    /// Print a query result.
+    pub fn print_match(&self, finding: Finding) {
+        self.mark_found();
+
+        if let Some(files) = &self.files {
+            *files
+                .counts
+                .lock()
+                .unwrap()
+                .entry(finding.path().to_string())
+                .or_insert(0) += 1;
+            return;
+        }
+
+        if let Some(variables) = &self.variables {
+            let mut counts = variables.counts.lock().unwrap();
+            for (var, value) in &finding.variables {
+                if variables.aggregate.as_ref().map_or(false, |a| a != var) {
+                    continue;
+                }
+                *counts
+                    .entry(var.clone())
+                    .or_default()
+                    .entry(value.clone())
+                    .or_insert(0) += 1;
+            }
+            if variables.aggregate.is_some() {
+                return;
+            }
+        }
+
+        if let Some(groups) = &self.groups {
+            let value = finding
+                .variables
+                .iter()
+                .find(|(var, _)| *var == groups.variable)
+                .map(|(_, value)| value.clone());
+            groups
+                .results
+                .lock()
+                .unwrap()
+                .entry(value)
+                .or_default()
+                .push(finding);
+            return;
+        }
+
+        if let Some(dedup) = &self.dedup {
+            let mut d = dedup.lock().unwrap();
+            match d.index.get(&finding.snippet) {
+                Some(&i) => d.groups[i].duplicates.push(finding.location),
+                None => {
+                    let i = d.groups.len();
+                    d.index.insert(finding.snippet.clone(), i);
+                    d.groups.push(Group {
+                        representative: finding,
+                        duplicates: vec![],
+                    });
+                }
+            }
+            return;
+        }
+
+        match finding.body {
+            Body::Text(text) => self.print(format!("{}\n{}", finding.location.bold(), text)),
+            Body::Lines(lines) => self.print(lines),
+            Body::Json(value) => self.print(value.to_string()),
+            Body::Sarif(value) => self.add(Item::Sarif(value)),
+            Body::Codequality(value) => self.add(Item::Codequality(value)),
+        }
+    }
+
+    /// Print a line that precedes the results, such as the header of a CSV file.
+    pub fn header(&self, line: &str) {
+        self.line(line)
+    }
+
+    /// Print a single formatted result or add it to the sample.
+    pub fn print(&self, result: String) {
+        self.mark_found();
+        self.add(Item::Line(result))
+    }
+
+    fn add(&self, item: Item) {
+        match &self.sample {
+            Some(s) => s.lock().unwrap().add(item),
+            None => self.write(item),
+        }
+    }
+
+    fn write(&self, item: Item) {
+        match item {
+            Item::Line(line) => self.line(&line),
+            Item::Sarif(value) => match &self.sarif {
+                Some(sarif) => sarif.results.lock().unwrap().push(value),
+                None => self.line(&value.to_string()),
+            },
+            Item::Codequality(value) => match &self.codequality {
+                Some(issues) => issues.lock().unwrap().push(value),
+                None => self.line(&value.to_string()),
+            },
+        }
+    }
+
+    fn line(&self, line: &str) {
+        exit_on_error(self.sink.lock().unwrap().line(line));
+    }
+
+    /// Flush all buffered results. Needs to be called after the search finished.
+    pub fn finish(&self) {
+        self.flush_results();
+        exit_on_error(self.sink.lock().unwrap().finish());
+
+        let mut skipped = self.skipped.lock().unwrap();
+        if !skipped.is_empty() {
+            skipped.sort();
+            eprintln!("{}", format!("Skipped {} file(s):", skipped.len()).yellow());
+            for (path, reason) in skipped.iter() {
+                eprintln!("  {}: {}", path.bold(), reason);
+            }
+        }
+    }
+
+    /// Report `path` as skipped in `finish`.
+    pub fn skip(&self, path: &Path, reason: String) {
+        let path = path.display().to_string();
+        self.skipped.lock().unwrap().push((path, reason));
+    }
+
+    fn flush_results(&self) {
+        if let Some(sample) = &self.sample {
+            for item in sample.lock().unwrap().items.drain(..) {
+                self.write(item);
+            }
+        }
+
+        if let Some(dedup) = &self.dedup {
+            let groups = std::mem::take(&mut dedup.lock().unwrap().groups);
+            let groups = match self.sample_size {
+                Some(size) => {
+                    let mut r = Reservoir::new(size);
+                    groups.into_iter().for_each(|g| r.add(g));
+                    r.items
+                }
+                None => groups,
+            };
+            for g in groups {
+                self.write(g.into_item());
+            }
+        }
+
+        if let Some(groups) = &self.groups {
+            self.print_groups(groups);
+        }
+
+        if let Some(files) = &self.files {
+            self.print_files(files);
+        }
+
+        if let Some(sarif) = &self.sarif {
+            let results = std::mem::take(&mut *sarif.results.lock().unwrap());
+            self.line(&sarif_report(&sarif.rules, results));
+        }
+
+        if let Some(issues) = &self.codequality {
+            let issues = std::mem::take(&mut *issues.lock().unwrap());
+            self.line(&serde_json::to_string_pretty(&issues).unwrap());
+        }
+
+        if let Some(variables) = &self.variables {
+            let sorted = variables.sorted();
+            if variables.aggregate.is_some() {
+                for (value, count) in sorted.into_iter().flat_map(|(_, values)| values) {
+                    if variables.json {
+                        self.line(&json!({ "value": value, "count": count }).to_string());
+                    } else {
+                        self.line(&format!("{:>7} {}", count, value));
+                    }
+                }
+                return;
+            }
+            if variables.json {
+                let report: serde_json::Map<String, Value> = sorted
+                    .into_iter()
+                    .map(|(var, values)| {
+                        let values = values.into_iter().map(|(v, count)| (v, json!(count)));
+                        (var, Value::Object(values.collect()))
+                    })
+                    .collect();
+                self.line(&json!({ "variables": report }).to_string());
+                return;
+            }
+            for (var, values) in sorted {
+                let values: Vec<String> = values
+                    .iter()
+                    .map(|(value, count)| format!("{} ({})", value, count))
+                    .collect();
+                let line = format!("{}: {}", var.bold(), values.join(", "));
+                if self.sarif.is_some() || self.codequality.is_some() {
+                    eprintln!("{}", line);
+                } else {
+                    self.line(&line);
+                }
+            }
+        }
+    }
+
+    // Print the results for --group-by. Text results get a header line per group,
+    // JSON results a "group" field. Other formats only keep the order.
+    fn print_groups(&self, groups: &Grouped) {
+        for (value, findings) in groups.sorted() {
+            let header = match &value {
+                Some(value) => format!("{} = {}", groups.variable, value),
+                None => format!("{} not bound", groups.variable),
+            };
+            let count = findings.len();
+            for (i, finding) in findings.into_iter().enumerate() {
+                match finding.body {
+                    Body::Text(text) => {
+                        if i == 0 {
+                            let count = format!("({} result(s))", count);
+                            self.line(&format!("{} {}\n", header.bold(), count));
+                        }
+                        self.line(&format!("{}\n{}", finding.location.bold(), text));
+                    }
+                    Body::Lines(lines) => self.line(&lines),
+                    Body::Json(mut v) => {
+                        v["group"] = json!(value);
+                        self.line(&v.to_string());
+                    }
+                    // Rejected by the command line parser
+                    Body::Sarif(_) | Body::Codequality(_) => (),
+                }
+            }
+        }
+    }
+
+    // Print the files for --count, --files-with-matches or --files-without-match.
+    fn print_files(&self, files: &Files) {
+        let counts = std::mem::take(&mut *files.counts.lock().unwrap());
+        let mut lines: Vec<(String, usize)> = match files.report {
+            FileReport::Count | FileReport::WithMatches => counts.into_iter().collect(),
+            FileReport::WithoutMatch => {
+                // Files that were skipped were not completely searched.
+                let skipped = self.skipped.lock().unwrap();
+                files
+                    .searched
+                    .iter()
+                    .filter(|f| !counts.contains_key(*f) && !skipped.iter().any(|(s, _)| s == *f))
+                    .map(|f| (f.clone(), 0))
+                    .collect()
+            }
+        };
+        lines.sort();
+
+        for (path, count) in lines {
+            let line = match (files.report, files.json) {
+                (FileReport::Count, true) => json!({ "path": path, "count": count }).to_string(),
+                (FileReport::Count, false) => format!("{}:{}", path, count),
+                (_, true) => json!({ "path": path }).to_string(),
+                (_, false) => path,
+            };
+            self.line(&line);
+        }
+    }
+}
+
+fn exit_on_error(result: std::io::Result<()>) {
+    if let Err(e) = result {
+        eprintln!("Failed to write results: {}", e);
+        std::process::exit(2)
+    }
+}
+
+/// Returns the id of the SARIF rule for the pattern with index `query_index`.
+pub fn sarif_rule_id(query_index: usize) -> String {
+    format!("weggli/pattern-{}", query_index)
+}
+
+/// Returns a SARIF rule descriptor for a query.
+pub fn sarif_rule(id: &str, pattern: &str, description: Option<&str>) -> Value {
+    json!({
+        "id": id,
+        "shortDescription": { "text": description.unwrap_or(pattern) },
+        "fullDescription": { "text": pattern },
+    })
+}
+
+/// Returns a SARIF 2.1.0 log with a single run containing `results`.
+fn sarif_report(rules: &[Value], results: Vec<Value>) -> String {
+    let report = json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "weggli",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "informationUri": "https://github.com/weggli-rs/weggli",
+                    "rules": rules,
+                }
+            },
+            "results": results,
+        }]
+    });
+    serde_json::to_string_pretty(&report).unwrap()
+}
+
+/// Returns a GitHub Actions workflow command that annotates the `lines` of `path`
+/// with `message`. `level` is error, warning or notice.
+pub fn github_annotation(
+    level: &str,
+    path: &str,
+    lines: (usize, usize),
+    title: &str,
+    message: &str,
+) -> String {
+    // see https://docs.github.com/en/actions/using-workflows/workflow-commands-for-github-actions
+    let data = |s: &str| {
+        s.replace('%', "%25")
+            .replace('\r', "%0D")
+            .replace('\n', "%0A")
+    };
+    let property = |s: &str| data(s).replace(':', "%3A").replace(',', "%2C");
+    format!(
+        "::{} file={},line={},endLine={},title={}::{}",
+        level,
+        property(path),
+        lines.0,
+        lines.1,
+        property(title),
+        data(message)
+    )
+}
+
+/// 64-bit FNV-1a hash of `parts`, which are separated by a NUL byte.
+pub fn fnv1a(parts: &[&str]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        for b in part.bytes().chain(std::iter::once(0)) {
+            hash = (hash ^ b as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+/// Returns an issue of a GitLab Code Quality report. `severity` is one of info,
+/// minor, major, critical or blocker.
+pub fn codequality_issue(
+    check_name: &str,
+    severity: &str,
+    description: &str,
+    path: &str,
+    line: usize,
+    snippet: &str,
+) -> Value {
+    // GitLab tracks issues across pipelines by their fingerprint, so it only
+    // depends on the contents of the issue.
+    let hash = fnv1a(&[check_name, path, &line.to_string(), snippet]);
+    json!({
+        "description": description,
+        "check_name": check_name,
+        "fingerprint": format!("{:016x}", hash),
+        "severity": severity,
+        "location": { "path": path, "lines": { "begin": line } },
+    })
+}
+
+/// Returns the 1-based line and column (in bytes) of `offset` in `source`.
+pub fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let p = Position::of(source, offset);
+    (p.line, p.column)
+}
+
+/// Returns `values` as a row of a CSV file (RFC 4180) or, with `tab`, of a TSV
+/// file. TSV has no quoting, so tabs and line breaks are replaced with spaces.
+pub fn table_row<S: AsRef<str>>(values: &[S], tab: bool) -> String {
+    let values = values.iter().map(|v| {
+        let v = v.as_ref();
+        if tab {
+            v.replace(['\t', '\n', '\r'], " ")
+        } else if v.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", v.replace('"', "\"\""))
+        } else {
+            v.to_string()
+        }
+    });
+    values
+        .collect::<Vec<_>>()
+        .join(if tab { "\t" } else { "," })
+}
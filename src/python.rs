@@ -21,12 +21,10 @@ use pyo3::wrap_pyfunction;
 use crate::parse_search_pattern;
 use crate::query::QueryTree;
 use crate::result::QueryResult;
-use crate::QueryError;
+use crate::{ParsedSource, QueryError};
 
-impl std::convert::From<QueryError> for PyErr {
-    fn from(err: QueryError) -> PyErr {
-        PyValueError::new_err(err.message)
-    }
+fn to_py_err(err: QueryError) -> PyErr {
+    PyValueError::new_err(err.message)
 }
 
 #[pyclass]
@@ -42,7 +40,7 @@ struct QueryResultPy {
 #[pyfunction(cpp = "false")]
 #[pyo3(text_signature = "(query, cpp)")]
 fn parse_query(q: &str, cpp: bool) -> PyResult<QueryTreePy> {
-    let qt = parse_search_pattern(q, cpp, false, None)?;
+    let qt = parse_search_pattern(q, cpp, false, None).map_err(to_py_err)?;
     Ok(QueryTreePy { qt })
 }
 
@@ -55,9 +53,7 @@ fn identifiers(p: &QueryTreePy) -> PyResult<Vec<String>> {
 #[pyfunction(cpp = "false")]
 #[pyo3(text_signature = "(p, source, cpp)")]
 fn matches(p: &QueryTreePy, source: &str, cpp: bool) -> PyResult<Vec<QueryResultPy>> {
-    let source_tree = crate::parse(source, cpp);
-
-    let matches = p.qt.matches(source_tree.root_node(), source);
+    let matches = ParsedSource::new(source, cpp).matches(&p.qt);
 
     let r = matches.into_iter().map(|qr| QueryResultPy { qr }).collect();
 
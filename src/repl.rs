@@ -0,0 +1,237 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Interactive mode (weggli repl): parse a code base once, keep the syntax
+//! trees in memory and run queries against them as they are typed.
+
+use colored::Colorize;
+use rayon::iter::ParallelBridge;
+use rayon::prelude::*;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::{Highlighter, MatchingBracketHighlighter};
+use rustyline::hint::Hinter;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Editor, Helper};
+use std::borrow::Cow;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+use tree_sitter::Tree;
+use weggli::parse_search_pattern;
+use weggli::result::Position;
+
+const HELP: &str = "Enter a weggli pattern to search the loaded files.
+Patterns with unbalanced brackets continue on the next line.
+Commands:
+  :help   Show this message.
+  :files  Show the number of loaded files.
+  :quit   Exit (or press Ctrl-D).";
+
+struct SourceFile {
+    path: String,
+    source: Arc<String>,
+    tree: Tree,
+}
+
+/// Line editor helper that asks for more input until all brackets in the
+/// pattern are closed, and highlights matching brackets.
+struct ReplHelper {
+    highlighter: MatchingBracketHighlighter,
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        self.highlighter.highlight(line, pos)
+    }
+
+    fn highlight_char(&self, line: &str, pos: usize) -> bool {
+        self.highlighter.highlight_char(line, pos)
+    }
+}
+
+impl Validator for ReplHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        Ok(match check_brackets(ctx.input()) {
+            Ok(0) => ValidationResult::Valid(None),
+            Ok(_) => ValidationResult::Incomplete,
+            Err(c) => ValidationResult::Invalid(Some(format!("  unmatched '{}'", c))),
+        })
+    }
+}
+
+impl Helper for ReplHelper {}
+
+/// Returns the number of brackets that are still open at the end of `input`,
+/// or the first closing bracket that doesn't match an opening one.
+/// Brackets inside string and character literals are ignored.
+fn check_brackets(input: &str) -> Result<usize, char> {
+    let mut stack = Vec::new();
+    let mut quote = None;
+    let mut escaped = false;
+
+    for c in input.chars() {
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+
+        match c {
+            '"' | '\'' => quote = Some(c),
+            '(' | '[' | '{' => stack.push(c),
+            ')' | ']' | '}' => {
+                let open = match c {
+                    ')' => '(',
+                    ']' => '[',
+                    _ => '{',
+                };
+                if stack.pop() != Some(open) {
+                    return Err(c);
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(stack.len())
+}
+
+/// Parse all files below `path` with the given `extensions`.
+fn load(path: &Path, cpp: bool, extensions: Vec<String>) -> Vec<SourceFile> {
+    let loader = weggli::Loader::default();
+    let mut files: Vec<SourceFile> = weggli::par_iter_files(path, extensions, true)
+        .par_bridge()
+        .flat_map_iter(|path| {
+            loader
+                .load(&path, |_| vec![cpp])
+                .into_iter()
+                .filter_map(move |parsed| match parsed {
+                    Ok(file) => Some(SourceFile {
+                        path: path.display().to_string(),
+                        source: file.source,
+                        tree: file.tree,
+                    }),
+                    Err(e) => {
+                        warn!("{}: {}", path.display(), e);
+                        None
+                    }
+                })
+        })
+        .collect();
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    files
+}
+
+/// Run `pattern` against all loaded files and print the results.
+fn search(files: &[SourceFile], pattern: &str, cpp: bool) {
+    let qt = match parse_search_pattern(pattern, cpp, false, None) {
+        Ok(qt) => qt,
+        Err(e) => {
+            eprintln!("{}", e.message);
+            return;
+        }
+    };
+
+    let start = Instant::now();
+    let results: Vec<(&SourceFile, Vec<weggli::result::QueryResult>)> = files
+        .par_iter()
+        .map(|f| (f, qt.matches(f.tree.root_node(), &f.source)))
+        .filter(|(_, r)| !r.is_empty())
+        .collect();
+
+    let mut count = 0;
+    for (f, matches) in &results {
+        for m in matches {
+            let line = Position::of(&f.source, m.start_offset()).line;
+            let location = format!("{}:{}", f.path, line);
+            println!("{}\n{}", location.bold(), m.display(&f.source, 5, 5, false));
+            count += 1;
+        }
+    }
+
+    println!(
+        "{}",
+        format!(
+            "{} matches in {} files ({:.2?})",
+            count,
+            results.len(),
+            start.elapsed()
+        )
+        .dimmed()
+    );
+}
+
+/// Start the interactive mode for the code base at `path`.
+pub fn run(path: &Path, cpp: bool, extensions: Vec<String>) {
+    let start = Instant::now();
+    let files = load(path, cpp, extensions);
+    eprintln!(
+        "Parsed {} files in {:.2?}. Type :help for help.",
+        files.len(),
+        start.elapsed()
+    );
+
+    let mut editor = match Editor::<ReplHelper>::new() {
+        Ok(e) => e,
+        Err(e) => {
+            eprintln!("Failed to initialize line editor: {}", e);
+            std::process::exit(2)
+        }
+    };
+    editor.set_helper(Some(ReplHelper {
+        highlighter: MatchingBracketHighlighter::new(),
+    }));
+
+    loop {
+        let line = match editor.readline("weggli> ") {
+            Ok(line) => line,
+            // Ctrl-C discards the current input
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("{}", e);
+                break;
+            }
+        };
+
+        let input = line.trim();
+        if input.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(input);
+
+        match input {
+            ":q" | ":quit" | ":exit" => break,
+            ":h" | ":help" => println!("{}", HELP),
+            ":files" => println!("{} files loaded", files.len()),
+            _ if input.starts_with(':') => eprintln!("Unknown command {}", input.red()),
+            _ => search(&files, input, cpp),
+        }
+    }
+}
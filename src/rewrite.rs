@@ -0,0 +1,338 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Search & replace mode (--rewrite): replace the code matched by a pattern
+//! with a template and either print a unified diff or edit files in place.
+
+use colored::Colorize;
+use regex::{Captures, Regex};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use weggli::result::QueryResult;
+
+/// Number of unchanged lines shown around each change in the diff.
+const CONTEXT: usize = 3;
+
+struct Edit {
+    range: Range<usize>,
+    replacement: String,
+}
+
+/// The edits for a file, together with the searched source they apply to.
+struct FileEdits {
+    source: Arc<String>,
+    edits: Vec<Edit>,
+}
+
+pub struct Rewriter {
+    template: String,
+    // node kind of the statement or expression in the search pattern
+    kind: String,
+    edits: Mutex<HashMap<String, FileEdits>>,
+}
+
+impl Rewriter {
+    /// Create a rewriter for `pattern`. Only patterns consisting of a single
+    /// statement or expression can be rewritten, as the matched node is
+    /// replaced with the expanded `template`.
+    pub fn new(template: &str, pattern: &str, cpp: bool) -> Result<Rewriter, String> {
+        let kind = target_kind(pattern, cpp).ok_or_else(|| {
+            "--rewrite needs a pattern that consists of a single statement or expression"
+                .to_string()
+        })?;
+
+        Ok(Rewriter {
+            template: template.to_string(),
+            kind,
+            edits: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Variables referenced in the replacement template.
+    pub fn variables(&self) -> Vec<String> {
+        variable_regex()
+            .find_iter(&self.template)
+            .map(|m| m.as_str().to_string())
+            .collect()
+    }
+
+    /// Record the replacement for the match `m` in `path`. The edits are applied
+    /// to `source`, the text the match was found in.
+    pub fn add(&self, path: &str, source: &Arc<String>, root: tree_sitter::Node, m: &QueryResult) {
+        let function = m.function_range();
+        let captures = m
+            .captures
+            .iter()
+            .map(|c| &c.range)
+            .filter(|r| **r != function);
+        let start = captures.clone().map(|r| r.start).min();
+        let end = captures.map(|r| r.end).max();
+
+        let node = match (start, end) {
+            (Some(start), Some(end)) => root.descendant_for_byte_range(start, end),
+            _ => None,
+        };
+
+        // The smallest node containing all captures might only be a part of the
+        // matched code (e.g. the function name of a call), so we look for the
+        // enclosing node of the same kind as the pattern.
+        let mut node = match node {
+            Some(n) => n,
+            None => return,
+        };
+        while node.kind() != self.kind {
+            node = match node.parent() {
+                Some(p) => p,
+                None => {
+                    warn!("{}: unable to find rewrite target for match", path);
+                    return;
+                }
+            };
+        }
+
+        let replacement = variable_regex()
+            .replace_all(&self.template, |c: &Captures| {
+                m.value(&c[0], source).unwrap_or(&c[0]).to_string()
+            })
+            .into_owned();
+
+        self.edits
+            .lock()
+            .unwrap()
+            .entry(path.to_string())
+            .or_insert_with(|| FileEdits {
+                source: source.clone(),
+                edits: Vec::new(),
+            })
+            .edits
+            .push(Edit {
+                range: node.byte_range(),
+                replacement,
+            });
+    }
+
+    /// Apply all recorded edits to the searched sources. Prints a unified diff for
+    /// every changed file, or writes the files back to disk when `in_place` is set.
+    /// Files that changed on disk since they were searched (or that are not valid
+    /// UTF-8) are not written. Returns the number of applied rewrites.
+    pub fn finish(self, in_place: bool) -> usize {
+        let mut edits: Vec<(String, FileEdits)> =
+            self.edits.into_inner().unwrap().into_iter().collect();
+        edits.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut count = 0;
+        for (path, FileEdits { source, mut edits }) in edits {
+            let file = weggli::long_path(Path::new(&path));
+            if in_place {
+                match std::fs::read(&file) {
+                    Ok(current) if current == source.as_bytes() => (),
+                    Ok(_) => {
+                        warn!(
+                            "{}: not rewriting file that changed since it was searched",
+                            path
+                        );
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("{}: {}", path, e);
+                        continue;
+                    }
+                }
+            }
+
+            // Matches can overlap (e.g. nested calls). Keep the outermost
+            // edit, the inner one can be applied by running weggli again.
+            edits.sort_by(|a, b| {
+                a.range
+                    .start
+                    .cmp(&b.range.start)
+                    .then(b.range.end.cmp(&a.range.end))
+            });
+            let mut end = 0;
+            edits.retain(|e| {
+                let keep = e.range.start >= end;
+                if keep {
+                    end = e.range.end;
+                }
+                keep
+            });
+            count += edits.len();
+
+            if in_place {
+                if let Err(e) = std::fs::write(&file, apply(&source, 0..source.len(), &edits)) {
+                    eprintln!("Failed to write {}: {}", path, e);
+                    std::process::exit(2)
+                }
+            } else {
+                print!("{}", diff(&path, &source, &edits));
+            }
+        }
+
+        if in_place {
+            info!("applied {} rewrites", count);
+        }
+        count
+    }
+}
+
+fn variable_regex() -> Regex {
+    Regex::new(r"\$[[:word:]]+").unwrap()
+}
+
+/// Find the node kind of the single statement or expression in `pattern`.
+fn target_kind(pattern: &str, cpp: bool) -> Option<String> {
+    let mut pattern = pattern.to_string();
+    let mut tree = weggli::parse(&pattern, cpp);
+    if tree.root_node().has_error() {
+        pattern.push(';');
+        tree = weggli::parse(&pattern, cpp);
+    }
+
+    // not: statements don't match any code, so they can be ignored.
+    let is_negation = |n: &tree_sitter::Node| {
+        n.kind() == "labeled_statement"
+            && n.child_by_field_name("label")
+                .map_or(false, |l| &pattern[l.byte_range()] == "not")
+    };
+
+    let mut node = tree.root_node();
+    loop {
+        match node.kind() {
+            "translation_unit" | "compound_statement" => {
+                let mut cursor = node.walk();
+                let mut children = node
+                    .named_children(&mut cursor)
+                    .filter(|c| c.kind() != "comment" && !is_negation(c));
+                node = children.next()?;
+                if children.next().is_some() {
+                    return None;
+                }
+            }
+            "expression_statement" => node = node.named_child(0)?,
+            "ERROR" => return None,
+            kind => return Some(kind.to_string()),
+        }
+    }
+}
+
+/// Return the code in `range` of `source` with all `edits` applied.
+/// `edits` are sorted, non-overlapping and contained in `range`.
+fn apply(source: &str, range: Range<usize>, edits: &[Edit]) -> String {
+    let mut result = String::new();
+    let mut offset = range.start;
+    for e in edits {
+        result.push_str(&source[offset..e.range.start]);
+        result.push_str(&e.replacement);
+        offset = e.range.end;
+    }
+    result.push_str(&source[offset..range.end]);
+    result
+}
+
+/// Create a unified diff for the `edits` of the file at `path`.
+fn diff(path: &str, source: &str, edits: &[Edit]) -> String {
+    let mut line_starts = vec![0];
+    line_starts.extend(source.match_indices('\n').map(|(i, _)| i + 1));
+    let lines: Vec<&str> = source.split('\n').collect();
+
+    let line_of = |offset: usize| line_starts.partition_point(|&s| s <= offset) - 1;
+    let line_end = |line: usize| line_starts.get(line + 1).map_or(source.len(), |s| s - 1);
+
+    // Number of lines, ignoring the empty string after a trailing newline
+    let line_count = lines.len() - source.ends_with('\n') as usize;
+
+    // Group edits that touch the same lines: (first line, end line, edits)
+    let mut changes: Vec<(usize, usize, Range<usize>)> = Vec::new();
+    for (i, e) in edits.iter().enumerate() {
+        let start = line_of(e.range.start);
+        let end = line_of(e.range.end.max(e.range.start + 1) - 1) + 1;
+        match changes.last_mut() {
+            Some(c) if start < c.1 => {
+                c.1 = c.1.max(end);
+                c.2.end = i + 1;
+            }
+            _ => changes.push((start, end, i..i + 1)),
+        }
+    }
+
+    // Replacement lines for each change
+    let new_lines: Vec<Vec<String>> = changes
+        .iter()
+        .map(|(start, end, e)| {
+            let range = line_starts[*start]..line_end(end - 1);
+            apply(source, range, &edits[e.clone()])
+                .split('\n')
+                .map(|s| s.to_string())
+                .collect()
+        })
+        .collect();
+
+    let mut out = format!("{}\n", format!("--- {}", path).bold());
+    out.push_str(&format!("{}\n", format!("+++ {}", path).bold()));
+
+    // Lines added or removed by all changes before the current hunk.
+    let mut delta: isize = 0;
+    let mut i = 0;
+    while i < changes.len() {
+        // Merge changes whose context overlaps into a single hunk.
+        let mut j = i + 1;
+        while j < changes.len() && changes[j].0 <= changes[j - 1].1 + 2 * CONTEXT {
+            j += 1;
+        }
+
+        let start = changes[i].0.saturating_sub(CONTEXT);
+        let end = (changes[j - 1].1 + CONTEXT).min(line_count);
+        let added: isize = (i..j)
+            .map(|k| new_lines[k].len() as isize - (changes[k].1 - changes[k].0) as isize)
+            .sum();
+        let old_len = end - start;
+        let new_len = (old_len as isize + added) as usize;
+        let new_start = (start as isize + delta) as usize;
+
+        let header = format!(
+            "@@ -{},{} +{},{} @@",
+            start + 1,
+            old_len,
+            new_start + 1,
+            new_len
+        );
+        out.push_str(&format!("{}\n", header.cyan()));
+
+        let mut line = start;
+        for k in i..j {
+            let (first, last) = (changes[k].0, changes[k].1);
+            for l in &lines[line..first] {
+                out.push_str(&format!(" {}\n", l));
+            }
+            for l in &lines[first..last] {
+                out.push_str(&format!("{}\n", format!("-{}", l).red()));
+            }
+            for l in &new_lines[k] {
+                out.push_str(&format!("{}\n", format!("+{}", l).green()));
+            }
+            line = last;
+        }
+        for l in &lines[line..end] {
+            out.push_str(&format!(" {}\n", l));
+        }
+
+        delta += added;
+        i = j;
+    }
+    out
+}
@@ -0,0 +1,120 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Rule files (--rules): weggli patterns with an ID and metadata, stored as
+//! YAML or TOML files so that a library of queries can be run in a single scan.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    #[default]
+    Warning,
+    #[serde(alias = "info")]
+    Note,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub id: String,
+    pub pattern: String,
+    #[serde(default)]
+    pub severity: Severity,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    // Regex constraints using the same var=regex syntax as -R
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub regex: Vec<String>,
+    // Context lines for results of this rule, overriding --before and --after
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<usize>,
+    // Print the whole function for results of this rule (like --whole-function)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub whole_function: bool,
+}
+
+/// Load all rule files in `path`. `path` can be a single rule file or a directory,
+/// which is searched recursively for .yml, .yaml and .toml files.
+/// Rules are returned sorted by file name so that the output order is stable.
+pub fn load_rules(path: &Path) -> Result<Vec<Rule>, String> {
+    let mut files: Vec<PathBuf> = if path.is_dir() {
+        WalkDir::new(path)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .map(|e| e.into_path())
+            .filter(|p| p.is_file() && format(p).is_some())
+            .collect()
+    } else {
+        vec![path.to_path_buf()]
+    };
+    files.sort();
+
+    if files.is_empty() {
+        return Err(format!("no rule files found in {}", path.display()));
+    }
+
+    let mut ids = HashSet::new();
+    let mut rules = Vec::with_capacity(files.len());
+    for f in files {
+        let rule = load_rule(&f).map_err(|e| format!("{}: {}", f.display(), e))?;
+        if !ids.insert(rule.id.clone()) {
+            return Err(format!("{}: duplicate rule id '{}'", f.display(), rule.id));
+        }
+        rules.push(rule);
+    }
+    Ok(rules)
+}
+
+enum Format {
+    Yaml,
+    Toml,
+}
+
+fn format(path: &Path) -> Option<Format> {
+    match path.extension()?.to_str()? {
+        "yml" | "yaml" => Some(Format::Yaml),
+        "toml" => Some(Format::Toml),
+        _ => None,
+    }
+}
+
+fn load_rule(path: &Path) -> Result<Rule, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    match format(path) {
+        Some(Format::Yaml) => serde_yaml::from_str(&content).map_err(|e| e.to_string()),
+        Some(Format::Toml) => toml::from_str(&content).map_err(|e| e.to_string()),
+        None => Err("rule files need to end with .yml, .yaml or .toml".to_string()),
+    }
+}
@@ -0,0 +1,398 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Search files on disk from other Rust programs.
+//! `search` walks the input paths, skips files that don't contain the identifiers
+//! used in the queries, and parses and searches the remaining files in parallel.
+//! Files are loaded and parsed by `Loader`, just like in the weggli binary.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+
+use ignore::{DirEntry, WalkBuilder, WalkState};
+use memchr::memmem;
+use rayon::prelude::*;
+
+use crate::file::{Loader, ParsedFile, Skipped};
+use crate::query::QueryTree;
+use crate::result::{JoinMode, QueryResult};
+
+/// Options for `search`.
+#[derive(Clone, Debug)]
+pub struct SearchOptions {
+    /// Parse files with the C++ grammar. Needs to match the grammar the
+    /// queries were built for.
+    pub cpp: bool,
+    /// Detect the grammar of each file with `is_cpp_file` instead of using `cpp`.
+    /// Queries only run on files that were parsed with the grammar they were built
+    /// for, so they are usually passed for both grammars.
+    pub auto_lang: bool,
+    /// File extensions searched in directories. Files that are passed
+    /// directly are always searched.
+    pub extensions: Vec<String>,
+    /// Skip files in directories that are excluded by .gitignore, .ignore or
+    /// .weggliignore files.
+    pub ignore_files: bool,
+    /// Skip files larger than this many bytes.
+    pub max_filesize: Option<u64>,
+    /// How variables are compared when joining the results of multiple queries.
+    /// Variables without an entry use JoinMode::Equal.
+    pub join: HashMap<String, JoinMode>,
+}
+
+impl Default for SearchOptions {
+    fn default() -> SearchOptions {
+        SearchOptions {
+            cpp: false,
+            auto_lang: false,
+            extensions: vec!["c".to_string(), "h".to_string()],
+            ignore_files: true,
+            max_filesize: None,
+            join: HashMap::new(),
+        }
+    }
+}
+
+impl SearchOptions {
+    /// Returns true if the file at `path` with `contents` is parsed with the C++ grammar.
+    pub(crate) fn is_cpp(&self, path: &Path, contents: &[u8]) -> bool {
+        if self.auto_lang {
+            crate::is_cpp_file(path, contents)
+        } else {
+            self.cpp
+        }
+    }
+
+    pub(crate) fn loader(&self) -> Loader {
+        Loader::default().with_max_filesize(self.max_filesize)
+    }
+}
+
+/// A query result in a file on disk.
+pub struct Match {
+    pub path: PathBuf,
+    /// Index of the query in the `queries` passed to `search`.
+    pub query: usize,
+    pub source: Arc<String>,
+    pub result: QueryResult,
+}
+
+/// Run all `queries` on the files in `paths` and pass every result to `callback`.
+/// Directories are searched recursively for files with one of `options.extensions`,
+/// skipping hidden files and directories and files excluded by ignore files.
+///
+/// Files are searched in parallel while the directories are still walked, so `callback`
+/// is called from multiple threads and results are not ordered. With a single query,
+/// results are delivered as soon as a file has been searched. With multiple queries,
+/// results need to be joined (see `vfs::join`) and are delivered once all files have
+/// been searched.
+pub fn search<I, P, F>(queries: &[QueryTree], paths: I, options: &SearchOptions, callback: F)
+where
+    I: IntoIterator<Item = P>,
+    P: AsRef<Path>,
+    F: Fn(Match) + Sync,
+{
+    let prefilter = Prefilter::new(queries);
+    let loader = options.loader();
+    let results: Mutex<Vec<Vec<Match>>> = Mutex::new(queries.iter().map(|_| Vec::new()).collect());
+
+    find_files(paths, options).par_bridge().for_each(|path| {
+        let mut could_match = Vec::new();
+        let parsed = loader.load(&path, |contents| {
+            let cpp = options.is_cpp(&path, contents);
+            could_match = prefilter.check(contents, cpp);
+            Prefilter::languages(&could_match, cpp)
+        });
+
+        for file in parsed {
+            let file = match file {
+                Ok(file) => file,
+                Err(Skipped::NoPotentialMatch) => continue,
+                Err(e) => {
+                    warn!("{}: {}", path.display(), e);
+                    continue;
+                }
+            };
+
+            let matches = file_matches(queries, &file, &could_match).map(|(i, result)| Match {
+                path: path.clone(),
+                query: i,
+                source: file.source.clone(),
+                result,
+            });
+
+            if queries.len() == 1 {
+                matches.for_each(&callback);
+            } else {
+                let mut results = results.lock().unwrap();
+                matches.for_each(|m| results[m.query].push(m));
+            }
+        }
+    });
+
+    if queries.len() > 1 {
+        let mut results = results.into_inner().unwrap();
+        crate::vfs::join(
+            &mut results,
+            |r| r.result.join_keys(&r.source, &options.join),
+            |a, b| {
+                a.result
+                    .chainable_with(&a.source, &b.result, &b.source, &options.join)
+            },
+        );
+        results.into_iter().flatten().for_each(callback);
+    }
+}
+
+/// Skips files that can't match before they are parsed. A query can only match in
+/// files that contain all of its identifiers and are parsed with its grammar.
+pub(crate) struct Prefilter {
+    finders: Vec<Vec<memmem::Finder<'static>>>,
+    languages: Vec<&'static str>,
+}
+
+impl Prefilter {
+    pub(crate) fn new(queries: &[QueryTree]) -> Prefilter {
+        Prefilter {
+            finders: queries
+                .iter()
+                .map(|qt| {
+                    qt.identifiers()
+                        .iter()
+                        .map(|i| memmem::Finder::new(i).into_owned())
+                        .collect()
+                })
+                .collect(),
+            languages: queries.iter().map(|qt| qt.language().name()).collect(),
+        }
+    }
+
+    /// Returns for each query if it could match in `contents`, parsed with the C++
+    /// grammar if `cpp` is set.
+    pub(crate) fn check(&self, contents: &[u8], cpp: bool) -> Vec<bool> {
+        let language = crate::language::for_cpp(cpp).name();
+        self.finders
+            .iter()
+            .zip(&self.languages)
+            .map(|(f, &l)| l == language && f.iter().all(|f| f.find(contents).is_some()))
+            .collect()
+    }
+
+    /// The grammars to parse a file with for `Loader`: `cpp` if any query
+    /// `could_match` (see `check`), otherwise none.
+    pub(crate) fn languages(could_match: &[bool], cpp: bool) -> Vec<bool> {
+        if could_match.contains(&true) {
+            vec![cpp]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+/// Run the `queries` that `could_match` (see `Prefilter::check`) on `file`.
+/// Returns the index of the query together with each result.
+pub(crate) fn file_matches<'a>(
+    queries: &'a [QueryTree],
+    file: &'a ParsedFile,
+    could_match: &'a [bool],
+) -> impl Iterator<Item = (usize, QueryResult)> + 'a {
+    queries
+        .iter()
+        .enumerate()
+        .filter(move |(i, _)| could_match[*i])
+        .flat_map(move |(i, qt)| {
+            qt.matches(file.tree.root_node(), &file.source)
+                .into_iter()
+                .map(move |r| (i, r))
+        })
+}
+
+/// Returns the files in `paths` as they are found. Directories are walked in parallel
+/// (see `par_iter_files`) for files with one of `options.extensions`.
+pub(crate) fn find_files<I, P>(
+    paths: I,
+    options: &SearchOptions,
+) -> impl Iterator<Item = PathBuf> + Send
+where
+    I: IntoIterator<Item = P>,
+    P: AsRef<Path>,
+{
+    let paths: Vec<PathBuf> = paths
+        .into_iter()
+        .map(|p| p.as_ref().to_path_buf())
+        .collect();
+    let (extensions, ignore_files) = (options.extensions.clone(), options.ignore_files);
+    paths.into_iter().flat_map(move |p| {
+        let files: Box<dyn Iterator<Item = PathBuf> + Send> = if p.is_dir() {
+            Box::new(par_iter_files(&p, extensions.clone(), ignore_files))
+        } else {
+            Box::new(std::iter::once(p))
+        };
+        files
+    })
+}
+
+/// Name of weggli's own ignore files, which use the same syntax as .gitignore.
+pub const IGNORE_FILENAME: &str = ".weggliignore";
+
+/// Recursively iterate through all files under `path` that match an ending listed in `extensions`.
+/// Hidden files and directories are skipped, as are files excluded by .gitignore, .ignore
+/// or .weggliignore files (see `iter_files_with`).
+pub fn iter_files(path: &Path, extensions: Vec<String>) -> impl Iterator<Item = PathBuf> {
+    iter_files_with(path, extensions, true)
+}
+
+/// Like `iter_files`, but only skips files excluded by .gitignore, .ignore and .weggliignore
+/// files (in `path`, its subdirectories and its parents) if `ignore_files` is set.
+/// .gitignore files are used even outside of git repositories.
+pub fn iter_files_with(
+    path: &Path,
+    extensions: Vec<String>,
+    ignore_files: bool,
+) -> impl Iterator<Item = PathBuf> {
+    let walk = Walk::new(path, extensions);
+    walk.builder(ignore_files)
+        .build()
+        .filter_map(|e| e.ok())
+        .filter_map(move |entry| walk.file(entry))
+}
+
+/// Like `iter_files_with`, but the directory tree is walked by multiple threads in the
+/// background. Files are returned as soon as they are found, in no particular order,
+/// so they can be searched while the walk is still running.
+pub fn par_iter_files(
+    path: &Path,
+    extensions: Vec<String>,
+    ignore_files: bool,
+) -> impl Iterator<Item = PathBuf> {
+    let walk = Walk::new(path, extensions);
+    let walker = walk.builder(ignore_files).build_parallel();
+    let (tx, rx) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        let walk = &walk;
+        walker.run(|| {
+            let tx = tx.clone();
+            Box::new(move |entry| {
+                if let Some(file) = entry.ok().and_then(|e| walk.file(e)) {
+                    // The receiver is gone if the caller stopped iterating early
+                    if tx.send(file).is_err() {
+                        return WalkState::Quit;
+                    }
+                }
+                WalkState::Continue
+            })
+        })
+    });
+    rx.into_iter()
+}
+
+/// The files under a directory with one of the `extensions`, shared by the
+/// sequential and the parallel walk.
+struct Walk {
+    root: PathBuf,
+    // On Windows, the directory is walked using its extended-length form so that files
+    // nested deeper than MAX_PATH are found. They are still reported relative to `root`.
+    verbatim_root: Option<PathBuf>,
+    extensions: Vec<String>,
+}
+
+impl Walk {
+    fn new(path: &Path, extensions: Vec<String>) -> Walk {
+        Walk {
+            root: path.to_path_buf(),
+            verbatim_root: if cfg!(windows) { verbatim(path) } else { None },
+            extensions,
+        }
+    }
+
+    fn builder(&self, ignore_files: bool) -> WalkBuilder {
+        let mut walker = WalkBuilder::new(self.verbatim_root.as_ref().unwrap_or(&self.root));
+        walker.standard_filters(ignore_files).hidden(true);
+        if ignore_files {
+            walker
+                .require_git(false)
+                .add_custom_ignore_filename(IGNORE_FILENAME);
+        }
+        walker
+    }
+
+    /// Returns the path of `entry` if it is a file with one of the extensions.
+    fn file(&self, entry: DirEntry) -> Option<PathBuf> {
+        if entry.file_type().map_or(true, |t| t.is_dir()) {
+            return None;
+        }
+
+        let ext = entry.path().extension()?.to_str().unwrap_or_default();
+        if !self.extensions.iter().any(|e| e == ext) {
+            return None;
+        }
+
+        Some(match &self.verbatim_root {
+            Some(verbatim_root) => match entry.path().strip_prefix(verbatim_root) {
+                Ok(relative) if relative.as_os_str().is_empty() => self.root.clone(),
+                Ok(relative) => self.root.join(relative),
+                Err(_) => entry.into_path(),
+            },
+            None => entry.into_path(),
+        })
+    }
+}
+
+/// Returns a path that can be used to access `path` even if it is longer than
+/// MAX_PATH (260 characters) on Windows. Other paths are returned unchanged.
+pub fn long_path(path: &Path) -> Cow<'_, Path> {
+    if cfg!(windows) && path.as_os_str().len() >= 260 {
+        if let Some(p) = verbatim(path) {
+            return Cow::Owned(p);
+        }
+    }
+    Cow::Borrowed(path)
+}
+
+/// Returns the extended-length form of a Windows path (\\?\C:\.. or
+/// \\?\UNC\server\share\..), or None if `path` already uses it.
+fn verbatim(path: &Path) -> Option<PathBuf> {
+    let s = path.to_str()?;
+    if s.starts_with(r"\\?\") {
+        return None;
+    }
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir().ok()?.join(path)
+    };
+
+    // Extended-length paths are not normalized by Windows, so . and .. are resolved here.
+    let mut normalized = PathBuf::new();
+    for c in absolute.components() {
+        match c {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            c => normalized.push(c),
+        }
+    }
+
+    let s = normalized.to_str()?.replace('/', "\\");
+    Some(PathBuf::from(match s.strip_prefix(r"\\") {
+        Some(unc) => format!(r"\\?\UNC\{}", unc),
+        None => format!(r"\\?\{}", s),
+    }))
+}
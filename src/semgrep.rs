@@ -0,0 +1,354 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Conversion of Semgrep rules into weggli rules (weggli import-semgrep).
+//! Only the subset of Semgrep that maps onto weggli's query language is supported:
+//! metavariables, `...` in statement sequences, argument lists and blocks, `<... e ...>`,
+//! `pattern-not` for a statement inserted into the pattern and `metavariable-regex`.
+
+use crate::rules::{Rule, Severity};
+use colored::Colorize;
+use regex::Regex;
+use serde_yaml::Value;
+use std::path::Path;
+
+/// Result of converting a Semgrep rule file.
+struct Import {
+    rules: Vec<Rule>,
+    // (rule id, reason) for every rule that could not be converted
+    failed: Vec<(String, String)>,
+}
+
+/// Convert the Semgrep rule file at `path` and write one weggli rule file per
+/// converted rule to `output`, or print the rules if no directory is given.
+/// Rules that could not be converted are reported with the reason.
+pub fn run(path: &Path, output: Option<&Path>) -> Result<(), String> {
+    let import = import(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    if let Some(dir) = output {
+        std::fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    }
+    for rule in &import.rules {
+        let yaml = serde_yaml::to_string(rule).map_err(|e| e.to_string())?;
+        match output {
+            Some(dir) => {
+                let file = dir.join(file_name(&rule.id));
+                std::fs::write(&file, yaml).map_err(|e| format!("{}: {}", file.display(), e))?;
+            }
+            None => print!("{}", yaml),
+        }
+    }
+
+    let total = import.rules.len() + import.failed.len();
+    eprintln!("Converted {} of {} rules", import.rules.len(), total);
+    if !import.failed.is_empty() {
+        eprintln!(
+            "{}",
+            format!("Could not convert {} rule(s):", import.failed.len()).yellow()
+        );
+        for (id, reason) in &import.failed {
+            eprintln!("  {}: {}", id.bold(), reason);
+        }
+    }
+    Ok(())
+}
+
+/// Convert all rules in the Semgrep rule file at `path`.
+fn import(path: &Path) -> Result<Import, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let doc: Value = serde_yaml::from_str(&content).map_err(|e| e.to_string())?;
+    let rules = doc
+        .get("rules")
+        .and_then(|r| r.as_sequence())
+        .ok_or("expected a list of rules in 'rules'")?;
+
+    let mut result = Import {
+        rules: vec![],
+        failed: vec![],
+    };
+    for (i, r) in rules.iter().enumerate() {
+        let id = r
+            .get("id")
+            .and_then(|id| id.as_str())
+            .map_or_else(|| format!("rule-{}", i), |id| id.to_string());
+        match convert_rule(&id, r) {
+            Ok(rule) => result.rules.push(rule),
+            Err(e) => result.failed.push((id, e)),
+        }
+    }
+    Ok(result)
+}
+
+fn convert_rule(id: &str, rule: &Value) -> Result<Rule, String> {
+    let languages: Vec<&str> = rule
+        .get("languages")
+        .and_then(|l| l.as_sequence())
+        .map(|l| l.iter().filter_map(|l| l.as_str()).collect())
+        .unwrap_or_default();
+    let cpp = match languages.as_slice() {
+        l if l.contains(&"c") => false,
+        l if l.contains(&"cpp") || l.contains(&"c++") => true,
+        _ => return Err("not a C or C++ rule".to_string()),
+    };
+
+    let mut positive = None;
+    let mut negative = vec![];
+    let mut regex = vec![];
+
+    match (rule.get("pattern"), rule.get("patterns")) {
+        (Some(p), None) => positive = Some(string(p, "pattern")?),
+        (None, Some(patterns)) => {
+            let patterns = patterns
+                .as_sequence()
+                .ok_or("'patterns' needs to be a list")?;
+            for p in patterns {
+                let (key, value) = match p.as_mapping().map(|m| m.iter().collect::<Vec<_>>()) {
+                    Some(entries) if entries.len() == 1 => entries[0],
+                    _ => return Err("invalid entry in 'patterns'".to_string()),
+                };
+                match key.as_str().unwrap_or_default() {
+                    "pattern" if positive.is_none() => positive = Some(string(value, "pattern")?),
+                    "pattern" => return Err("multiple positive patterns".to_string()),
+                    "pattern-not" => negative.push(string(value, "pattern-not")?),
+                    "metavariable-regex" => {
+                        let var = value.get("metavariable").and_then(|v| v.as_str());
+                        let re = value.get("regex").and_then(|v| v.as_str());
+                        match (var, re) {
+                            // Semgrep regexes only match at the start of the value.
+                            (Some(var), Some(re)) => regex.push(format!("{}=^(?:{})", var, re)),
+                            _ => return Err("invalid metavariable-regex".to_string()),
+                        }
+                    }
+                    other => return Err(format!("'{}' is not supported", other)),
+                }
+            }
+        }
+        (None, None) => {
+            for key in ["pattern-either", "pattern-regex", "pattern-sources"] {
+                if rule.get(key).is_some() {
+                    return Err(format!("'{}' is not supported", key));
+                }
+            }
+            return Err("rule has no pattern".to_string());
+        }
+        (Some(_), Some(_)) => return Err("rule has both 'pattern' and 'patterns'".to_string()),
+    }
+
+    let positive = positive.ok_or("rule has no positive pattern")?;
+    let mut statements = translate(&positive)?;
+    for n in negative {
+        statements = add_negation(&statements, &translate(&n)?)?;
+    }
+    let pattern = to_pattern(&statements, cpp);
+
+    // Semgrep metavariables match any expression, but weggli variables only match
+    // identifiers. Metavariables that are only used once don't need to be bound,
+    // so they are replaced with a wildcard, which matches any expression as well.
+    // Called metavariables are kept, as _(..) would match any expression instead
+    // of a call.
+    let metavariable = Regex::new(r"\$[[:word:]]+").unwrap();
+    let mut counts = std::collections::HashMap::new();
+    for m in metavariable.find_iter(&pattern) {
+        *counts.entry(m.as_str()).or_insert(0) += 1;
+    }
+    for r in &regex {
+        counts.insert(r.split('=').next().unwrap(), 2);
+    }
+    // <... $X ...> was translated to _($X), which matches any expression if $X
+    // isn't bound.
+    let deep = Regex::new(r"(^|[^[:word:]$])_\((\$[[:word:]]+)\)").unwrap();
+    let pattern = deep.replace_all(&pattern, |c: &regex::Captures| match counts[&c[2]] {
+        1 => format!("{}_", &c[1]),
+        _ => c[0].to_string(),
+    });
+    let metavariable = Regex::new(r"(\$[[:word:]]+)(\s*\()?").unwrap();
+    let pattern = metavariable
+        .replace_all(&pattern, |c: &regex::Captures| {
+            match (counts[&c[1]], c.get(2)) {
+                (1, None) => "_".to_string(),
+                _ => c[0].to_string(),
+            }
+        })
+        .to_string();
+
+    let regex_map = regex
+        .iter()
+        .map(|r| {
+            let (var, re) = r.split_once('=').unwrap();
+            Regex::new(re)
+                .map(|re| (var.to_string(), (false, re)))
+                .map_err(|e| format!("invalid regex for {}: {}", var, e))
+        })
+        .collect::<Result<_, _>>()?;
+    weggli::parse_search_pattern(
+        &pattern,
+        cpp,
+        false,
+        Some(weggli::RegexMap::new(regex_map).into()),
+    )
+    .map_err(|e| format!("translated pattern '{}' is invalid: {}", pattern, e.message))?;
+
+    let severity = match rule.get("severity").and_then(|s| s.as_str()) {
+        Some("ERROR") => Severity::Error,
+        Some("INFO") => Severity::Note,
+        _ => Severity::Warning,
+    };
+    let description = rule
+        .get("message")
+        .and_then(|m| m.as_str())
+        .map(|m| m.split_whitespace().collect::<Vec<_>>().join(" "));
+
+    Ok(Rule {
+        id: id.to_string(),
+        pattern,
+        severity,
+        description,
+        regex,
+        before: None,
+        after: None,
+        whole_function: false,
+    })
+}
+
+fn string(value: &Value, key: &str) -> Result<String, String> {
+    value
+        .as_str()
+        .map(|s| s.to_string())
+        .ok_or_else(|| format!("'{}' needs to be a string", key))
+}
+
+/// Translate a Semgrep pattern into a list of weggli statements. `...` lines
+/// between statements are dropped, as weggli allows arbitrary statements between
+/// the statements of a query.
+fn translate(pattern: &str) -> Result<Vec<String>, String> {
+    if pattern.contains("$...") {
+        return Err("ellipsis metavariables ($...X) are not supported".to_string());
+    }
+
+    let deep = Regex::new(r"<\.\.\.\s*(.*?)\s*\.\.\.>").unwrap();
+    let condition = Regex::new(r"\b(if|while|switch)\s*\(\s*\.\.\.\s*\)").unwrap();
+    let trailing_args = Regex::new(r",\s*\.\.\.\s*\)").unwrap();
+    let any_args = Regex::new(r"\(\s*\.\.\.\s*\)").unwrap();
+    let any_body = Regex::new(r"^((?:if|while|for|switch)\b.*?)\s*\{\s*\.\.\.\s*\}$").unwrap();
+    // $_ is an anonymous metavariable in Semgrep, but a normal variable in weggli.
+    let anonymous = Regex::new(r"\$_\b").unwrap();
+
+    let mut statements: Vec<String> = vec![];
+    let mut current = String::new();
+    // Nesting level of { } at the end of `current`
+    let mut depth = 0;
+    for line in pattern.lines().map(|l| l.trim()) {
+        if line == "..." || line == "...;" {
+            // Inside of a block, weggli allows additional statements as well.
+            if depth == 0 && !current.is_empty() {
+                statements.push(std::mem::take(&mut current));
+            }
+            continue;
+        }
+        if !current.is_empty() {
+            current.push(' ');
+        }
+        current.push_str(line);
+        depth += line.matches('{').count() as isize - line.matches('}').count() as isize;
+        // Statements can span multiple lines, so we only split on complete lines.
+        if depth <= 0 && (line.ends_with(';') || line.ends_with('}')) {
+            statements.push(std::mem::take(&mut current));
+            depth = 0;
+        }
+    }
+    if !current.is_empty() {
+        statements.push(current);
+    }
+
+    statements
+        .into_iter()
+        .map(|s| {
+            // weggli matches calls with additional arguments, so `...` at the end
+            // of an argument list can be dropped.
+            let s = deep.replace_all(&s, "_($1)");
+            let s = condition.replace_all(&s, "$1 (_)");
+            let s = trailing_args.replace_all(&s, ")");
+            let s = any_args.replace_all(&s, "()");
+            let s = any_body.replace(&s, "$1 _;");
+            let s = anonymous.replace_all(&s, "_");
+            if s.contains("...") {
+                Err(format!("unsupported use of '...' in '{}'", s))
+            } else {
+                Ok(s.into_owned())
+            }
+        })
+        .collect()
+}
+
+/// Translate `pattern-not` into a weggli negation. This is only possible if the
+/// negative pattern is the positive pattern with a single additional statement,
+/// which then must not appear at that position.
+fn add_negation(positive: &[String], negative: &[String]) -> Result<Vec<String>, String> {
+    let plain: Vec<&String> = positive
+        .iter()
+        .filter(|s| !s.starts_with("not: "))
+        .collect();
+    let fail = || {
+        "pattern-not is only supported for the pattern with one additional statement".to_string()
+    };
+    if negative.len() != plain.len() + 1 {
+        return Err(fail());
+    }
+
+    let position = (0..negative.len())
+        .find(|&i| {
+            let mut rest: Vec<&String> = negative.iter().collect();
+            rest.remove(i);
+            rest == plain
+        })
+        .ok_or_else(fail)?;
+
+    // Insert the negation before the statement that follows it in the positive pattern.
+    let mut result = positive.to_vec();
+    let index = match plain.get(position) {
+        Some(next) => positive.iter().position(|s| &s == next).unwrap(),
+        None => positive.len(),
+    };
+    result.insert(index, format!("not: {}", negative[position]));
+    Ok(result)
+}
+
+/// Join `statements` into a weggli pattern. Sequences of statements are
+/// wrapped in a compound statement.
+fn to_pattern(statements: &[String], cpp: bool) -> String {
+    if statements.len() == 1 {
+        let tree = weggli::parse(&statements[0], cpp);
+        if tree.root_node().named_child_count() <= 1 {
+            return statements[0].clone();
+        }
+    }
+    format!("{{{}}}", statements.join(" "))
+}
+
+/// Returns a file name for the rule `id` that only contains safe characters.
+fn file_name(id: &str) -> String {
+    let name: String = id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{}.yml", name.trim_start_matches('.'))
+}
@@ -0,0 +1,198 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Support for splitting a scan across multiple machines (--shard) and
+//! combining the saved results of each shard (merge-results).
+
+use colored::Colorize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+
+use weggli::result::{join_keys, JoinKeys, JoinMode};
+
+use crate::output::{Body, Finding, Output};
+
+/// Version of the --save-results file format.
+const FORMAT_VERSION: u64 = 1;
+
+/// Returns true if `file` belongs to shard `k` (0-based) of `n`.
+/// Files are assigned based on a stable hash of their path relative to the search
+/// root, so the same corpus is partitioned identically on every machine.
+pub fn in_shard(file: &Path, root: &Path, (k, n): (usize, usize)) -> bool {
+    let relative = file.strip_prefix(root).unwrap_or(file).to_string_lossy();
+    // Use the same separator on all platforms, so that shards match across machines.
+    let relative = if cfg!(windows) {
+        relative.replace('\\', "/")
+    } else {
+        relative.into_owned()
+    };
+    let mut hasher = rustc_hash::FxHasher::default();
+    hasher.write(relative.as_bytes());
+    hasher.finish() % n as u64 == k as u64
+}
+
+/// A single query result as stored in a --save-results file.
+pub struct SavedResult {
+    pub query_index: usize,
+    pub location: String,
+    pub display: String,
+    pub snippet: String,
+    pub vars: HashMap<String, String>,
+}
+
+impl SavedResult {
+    fn to_json(&self) -> Value {
+        json!({
+            "query": self.query_index,
+            "location": self.location,
+            "display": self.display,
+            "snippet": self.snippet,
+            "vars": self.vars,
+        })
+    }
+
+    fn from_json(v: &Value) -> Option<SavedResult> {
+        Some(SavedResult {
+            query_index: v["query"].as_u64()? as usize,
+            location: v["location"].as_str()?.to_string(),
+            display: v["display"].as_str()?.to_string(),
+            snippet: v["snippet"].as_str()?.to_string(),
+            vars: v["vars"]
+                .as_object()?
+                .iter()
+                .map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+                .collect::<Option<_>>()?,
+        })
+    }
+
+    /// See QueryResult::join_keys
+    fn join_keys(&self, modes: &HashMap<String, JoinMode>) -> JoinKeys {
+        join_keys(self.vars.iter().map(|(k, v)| (k, v.as_str())), modes)
+    }
+
+    /// Checks if two results have compatible variable assignments.
+    /// See QueryResult::chainable_with
+    fn chainable(&self, other: &SavedResult, modes: &HashMap<String, JoinMode>) -> bool {
+        self.vars.keys().chain(other.vars.keys()).all(|k| {
+            modes.get(k).unwrap_or(&JoinMode::Equal).compatible(
+                self.vars.get(k).map(|v| v.as_str()),
+                other.vars.get(k).map(|v| v.as_str()),
+            )
+        })
+    }
+}
+
+/// Write the (not yet joined) results of all queries to `path`.
+pub fn save_results(path: &Path, patterns: &[String], results: &[SavedResult]) {
+    let doc = json!({
+        "version": FORMAT_VERSION,
+        "patterns": patterns,
+        "results": results.iter().map(|r| r.to_json()).collect::<Vec<_>>(),
+    });
+
+    if let Err(e) = std::fs::write(path, doc.to_string()) {
+        eprintln!("Failed to write {}: {}", path.display(), e);
+        std::process::exit(2)
+    }
+}
+
+/// Implements `weggli merge-results`: Load the saved results of all shards,
+/// run the multi-pattern join across all of them and print the remaining matches.
+pub fn merge_results(files: &[PathBuf], join: &HashMap<String, JoinMode>, output: &Output) {
+    let mut patterns: Option<Vec<String>> = None;
+    let mut results: Vec<Vec<SavedResult>> = Vec::new();
+
+    for file in files {
+        let doc = match load(file) {
+            Ok(doc) => doc,
+            Err(e) => {
+                eprintln!("{}: {}", file.display(), e.red());
+                std::process::exit(2)
+            }
+        };
+
+        match &patterns {
+            None => {
+                results.resize_with(doc.0.len(), Vec::new);
+                patterns = Some(doc.0);
+            }
+            Some(p) if *p != doc.0 => {
+                eprintln!(
+                    "{}: {}",
+                    file.display(),
+                    "results were generated with different search patterns".red()
+                );
+                std::process::exit(2)
+            }
+            Some(_) => (),
+        }
+
+        for r in doc.1 {
+            let i = r.query_index;
+            results[i].push(r);
+        }
+    }
+
+    weggli::vfs::join(
+        &mut results,
+        |r| r.join_keys(join),
+        |a, b| a.chainable(b, join),
+    );
+
+    for r in results.into_iter().flatten() {
+        output.print_match(Finding {
+            location: r.location,
+            snippet: r.snippet,
+            variables: r.vars.into_iter().collect(),
+            body: Body::Text(r.display),
+        });
+    }
+}
+
+fn load(file: &Path) -> Result<(Vec<String>, Vec<SavedResult>), String> {
+    let data = std::fs::read_to_string(file).map_err(|e| e.to_string())?;
+    let doc: Value = serde_json::from_str(&data).map_err(|e| e.to_string())?;
+
+    if doc["version"].as_u64() != Some(FORMAT_VERSION) {
+        return Err("unsupported result file version".to_string());
+    }
+
+    let invalid = || "invalid result file".to_string();
+
+    let patterns: Vec<String> = doc["patterns"]
+        .as_array()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|p| p.as_str().map(|s| s.to_string()))
+        .collect::<Option<_>>()
+        .ok_or_else(invalid)?;
+
+    let results: Vec<SavedResult> = doc["results"]
+        .as_array()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(SavedResult::from_json)
+        .collect::<Option<_>>()
+        .ok_or_else(invalid)?;
+
+    if results.iter().any(|r| r.query_index >= patterns.len()) {
+        return Err(invalid());
+    }
+
+    Ok((patterns, results))
+}
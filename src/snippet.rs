@@ -0,0 +1,69 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! weggli try: run a single pattern against an inline code snippet and show
+//! everything it captured.
+
+use colored::Colorize;
+use weggli::parse_search_pattern;
+use weggli::query::QueryTree;
+use weggli::result::QueryResult;
+
+/// Table of all captures of `m`: position, variable, node kind and text.
+fn capture_table(qt: &QueryTree, m: &QueryResult, source: &str) -> String {
+    let mut s = format!("{}\n", "captures:".bold());
+    for d in m.capture_details(qt, source) {
+        let position = format!("{}:{}", d.start.line, d.start.column);
+        let text = match d.text.find('\n') {
+            Some(i) => format!("{}...", &d.text[..i]),
+            None => d.text.to_string(),
+        };
+        s += &format!(
+            "  {:>7}  {:<10} {:<24} {}\n",
+            position,
+            d.variable.unwrap_or("-"),
+            d.kind.dimmed(),
+            text
+        );
+    }
+    s
+}
+
+/// Run `pattern` against the code in `source` and print all results with their captures.
+pub fn run(pattern: &str, source: &str, cpp: bool) {
+    let qt = parse_search_pattern(pattern, cpp, false, None).unwrap_or_else(|e| {
+        eprintln!("{}", e.message);
+        std::process::exit(2)
+    });
+
+    let tree = weggli::parse(source, cpp);
+    if tree.root_node().has_error() {
+        warn!("the code snippet contains syntax errors");
+    }
+
+    let matches = qt.matches(tree.root_node(), source);
+    for m in &matches {
+        println!("{}", m.display(source, 0, 0, true));
+        println!("{}", capture_table(&qt, m, source));
+    }
+
+    let summary = match matches.len() {
+        0 => "no matches".to_string(),
+        1 => "1 match".to_string(),
+        n => format!("{} matches", n),
+    };
+    println!("{}", summary.dimmed());
+}
@@ -0,0 +1,309 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Incremental join of multi-pattern results (--stream-join).
+//!
+//! Instead of collecting the results of all patterns and joining them once the
+//! search is done, every result is reported as soon as each other pattern has
+//! produced a compatible result. Only the distinct variable values of all results
+//! and the (already formatted) results that are still waiting for a partner are
+//! kept in memory, not the parsed source files. The number of waiting results is
+//! limited (see --stream-join-limit), the oldest ones are dropped beyond it.
+//!
+//! Both are indexed by the values of the variables that need to be equal in
+//! compatible results (see `QueryResult::join_keys`), so a new result is only
+//! compared with results that share these values.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+
+use rustc_hash::FxHashMap;
+use weggli::result::{JoinKeys, JoinMode, QueryResult};
+
+/// The parts of a result that are needed to find compatible results of other patterns.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct JoinKey {
+    // (variable, value) pairs, sorted by variable
+    vars: Vec<(String, String)>,
+    // path and range of the enclosing function, only set with --same-function
+    function: Option<(String, Range<usize>)>,
+    // keys of the variables that need to be equal in compatible results, and the path
+    // with --same-function
+    keys: JoinKeys,
+}
+
+impl JoinKey {
+    /// Returns the key of `result`. With `path`, results are only compatible with
+    /// results in the same function of the same file.
+    pub fn new(
+        result: &QueryResult,
+        source: &str,
+        path: Option<&str>,
+        modes: &HashMap<String, JoinMode>,
+    ) -> JoinKey {
+        let mut vars: Vec<(String, String)> = result
+            .vars
+            .keys()
+            .map(|k| (k.clone(), result.value(k, source).unwrap().to_string()))
+            .collect();
+        vars.sort();
+
+        // The path is indexed like a variable that always needs to be equal. Its
+        // empty name can't clash with a query variable.
+        let mut keys = result.join_keys(source, modes);
+        if let Some(path) = path {
+            keys.insert(0, (String::new(), path.to_string()));
+        }
+
+        JoinKey {
+            vars,
+            function: path.map(|p| (p.to_string(), result.function_range())),
+            keys,
+        }
+    }
+
+    fn value(&self, var: &str) -> Option<&str> {
+        let i = self
+            .vars
+            .binary_search_by(|(v, _)| v.as_str().cmp(var))
+            .ok()?;
+        Some(&self.vars[i].1)
+    }
+
+    /// See QueryResult::chainable_with and QueryResult::same_function
+    fn chainable(&self, other: &JoinKey, modes: &HashMap<String, JoinMode>) -> bool {
+        if let (Some((path, a)), Some((other_path, b))) = (&self.function, &other.function) {
+            if path != other_path || a.start >= b.end || b.start >= a.end {
+                return false;
+            }
+        }
+
+        self.vars.iter().chain(other.vars.iter()).all(|(var, _)| {
+            modes
+                .get(var)
+                .unwrap_or(&JoinMode::Equal)
+                .compatible(self.value(var), other.value(var))
+        })
+    }
+}
+
+/// Index of join keys by their values, for finding the keys that can be compatible
+/// with a given key. Unlike `vfs::join`, keys can be added and removed at any time.
+#[derive(Default)]
+struct KeyIndex {
+    // variables of the indexed keys -> keys with exactly these variables
+    groups: FxHashMap<Vec<String>, Group>,
+}
+
+#[derive(Default)]
+struct Group {
+    // id -> values of the variables
+    values: FxHashMap<usize, Vec<String>>,
+    // positions of the variables shared with looked up keys -> their values -> ids.
+    // Tables are created for the first lookup with a set of shared variables.
+    tables: FxHashMap<Vec<usize>, FxHashMap<Vec<String>, Vec<usize>>>,
+}
+
+fn variables(keys: &JoinKeys) -> Vec<String> {
+    keys.iter().map(|(var, _)| var.clone()).collect()
+}
+
+fn project(values: &[String], positions: &[usize]) -> Vec<String> {
+    positions.iter().map(|&i| values[i].clone()).collect()
+}
+
+impl KeyIndex {
+    fn insert(&mut self, id: usize, keys: &JoinKeys) {
+        let group = self.groups.entry(variables(keys)).or_default();
+        let values: Vec<String> = keys.iter().map(|(_, value)| value.clone()).collect();
+        for (positions, table) in group.tables.iter_mut() {
+            table
+                .entry(project(&values, positions))
+                .or_default()
+                .push(id);
+        }
+        group.values.insert(id, values);
+    }
+
+    fn remove(&mut self, id: usize, keys: &JoinKeys) {
+        let group = match self.groups.get_mut(&variables(keys)) {
+            Some(g) => g,
+            None => return,
+        };
+        let values = match group.values.remove(&id) {
+            Some(v) => v,
+            None => return,
+        };
+        for (positions, table) in group.tables.iter_mut() {
+            let projected = project(&values, positions);
+            if let Some(ids) = table.get_mut(&projected) {
+                ids.retain(|&i| i != id);
+                if ids.is_empty() {
+                    table.remove(&projected);
+                }
+            }
+        }
+    }
+
+    /// Returns the ids of all indexed keys whose shared variables have the same
+    /// values as in `keys`. These are the only keys that can be compatible with it.
+    fn candidates(&mut self, keys: &JoinKeys) -> Vec<usize> {
+        let mut result = Vec::new();
+        for (group_variables, group) in self.groups.iter_mut() {
+            let shared: Vec<(usize, usize)> = keys
+                .iter()
+                .enumerate()
+                .filter_map(|(i, (var, _))| Some((group_variables.binary_search(var).ok()?, i)))
+                .collect();
+            let positions: Vec<usize> = shared.iter().map(|&(j, _)| j).collect();
+
+            let values = &group.values;
+            let table = group
+                .tables
+                .entry(positions)
+                .or_insert_with_key(|positions| {
+                    let mut table: FxHashMap<Vec<String>, Vec<usize>> = FxHashMap::default();
+                    for (&id, v) in values {
+                        table.entry(project(v, positions)).or_default().push(id);
+                    }
+                    table
+                });
+
+            let lookup: Vec<String> = shared.iter().map(|&(_, i)| keys[i].1.clone()).collect();
+            result.extend(table.get(&lookup).into_iter().flatten().copied());
+        }
+        result
+    }
+}
+
+/// The distinct keys of the results of a pattern.
+#[derive(Default)]
+struct Seen {
+    keys: Vec<JoinKey>,
+    index: KeyIndex,
+}
+
+/// A result that does not have a compatible result for every other pattern yet.
+struct Pending<T> {
+    key: JoinKey,
+    // patterns without a compatible result
+    missing: Vec<usize>,
+    item: T,
+}
+
+/// Joins the results of multiple patterns while they are found.
+pub struct StreamJoin<'a, T> {
+    modes: &'a HashMap<String, JoinMode>,
+    seen: Vec<Seen>,
+    // waiting results by id, the oldest first
+    pending: BTreeMap<usize, Pending<T>>,
+    // ids of the waiting results that miss a result of each pattern
+    waiting: Vec<KeyIndex>,
+    next_id: usize,
+    limit: usize,
+    dropped: usize,
+}
+
+impl<'a, T> StreamJoin<'a, T> {
+    /// Join the results of `patterns` patterns. At most `limit` results wait for
+    /// compatible results of other patterns, the oldest ones are dropped beyond it.
+    pub fn new(
+        patterns: usize,
+        modes: &'a HashMap<String, JoinMode>,
+        limit: usize,
+    ) -> StreamJoin<'a, T> {
+        StreamJoin {
+            modes,
+            seen: (0..patterns).map(|_| Seen::default()).collect(),
+            pending: BTreeMap::new(),
+            waiting: (0..patterns).map(|_| KeyIndex::default()).collect(),
+            next_id: 0,
+            limit,
+            dropped: 0,
+        }
+    }
+
+    /// Add `item`, a result of pattern `query` with the given `key`. Returns all
+    /// items that have a compatible result for every other pattern now.
+    pub fn add(&mut self, query: usize, key: JoinKey, item: T) -> Vec<T> {
+        let mut ready = Vec::new();
+
+        let seen = &mut self.seen[query];
+        let candidates = seen.index.candidates(&key.keys);
+        if !candidates.iter().any(|&i| seen.keys[i] == key) {
+            seen.index.insert(seen.keys.len(), &key.keys);
+            seen.keys.push(key.clone());
+
+            // The new result might be the last missing partner of pending results.
+            for id in self.waiting[query].candidates(&key.keys) {
+                let p = self.pending.get_mut(&id).unwrap();
+                if !p.key.chainable(&key, self.modes) {
+                    continue;
+                }
+                p.missing.retain(|&q| q != query);
+                self.waiting[query].remove(id, &p.key.keys);
+                if p.missing.is_empty() {
+                    ready.push(self.pending.remove(&id).unwrap().item);
+                }
+            }
+        }
+
+        let modes = self.modes;
+        let missing: Vec<usize> = (0..self.seen.len())
+            .filter(|&q| q != query)
+            .filter(|&q| {
+                let seen = &mut self.seen[q];
+                !seen
+                    .index
+                    .candidates(&key.keys)
+                    .into_iter()
+                    .any(|i| seen.keys[i].chainable(&key, modes))
+            })
+            .collect();
+
+        if missing.is_empty() {
+            ready.push(item);
+            return ready;
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        for &q in &missing {
+            self.waiting[q].insert(id, &key.keys);
+        }
+        self.pending.insert(id, Pending { key, missing, item });
+
+        if self.pending.len() > self.limit {
+            let oldest = *self.pending.keys().next().unwrap();
+            let p = self.pending.remove(&oldest).unwrap();
+            for q in p.missing {
+                self.waiting[q].remove(oldest, &p.key.keys);
+            }
+            self.dropped += 1;
+        }
+        ready
+    }
+
+    /// Number of results that are still waiting for compatible results of other patterns.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Number of waiting results that were dropped because of the limit.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
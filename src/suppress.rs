@@ -0,0 +1,182 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Suppressed results: `// weggli-ignore[rule-id]` comments in the searched code
+//! and baseline files of known results (--baseline).
+
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+const MARKER: &str = "weggli-ignore";
+
+/// The weggli-ignore comments of a file.
+pub struct Comments {
+    // Suppressed line (1-based) -> rule IDs, empty for all rules
+    lines: HashMap<usize, Vec<String>>,
+}
+
+impl Comments {
+    /// Find all weggli-ignore comments in `source`. Each comment suppresses the
+    /// results on the line following it.
+    pub fn parse(source: &str) -> Comments {
+        let mut lines = HashMap::new();
+        if !source.contains(MARKER) {
+            return Comments { lines };
+        }
+
+        let re = Regex::new(r"(?://|/\*)\s*weggli-ignore(?:\[([^\]\n]*)\])?").unwrap();
+        for c in re.captures_iter(source) {
+            let line = source[..c.get(0).unwrap().start()].matches('\n').count() + 1;
+            let ids: &mut Vec<String> = lines.entry(line + 1).or_default();
+            match c.get(1) {
+                Some(list) => ids.extend(
+                    list.as_str()
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|id| !id.is_empty())
+                        .map(String::from),
+                ),
+                // weggli-ignore without IDs applies to all rules
+                None => ids.push(String::new()),
+            }
+        }
+        Comments { lines }
+    }
+
+    /// Returns true if one of the `lines` is suppressed for the rule `id`.
+    pub fn suppresses(&self, id: &str, lines: RangeInclusive<usize>) -> bool {
+        self.lines
+            .iter()
+            .any(|(line, ids)| lines.contains(line) && ids.iter().any(|i| i.is_empty() || i == id))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Entry {
+    rule: String,
+    path: String,
+    // Line of the result when the baseline was recorded, only for reference
+    line: usize,
+    fingerprint: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct BaselineFile {
+    version: u32,
+    results: Vec<Entry>,
+}
+
+const BASELINE_VERSION: u32 = 1;
+
+/// Known results loaded from a baseline file (--baseline). With --update-baseline,
+/// all results are recorded instead and written to the file by `finish`.
+pub struct Baseline {
+    path: PathBuf,
+    // Fingerprint -> number of recorded results that are not suppressed yet
+    remaining: Mutex<HashMap<String, usize>>,
+    recorded: Option<Mutex<Vec<Entry>>>,
+}
+
+impl Baseline {
+    pub fn load(path: &Path) -> Result<Baseline, String> {
+        let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let file: BaselineFile = serde_json::from_str(&content).map_err(|e| e.to_string())?;
+        if file.version != BASELINE_VERSION {
+            return Err(format!("unsupported baseline version {}", file.version));
+        }
+        let mut remaining = HashMap::new();
+        for e in file.results {
+            *remaining.entry(e.fingerprint).or_insert(0) += 1;
+        }
+        Ok(Baseline {
+            path: path.to_path_buf(),
+            remaining: Mutex::new(remaining),
+            recorded: None,
+        })
+    }
+
+    /// An empty baseline that records all results and replaces `path` by `finish`.
+    pub fn update(path: &Path) -> Baseline {
+        Baseline {
+            path: path.to_path_buf(),
+            remaining: Mutex::new(HashMap::new()),
+            recorded: Some(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Returns true if results are recorded with --update-baseline.
+    pub fn is_recording(&self) -> bool {
+        self.recorded.is_some()
+    }
+
+    /// Record the result of `rule` in `path` with the normalized matched code
+    /// `snippet` with --update-baseline.
+    pub fn record(&self, rule: &str, path: &str, line: usize, snippet: &str) {
+        if let Some(recorded) = &self.recorded {
+            recorded.lock().unwrap().push(Entry {
+                rule: rule.to_string(),
+                path: path.to_string(),
+                line,
+                fingerprint: fingerprint(rule, path, snippet),
+            });
+        }
+    }
+
+    /// Returns true if the result of `rule` in `path` with the normalized matched
+    /// code `snippet` is a known result. The line is not part of the comparison,
+    /// so results are still recognized when code above them changes. A result
+    /// that is recorded n times suppresses at most n identical results, so that
+    /// new copies of known code are still reported.
+    pub fn suppresses(&self, rule: &str, path: &str, snippet: &str) -> bool {
+        let mut remaining = self.remaining.lock().unwrap();
+        match remaining.get_mut(&fingerprint(rule, path, snippet)) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Write the recorded results with --update-baseline.
+    pub fn finish(&self) -> Result<(), String> {
+        let recorded = match &self.recorded {
+            Some(recorded) => recorded.lock().unwrap(),
+            None => return Ok(()),
+        };
+
+        let mut results = recorded.clone();
+        results.sort_by(|a, b| (&a.path, a.line, &a.rule).cmp(&(&b.path, b.line, &b.rule)));
+
+        let file = BaselineFile {
+            version: BASELINE_VERSION,
+            results,
+        };
+        let json = serde_json::to_string_pretty(&file).map_err(|e| e.to_string())?;
+        std::fs::write(&self.path, json + "\n")
+            .map_err(|e| format!("{}: {}", self.path.display(), e))
+    }
+}
+
+/// 64-bit FNV-1a hash of the rule, path and snippet of a result.
+fn fingerprint(rule: &str, path: &str, snippet: &str) -> String {
+    format!("{:016x}", crate::output::fnv1a(&[rule, path, snippet]))
+}
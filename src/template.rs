@@ -0,0 +1,154 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Custom output templates (--output-format) such as `{file}:{line} {$func} -> {$size}`.
+//! Fields in braces are replaced with the values of a result, `{{` and `}}` are
+//! literal braces and `\t` and `\n` are tabs and line breaks.
+
+use std::borrow::Cow;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    File,
+    Line,
+    Column,
+    Function,
+    Rule,
+    Snippet,
+    Variable(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+/// The values of a result that can be used in a template, besides the variables.
+pub struct Fields<'a> {
+    pub file: &'a str,
+    pub line: usize,
+    pub column: usize,
+    /// Name of the enclosing function (only looked up if the template uses it).
+    pub function: Option<&'a str>,
+    pub rule: Option<&'a str>,
+    pub snippet: &'a str,
+}
+
+impl Template {
+    /// Query variables used in the template.
+    pub fn variables(&self) -> impl Iterator<Item = &str> {
+        self.parts.iter().filter_map(|p| match p {
+            Part::Variable(v) => Some(v.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Returns true if the template contains {function}.
+    pub fn uses_function(&self) -> bool {
+        self.parts.contains(&Part::Function)
+    }
+
+    /// Fill in the template. Missing values (e.g. variables of another pattern
+    /// or results outside of functions) are replaced with an empty string.
+    pub fn render<'a>(
+        &self,
+        fields: &Fields,
+        value: &dyn Fn(&str) -> Option<Cow<'a, str>>,
+    ) -> String {
+        let mut s = String::new();
+        for p in &self.parts {
+            match p {
+                Part::Text(t) => s += t,
+                Part::File => s += fields.file,
+                Part::Line => s += &fields.line.to_string(),
+                Part::Column => s += &fields.column.to_string(),
+                Part::Function => s += fields.function.unwrap_or_default(),
+                Part::Rule => s += fields.rule.unwrap_or_default(),
+                Part::Snippet => s += fields.snippet,
+                Part::Variable(v) => s += &value(v).unwrap_or_default(),
+            }
+        }
+        s
+    }
+}
+
+impl std::str::FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |msg: String| format!("invalid output format '{}': {}", s, msg);
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = s.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '}' => return Err(err("unmatched '}' (use '}}' for a literal brace)".into())),
+                '\\' => match chars.next() {
+                    Some('t') => text.push('\t'),
+                    Some('n') => text.push('\n'),
+                    Some(c) => {
+                        text.push('\\');
+                        text.push(c);
+                    }
+                    None => text.push('\\'),
+                },
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(err("unclosed '{'".into())),
+                        }
+                    }
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(match name.trim() {
+                        "file" => Part::File,
+                        "line" => Part::Line,
+                        "column" | "col" => Part::Column,
+                        "function" => Part::Function,
+                        "rule" => Part::Rule,
+                        "snippet" => Part::Snippet,
+                        v if v.len() > 1
+                            && v.starts_with('$')
+                            && v[1..].chars().all(|c| c.is_alphanumeric() || c == '_') =>
+                        {
+                            Part::Variable(v.to_string())
+                        }
+                        other => return Err(err(format!("unknown field '{{{}}}'", other))),
+                    });
+                }
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Template { parts })
+    }
+}
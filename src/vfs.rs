@@ -0,0 +1,335 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Run queries against in-memory files instead of paths on disk.
+//!
+//! This is useful for embedders whose sources come from databases, code review
+//! systems or decompilers. `search` runs the same pipeline as the weggli binary:
+//! files are prefiltered on the identifiers used in the queries, parsed and
+//! searched, and the results of multiple queries are joined on their variables.
+//!
+//! Servers can wrap the results in a `ResultSet` to hand them out page by page.
+
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::sync::Arc;
+
+use rayon::prelude::*;
+use rustc_hash::FxHashMap;
+
+use crate::file::Loader;
+use crate::query::QueryTree;
+use crate::result::{JoinKeys, JoinMode, QueryResult};
+use crate::search::{file_matches, Prefilter};
+
+/// A query result in a virtual file.
+pub struct FileMatch {
+    /// Name of the file, as passed to `search`.
+    pub name: String,
+    /// Index of the query in the `queries` passed to `search`.
+    pub query: usize,
+    pub source: Arc<String>,
+    pub result: QueryResult,
+}
+
+/// Run all `queries` on the `(name, contents)` pairs in `files`.
+/// `cpp` selects the grammar used to parse the files and needs to match the
+/// grammar the queries were built for.
+/// With more than one query, only results whose variables are compatible with
+/// a result of every other query (in any file) are returned, see `join`.
+pub fn search<I, N, C>(queries: &[QueryTree], files: I, cpp: bool) -> Vec<FileMatch>
+where
+    I: IntoIterator<Item = (N, C)>,
+    N: Into<String>,
+    C: AsRef<[u8]>,
+{
+    search_with_join(queries, files, cpp, &HashMap::new())
+}
+
+/// Like `search`, but uses the given JoinMode for each variable when joining the
+/// results of multiple queries. Variables without an entry in `modes` use JoinMode::Equal.
+pub fn search_with_join<I, N, C>(
+    queries: &[QueryTree],
+    files: I,
+    cpp: bool,
+    modes: &HashMap<String, JoinMode>,
+) -> Vec<FileMatch>
+where
+    I: IntoIterator<Item = (N, C)>,
+    N: Into<String>,
+    C: AsRef<[u8]>,
+{
+    let prefilter = Prefilter::new(queries);
+    let loader = Loader::default();
+    let mut results: Vec<Vec<FileMatch>> = queries.iter().map(|_| Vec::new()).collect();
+
+    for (name, contents) in files {
+        let mut could_match = Vec::new();
+        let parsed = loader.parse(contents.as_ref(), |contents| {
+            could_match = prefilter.check(contents, cpp);
+            Prefilter::languages(&could_match, cpp)
+        });
+
+        // Files that can't match are the only ones that are skipped, there is no timeout.
+        let name = name.into();
+        for file in parsed.into_iter().flatten() {
+            for (i, result) in file_matches(queries, &file, &could_match) {
+                results[i].push(FileMatch {
+                    name: name.clone(),
+                    query: i,
+                    source: file.source.clone(),
+                    result,
+                });
+            }
+        }
+    }
+
+    join(
+        &mut results,
+        |r| r.result.join_keys(&r.source, modes),
+        |a, b| {
+            a.result
+                .chainable_with(&a.source, &b.result, &b.source, modes)
+        },
+    );
+
+    results.into_iter().flatten().collect()
+}
+
+/// A completed search whose results can be fetched incrementally.
+/// Results are ordered by file name, query index and position in the file, so
+/// the same search always produces the same pages.
+pub struct ResultSet {
+    matches: Vec<FileMatch>,
+    // Fingerprint of the results, used to reject cursors of other result sets.
+    id: u64,
+}
+
+/// A slice of a ResultSet, see `ResultSet::page`.
+pub struct Page<'a> {
+    pub matches: &'a [FileMatch],
+    /// Cursor for the next page, or None if this is the last one.
+    pub next: Option<String>,
+}
+
+/// Returned by `ResultSet::page` for cursors that don't belong to the result set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidCursor(pub String);
+
+impl std::fmt::Display for InvalidCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid cursor '{}'", self.0)
+    }
+}
+
+impl ResultSet {
+    pub fn new(mut matches: Vec<FileMatch>) -> ResultSet {
+        matches.sort_by(|a, b| {
+            (
+                &a.name,
+                a.query,
+                a.result.start_offset(),
+                a.result.function_range().end,
+            )
+                .cmp(&(
+                    &b.name,
+                    b.query,
+                    b.result.start_offset(),
+                    b.result.function_range().end,
+                ))
+        });
+
+        let mut hasher = rustc_hash::FxHasher::default();
+        for m in &matches {
+            hasher.write(m.name.as_bytes());
+            hasher.write_usize(m.query);
+            hasher.write_usize(m.result.start_offset());
+            hasher.write_usize(m.result.function_range().end);
+        }
+
+        ResultSet {
+            matches,
+            id: hasher.finish(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.matches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.matches.is_empty()
+    }
+
+    /// All results, in page order.
+    pub fn matches(&self) -> &[FileMatch] {
+        &self.matches
+    }
+
+    /// Return up to `limit` results, starting at `cursor`. Pass None to get
+    /// the first page and the `next` cursor of the previous page to continue.
+    /// Cursors are opaque strings that are only valid for the result set that
+    /// returned them (or an identical one, e.g. after re-running the search).
+    pub fn page(&self, cursor: Option<&str>, limit: usize) -> Result<Page<'_>, InvalidCursor> {
+        let start = match cursor {
+            None => 0,
+            Some(c) => self
+                .decode_cursor(c)
+                .ok_or_else(|| InvalidCursor(c.to_string()))?,
+        };
+
+        let end = start.saturating_add(limit.max(1)).min(self.matches.len());
+        let next = if end < self.matches.len() {
+            Some(format!("{:016x}{:x}", self.id, end))
+        } else {
+            None
+        };
+
+        Ok(Page {
+            matches: &self.matches[start..end],
+            next,
+        })
+    }
+
+    fn decode_cursor(&self, cursor: &str) -> Option<usize> {
+        if cursor.len() <= 16 || !cursor.is_char_boundary(16) {
+            return None;
+        }
+        let (id, offset) = cursor.split_at(16);
+        if u64::from_str_radix(id, 16).ok()? != self.id {
+            return None;
+        }
+        usize::from_str_radix(offset, 16)
+            .ok()
+            .filter(|&o| o <= self.matches.len())
+    }
+}
+
+/// Filter the results of multiple queries.
+/// We have a list of results for each query, but we still need to ensure that we only keep
+/// results for query A that can be combined with at least one result in query B (and C and D).
+///
+/// `join_keys` returns the keys of all variables that need to be equal in chainable
+/// results (see `QueryResult::join_keys`). The results of B are indexed by these keys,
+/// so `chainable` only needs to be checked for results of A and B whose keys match
+/// instead of comparing every pair of results.
+pub fn join<T: Sync>(
+    query_results: &mut [Vec<T>],
+    join_keys: impl Fn(&T) -> JoinKeys + Sync,
+    chainable: impl Fn(&T, &T) -> bool + Sync,
+) {
+    let mut keys: Vec<Vec<JoinKeys>> = query_results
+        .iter()
+        .map(|rv| rv.par_iter().map(&join_keys).collect())
+        .collect();
+
+    for a in 0..query_results.len() {
+        for b in a + 1..query_results.len() {
+            filter(query_results, &mut keys, a, b, &chainable);
+            filter(query_results, &mut keys, b, a, &chainable);
+        }
+    }
+}
+
+/// Only keep the results of query `a` that are chainable with at least one result of query `b`.
+fn filter<T: Sync>(
+    query_results: &mut [Vec<T>],
+    keys: &mut [Vec<JoinKeys>],
+    a: usize,
+    b: usize,
+    chainable: &(impl Fn(&T, &T) -> bool + Sync),
+) {
+    let index = JoinIndex::new(&keys[a], &keys[b]);
+    let (results_a, results_b) = (&query_results[a], &query_results[b]);
+    let keep: Vec<bool> = results_a
+        .par_iter()
+        .zip(keys[a].par_iter())
+        .map(|(r, k)| index.candidates(k).any(|i| chainable(r, &results_b[i])))
+        .collect();
+
+    let mut it = keep.iter();
+    query_results[a].retain(|_| *it.next().unwrap());
+    let mut it = keep.iter();
+    keys[a].retain(|_| *it.next().unwrap());
+}
+
+/// Index of the results of one query by the values of the variables they share
+/// with the results of another query.
+struct JoinIndex<'a> {
+    // variables of a result -> one table per distinct set of variables in the other query
+    tables: FxHashMap<Vec<&'a str>, Vec<JoinTable<'a>>>,
+}
+
+struct JoinTable<'a> {
+    // positions of the shared variables in the keys of the looked up result
+    positions: Vec<usize>,
+    // values of the shared variables -> indices of the indexed results
+    results: FxHashMap<Vec<&'a str>, Vec<usize>>,
+}
+
+impl<'a> JoinIndex<'a> {
+    /// Index `indexed` for lookups with the keys in `lookups`.
+    fn new(lookups: &'a [JoinKeys], indexed: &'a [JoinKeys]) -> JoinIndex<'a> {
+        let variables = |k: &'a JoinKeys| k.iter().map(|(var, _)| var.as_str()).collect();
+
+        let mut groups: FxHashMap<Vec<&str>, Vec<usize>> = FxHashMap::default();
+        for (i, k) in indexed.iter().enumerate() {
+            groups.entry(variables(k)).or_default().push(i);
+        }
+
+        let mut tables = FxHashMap::default();
+        for k in lookups {
+            let lookup: Vec<&str> = variables(k);
+            if tables.contains_key(&lookup) {
+                continue;
+            }
+
+            let group_tables = groups
+                .iter()
+                .map(|(group, members)| {
+                    let shared: Vec<(usize, usize)> = lookup
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(i, var)| Some((i, group.binary_search(var).ok()?)))
+                        .collect();
+
+                    let mut results: FxHashMap<Vec<&str>, Vec<usize>> = FxHashMap::default();
+                    for &m in members {
+                        let values = shared.iter().map(|&(_, j)| indexed[m][j].1.as_str());
+                        results.entry(values.collect()).or_default().push(m);
+                    }
+                    JoinTable {
+                        positions: shared.into_iter().map(|(i, _)| i).collect(),
+                        results,
+                    }
+                })
+                .collect();
+            tables.insert(lookup, group_tables);
+        }
+
+        JoinIndex { tables }
+    }
+
+    /// Returns the indices of all indexed results whose shared variables have the same
+    /// keys as `keys`.
+    fn candidates<'b>(&'b self, keys: &'b JoinKeys) -> impl Iterator<Item = usize> + 'b {
+        let variables: Vec<&str> = keys.iter().map(|(var, _)| var.as_str()).collect();
+        self.tables[&variables].iter().flat_map(move |t| {
+            let values: Vec<&str> = t.positions.iter().map(|&i| keys[i].1.as_str()).collect();
+            t.results.get(&values).into_iter().flatten().copied()
+        })
+    }
+}
@@ -0,0 +1,125 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Deduplication of warnings (--max-warnings).
+//! Every distinct warning is logged once, repeated and suppressed warnings are
+//! counted and reported by `summary` at the end of the run.
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+#[derive(Default)]
+struct Counts {
+    // Number of occurrences of each printed warning, in the order they were printed.
+    printed: Vec<(String, usize)>,
+    index: HashMap<String, usize>,
+    // Distinct warnings that weren't printed because of --max-warnings
+    suppressed: HashSet<String>,
+}
+
+struct DedupLogger {
+    inner: Box<dyn Log>,
+    max_warnings: Option<usize>,
+    counts: Mutex<Counts>,
+}
+
+impl Log for DedupLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() != Level::Warn || !self.enabled(record.metadata()) {
+            self.inner.log(record);
+            return;
+        }
+
+        let message = record.args().to_string();
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(&i) = counts.index.get(&message) {
+            counts.printed[i].1 += 1;
+        } else if self
+            .max_warnings
+            .map_or(false, |max| counts.printed.len() >= max)
+        {
+            counts.suppressed.insert(message);
+        } else {
+            let i = counts.printed.len();
+            counts.printed.push((message.clone(), 1));
+            counts.index.insert(message, i);
+            drop(counts);
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+static LOGGER: Mutex<Option<&'static DedupLogger>> = Mutex::new(None);
+
+/// Install `inner` as the logger, with deduplicated warnings. At most
+/// `max_warnings` distinct warnings are logged.
+pub fn init(inner: Box<dyn Log>, level: LevelFilter, max_warnings: Option<usize>) {
+    let logger: &'static DedupLogger = Box::leak(Box::new(DedupLogger {
+        inner,
+        max_warnings,
+        counts: Mutex::new(Counts::default()),
+    }));
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(level);
+        *LOGGER.lock().unwrap() = Some(logger);
+    }
+}
+
+/// Log how often warnings were repeated or suppressed.
+pub fn summary() {
+    let logger = match *LOGGER.lock().unwrap() {
+        Some(logger) => logger,
+        None => return,
+    };
+
+    let mut lines = Vec::new();
+    {
+        let counts = logger.counts.lock().unwrap();
+        for (message, count) in counts.printed.iter().filter(|(_, c)| *c > 1) {
+            let first_line = message.lines().next().unwrap_or_default();
+            lines.push(format!(
+                "'{}' repeated {} more time(s)",
+                first_line,
+                count - 1
+            ));
+        }
+        if !counts.suppressed.is_empty() {
+            lines.push(format!(
+                "{} more warning(s) not shown because of --max-warnings",
+                counts.suppressed.len()
+            ));
+        }
+    }
+
+    for line in lines {
+        logger.inner.log(
+            &Record::builder()
+                .level(Level::Warn)
+                .target(module_path!())
+                .args(format_args!("{}", line))
+                .build(),
+        );
+    }
+}
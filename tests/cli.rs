@@ -16,8 +16,47 @@ limitations under the License.
 
 use assert_cmd::prelude::*; // Add methods on commands
 use predicates::prelude::*; // Used for writing assertions
+use std::path::{Path, PathBuf};
 use std::process::Command; // Run programs
 
+/// A directory for test files in the system's temp directory. It is removed
+/// when it goes out of scope, also if the test fails.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> TempDir {
+        let dir = std::env::temp_dir().join(format!("weggli-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+}
+
+impl std::ops::Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<std::ffi::OsStr> for TempDir {
+    fn as_ref(&self) -> &std::ffi::OsStr {
+        self.0.as_os_str()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
 #[test]
 fn invalid_pattern() -> Result<(), Box<dyn std::error::Error>> {
     let mut cmd = Command::cargo_bin("weggli")?;
@@ -27,6 +66,50 @@ fn invalid_pattern() -> Result<(), Box<dyn std::error::Error>> {
         .failure()
         .stderr(predicate::str::contains("Query parsing failed"));
 
+    // Errors quote the pattern as it was written, not the rewritten pattern.
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("{count( >= 2 ): foo(;}").arg("directory");
+    cmd.assert()
+        .code(2)
+        .stderr(predicate::str::contains(
+            "{count( >= 2 ): foo( [MISSING ) ] ;}",
+        ))
+        .stderr(predicate::str::contains("count_ge_2").not());
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("{foo(); count(>0): bar(}").arg("directory");
+    cmd.assert()
+        .code(2)
+        .stderr(predicate::str::contains("{foo(); count(>0): bar(}"));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("memcpy($d, ..., foo(;").arg("directory");
+    cmd.assert()
+        .code(2)
+        .stderr(predicate::str::contains("memcpy($d, ..., foo("))
+        .stderr(predicate::str::contains("__ellipsis").not());
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("$x = {.a = _, not: .b = _};").arg("directory");
+    cmd.assert()
+        .code(2)
+        .stderr(predicate::str::contains("{.a = _, not: .b = _}"))
+        .stderr(predicate::str::contains("__not").not());
+
+    // Syntax errors inside a not: designator quote it once.
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("struct s $v = { .x = 1, not: .y };")
+        .arg("directory");
+    cmd.assert().code(2).stderr(predicate::str::contains(
+        "Query parsing failed: struct s $v = { .x = 1, not: .y };\n",
+    ));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("$x = {not: .b, .a = _ ;").arg("directory");
+    cmd.assert().code(2).stderr(predicate::str::contains(
+        "Query parsing failed: $x = {not: .b, .a = _ ;\n",
+    ));
+
     Ok(())
 }
 
@@ -39,7 +122,7 @@ fn flags_empty_results() -> Result<(), Box<dyn std::error::Error>> {
         .arg("foo();")
         .arg("directory");
     cmd.assert()
-        .failure()
+        .code(1)
         .stderr(predicate::str::contains("No files to parse."));
     Ok(())
 }
@@ -71,7 +154,7 @@ fn exclude_include() -> Result<(), Box<dyn std::error::Error>> {
         .arg("foo();")
         .arg("./third_party/examples/");
     cmd.assert()
-        .success()
+        .code(1)
         .stderr(predicate::str::is_empty())
         .stdout(predicate::str::is_empty());
 
@@ -81,7 +164,7 @@ fn exclude_include() -> Result<(), Box<dyn std::error::Error>> {
         .arg("foo();")
         .arg("./third_party/examples/");
     cmd.assert()
-        .success()
+        .code(1)
         .stderr(predicate::str::is_empty())
         .stdout(predicate::str::is_empty());
 
@@ -133,7 +216,60 @@ fn multi_pattern_empty() -> Result<(), Box<dyn std::error::Error>> {
         .arg("-p $func(_,hdr->sender);")
         .arg("-p _ $func($ptr *$src){memcpy (_,$src,_);}")
         .arg("./third_party/examples/cluster.c");
-    cmd.assert().success().stdout(predicate::str::is_empty());
+    cmd.assert().code(1).stdout(predicate::str::is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn prefilter_alternatives() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("prefilter-or");
+    std::fs::write(
+        dir.join("c.c"),
+        "void f() {\n    char *p = calloc(1, 2);\n    use(p);\n}\n",
+    )?;
+
+    // The file doesn't mention malloc, so it must not be skipped before parsing.
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("{$p = malloc(_); or: $p = calloc(_,_); use($p);}")
+        .arg(&dir);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("calloc(1, 2)"));
+
+    // Identifiers outside of the or: group are still required.
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("{$p = malloc(_); or: $p = calloc(_,_); free($p);}")
+        .arg(&dir);
+    cmd.assert().code(1).stdout(predicate::str::is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn prefilter_counts() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("prefilter-count");
+    std::fs::write(
+        dir.join("c.c"),
+        "void f() {\n    char *p = malloc(10);\n    use(p);\n}\n",
+    )?;
+
+    // free is missing from the file, which is what these quantifiers look for.
+    for quantifier in ["==0", "<1", "<=1"] {
+        let mut cmd = Command::cargo_bin("weggli")?;
+        cmd.arg(format!(
+            "{{$p = malloc(_); count({}): free($p);}}",
+            quantifier
+        ))
+        .arg(&dir);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("malloc(10)"));
+    }
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("{$p = malloc(_); count(>=1): free($p);}").arg(&dir);
+    cmd.assert().code(1).stdout(predicate::str::is_empty());
 
     Ok(())
 }
@@ -152,8 +288,222 @@ fn test_unique() -> Result<(), Box<dyn std::error::Error>> {
     cmd.arg("{$a = sdssplitargs(_); strcasecmp($b[0],_); }")
         .arg("./third_party/examples/cluster.c")
         .arg("--unique");
-    cmd.assert().success().stdout(predicate::str::is_empty());
+    cmd.assert().code(1).stdout(predicate::str::is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn normalized_values() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("normalize");
+    std::fs::write(
+        dir.join("a.c"),
+        "void f(struct s *s) {\n    copy(s->buf, s -> buf /* same */);\n}\n\
+         void g(struct s *s) {\n    use(s->buf);\n}\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--unique").arg("copy($a, $b);").arg(&dir);
+    cmd.assert().code(1).stdout(predicate::str::is_empty());
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--unique")
+        .arg("--raw-values")
+        .arg("copy($a, $b);")
+        .arg(&dir);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("a.c:1"));
+
+    // Results are joined on the normalized value of $b.
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("copy(_, $b);").arg("-p").arg("use($b);").arg(&dir);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("a.c:1").and(predicate::str::contains("a.c:4")));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--raw-values")
+        .arg("copy(_, $b);")
+        .arg("-p")
+        .arg("use($b);")
+        .arg(&dir);
+    cmd.assert().code(1).stdout(predicate::str::is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn list_variables() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--list-variables")
+        .arg("$f(_, _, $n);")
+        .arg("./third_party/examples/malloc.c");
+    cmd.assert().success().stdout(
+        predicate::str::contains("$f: fprintf (3), __madvise (1)")
+            .and(predicate::str::contains("$n: i (3), MADV_DONTNEED (1)")),
+    );
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--list-variables")
+        .arg("--format")
+        .arg("json")
+        .arg("$f($x, _, sizeof($t));")
+        .arg("./third_party/examples/cluster.c");
+    let output = cmd.output()?;
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let report: serde_json::Value = serde_json::from_str(stdout.lines().last().unwrap())?;
+    assert_eq!(report["variables"]["$f"]["memset"], 6);
+    assert_eq!(report["variables"]["$f"]["memcpy"], 5);
+    Ok(())
+}
+
+#[test]
+fn aggregate() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--aggregate")
+        .arg("func")
+        .arg("$func($x, _, sizeof($t));")
+        .arg("./third_party/examples/cluster.c");
+    let output = cmd.output()?;
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let lines: Vec<_> = stdout.lines().map(|l| l.trim()).collect();
+    assert_eq!(
+        lines,
+        [
+            "6 memset",
+            "5 memcpy",
+            "2 memcmp",
+            "2 syncReadLine",
+            "1 anetSockName"
+        ]
+    );
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--aggregate")
+        .arg("$func")
+        .arg("--format")
+        .arg("sarif")
+        .arg("$func(_);")
+        .arg("./third_party/examples/cluster.c");
+    cmd.assert().failure();
+    Ok(())
+}
+
+#[test]
+fn group_by() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--group-by")
+        .arg("func")
+        .arg("--format=oneline")
+        .arg("$func($x, _, sizeof($t));")
+        .arg("./third_party/examples/cluster.c");
+    let output = cmd.output()?;
+    assert!(output.status.success());
+
+    // Largest groups first, results in a group ordered by line
+    let stdout = String::from_utf8(output.stdout)?;
+    let calls: Vec<(&str, usize)> = stdout
+        .lines()
+        .map(|l| {
+            let mut parts = l.split(':');
+            let line = parts.nth(1).unwrap().parse().unwrap();
+            let code = parts.nth(1).unwrap().trim();
+            (code.split('(').next().unwrap(), line)
+        })
+        .collect();
+    let funcs: Vec<&str> = calls.iter().map(|c| c.0).collect();
+    assert_eq!(funcs[..6], ["memset"; 6]);
+    assert_eq!(funcs[6..11], ["memcpy"; 5]);
+    assert!(calls[..6].windows(2).all(|w| w[0].1 <= w[1].1));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--group-by=func")
+        .arg("$func($x, _, sizeof($t));")
+        .arg("./third_party/examples/cluster.c");
+    cmd.assert().success().stdout(
+        predicate::str::starts_with("$func = memset (6 result(s))\n").and(
+            predicate::str::contains("$func = anetSockName (1 result(s))"),
+        ),
+    );
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--group-by=func")
+        .arg("--format=json")
+        .arg("$func($x, _, sizeof($t));")
+        .arg("./third_party/examples/cluster.c");
+    cmd.assert().success().stdout(
+        predicate::str::starts_with("{").and(predicate::str::contains(r#""group":"memset""#)),
+    );
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--group-by=func")
+        .arg("--format=sarif")
+        .arg("$func(_);")
+        .arg("./third_party/examples/cluster.c");
+    cmd.assert().code(2);
+    Ok(())
+}
+
+#[test]
+fn format_targets() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--format")
+        .arg("targets")
+        .arg("--anchor")
+        .arg("file")
+        .arg("memcpy(_, _, _);")
+        .arg("./third_party/examples/invalid-utf8.c");
+    let output = cmd.output()?;
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(lines.len(), 1);
+    let target: serde_json::Value = serde_json::from_str(lines[0])?;
+    assert_eq!(target["function"], "MyBuggyFunction");
+    assert_eq!(target["start"], 8);
+    assert_eq!(target["end"], 87);
+    assert_eq!(target["line"], 3);
+    assert_eq!(target["end_line"], 7);
+    assert_eq!(target["query"], 0);
+    Ok(())
+}
+
+#[test]
+fn max_filesize() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--max-filesize")
+        .arg("10K")
+        .arg("memcpy(_, _, _);")
+        .arg("./third_party/examples/");
+    // Warnings are logged to stderr.
+    cmd.assert()
+        .success()
+        .stdout(
+            predicate::str::contains("invalid-utf8.c:3")
+                .and(predicate::str::is_match(r"cluster\.c:\d")?.not()),
+        )
+        .stderr(predicate::str::contains("cluster.c: skipping file"));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--force-large")
+        .arg("memcpy(_, _, _);")
+        .arg("./third_party/examples/cluster.c");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("cluster.c"));
 
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--max-filesize")
+        .arg("10MB")
+        .arg("memcpy(_, _, _);")
+        .arg("./third_party/examples/");
+    cmd.assert().failure();
     Ok(())
 }
 
@@ -186,7 +536,7 @@ fn regex_constraint() -> Result<(), Box<dyn std::error::Error>> {
     cmd.arg("char $buf[10];")
         .arg("./third_party/examples/invalid-utf8.c")
         .arg("-Rbuf=foo");
-    cmd.assert().success().stdout(predicate::str::is_empty());
+    cmd.assert().code(1).stdout(predicate::str::is_empty());
 
     let mut cmd = Command::cargo_bin("weggli")?;
 
@@ -220,7 +570,7 @@ fn regex_constraint() -> Result<(), Box<dyn std::error::Error>> {
     cmd.arg("{char buf[10]; not: memcpy($buf, _, _);}")
         .arg("./third_party/examples/invalid-utf8.c")
         .arg("-Rbuf=buf");
-    cmd.assert().success().stdout(predicate::str::is_empty());
+    cmd.assert().code(1).stdout(predicate::str::is_empty());
 
     let mut cmd = Command::cargo_bin("weggli")?;
 
@@ -233,3 +583,2074 @@ fn regex_constraint() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[test]
+fn auto_lang() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("--auto-lang")
+        .arg("memcpy(buf,_,_);")
+        .arg("./third_party/examples/");
+    cmd.assert().success().stdout(
+        predicate::str::contains("int Packet::parse(const char *src, size_t size) {").and(
+            predicate::str::contains("void MyBuggyFunction( void* data )"),
+        ),
+    );
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("--auto-lang")
+        .arg("--cpp")
+        .arg("memcpy(buf,_,_);")
+        .arg("./third_party/examples/");
+    cmd.assert().failure();
+
+    Ok(())
+}
+
+#[test]
+fn invert_match() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("--invert-match")
+        .arg("memcpy(_);")
+        .args(["-p", "strcasecmp(_);"])
+        .arg("./third_party/examples/cluster.c");
+    cmd.assert().success().stdout(
+        predicate::str::contains("cluster.c:4056 clusterCommand")
+            .and(predicate::str::contains("clusterLoadConfig").not()),
+    );
+
+    Ok(())
+}
+
+#[test]
+fn pattern_context() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("-A0")
+        .arg("-B0")
+        .arg("memcpy(_);")
+        .arg("./third_party/examples/invalid-utf8.c");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("char buf[10]").not());
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("-A0")
+        .arg("-B0")
+        .arg("--whole-function")
+        .arg("memcpy(_);")
+        .arg("./third_party/examples/invalid-utf8.c");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("char buf[10]"));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("-A0")
+        .arg("-B0")
+        .arg("--pattern-context=0=1,0")
+        .arg("memcpy(_);")
+        .arg("./third_party/examples/invalid-utf8.c");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("char buf[10]"));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("--pattern-context=0=a,b")
+        .arg("memcpy(_);")
+        .arg("./third_party/examples/invalid-utf8.c");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("is not of the form"));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("--pattern-context=5=1,1")
+        .arg("memcpy(_);")
+        .arg("./third_party/examples/invalid-utf8.c");
+    cmd.assert()
+        .code(2)
+        .stderr(predicate::str::contains("there is no pattern with index 5"));
+
+    // Rules can set their own context
+    let dir = TempDir::new("rule-context");
+    std::fs::write(
+        dir.join("memcpy.yml"),
+        "id: memcpy\npattern: 'memcpy(_);'\nbefore: 0\nafter: 0\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--rules")
+        .arg(dir.join("memcpy.yml"))
+        .arg("./third_party/examples/invalid-utf8.c");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("char buf[10]").not());
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--rules")
+        .arg(dir.join("memcpy.yml"))
+        .arg("--pattern-context=0=1,0")
+        .arg("./third_party/examples/invalid-utf8.c");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("char buf[10]"));
+
+    std::fs::write(
+        dir.join("memcpy.yml"),
+        "id: memcpy\npattern: 'memcpy(_);'\nwhole_function: true\n",
+    )?;
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("-A0")
+        .arg("-B0")
+        .arg("--rules")
+        .arg(dir.join("memcpy.yml"))
+        .arg("./third_party/examples/invalid-utf8.c");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("char buf[10]"));
+
+    Ok(())
+}
+
+#[test]
+fn unique_by() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("$func(_,_,_);")
+        .arg("./third_party/examples/cluster.c");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("cluster.c:").count(247));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("--unique-by=func")
+        .arg("$func(_,_,_);")
+        .arg("./third_party/examples/cluster.c");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("cluster.c:").count(54));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("--unique-by=$foo")
+        .arg("$func(_,_,_);")
+        .arg("./third_party/examples/cluster.c");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("is not a valid query variable"));
+
+    Ok(())
+}
+
+#[test]
+fn normalization() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("-v")
+        .arg("memcpy(_, _, _)")
+        .arg("./third_party/examples/invalid-utf8.c");
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "query normalized to '{memcpy(_, _, _);}' (added missing ';', wrapped in {})",
+        ))
+        .stdout(predicate::str::contains("memcpy("));
+
+    // Without -v, the query is normalized without a message
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("memcpy(_, _, _)")
+        .arg("./third_party/examples/invalid-utf8.c");
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::is_empty())
+        .stdout(predicate::str::contains("normalized").not());
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--no-normalize")
+        .arg("memcpy(_, _, _);")
+        .arg("./third_party/examples/invalid-utf8.c");
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "Did you mean '{memcpy(_, _, _);}'?",
+    ));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--log-format=json")
+        .arg("memcpy(_, _, _);")
+        .arg("./third_party/examples/invalid-utf8.c");
+    cmd.assert().success().stderr(predicate::str::contains(
+        r#""event":"normalized","normalizations":["wrapped in {}"],"pattern":"memcpy(_, _, _);","query":"{memcpy(_, _, _);}""#,
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn log_format_json() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("--log-format=json")
+        .arg("memcpy(_);")
+        .arg("./third_party/examples/invalid-utf8.c");
+    cmd.assert().success().stderr(
+        predicate::str::contains(r#""event":"started","files":1"#)
+            .and(predicate::str::contains(r#""count":1,"event":"matches""#))
+            .and(predicate::str::contains(r#""event":"finished""#)),
+    );
+
+    Ok(())
+}
+
+#[test]
+fn fallback_cpp() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.args(["--extensions", "cc", "--"])
+        .arg("_ $x::parse(_) {memcpy(_);}")
+        .arg("./third_party/examples/");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("This query is valid in C++ mode"));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("--fallback-cpp")
+        .args(["--extensions", "cc", "--"])
+        .arg("_ $x::parse(_) {memcpy(_);}")
+        .arg("./third_party/examples/");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("int Packet::parse("));
+
+    Ok(())
+}
+
+#[test]
+fn function_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("--max-function-lines=20")
+        .arg("memcpy(_,_,_);")
+        .arg("./third_party/examples/cluster.c");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("cluster.c:").count(7));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("--min-complexity=30")
+        .arg("memcpy(_,_,_);")
+        .arg("./third_party/examples/cluster.c");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("cluster.c:89"))
+        .stdout(predicate::str::contains("cluster.c:").count(2));
+
+    Ok(())
+}
+
+#[test]
+fn sample() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("--sample=3")
+        .arg("memcpy(_,_,_);")
+        .arg("./third_party/examples/cluster.c");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("cluster.c:").count(3));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("--sample=100")
+        .arg("memcpy(_,_,_);")
+        .arg("./third_party/examples/cluster.c");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("cluster.c:").count(19));
+
+    Ok(())
+}
+
+#[test]
+fn dedup_snippets() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    // Search the same file twice to get identical snippets.
+    cmd.arg("--dedup-snippets").arg("memcpy(_,_,_);").arg("-");
+    assert_cmd::Command::from_std(cmd)
+        .write_stdin("./third_party/examples/cluster.c\n./third_party/examples/cluster.c\n")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("cluster.c:").count(38))
+        .stdout(predicate::str::contains("identical code in 1 more location(s):").count(19));
+
+    Ok(())
+}
+
+#[test]
+fn shard_and_merge_results() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("shard");
+
+    let shards: Vec<_> = (1..=3)
+        .map(|k| dir.join(format!("shard{}.json", k)))
+        .collect();
+
+    for (k, file) in shards.iter().enumerate() {
+        let mut cmd = Command::cargo_bin("weggli")?;
+
+        cmd.arg(format!("--shard={}/3", k + 1))
+            .arg("--save-results")
+            .arg(file)
+            .arg("$x = malloc(_);")
+            .arg("-p")
+            .arg("memcpy($x,_,_);")
+            .arg("./third_party/examples/");
+        cmd.assert()
+            .code(predicate::in_iter([0, 1]))
+            .stdout(predicate::str::is_empty());
+    }
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("merge-results").args(&shards);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("examples/").count(4));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("--shard=4/3")
+        .arg("foo;")
+        .arg("./third_party/examples/");
+    cmd.assert().failure();
+
+    // One of two shards of a single file is empty. It still saves its (empty)
+    // results and exits like a search without results.
+    std::fs::create_dir_all(dir.join("src"))?;
+    std::fs::write(dir.join("src/a.c"), "void f() {\n    foo(1);\n}\n")?;
+    let mut codes = Vec::new();
+    for k in 1..=2 {
+        let file = dir.join(format!("single{}.json", k));
+        let mut cmd = Command::cargo_bin("weggli")?;
+        cmd.arg(format!("--shard={}/2", k))
+            .arg("--save-results")
+            .arg(&file)
+            .arg("foo(_);")
+            .arg(dir.join("src"));
+        codes.push(cmd.output()?.status.code());
+        assert!(file.exists());
+    }
+    codes.sort();
+    assert_eq!(codes, vec![Some(0), Some(1)]);
+
+    Ok(())
+}
+
+#[test]
+fn join_modes() -> Result<(), Box<dyn std::error::Error>> {
+    let count = |join: Option<&str>, other: &str| -> Result<_, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("weggli")?;
+        if let Some(j) = join {
+            cmd.arg(format!("--join={}", j));
+        }
+        cmd.arg("{$x = malloc(_); memcpy($x,_,_);}")
+            .arg("-p")
+            .arg(other)
+            .arg("./third_party/examples/");
+        let output = cmd.output()?;
+        assert!(matches!(output.status.code(), Some(0) | Some(1)));
+        Ok(String::from_utf8(output.stdout)?
+            .matches("examples/")
+            .count())
+    };
+
+    assert_eq!(count(None, "{free($x);}")?, 0);
+    assert_eq!(count(Some("x=required"), "{free($x);}")?, 0);
+    assert_eq!(count(Some("x=ignore"), "{free($x);}")?, 4);
+    assert_eq!(count(Some("x=differ"), "{free($x);}")?, 4);
+
+    // $x is not bound by the second pattern: its results are compatible with all
+    // results of the first one, unless $x is required.
+    assert_eq!(count(None, "{free($y);}")?, 4);
+    assert_eq!(count(Some("x=required"), "{free($y);}")?, 0);
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("--join=x=bogus")
+        .arg("{free($x);}")
+        .arg("./third_party/examples/");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("is not a valid join mode"));
+
+    Ok(())
+}
+
+#[test]
+fn json_output() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("--json")
+        .arg("$x = malloc(_);")
+        .arg("./third_party/examples/malloc.c");
+    let output = cmd.output()?;
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let results: Vec<serde_json::Value> = stdout
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    assert_eq!(results.len(), 3);
+
+    let r = &results[0];
+    assert!(r["path"].as_str().unwrap().ends_with("malloc.c"));
+    assert_eq!(r["query"], 0);
+    assert_eq!(r["line"], 369);
+    assert_eq!(r["column"], 1);
+    assert_eq!(r["snippet"], "\tvoid *p = malloc(n);");
+    assert_eq!(r["vars"]["$x"], "p");
+    assert_eq!(r["captures"].as_array().unwrap().len(), 3);
+    assert!(r.get("metrics").is_none());
+
+    // Warnings are logged to stderr, so every line of stdout is still a result.
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--json")
+        .arg("--max-filesize=10K")
+        .arg("memcpy(_, _, _);")
+        .arg("./third_party/examples/");
+    let output = cmd.output()?;
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stderr)?.contains("cluster.c: skipping file"));
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let results: Vec<serde_json::Value> = stdout
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    assert!(!results.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("--json")
+        .arg("--metrics")
+        .arg("$x = malloc(_);")
+        .arg("./third_party/examples/malloc.c");
+    let output = cmd.output()?;
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let r: serde_json::Value = serde_json::from_str(stdout.lines().next().unwrap())?;
+    assert_eq!(
+        r["metrics"],
+        serde_json::json!({ "lines": 10, "callees": 3, "conditions": 3, "complexity": 5 })
+    );
+
+    Ok(())
+}
+
+#[test]
+fn explain_results() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("--json")
+        .arg("--explain-results")
+        .arg("{char $buf[_]; memcpy($buf, _, _);}")
+        .arg("./third_party/examples/invalid-utf8.c");
+    let output = cmd.output()?;
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let r: serde_json::Value = serde_json::from_str(stdout.lines().next().unwrap())?;
+    let explanation: Vec<_> = r["explanation"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|a| {
+            let clause = a["clause"].as_str().unwrap();
+            (a["line"].as_u64().unwrap(), a["variable"].as_str(), clause)
+        })
+        .collect();
+    assert!(explanation.contains(&(5, Some("$buf"), "char $buf[_];")));
+    assert!(explanation.contains(&(6, None, "memcpy($buf, _, _);")));
+    assert!(explanation.contains(&(6, Some("$buf"), "memcpy($buf, _, _);")));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--explain-results")
+        .arg("memcpy($a, _, _);")
+        .arg("./third_party/examples/invalid-utf8.c");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("explanation:"))
+        .stdout(predicate::str::is_match(
+            r"6: \$a = buf +from memcpy\(\$a, _, _\);",
+        )?);
+
+    Ok(())
+}
+
+#[test]
+fn sarif_output() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("--format=sarif")
+        .arg("$x = malloc(_);")
+        .arg("./third_party/examples/malloc.c");
+    let output = cmd.output()?;
+    assert!(output.status.success());
+
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(report["version"], "2.1.0");
+
+    let run = &report["runs"][0];
+    let rules = run["tool"]["driver"]["rules"].as_array().unwrap();
+    assert_eq!(rules.len(), 1);
+    assert_eq!(rules[0]["id"], "weggli/pattern-0");
+
+    let results = run["results"].as_array().unwrap();
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0]["ruleId"], "weggli/pattern-0");
+
+    let location = &results[0]["locations"][0]["physicalLocation"];
+    assert_eq!(
+        location["artifactLocation"]["uri"],
+        "third_party/examples/malloc.c"
+    );
+    assert_eq!(location["region"]["startLine"], 371);
+    assert_eq!(
+        location["region"]["snippet"]["text"],
+        "\tvoid *p = malloc(n);"
+    );
+
+    // Warnings don't end up in the report.
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--format=sarif")
+        .arg("--max-filesize=10K")
+        .arg("memcpy(_, _, _);")
+        .arg("./third_party/examples/");
+    let output = cmd.output()?;
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stderr)?.contains("cluster.c: skipping file"));
+
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    assert!(!report["runs"][0]["results"].as_array().unwrap().is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn annotation_formats() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--format=github")
+        .arg("$x = malloc(_);")
+        .arg("./third_party/examples/malloc.c");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::starts_with(
+            "::warning file=third_party/examples/malloc.c,line=371,endLine=371,\
+             title=weggli/pattern-0::Match for pattern 0\n",
+        ))
+        .stdout(predicate::str::contains("::warning").count(3));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--format=gitlab-codequality")
+        .arg("$x = malloc(_);")
+        .arg("./third_party/examples/malloc.c");
+    let output = cmd.output()?;
+    assert!(output.status.success());
+
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    let issues = report.as_array().unwrap();
+    assert_eq!(issues.len(), 3);
+    assert_eq!(issues[0]["check_name"], "weggli/pattern-0");
+    assert_eq!(issues[0]["severity"], "minor");
+    assert_eq!(
+        issues[0]["location"]["path"],
+        "third_party/examples/malloc.c"
+    );
+    assert_eq!(issues[0]["location"]["lines"]["begin"], 371);
+    assert_ne!(issues[0]["fingerprint"], issues[1]["fingerprint"]);
+
+    // An empty report is still valid
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--format=gitlab-codequality")
+        .arg("nothing_matches_this(_);")
+        .arg("./third_party/examples/malloc.c");
+    cmd.assert().code(1).stdout("[]\n");
+
+    Ok(())
+}
+
+#[test]
+fn rule_files() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+
+    cmd.arg("--rules=./tests/rules")
+        .arg("--json")
+        .arg("./third_party/examples/");
+    let output = cmd.output()?;
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let results: Vec<serde_json::Value> = stdout
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+
+    let count = |id: &str| results.iter().filter(|r| r["rule"] == id).count();
+    assert_eq!(count("malloc-assign"), 3);
+    assert_eq!(count("memcpy-size"), 10);
+    assert!(
+        results
+            .iter()
+            .filter(|r| r["rule"] == "memcpy-size")
+            .all(|r| r["severity"] == "error"
+                && r["vars"]["$size"].as_str().unwrap().ends_with("LEN"))
+    );
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--rules=./tests/rules/malloc-assign.yml")
+        .arg("./third_party/examples/malloc.c");
+    cmd.assert().success().stdout(predicate::str::contains(
+        "note[malloc-assign]: Assignment of malloc result.",
+    ));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--rules=./tests/rules")
+        .arg("-p")
+        .arg("foo();")
+        .arg("./third_party/examples/");
+    cmd.assert().failure();
+
+    Ok(())
+}
+
+#[test]
+fn skip_broken_rules() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("rules");
+    std::fs::copy(
+        "./tests/rules/malloc-assign.yml",
+        dir.join("malloc-assign.yml"),
+    )?;
+    std::fs::write(
+        dir.join("invalid-pattern.yml"),
+        "id: invalid-pattern\npattern: '{foo('\n",
+    )?;
+    std::fs::write(
+        dir.join("invalid-variable.yml"),
+        "id: invalid-variable\npattern: 'memcpy($a, _, _);'\nregex: ['$b=foo']\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--rules").arg(&dir).arg("./third_party/examples/");
+    cmd.assert().failure();
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--rules")
+        .arg(&dir)
+        .arg("--skip-broken-rules")
+        .arg("./third_party/examples/");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("note[malloc-assign]").count(3))
+        .stderr(
+            predicate::str::contains("Skipped 2 broken rule(s):")
+                .and(predicate::str::contains("invalid-pattern"))
+                .and(predicate::str::contains(
+                    "invalid-variable: '$b' is not a valid",
+                )),
+        );
+
+    Ok(())
+}
+
+#[test]
+fn import_semgrep() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("semgrep");
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("import-semgrep")
+        .arg("./tests/semgrep/rules.yaml")
+        .arg("-o")
+        .arg(dir.join("rules"));
+    cmd.assert().success().stderr(
+        predicate::str::contains("Converted 4 of 7 rules")
+            .and(predicate::str::contains(
+                "any-alloc: 'pattern-either' is not supported",
+            ))
+            .and(predicate::str::contains("python-eval: not a C or C++ rule"))
+            .and(predicate::str::contains(
+                "printf-args: ellipsis metavariables",
+            )),
+    );
+
+    let rule = std::fs::read_to_string(dir.join("rules/use-after-free.yml"))?;
+    assert!(rule.contains("pattern: \"{free($P); not: $P = NULL; $P[_] = _;}\""));
+    assert!(rule.contains("severity: error"));
+    // Identifiers ending in _ are kept, called metavariables aren't replaced
+    let rule = std::fs::read_to_string(dir.join("rules/custom-free.yml"))?;
+    assert!(rule.contains("pattern: free_(_);"));
+    let rule = std::fs::read_to_string(dir.join("rules/callback.yml"))?;
+    assert!(rule.contains("pattern: $CB(_);"));
+
+    std::fs::write(
+        dir.join("a.c"),
+        "void f(char *p) { free(p); p[0] = 1; }\n\
+         void g(char *p) { free(p); p = NULL; p[0] = 1; }\n\
+         void h(char *d, char *s, int len) { memcpy(d, s + 1, len); memcpy(d, s, 10); }\n",
+    )?;
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--rules")
+        .arg(dir.join("rules"))
+        .arg(dir.join("a.c"));
+    cmd.assert().success().stdout(
+        predicate::str::contains("error[use-after-free]")
+            .count(1)
+            .and(predicate::str::contains("a.c:1"))
+            .and(predicate::str::contains("warning[memcpy-length]").count(1)),
+    );
+
+    Ok(())
+}
+
+#[test]
+fn string_length() -> Result<(), Box<dyn std::error::Error>> {
+    let count = |constraint: &str| -> Result<usize, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("weggli")?;
+        cmd.arg("--json")
+            .arg("--string-length")
+            .arg(constraint)
+            .arg("serverLog(_, $fmt);")
+            .arg("./third_party/examples/cluster.c");
+        let output = cmd.output()?;
+        assert!(output.status.success());
+        Ok(String::from_utf8(output.stdout)?.lines().count())
+    };
+
+    assert_eq!(count("fmt>80")?, 6);
+    assert_eq!(count("fmt<=80")?, 65);
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--string-length=fmt>")
+        .arg("serverLog(_, $fmt);")
+        .arg("./third_party/examples/cluster.c");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("not a valid constraint"));
+
+    Ok(())
+}
+
+#[test]
+fn variable_constraint() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("constraint");
+    std::fs::write(
+        dir.join("a.c"),
+        "void f(char *a, char *b, int n) {\n    memcpy(a, b, n);\n    memcpy(a, a, n);\n    \
+         memcpy(n, b, n);\n}\n",
+    )?;
+
+    let count = |args: &[&str]| -> Result<usize, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("weggli")?;
+        cmd.arg("--json")
+            .args(args)
+            .arg("memcpy($dst, $src, $len);")
+            .arg(&dir);
+        let output = cmd.output()?;
+        assert!(output.status.success());
+        Ok(String::from_utf8(output.stdout)?.lines().count())
+    };
+
+    assert_eq!(count(&["--constraint", "$dst!=$src"])?, 2);
+    assert_eq!(count(&["--constraint", "$dst==$src"])?, 1);
+    assert_eq!(
+        count(&["--constraint", "$dst!=$src", "--constraint", "$dst!=$len"])?,
+        1
+    );
+    assert_eq!(count(&["--unique"])?, 1);
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--constraint=$dst!=$x")
+        .arg("memcpy($dst, $src, $len);")
+        .arg(&dir);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("not a valid query variable"));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--constraint=$dst<$src")
+        .arg("memcpy($dst, $src, $len);")
+        .arg(&dir);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("not a valid constraint"));
+
+    Ok(())
+}
+
+#[test]
+fn rewrite() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("rewrite");
+    let file = dir.join("a.c");
+    let source = "void f(char *d, char *s, int n) {\n    memcpy(d, s, n);\n    \
+                  memcpy(s,\n           d, n);\n}\n";
+    std::fs::write(&file, source)?;
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--rewrite")
+        .arg("memcpy_s($dst, $n, $src, $n)")
+        .arg("memcpy($dst, $src, $n);")
+        .arg(&dir);
+    cmd.assert().success().stdout(predicate::str::contains(
+        "@@ -1,5 +1,4 @@\n void f(char *d, char *s, int n) {\n\
+         -    memcpy(d, s, n);\n+    memcpy_s(d, n, s, n);\n\
+         -    memcpy(s,\n-           d, n);\n+    memcpy_s(s, n, d, n);\n }\n",
+    ));
+    assert_eq!(std::fs::read_to_string(&file)?, source);
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--rewrite")
+        .arg("memcpy_s($dst, $n, $src, $n)")
+        .arg("--in-place")
+        .arg("memcpy($dst, $src, $n);")
+        .arg(&dir);
+    cmd.assert().success().stdout(predicate::str::is_empty());
+    assert_eq!(
+        std::fs::read_to_string(&file)?,
+        "void f(char *d, char *s, int n) {\n    memcpy_s(d, n, s, n);\n    \
+         memcpy_s(s, n, d, n);\n}\n"
+    );
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--rewrite=x").arg("{foo(); bar();}").arg(&dir);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("single statement or expression"));
+
+    Ok(())
+}
+
+#[test]
+fn repl() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("repl").arg("./third_party/examples/");
+    assert_cmd::Command::from_std(cmd)
+        .write_stdin("{\n  int $x[_];\n  memcpy($x, _, _);\n}\n:files\n{foo();)\n")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("0 matches in 0 files"))
+        .stdout(predicate::str::contains("4 files loaded"))
+        .stderr(predicate::str::contains("unmatched ')'"));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("repl").arg("./third_party/examples/");
+    assert_cmd::Command::from_std(cmd)
+        .write_stdin("memcpy(_);\n:quit\nfoo();\n")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("22 matches in 3 files"))
+        .stdout(predicate::str::contains("foo").not());
+
+    Ok(())
+}
+
+#[test]
+fn try_pattern() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("try")
+        .arg("memcpy($dst, _, $len);")
+        .arg("--against")
+        .arg("int f() { memcpy(a, b, c); }");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("int f() { memcpy(a, b, c); }"))
+        .stdout(predicate::str::is_match(r"1:18 +\$dst +identifier +a")?)
+        .stdout(predicate::str::is_match(r"1:24 +\$len +identifier +c")?)
+        .stdout(predicate::str::contains("1 match"));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("try")
+        .arg("free($p);")
+        .arg("--against")
+        .arg("int f() { g(a); }");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("no matches"));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("try")
+        .arg("{foo")
+        .arg("--against")
+        .arg("int f() {}");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("Query parsing failed"));
+
+    Ok(())
+}
+
+#[test]
+fn index() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("index");
+    std::fs::write(
+        dir.join("a.c"),
+        "void a(char *d) {\n    memcpy(d, s, 1);\n}\n",
+    )?;
+    std::fs::write(
+        dir.join("b.c"),
+        "void b(char *d) {\n    memcpy_s(d, 1, s, 1);\n}\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("index").arg(&dir);
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains("Indexed 2 files (0 unchanged)"));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--use-index")
+        .arg("--log-format=json")
+        .arg("memcpy(_);")
+        .arg(&dir);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("a.c:1"))
+        .stderr(predicate::str::contains(
+            r#"b.c","reason":"no potential match (index)""#,
+        ));
+
+    // Modified files are searched even if the index says they can't match.
+    std::fs::write(
+        dir.join("b.c"),
+        "void b(char *d) {\n    memcpy(d, s, 20);\n}\n",
+    )?;
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--use-index").arg("memcpy(_);").arg(&dir);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("a.c:1").and(predicate::str::contains("b.c:1")));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("index").arg(&dir);
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains("Indexed 2 files (1 unchanged)"));
+
+    Ok(())
+}
+
+#[test]
+fn color() -> Result<(), Box<dyn std::error::Error>> {
+    let escape = predicate::str::contains("\u{1b}[");
+
+    // Output is not a terminal, so colors are disabled by default.
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("malloc(_);").arg("./third_party/examples/malloc.c");
+    cmd.assert().success().stdout(escape.clone().not());
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("-C")
+        .arg("malloc(_);")
+        .arg("./third_party/examples/malloc.c");
+    cmd.assert().success().stdout(escape.clone());
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.env("CLICOLOR_FORCE", "1")
+        .arg("malloc(_);")
+        .arg("./third_party/examples/malloc.c");
+    cmd.assert().success().stdout(escape.clone());
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.env("CLICOLOR_FORCE", "1")
+        .arg("--color=never")
+        .arg("malloc(_);")
+        .arg("./third_party/examples/malloc.c");
+    cmd.assert().success().stdout(escape.not());
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--color=sometimes")
+        .arg("malloc(_);")
+        .arg("./third_party/examples/malloc.c");
+    cmd.assert().failure();
+    Ok(())
+}
+
+#[test]
+fn output_file() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("output");
+    let source: String = (0..1200)
+        .map(|i| {
+            format!(
+                "void f{}(char *a, char *b) {{ memcpy(a, b, n{}); }}\n",
+                i, i
+            )
+        })
+        .collect();
+    std::fs::write(dir.join("a.c"), source)?;
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--output")
+        .arg(dir.join("results.json.zst"))
+        .arg("memcpy(_, _, $n);")
+        .arg(dir.join("a.c"));
+    cmd.assert().success().stdout(predicate::str::is_empty());
+
+    // The output consists of multiple zstd frames.
+    let compressed = std::fs::read(dir.join("results.json.zst"))?;
+    let results = String::from_utf8(zstd::decode_all(compressed.as_slice())?)?;
+    assert_eq!(results.lines().count(), 1200);
+    for line in results.lines() {
+        let value: serde_json::Value = serde_json::from_str(line)?;
+        assert!(value["vars"]["$n"].is_string());
+    }
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--format=text")
+        .arg("-o")
+        .arg(dir.join("results.txt"))
+        .arg("memcpy(_, _, n1199);")
+        .arg(dir.join("a.c"));
+    cmd.assert().success().stdout(predicate::str::is_empty());
+    let results = std::fs::read_to_string(dir.join("results.txt"))?;
+    assert!(results.contains("void f1199(char *a, char *b) { memcpy(a, b, n1199); }"));
+    assert!(!results.contains('\u{1b}'));
+
+    Ok(())
+}
+
+#[test]
+fn same_function() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("same-function");
+    std::fs::write(
+        dir.join("a.c"),
+        "void f(struct s *p) {\n    free(p);\n    p->x = 1;\n}\nvoid g(struct s *q) {\n    \
+         free(q);\n}\nvoid h(struct s *q) {\n    q->y = 2;\n}\n",
+    )?;
+
+    let functions = |args: &[&str]| -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("weggli")?;
+        cmd.arg("--format=targets")
+            .args(args)
+            .arg("{free($p);}")
+            .arg("-p")
+            .arg("{$p->_;}")
+            .arg(&dir);
+        let output = cmd.output()?;
+        assert!(output.status.success());
+        let mut functions = String::from_utf8(output.stdout)?
+            .lines()
+            .map(|l| {
+                let v: serde_json::Value = serde_json::from_str(l).unwrap();
+                v["function"].as_str().unwrap().to_string()
+            })
+            .collect::<Vec<_>>();
+        functions.sort();
+        Ok(functions)
+    };
+
+    assert_eq!(functions(&[])?, ["f", "f", "g", "h"]);
+    assert_eq!(functions(&["--same-function"])?, ["f", "f"]);
+
+    Ok(())
+}
+
+#[test]
+fn stream_join() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("stream-join");
+    std::fs::write(
+        dir.join("a.c"),
+        "void f(struct s *p) {\n    free(p);\n    p->x = 1;\n}\nvoid g(struct s *q) {\n    \
+         free(q);\n}\nvoid h(struct s *q) {\n    q->y = 2;\n}\nvoid k(struct s *r) {\n    \
+         free(r);\n}\n",
+    )?;
+    std::fs::write(dir.join("b.c"), "void l(struct s *r) {\n    free(r);\n}\n")?;
+
+    let results = |args: &[&str]| -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("weggli")?;
+        cmd.arg("--format=targets")
+            .args(args)
+            .arg("{free($p);}")
+            .arg("-p")
+            .arg("{$p->_;}")
+            .arg(&dir);
+        let output = cmd.output()?;
+        assert!(output.status.success());
+        let mut functions = String::from_utf8(output.stdout)?
+            .lines()
+            .map(|l| {
+                let v: serde_json::Value = serde_json::from_str(l).unwrap();
+                v["function"].as_str().unwrap().to_string()
+            })
+            .collect::<Vec<_>>();
+        functions.sort();
+        Ok(functions)
+    };
+
+    assert_eq!(results(&[])?, ["f", "f", "g", "h"]);
+    assert_eq!(results(&["--stream-join"])?, ["f", "f", "g", "h"]);
+    assert_eq!(results(&["--stream-join", "--same-function"])?, ["f", "f"]);
+    assert_eq!(results(&["--stream-join", "--unique-by", "$p"])?.len(), 2);
+    // Without waiting results, only results of the second pattern can be reported.
+    assert_eq!(
+        results(&["--stream-join", "--stream-join-limit=0"])?,
+        ["f", "h"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn ineffective_regex() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("-R")
+        .arg("$p=buf")
+        .arg("{memcpy(_, _, _); not: free($p);}")
+        .arg("./third_party/examples/invalid-utf8.c");
+    cmd.assert().stderr(predicate::str::contains(
+        "[WARN] '$p' only appears in negative sub queries",
+    ));
+
+    // The warning is logged, so it is part of machine-readable output.
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--log-format=json")
+        .arg("-R")
+        .arg("$p=buf")
+        .arg("{memcpy(_, _, _); not: free($p);}")
+        .arg("./third_party/examples/invalid-utf8.c");
+    cmd.assert().stderr(predicate::str::contains(
+        r#"{"level":"WARN","message":"'$p' only appears in negative sub queries"#,
+    ));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("-R")
+        .arg("$n=^size")
+        .arg("{memcpy(_, _, $n[>10]);}")
+        .arg("./third_party/examples/invalid-utf8.c");
+    cmd.assert().stderr(predicate::str::contains(
+        "the regex '^size' can never match a number",
+    ));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("-R")
+        .arg("$n=^(0x)?1")
+        .arg("{memcpy(_, _, $n[>10]);}")
+        .arg("./third_party/examples/invalid-utf8.c");
+    cmd.assert()
+        .stderr(predicate::str::contains("can never match").not());
+    Ok(())
+}
+
+#[test]
+fn threads() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--threads=1")
+        .arg("{char buf[10]; memcpy(buf, _, _);}")
+        .arg("./third_party/examples/");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("invalid-utf8.c"));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--parse-threads=1")
+        .arg("--query-threads=2")
+        .arg("{char buf[10]; memcpy(buf, _, _);}")
+        .arg("./third_party/examples/");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("invalid-utf8.c"));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--threads=many").arg("foo();").arg(".");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("'many' is not a number"));
+    Ok(())
+}
+
+#[test]
+fn timeout_per_file() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("timeout");
+    // Every pair of statements needs to be compared, which takes several seconds.
+    let body: String = (1..3000)
+        .map(|i| format!("    v{} = v{} + 1;\n", i, i - 1))
+        .collect();
+    std::fs::write(dir.join("slow.c"), format!("void slow() {{\n{}}}\n", body))?;
+    std::fs::write(
+        dir.join("fast.c"),
+        "void fast() {\n    a = 1;\n    b = a + 1;\n}\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--timeout-per-file=0.2")
+        .arg("{$a = _; $b = $a + 1;}")
+        .arg(&dir);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("void fast()").and(predicate::str::contains("slow").not()))
+        .stderr(
+            predicate::str::contains("Skipped 1 file(s):").and(predicate::str::contains(
+                "slow.c: queries timed out (--timeout-per-file)",
+            )),
+        );
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--timeout-per-file=0").arg("foo();").arg(&dir);
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "'0' is not a positive number of seconds",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn context_statements() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("statements");
+    std::fs::write(
+        dir.join("a.c"),
+        "int f(int a) {\n    int x = 1;\n    // note\n    int y = 2;\n    call(a,\n         x,\n         y);\n    int z = 3;\n    int w = 4;\n    return x;\n}\n",
+    )?;
+
+    // The comment is not counted, the wrapped call is one statement.
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--context-statements=1")
+        .arg("int z = 3;")
+        .arg(&dir);
+    cmd.assert().success().stdout(
+        predicate::str::contains(
+            "    call(a,\n         x,\n         y);\n    int z = 3;\n    int w = 4;\n",
+        )
+        .and(predicate::str::contains("int y = 2;").not())
+        .and(predicate::str::contains("return x;").not()),
+    );
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--context-statements=2").arg("call(_);").arg(&dir);
+    cmd.assert().success().stdout(
+        predicate::str::contains("    int x = 1;\n    // note\n    int y = 2;\n")
+            .and(predicate::str::contains("int w = 4;"))
+            .and(predicate::str::contains("return x;").not()),
+    );
+
+    Ok(())
+}
+
+#[test]
+fn sample_files() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("sample-files");
+    for i in 0..10 {
+        std::fs::write(dir.join(format!("{}.c", i)), "void f() {\n    foo();\n}\n")?;
+    }
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--sample-files=3").arg("foo();").arg(&dir);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(".c:1").count(3));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--sample-files=15%").arg("foo();").arg(&dir);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(".c:1").count(2));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--sample-files=0%").arg("foo();").arg(&dir);
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "'0%' is not a number of files or a percentage",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn filter() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("filter");
+    std::fs::write(
+        dir.join("a.c"),
+        "void f() {\n    memcpy(buffer, src, 16);\n}\nvoid g() {\n    memcpy(b, b, 16);\n}\nvoid h() {\n    strncpy(dst, src, 4);\n}\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--filter=len($dst) > 1 and not eq($src, $dst)")
+        .arg("memcpy($dst, $src, _);")
+        .arg(&dir);
+    cmd.assert().success().stdout(
+        predicate::str::contains("void f()")
+            .and(predicate::str::contains("void g()").not())
+            .and(predicate::str::contains("void h()").not()),
+    );
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--filter")
+        .arg("match($fn, \"^str\") or $dst == \"b\"")
+        .arg("$fn($dst, _, _);")
+        .arg(&dir);
+    cmd.assert().success().stdout(
+        predicate::str::contains("void g()")
+            .and(predicate::str::contains("void h()"))
+            .and(predicate::str::contains("void f()").not()),
+    );
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--filter=has($x)")
+        .arg("memcpy($dst, _, _);")
+        .arg(&dir);
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "'$x' is not a valid query variable",
+    ));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--filter=len($dst) >")
+        .arg("memcpy($dst, _, _);")
+        .arg(&dir);
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "invalid filter 'len($dst) >': unexpected end of the expression",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn functions() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("functions");
+    std::fs::write(
+        dir.join("a.c"),
+        "static int\nadd(int a,\n    int b)\n{\n    return a + b;\n}\n\nvoid z(void) {}\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("functions").arg(&dir);
+    cmd.assert().success().stdout(
+        predicate::str::contains("a.c:1-6: static int add(int a, int b)\n")
+            .and(predicate::str::contains("a.c:8-8: void z(void)\n")),
+    );
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("functions").arg("--json").arg(dir.join("a.c"));
+    cmd.assert().success().stdout(
+        predicate::str::contains(r#""function":"add""#)
+            .and(predicate::str::contains(r#""signature":"void z(void)""#))
+            .and(predicate::str::contains(r#""end_line":6"#)),
+    );
+
+    Ok(())
+}
+
+#[test]
+fn file_reports() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("file-reports");
+    std::fs::write(dir.join("a.c"), "void f() {\n    foo();\n    foo();\n}\n")?;
+    std::fs::write(dir.join("b.c"), "void g() {\n    foo();\n}\n")?;
+    std::fs::write(dir.join("c.c"), "void h() {\n    bar();\n}\n")?;
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("-c").arg("foo();").arg(&dir);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("a.c:2\n"))
+        .stdout(predicate::str::contains("b.c:1\n"))
+        .stdout(predicate::str::contains("c.c").not());
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--files-with-matches").arg("foo();").arg(&dir);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("a.c\n").count(1))
+        .stdout(predicate::str::contains("b.c\n"))
+        .stdout(predicate::str::contains("c.c").not());
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("-L").arg("foo();").arg(&dir);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("c.c\n"))
+        .stdout(predicate::str::contains("a.c").not())
+        .stdout(predicate::str::contains("b.c").not());
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("-c").arg("--format=json").arg("foo();").arg(&dir);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("\"count\":2"));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("-c").arg("-L").arg("foo();").arg(&dir);
+    cmd.assert().failure();
+
+    Ok(())
+}
+
+#[test]
+fn exit_codes() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("-q")
+        .arg("memcpy(_, _, _);")
+        .arg("./third_party/examples/");
+    cmd.assert().code(0).stdout(predicate::str::is_empty());
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("-q")
+        .arg("does_not_exist();")
+        .arg("./third_party/examples/");
+    cmd.assert().code(1).stdout(predicate::str::is_empty());
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("memcpy(_, _, _").arg("./third_party/examples/");
+    cmd.assert().code(2);
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--sample=abc")
+        .arg("foo();")
+        .arg("./third_party/examples/");
+    cmd.assert().code(2);
+
+    Ok(())
+}
+
+#[test]
+fn oneline_only_matching() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("oneline");
+    std::fs::write(
+        dir.join("a.c"),
+        "void f(char *d, char *s, int n) {\n    if (n > 0)\n        memcpy(d,\n               s, n);\n    memcpy(d, s, 4); free(d);\n}\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--format=oneline")
+        .arg("memcpy($a, $b, _);")
+        .arg(&dir);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("a.c:3:9: memcpy(d,\n"))
+        .stdout(predicate::str::contains(
+            "a.c:5:5: memcpy(d, s, 4); free(d);\n",
+        ));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--format=oneline")
+        .arg("--only-matching")
+        .arg("memcpy($a, $b, _);")
+        .arg(&dir);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("a.c:3:9: memcpy(d, s, n);\n"))
+        .stdout(predicate::str::contains("a.c:5:5: memcpy(d, s, 4);\n"));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--only-matching")
+        .arg("{memcpy($a, _, 4); free($a);}")
+        .arg(&dir);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("memcpy(d, s, 4);\nfree(d);\n"))
+        .stdout(predicate::str::contains("void f").not());
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--only-matching")
+        .arg("--format=json")
+        .arg("free($a);")
+        .arg(&dir);
+    cmd.assert().code(2);
+
+    Ok(())
+}
+
+#[test]
+fn output_format() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("output-format");
+    std::fs::write(
+        dir.join("a.c"),
+        "void f(char *d, char *s, int n) {\n    memcpy(d, s, n /* size */);\n}\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--output-format={file}:{line}\\t{function}: {$fn} -> {$size} {{}}")
+        .arg("$fn(_, _, $size);")
+        .arg(&dir);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::ends_with("a.c:1\tf: memcpy -> n {}\n"));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--output-format={$size}")
+        .arg("--raw-values")
+        .arg("$fn(_, _, $size);")
+        .arg(&dir);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::diff("n /* size */\n"));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--output-format={file} {size}")
+        .arg("$fn(_, _, $size);")
+        .arg(&dir);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("unknown field '{size}'"));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--output-format={$len}")
+        .arg("$fn(_, _, $size);")
+        .arg(&dir);
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "'$len' is not a valid query variable",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn dual_headers() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("dual-headers");
+    std::fs::write(
+        dir.join("a.h"),
+        "template <typename T>\nvoid t(T x) {\n    foo(x);\n}\n",
+    )?;
+    std::fs::write(dir.join("b.h"), "void g() {\n    foo(y);\n}\n")?;
+    std::fs::write(
+        dir.join("c.c"),
+        "template <typename T>\nvoid t(T x) {\n    foo(x);\n}\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("foo($a);").arg(&dir);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("b.h:1"))
+        .stdout(predicate::str::contains("a.h").not());
+
+    // Headers are searched as C++ as well, but results in both modes are only reported once
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--dual-headers").arg("foo($a);").arg(&dir);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("a.h:2"))
+        .stdout(predicate::str::contains("b.h:1").count(1))
+        .stdout(predicate::str::contains("c.c").not());
+
+    Ok(())
+}
+
+#[test]
+fn csv_format() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("csv-format");
+    std::fs::write(
+        dir.join("a.c"),
+        "void f(char *d, char *s, int n) {\n    unsigned /*\t\"a,b\" */ long x = 1;\n    memcpy(d, s, n);\n}\n",
+    )?;
+    let path = dir.join("a.c").display().to_string();
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--format=csv")
+        .arg("$fn(_, $src, $size);")
+        .arg(&dir);
+    cmd.assert()
+        .success()
+        .stdout(format!("path,line,$fn,$size,$src\n{},1,memcpy,n,s\n", path));
+
+    // Values are quoted if needed
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--format=csv")
+        .arg("--raw-values")
+        .arg("$t x = _;")
+        .arg(&dir);
+    cmd.assert().success().stdout(format!(
+        "path,line,$t\n{},1,\"unsigned /*\t\"\"a,b\"\" */ long\"\n",
+        path
+    ));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--format=tsv")
+        .arg("--raw-values")
+        .arg("$t x = _;")
+        .arg(&dir);
+    cmd.assert().success().stdout(format!(
+        "path\tline\t$t\n{}\t1\tunsigned /* \"a,b\" */ long\n",
+        path
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn blame() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("blame");
+    std::fs::write(dir.join("a.c"), "void f() {\n    foo(1);\n}\n")?;
+    std::fs::write(dir.join("untracked.c"), "void g() {\n    foo(2);\n}\n")?;
+
+    let git = |args: &[&str]| {
+        std::process::Command::new("git")
+            .args([
+                "-c",
+                "user.name=Jane Doe",
+                "-c",
+                "user.email=jane@example.com",
+            ])
+            .args(args)
+            .current_dir(&dir)
+            .env("GIT_AUTHOR_DATE", "2021-09-28T12:00:00Z")
+            .env("GIT_COMMITTER_DATE", "2021-09-28T12:00:00Z")
+            .output()
+    };
+    git(&["init", "-q"])?;
+    git(&["add", "a.c"])?;
+    git(&["commit", "-q", "-m", "initial"])?;
+
+    let results = || -> Result<Vec<serde_json::Value>, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("weggli")?;
+        cmd.arg("--blame")
+            .arg("--format=json")
+            .arg("foo(_);")
+            .arg(&dir);
+        let output = cmd.output()?;
+        assert!(output.status.success());
+        let mut results: Vec<serde_json::Value> = String::from_utf8(output.stdout)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        results.sort_by_key(|r| r["path"].as_str().unwrap().to_string());
+        Ok(results)
+    };
+
+    let r = results()?;
+    assert_eq!(r.len(), 2);
+    assert_eq!(r[0]["blame"]["author"], "Jane Doe");
+    assert_eq!(r[0]["blame"]["email"], "jane@example.com");
+    assert_eq!(r[0]["blame"]["date"], "2021-09-28T12:00:00Z");
+    assert_eq!(r[0]["blame"]["commit"].as_str().unwrap().len(), 40);
+    assert_eq!(r[1]["blame"], serde_json::Value::Null);
+
+    // Uncommitted changes
+    std::fs::write(dir.join("a.c"), "void f() {\n    foo(3);\n}\n")?;
+    let r = results()?;
+    assert_eq!(
+        r[0]["blame"],
+        serde_json::json!({ "commit": null, "uncommitted": true })
+    );
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--blame").arg("foo(_);").arg(&dir);
+    cmd.assert().code(2).stderr(predicate::str::contains(
+        "--blame can only be used with --format json or sarif",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn max_warnings() -> Result<(), Box<dyn std::error::Error>> {
+    // Floats can't be matched numerically, each one triggers a warning.
+    let pattern = "{foo(1.5); bar(1.5); baz(2.5); qux(3.5);}";
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg(pattern).arg("./third_party/examples/");
+    let output = cmd.output()?;
+    let stderr = String::from_utf8(output.stderr)?;
+    assert_eq!(stderr.matches("Could not parse 1.5").count(), 2);
+    assert!(stderr.contains(
+        "'Could not parse 1.5 as a number. Forcing string matching' repeated 1 more time(s)"
+    ));
+    assert!(stderr.contains("Could not parse 2.5"));
+    assert!(stderr.contains("Could not parse 3.5"));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--max-warnings=1")
+        .arg(pattern)
+        .arg("./third_party/examples/");
+    let output = cmd.output()?;
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(!stderr.contains("Could not parse 2.5"));
+    assert!(!stderr.contains("Could not parse 3.5"));
+    assert!(stderr.contains("2 more warning(s) not shown because of --max-warnings"));
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--max-warnings=x")
+        .arg(pattern)
+        .arg("./third_party/examples/");
+    cmd.assert().failure();
+
+    Ok(())
+}
+
+#[test]
+fn suppressions() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("suppress");
+    let file = dir.join("a.c");
+    std::fs::write(
+        &file,
+        "void f() {\n\
+         \x20   // weggli-ignore\n\
+         \x20   foo(1);\n\
+         \x20   /* weggli-ignore[other, weggli/pattern-0] */\n\
+         \x20   foo(2);\n\
+         \x20   // weggli-ignore[other]\n\
+         \x20   foo(3);\n\
+         \x20   foo(4);\n\
+         }\n",
+    )?;
+
+    let lines = |extra: &[&str]| -> Result<String, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("weggli")?;
+        cmd.arg("--format=oneline")
+            .args(extra)
+            .arg("foo(_);")
+            .arg(&dir);
+        Ok(String::from_utf8(cmd.output()?.stdout)?)
+    };
+    let path = file.display();
+    assert_eq!(
+        lines(&[])?,
+        format!("{}:7:5: foo(3);\n{}:8:5: foo(4);\n", path, path)
+    );
+
+    // Record the current results, then only report new ones
+    let baseline = dir.join("baseline.json");
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--baseline")
+        .arg(&baseline)
+        .arg("--update-baseline")
+        .arg("foo(_);")
+        .arg(&dir);
+    cmd.assert().success();
+    let recorded: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&baseline)?)?;
+    assert_eq!(recorded["results"].as_array().unwrap().len(), 2);
+
+    std::fs::write(
+        &file,
+        "void f() {\n    foo(5);\n    foo(3);\n    foo(4);\n    foo(3);\n}\n",
+    )?;
+    // A recorded result only suppresses one copy of the same code
+    let b = baseline.to_str().unwrap();
+    assert_eq!(
+        lines(&["--baseline", b])?,
+        format!("{}:2:5: foo(5);\n{}:5:5: foo(3);\n", path, path)
+    );
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--baseline")
+        .arg(dir.join("missing.json"))
+        .arg("foo(_);")
+        .arg(&dir);
+    cmd.assert()
+        .code(2)
+        .stderr(predicate::str::contains("Failed to load baseline"));
+
+    Ok(())
+}
+
+#[test]
+fn diff() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("diff");
+    std::fs::write(dir.join("a.c"), "void f() {\n    foo(1);\n}\n")?;
+    std::fs::write(dir.join("b.c"), "void g() {\n    foo(2);\n}\n")?;
+
+    let git = |args: &[&str]| {
+        std::process::Command::new("git")
+            .args([
+                "-c",
+                "user.name=Jane Doe",
+                "-c",
+                "user.email=jane@example.com",
+            ])
+            .args(args)
+            .current_dir(&dir)
+            .output()
+    };
+    git(&["init", "-q"])?;
+    git(&["add", "."])?;
+    git(&["commit", "-q", "-m", "initial"])?;
+
+    let lines = |extra: &[&str]| -> Result<String, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("weggli")?;
+        cmd.arg("--format=oneline")
+            .args(extra)
+            .arg("foo(_);")
+            .arg(&dir);
+        // Files are searched in parallel
+        let stdout = String::from_utf8(cmd.output()?.stdout)?;
+        let mut lines: Vec<&str> = stdout.lines().collect();
+        lines.sort();
+        Ok(lines.join("\n") + "\n")
+    };
+
+    // Nothing changed
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--diff=HEAD").arg("foo(_);").arg(&dir);
+    cmd.assert().code(1).stdout("");
+
+    // A modified and an untracked file
+    std::fs::write(dir.join("a.c"), "void f() {\n    foo(1);\n    foo(3);\n}\n")?;
+    std::fs::write(dir.join("c.c"), "void h() {\n    foo(4);\n}\n")?;
+    let a = dir.join("a.c").display().to_string();
+    let c = dir.join("c.c").display().to_string();
+    assert_eq!(
+        lines(&["--diff=HEAD"])?,
+        format!(
+            "{}:2:5: foo(1);\n{}:3:5: foo(3);\n{}:2:5: foo(4);\n",
+            a, a, c
+        )
+    );
+    assert_eq!(
+        lines(&["--diff=HEAD", "--changed-lines"])?,
+        format!("{}:3:5: foo(3);\n{}:2:5: foo(4);\n", a, c)
+    );
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--diff=no-such-rev").arg("foo(_);").arg(&dir);
+    cmd.assert().code(2).stderr(predicate::str::contains(
+        "Failed to get changes since no-such-rev",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn function_header() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("header");
+    let file = dir.join("a.c");
+    let source = "static int\nparse(char *buf,\n      size_t len)\n{\n    int x = 1;\n    \
+                  memcpy(buf, src, len);\n    return x;\n}\n";
+    std::fs::write(&file, source)?;
+
+    let header = |mode: &str| -> Result<String, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("weggli")?;
+        cmd.arg("--color=never")
+            .arg("-A0")
+            .arg("-B0")
+            .arg(format!("--function-header={}", mode))
+            .arg("memcpy(_, _, _);")
+            .arg(&file);
+        let stdout = String::from_utf8(cmd.output()?.stdout)?;
+        // The lines between the location and the match
+        let lines: Vec<&str> = stdout
+            .lines()
+            .skip(1)
+            .take_while(|l| !l.contains("memcpy"))
+            .collect();
+        Ok(lines.join("\n"))
+    };
+
+    assert_eq!(header("line")?, "static int\n...");
+    assert_eq!(
+        header("signature")?,
+        "static int\nparse(char *buf,\n      size_t len)\n..."
+    );
+    assert_eq!(header("name")?, "parse\n...");
+    assert_eq!(header("none")?, "");
+
+    // Without a header, the before-context is still shown
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--color=never")
+        .arg("-A0")
+        .arg("-B2")
+        .arg("--function-header=none")
+        .arg("memcpy(_, _, _);")
+        .arg(&file);
+    let stdout = String::from_utf8(cmd.output()?.stdout)?;
+    let lines: Vec<&str> = stdout.lines().skip(1).take(3).collect();
+    assert_eq!(lines, ["{", "    int x = 1;", "    memcpy(buf, src, len);"]);
+
+    Ok(())
+}
+
+#[test]
+fn files_from() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("files-from");
+    std::fs::create_dir_all(dir.join("src"))?;
+    std::fs::write(dir.join("a.c"), "void f() {\n    foo(1);\n}\n")?;
+    std::fs::write(dir.join("b.txt"), "void g() {\n    foo(2);\n}\n")?;
+    std::fs::write(dir.join("src/c.c"), "void h() {\n    foo(3);\n}\n")?;
+    let path = |f: &str| dir.join(f).display().to_string();
+    std::fs::write(
+        dir.join("list.txt"),
+        format!("{}\n\n{}\n", path("a.c"), path("b.txt")),
+    )?;
+
+    let files = |cmd: &mut assert_cmd::Command| -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let stdout = String::from_utf8(cmd.output()?.stdout)?;
+        let mut files: Vec<String> = stdout.lines().map(String::from).collect();
+        files.sort();
+        Ok(files)
+    };
+
+    // Listed files are searched regardless of their extension
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--files-with-matches")
+        .arg("--files-from")
+        .arg(dir.join("list.txt"))
+        .arg("foo(_);");
+    let mut cmd = assert_cmd::Command::from_std(cmd);
+    assert_eq!(files(&mut cmd)?, vec![path("a.c"), path("b.txt")]);
+
+    // The list from STDIN and a directory
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--files-with-matches")
+        .arg("--files-from=-")
+        .arg("foo(_);")
+        .arg(dir.join("src"));
+    let mut cmd = assert_cmd::Command::from_std(cmd);
+    cmd.write_stdin(path("a.c") + "\n");
+    assert_eq!(files(&mut cmd)?, vec![path("a.c"), path("src/c.c")]);
+
+    // Paths that aren't valid UTF-8 are used as they are
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let name = std::ffi::OsStr::from_bytes(b"\xff.c");
+        std::fs::write(dir.join(name), "void i() {\n    foo(4);\n}\n")?;
+        let mut cmd = Command::cargo_bin("weggli")?;
+        cmd.arg("--files-with-matches")
+            .arg("--files-from=-")
+            .arg("foo(_);");
+        let mut cmd = assert_cmd::Command::from_std(cmd);
+        let line = dir.join(name).as_os_str().as_bytes().to_vec();
+        cmd.write_stdin([line, b"\n".to_vec()].concat());
+        assert_eq!(files(&mut cmd)?, vec![dir.join(name).display().to_string()]);
+    }
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--files-from")
+        .arg(dir.join("missing.txt"))
+        .arg("foo(_);");
+    cmd.assert()
+        .code(2)
+        .stderr(predicate::str::contains("Failed to read file list"));
+
+    Ok(())
+}
+
+#[test]
+fn no_match_cache() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("no-match-cache");
+    std::fs::write(dir.join("a.c"), "void f() {\n    foo(1);\n}\n")?;
+    // Skipped by the identifier prefilter
+    std::fs::write(dir.join("b.c"), "void g() {\n    bar(2);\n}\n")?;
+    // Parsed, but without a match
+    std::fs::write(dir.join("c.c"), "void h() {\n    int foo;\n}\n")?;
+
+    let run = || -> Result<(String, String), Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("weggli")?;
+        cmd.arg("--log-format=json")
+            .arg("--format=oneline")
+            .arg("--no-match-cache")
+            .arg(dir.join(".cache"))
+            .arg("foo(_);")
+            .arg(&dir);
+        let output = cmd.output()?;
+        let mut lines: Vec<String> = String::from_utf8(output.stdout)?
+            .lines()
+            .map(String::from)
+            .collect();
+        lines.sort();
+        Ok((lines.join("\n"), String::from_utf8(output.stderr)?))
+    };
+    let a = dir.join("a.c").display().to_string();
+    let c = dir.join("c.c").display().to_string();
+
+    let (stdout, stderr) = run()?;
+    assert_eq!(stdout, format!("{}:2:5: foo(1);", a));
+    assert!(!stderr.contains("no match (cache)"));
+
+    // Files without results are skipped, the results are the same
+    let (stdout, stderr) = run()?;
+    assert_eq!(stdout, format!("{}:2:5: foo(1);", a));
+    assert_eq!(stderr.matches(r#""reason":"no match (cache)""#).count(), 2);
+
+    // Modified files are searched again
+    std::fs::write(dir.join("c.c"), "void h() {\n    foo(3);\n}\n")?;
+    let (stdout, stderr) = run()?;
+    assert_eq!(stdout, format!("{}:2:5: foo(1);\n{}:2:5: foo(3);", a, c));
+    assert_eq!(stderr.matches(r#""reason":"no match (cache)""#).count(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn ignore_files() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir::new("ignore");
+    std::fs::create_dir_all(dir.join("vendor"))?;
+    std::fs::create_dir_all(dir.join("gen"))?;
+    std::fs::write(dir.join("a.c"), "void f() {\n    foo(1);\n}\n")?;
+    std::fs::write(dir.join("vendor/b.c"), "void g() {\n    foo(2);\n}\n")?;
+    std::fs::write(dir.join("gen/c.c"), "void h() {\n    foo(3);\n}\n")?;
+    std::fs::write(dir.join(".gitignore"), "vendor/\n")?;
+    std::fs::write(dir.join(".weggliignore"), "gen/\n")?;
+
+    let files = |extra: &[&str]| -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("weggli")?;
+        cmd.arg("--files-with-matches")
+            .args(extra)
+            .arg("foo(_);")
+            .arg(&dir);
+        // Files are searched in parallel
+        let stdout = String::from_utf8(cmd.output()?.stdout)?;
+        let mut files: Vec<String> = stdout.lines().map(String::from).collect();
+        files.sort();
+        Ok(files)
+    };
+
+    let path = |f: &str| dir.join(f).display().to_string();
+    assert_eq!(files(&[])?, vec![path("a.c")]);
+    assert_eq!(
+        files(&["--no-ignore"])?,
+        vec![path("a.c"), path("gen/c.c"), path("vendor/b.c")]
+    );
+
+    // Files passed directly are always searched
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--files-with-matches")
+        .arg("foo(_);")
+        .arg(dir.join("vendor/b.c"));
+    cmd.assert()
+        .success()
+        .stdout(format!("{}\n", path("vendor/b.c")));
+
+    Ok(())
+}
+
+#[test]
+fn raw_tsquery() -> Result<(), Box<dyn std::error::Error>> {
+    let sexp = r#"(call_expression function: (identifier) @f (#eq? @f "memcpy")
+        arguments: (argument_list . (_) @dst))"#;
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--format=csv")
+        .arg("--raw-tsquery")
+        .arg(sexp)
+        .arg("-R")
+        .arg("$dst=^buf")
+        .arg("./third_party/examples/");
+    cmd.assert().success().stdout(
+        predicate::str::starts_with("path,line,$dst,$f\n")
+            .and(predicate::str::contains("invalid-utf8.c,3,buf,memcpy\n"))
+            .and(predicate::str::contains(",n->ip,").not()),
+    );
+
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--raw-tsquery")
+        .arg("(call_expression")
+        .arg("./third_party/examples/");
+    cmd.assert()
+        .code(2)
+        .stderr(predicate::str::contains("invalid tree-sitter query"));
+
+    // The search path is the only positional argument
+    let mut cmd = Command::cargo_bin("weggli")?;
+    cmd.arg("--raw-tsquery")
+        .arg(sexp)
+        .arg("{memcpy(_);}")
+        .arg(".");
+    cmd.assert().failure();
+
+    Ok(())
+}
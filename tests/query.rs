@@ -800,7 +800,7 @@ fn subexpression_with_multiple_args() {
     // An unfortunate effect of our sub expression syntax _($x) is
     // that people might wrongly use it as a wildcard function call
     // _($a, $b). This doesn't work (you want to use $fn($a,$b) instead).
-    // As we don't support sub expressions with multiple arguments, we
+    // A wildcard argument is meaningless in a sub expression, so we
     // can just transparently convert _(_, $b) to $something(_, $b) and
     // warn the user.
 
@@ -953,4 +953,1424 @@ fn test_number2() {
 
     let matches = parse_and_match_cpp(needle, source);
     assert_eq!(matches, 1);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_function_signature() {
+    let needle = "_ $f(_, size_t $len);";
+
+    let source = r#"
+    int parse(const char *buf, size_t len);
+    char *copy(char *dst, size_t n);
+    void noop(int a);
+
+    int parse(const char *buf, size_t len) {
+        return 0;
+    }
+
+    void local() {
+        int nested(char *, size_t n);
+    }
+    "#;
+
+    let matches = parse_and_match(needle, source);
+    assert_eq!(matches, 4);
+
+    let matches = parse_and_match_cpp(needle, source);
+    assert_eq!(matches, 4);
+}
+
+#[test]
+fn test_switch_case() {
+    let source = r#"
+    void fallthrough(int x) {
+        switch (x) {
+        case 1:
+            a();
+        case 2:
+            b();
+            break;
+        default:
+            c();
+        }
+    }
+
+    void no_fallthrough(int x) {
+        switch (x) {
+        case A:
+            a();
+            break;
+        case B:
+            b();
+            break;
+        }
+    }
+    "#;
+
+    let needle = "switch ($x) { case $A: _; not: break; case _: _; }";
+    let matches = parse_and_match(needle, source);
+    assert_eq!(matches, 1);
+
+    let needle = "switch ($x) { case $A: _; case B: _; }";
+    let matches = parse_and_match(needle, source);
+    assert_eq!(matches, 1);
+
+    let needle = "switch ($x) { default: _; }";
+    let matches = parse_and_match(needle, source);
+    assert_eq!(matches, 1);
+
+    let needle = "switch ($x) { not: default: _; }";
+    let matches = parse_and_match(needle, source);
+    assert_eq!(matches, 1);
+}
+
+#[test]
+fn test_only_negations() {
+    let needle = "{not: free(_);}";
+    let source = r#"
+    void a() { free(x); }
+    void b() { g(x); }
+    "#;
+
+    let matches = parse_and_match(needle, source);
+    assert_eq!(matches, 1);
+}
+
+#[test]
+fn test_unused_return_value() {
+    let needle = "{unused: foo(_);}";
+    let source = r#"
+    void a() { foo(1); }
+    void b() { x = foo(2); }
+    void c() { if (foo(3)) {} }
+    void d() { return foo(4); }
+    void e() { x->foo(5); }
+    "#;
+
+    let matches = parse_and_match(needle, source);
+    assert_eq!(matches, 2);
+
+    let needle = "{unused: $f(_);}";
+    let matches = parse_and_match(needle, source);
+    assert_eq!(matches, 2);
+
+    let needle = "{strict: foo(_);}";
+    let matches = parse_and_match(needle, source);
+    assert_eq!(matches, 1);
+}
+
+#[test]
+fn test_uninitialized_multi_declarator() {
+    let needle = "{$t $x; not: $x = _; use($x);}";
+    let source = r#"
+    void a() {
+        int x, y, z;
+        x = 1;
+        use(x);
+        use(z);
+    }
+    void b() {
+        int x = 0, y;
+        use(y);
+    }
+    void c() {
+        int x = 0, y = 1;
+        use(y);
+    }
+    void d() {
+        int x, y;
+        y = 1;
+        use(y);
+    }
+    "#;
+
+    let results = parse_and_match_helper(needle, source, false);
+    let values: Vec<&str> = results
+        .iter()
+        .map(|r| r.value("$x", source).unwrap())
+        .collect();
+    assert_eq!(values, vec!["z", "y"]);
+
+    let needle = "{_* $p; not: $p = _; use($p);}";
+    let source = r#"
+    void a() {
+        char *p, *q;
+        p = malloc(10);
+        use(p);
+        use(q);
+    }
+    "#;
+
+    let results = parse_and_match_helper(needle, source, false);
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].value("$p", source), Some("q"));
+}
+
+#[test]
+fn test_multi_declarator() {
+    let source = r#"
+    void f() {
+        int a, b = 1, *c;
+        int *d = 0, e;
+    }
+    "#;
+
+    let values = |needle: &str, cpp: bool| -> Vec<String> {
+        let results = parse_and_match_helper(needle, source, cpp);
+        results
+            .iter()
+            .map(|r| r.value("$x", source).unwrap().to_string())
+            .collect()
+    };
+
+    for cpp in [false, true].iter().copied() {
+        assert_eq!(values("{$t $x;}", cpp), vec!["a", "e"]);
+        assert_eq!(values("{$t $x = _;}", cpp), vec!["b"]);
+        assert_eq!(values("{$t *$x;}", cpp), vec!["c"]);
+        assert_eq!(values("{$t *$x = _;}", cpp), vec!["d"]);
+    }
+}
+
+#[test]
+fn test_array_size_variable() {
+    let source = r#"
+    void a(char *src) {
+        char buf[0x10];
+        memcpy(buf, src, 16);
+    }
+    void b(char *src) {
+        char buf[SIZE];
+        memcpy(buf, src, SIZE);
+    }
+    void c(char *src) {
+        char buf[16];
+        memcpy(buf, src, 32);
+    }
+    void d() {
+        char x[8], y[010];
+    }
+    "#;
+
+    let needle = "{char $buf[$n]; memcpy($buf, _, $n);}";
+    let results = parse_and_match_helper(needle, source, false);
+    let sizes: Vec<&str> = results
+        .iter()
+        .map(|r| r.value("$n", source).unwrap())
+        .collect();
+    assert_eq!(sizes, vec!["0x10", "SIZE"]);
+
+    let needle = "{_ $x[$n], $y[$n];}";
+    let matches = parse_and_match(needle, source);
+    assert_eq!(matches, 1);
+}
+
+#[test]
+fn test_string_length() {
+    let source = r#"
+    void a() {
+        printf("%s\n");
+        printf("a much longer format string: %s %s %s\n");
+        printf(fmt);
+    }
+    void b(char *src) {
+        char buf[4];
+        strcpy(buf, "abc");
+    }
+    void c(char *src) {
+        char buf[4];
+        strcpy(buf, "ab\x43\n");
+    }
+    "#;
+
+    let matches = |needle: &str, constraint: &str| {
+        let tree = weggli::parse(needle, false);
+        let source_tree = weggli::parse(source, false);
+        let constraints = weggli::QueryOptions::default()
+            .with_length_constraints(vec![constraint.parse().unwrap()]);
+        let qt = build_query_tree(needle, &mut tree.walk(), false, Some(constraints)).unwrap();
+        qt.matches(source_tree.root_node(), source).len()
+    };
+
+    assert_eq!(matches("{printf($fmt);}", "fmt>10"), 1);
+    assert_eq!(matches("{printf($fmt);}", "$fmt<=10"), 1);
+    assert_eq!(matches("{printf($fmt);}", "fmt!=0"), 2);
+    assert_eq!(matches("{printf($fmt);}", "fmt==3"), 1);
+
+    let needle = "{char $buf[$n]; strcpy($buf, $src);}";
+    assert_eq!(matches(needle, "src>=$n"), 1);
+    assert_eq!(matches(needle, "src<$n"), 1);
+    assert_eq!(matches(needle, "src==4"), 1);
+
+    assert!("src".parse::<weggli::LengthConstraint>().is_err());
+    assert!("src>".parse::<weggli::LengthConstraint>().is_err());
+    assert!("src>a-b".parse::<weggli::LengthConstraint>().is_err());
+}
+
+#[test]
+fn test_excluded_identifiers() {
+    let source = r#"
+    void foo(char *dst, char *src) {
+        memcpy(dst, src, sizeof(dst));
+        memmove(dst, src, sizeof(dst));
+        strncpy(dst, src, sizeof(dst));
+        snprintf(dst, sizeof(dst), "%s", src);
+    }
+    "#;
+
+    let matches = |needle: &str| {
+        let qt = weggli::parse_search_pattern(needle, false, false, None).unwrap();
+        let source_tree = weggli::parse(source, false);
+        qt.matches(source_tree.root_node(), source)
+            .iter()
+            .map(|r| r.value("$f", source).unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(matches("$f(_, _, sizeof(_));").len(), 3);
+    assert_eq!(
+        matches("$f!{memcpy,memmove}(_, _, sizeof(_));"),
+        vec!["strncpy"]
+    );
+    assert_eq!(matches("$f!{ memcpy }(_, _, sizeof(_));").len(), 2);
+    assert_eq!(
+        matches("{$f!{memcpy,memmove}(_, _, sizeof(_)); snprintf(_);}"),
+        vec!["strncpy"]
+    );
+
+    assert!(weggli::parse_search_pattern("$f!{1a}(_);", false, false, None).is_err());
+    assert!(weggli::parse_search_pattern("$f!{}(_);", false, false, None).is_err());
+}
+
+#[test]
+fn test_or() {
+    let source = r#"
+    void a() { char *p = malloc(10); use(p); }
+    void b() { char *p; p = calloc(1, 10); use(p); }
+    void c() { char *p; p = realloc(q, 10); use(p); }
+    void d() { char *p; p = calloc(1, 10); other(p); }
+    void e() { char *p; use(p); p = calloc(1, 10); }
+    "#;
+
+    let needle = "{$p = malloc(_); or: $p = calloc(_,_); use($p);}";
+    assert_eq!(parse_and_match(needle, source), 2);
+
+    let needle = "{$p = malloc(_); or: $p = calloc(_,_); or: $p = realloc(_,_); use($p);}";
+    assert_eq!(parse_and_match(needle, source), 3);
+
+    let needle = "{use($p); $p = malloc(_); or: $p = calloc(_,_);}";
+    assert_eq!(parse_and_match(needle, source), 1);
+
+    let needle = "{$p = calloc(_,_); use($p); or: other($p);}";
+    assert_eq!(parse_and_match(needle, source), 2);
+
+    let tree = weggli::parse("{or: foo();}", false);
+    assert!(build_query_tree("{or: foo();}", &mut tree.walk(), false, None).is_err());
+}
+
+#[test]
+fn test_local_variables() {
+    let source = r#"
+    void a() { char *p = malloc(n); use(p); }
+    void b() { char *p = malloc(n); k = 1; use(p); }
+    void c() { char *p = malloc(n); n = 1; use(p); }
+    "#;
+
+    let needle = "{$p = malloc($n); not: $n = _; use($p);}";
+    assert_eq!(parse_and_match(needle, source), 2);
+
+    let needle = "{$p = malloc($n); not: $_n = _; use($p);}";
+    assert_eq!(parse_and_match(needle, source), 1);
+
+    // local variables are still unified inside of their block
+    let needle = "{$_p = malloc(_); use($_p);}";
+    assert_eq!(parse_and_match(needle, source), 3);
+    let needle = "{$_p = malloc(_); use($_p); $_p = 1;}";
+    assert_eq!(parse_and_match(needle, source), 0);
+
+    // and not visible in the final result
+    let results = parse_and_match_helper("{$_p = malloc($n);}", source, false);
+    assert!(results
+        .iter()
+        .all(|r| r.vars.len() == 1 && r.value("$n", source).is_some()));
+}
+
+#[test]
+fn test_show() {
+    let source = "void a() { char *p = malloc(n); if (p) { use(p); } }";
+
+    let needle = "{$p = malloc(_); show: if ($p) {use($p);}}";
+    let matches = parse_and_match_helper(needle, source, false);
+    assert_eq!(matches.len(), 1);
+    let statement = source.find("if (p)").unwrap()..source.rfind('}').unwrap() - 1;
+    assert!(matches[0].captures.iter().any(|c| c.range == statement));
+
+    let matches = parse_and_match_helper("{$p = malloc(_); if ($p) {use($p);}}", source, false);
+    assert!(!matches[0].captures.iter().any(|c| c.range == statement));
+
+    let needle = "{$p = malloc(_); show: use($p);}";
+    assert_eq!(parse_and_match(needle, source), 1);
+}
+
+#[test]
+fn test_anchor() {
+    let source = r#"
+    static char gbuf[100];
+    struct S {
+        void f() { if (x) { memcpy(buf, src, n); } }
+        void g() { free(buf); }
+    };
+    void h(char *p) { memcpy(p, q, n); { free(p); } }
+    "#;
+
+    let matches = |needle: &str, anchor: weggli::Anchor| -> Vec<std::ops::Range<usize>> {
+        let tree = weggli::parse(needle, true);
+        let constraints = weggli::QueryOptions::default().with_anchor(anchor);
+        let qt = build_query_tree(needle, &mut tree.walk(), true, Some(constraints)).unwrap();
+        let source_tree = weggli::parse(source, true);
+        qt.matches(source_tree.root_node(), source)
+            .iter()
+            .map(|m| m.function_range())
+            .collect()
+    };
+    let text = |r: &std::ops::Range<usize>| source[r.clone()].split('\n').next().unwrap().trim();
+
+    let needle = "{memcpy($p,_,_); free($p);}";
+    let r = matches(needle, weggli::Anchor::Function);
+    assert_eq!(
+        r.iter().map(text).collect::<Vec<_>>(),
+        ["void h(char *p) { memcpy(p, q, n); { free(p); } }"]
+    );
+
+    let r = matches(needle, weggli::Anchor::Class);
+    assert_eq!(r.iter().map(text).collect::<Vec<_>>(), ["struct S {"]);
+
+    let r = matches(needle, weggli::Anchor::File);
+    assert_eq!(r.len(), 2);
+    assert!(r.iter().all(|r| text(r) == "static char gbuf[100];"));
+
+    let r = matches(needle, weggli::Anchor::Compound);
+    assert_eq!(r.len(), 1);
+    assert_eq!(text(&r[0]), "{ memcpy(p, q, n); { free(p); } }");
+
+    let r = matches("{memcpy($p,_,_);}", weggli::Anchor::Compound);
+    assert_eq!(
+        r.iter().map(text).collect::<Vec<_>>(),
+        [
+            "{ memcpy(buf, src, n); }",
+            "{ memcpy(p, q, n); { free(p); } }"
+        ]
+    );
+
+    assert_eq!(
+        matches("{static char $b[_];}", weggli::Anchor::Function).len(),
+        0
+    );
+    assert_eq!(
+        matches("{static char $b[_];}", weggli::Anchor::File).len(),
+        1
+    );
+}
+
+#[test]
+fn test_never() {
+    let source = r#"
+    void a() { free(p); p = malloc(1); use(p); }
+    void b() { p = malloc(1); use(p); }
+    void c() { if (x) { free(p); } { p = malloc(1); use(p); } }
+    void d() { p = malloc(1); use(p); free(q); }
+    "#;
+
+    let needle = "{$p = malloc(_); not: free($p); use($p);}";
+    assert_eq!(parse_and_match(needle, source), 4);
+
+    let needle = "{$p = malloc(_); never: free($p); use($p);}";
+    assert_eq!(parse_and_match(needle, source), 2);
+
+    let needle = "{never: free($p); $p = malloc(_); use($p);}";
+    assert_eq!(parse_and_match(needle, source), 2);
+
+    let needle = "{$p = malloc(_); never: free(_);}";
+    assert_eq!(parse_and_match(needle, source), 1);
+}
+
+#[test]
+fn test_inline_regex() {
+    let source = r#"
+    void foo(char *dst, char *safe_src, char *src) {
+        memcpy(dst, safe_src, 10);
+        memmove(dst, src, 10);
+        strncpy(dst, src, 10);
+    }
+    "#;
+
+    let matches = |needle: &str| {
+        let qt = weggli::parse_search_pattern(needle, false, false, None).unwrap();
+        let source_tree = weggli::parse(source, false);
+        qt.matches(source_tree.root_node(), source)
+            .iter()
+            .map(|r| r.value("$f", source).unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(matches(r#"$f~"^mem"(_, _, _);"#), vec!["memcpy", "memmove"]);
+    assert_eq!(matches(r#"$f!~"^mem"(_, _, _);"#), vec!["strncpy"]);
+    assert_eq!(
+        matches(r#"$f~"^mem"(_, $buf!~"^safe_", _);"#),
+        vec!["memmove"]
+    );
+    assert_eq!(
+        matches(r#"{$f~"^mem"(_, $s, _); $g(_, $s, _);}"#),
+        vec!["memmove"]
+    );
+    assert_eq!(
+        matches(r#"$f~"\"?cpy"(_, _, _);"#),
+        vec!["memcpy", "strncpy"]
+    );
+
+    assert!(weggli::parse_search_pattern(r#"$f~"("(_);"#, false, false, None).is_err());
+    let needle = r#"{$f~"a"(_); $f~"b"(_);}"#;
+    assert!(weggli::parse_search_pattern(needle, false, false, None).is_err());
+}
+
+#[test]
+fn test_vfs_search() {
+    let files = vec![
+        ("a.c", "void a() { char *p = malloc(10); use(p); }"),
+        ("b.c", "void b() { memcpy(p, src, n); memcpy(q, src, n); }"),
+        ("c.c", "void c() { int x = 1; }"),
+    ];
+
+    let query = |pattern: &str| weggli::parse_search_pattern(pattern, false, false, None).unwrap();
+
+    let results = weggli::vfs::search(&[query("$x = malloc(_);")], files.clone(), false);
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].name, "a.c");
+    assert_eq!(results[0].result.value("$x", &results[0].source), Some("p"));
+
+    // Results are joined across files
+    let queries = [query("$x = malloc(_);"), query("memcpy($x, _, _);")];
+    let results = weggli::vfs::search(&queries, files.clone(), false);
+    let names: Vec<_> = results.iter().map(|r| (r.name.as_str(), r.query)).collect();
+    assert_eq!(names, [("a.c", 0), ("b.c", 1)]);
+
+    let queries = [query("$x = malloc(_);"), query("free($x);")];
+    assert!(weggli::vfs::search(&queries, files, false).is_empty());
+}
+
+#[test]
+fn test_search_callback() {
+    let query = |pattern: &str| weggli::parse_search_pattern(pattern, false, false, None).unwrap();
+    let options = weggli::SearchOptions::default();
+
+    let results = std::sync::Mutex::new(Vec::new());
+    weggli::search(
+        &[query("memcpy(_);")],
+        ["./third_party/examples"],
+        &options,
+        |m| {
+            results.lock().unwrap().push(m.path);
+        },
+    );
+    let results = results.into_inner().unwrap();
+    assert_eq!(results.len(), 22);
+    assert!(results.iter().all(|p| p.extension().unwrap() == "c"));
+
+    // Files are searched even if their extension is not in `options.extensions`.
+    let results = std::sync::Mutex::new(Vec::new());
+    let queries = [query("$x = malloc(_);"), query("memcpy($x, _, _);")];
+    weggli::search(
+        &queries,
+        ["./third_party/examples/malloc.c"],
+        &options,
+        |m| {
+            assert_eq!(m.result.value("$x", &m.source), Some("new"));
+            results.lock().unwrap().push(m.query);
+        },
+    );
+    let mut results = results.into_inner().unwrap();
+    results.sort();
+    assert_eq!(results, [0, 0, 1, 1]);
+}
+
+#[test]
+fn test_search_options() {
+    let query = |cpp| weggli::parse_search_pattern("memcpy(_);", cpp, false, None).unwrap();
+    let files = |queries: &[weggli::query::QueryTree], options: &weggli::SearchOptions| {
+        let results = std::sync::Mutex::new(Vec::new());
+        weggli::search(queries, ["./third_party/examples"], options, |m| {
+            results.lock().unwrap().push(m.path);
+        });
+        let mut results = results.into_inner().unwrap();
+        results.sort();
+        results.dedup();
+        results
+    };
+
+    // With --auto-lang, C++ queries only run on files detected as C++.
+    let options = weggli::SearchOptions {
+        auto_lang: true,
+        extensions: vec!["c".to_string(), "cc".to_string()],
+        ..Default::default()
+    };
+    let results = files(&[query(true)], &options);
+    assert_eq!(results.len(), 1);
+    assert!(results[0].ends_with("namespace.cc"));
+    assert_eq!(files(&[query(false)], &options).len(), 3);
+
+    // Files larger than max_filesize are skipped.
+    let options = weggli::SearchOptions {
+        max_filesize: Some(100),
+        ..options
+    };
+    assert!(files(&[query(true)], &options).is_empty());
+    let results = files(&[query(false)], &options);
+    assert_eq!(results.len(), 1);
+    assert!(results[0].ends_with("invalid-utf8.c"));
+}
+
+#[test]
+fn test_par_iter_files() {
+    let extensions = vec!["c".to_string(), "h".to_string()];
+    let root = std::path::Path::new("./third_party");
+    let mut expected: Vec<_> = weggli::iter_files(root, extensions.clone()).collect();
+    expected.sort();
+    assert!(!expected.is_empty());
+
+    // The same files, in no particular order.
+    let mut files: Vec<_> = weggli::par_iter_files(root, extensions, true).collect();
+    files.sort();
+    assert_eq!(files, expected);
+
+    // Stopping early doesn't block the walk.
+    assert_eq!(
+        weggli::par_iter_files(root, vec!["c".to_string()], true)
+            .take(1)
+            .count(),
+        1
+    );
+}
+
+#[test]
+fn test_functions() {
+    let options = weggli::SearchOptions::default();
+    let functions = std::sync::Mutex::new(Vec::new());
+    weggli::functions(["./third_party/examples/malloc.c"], &options, |f| {
+        functions.lock().unwrap().push(f);
+    });
+    let mut functions = functions.into_inner().unwrap();
+    functions.sort_by_key(|f| f.range.start);
+
+    let lock = &functions[0];
+    assert_eq!(lock.name.as_deref(), Some("lock"));
+    assert_eq!(lock.signature, "static inline void lock(volatile int *lk)");
+    assert_eq!(lock.lines.start, 61);
+    assert!(functions
+        .iter()
+        .any(|f| f.name.as_deref() == Some("bin_index")));
+}
+
+#[test]
+fn test_result_pages() {
+    let files = vec![
+        ("b.c", "void b() { free(p); free(q); }"),
+        ("a.c", "void a() { free(x); free(y); free(z); }"),
+    ];
+    let qt = weggli::parse_search_pattern("free($p);", false, false, None).unwrap();
+    let results = weggli::vfs::ResultSet::new(weggli::vfs::search(&[qt], files, false));
+    assert_eq!(results.len(), 5);
+
+    let mut values = Vec::new();
+    let mut cursor = None;
+    loop {
+        let page = results.page(cursor.as_deref(), 2).unwrap();
+        assert!(page.matches.len() <= 2);
+        values.extend(page.matches.iter().map(|m| {
+            let v = m.result.value("$p", &m.source).unwrap();
+            format!("{}:{}", m.name, v)
+        }));
+        match page.next {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+    assert_eq!(values, ["a.c:x", "a.c:y", "a.c:z", "b.c:p", "b.c:q"]);
+
+    let next = results.page(None, 2).unwrap().next.unwrap();
+    let empty = weggli::vfs::ResultSet::new(Vec::new());
+    assert!(empty.page(None, 10).unwrap().next.is_none());
+    assert!(empty.page(Some(&next), 10).is_err());
+    assert!(results.page(Some("foo"), 10).is_err());
+}
+
+#[test]
+fn test_number_constraints() {
+    let source = r#"
+    void foo(char *src, int n) {
+        char small[16];
+        char large[0x2000];
+        memcpy(small, src, 16);
+        memcpy(large, src, 8192);
+        memcpy(large, src, n);
+    }
+    "#;
+
+    let matches = |needle: &str, constraints: &[&str]| {
+        let constraints = constraints.iter().map(|c| c.parse().unwrap()).collect();
+        let map = weggli::QueryOptions::default().with_number_constraints(constraints);
+        let qt = weggli::parse_search_pattern(needle, false, false, Some(map)).unwrap();
+        let source_tree = weggli::parse(source, false);
+        qt.matches(source_tree.root_node(), source).len()
+    };
+
+    assert_eq!(matches("memcpy(_, _, $n);", &[]), 1);
+    assert_eq!(matches("memcpy(_, _, $n);", &["n>16"]), 1);
+    assert_eq!(matches("memcpy(_, _, $n);", &["$n>=16"]), 2);
+    assert_eq!(matches("memcpy(_, _, $n);", &["n!=0x10"]), 1);
+    assert_eq!(matches("memcpy(_, _, $n[>16]);", &[]), 1);
+    assert_eq!(matches("memcpy(_, _, $n[ <= 16 ]);", &[]), 1);
+    assert_eq!(matches("{char $buf[$n[>4096]];}", &[]), 1);
+    assert_eq!(
+        matches("{char $buf[$n[>4096]]; memcpy($buf, _, $n);}", &[]),
+        1
+    );
+    assert_eq!(
+        matches("{char $buf[$n]; memcpy($buf, _, $n);}", &["n<100"]),
+        1
+    );
+
+    assert!(weggli::parse_search_pattern("memcpy(_, _, $n[>x]);", false, false, None).is_err());
+    assert!("n>".parse::<weggli::NumberConstraint>().is_err());
+    assert!(">5".parse::<weggli::NumberConstraint>().is_err());
+}
+
+#[test]
+fn test_capture_details() {
+    use weggli::result::Position;
+
+    let source = "void f() {\n  int n = 10;\n  memcpy(dst,\n         src, n);\n}\n";
+    let qt = weggli::parse_search_pattern("memcpy($a, _, $n);", false, false, None).unwrap();
+    let tree = weggli::parse(source, false);
+    let results = qt.matches(tree.root_node(), source);
+    assert_eq!(results.len(), 1);
+
+    let details = results[0].capture_details(&qt, source);
+    let dst = details.iter().find(|d| d.variable == Some("$a")).unwrap();
+    assert_eq!(dst.kind, "identifier");
+    assert_eq!(dst.text, "dst");
+    assert_eq!(dst.range, 34..37);
+    assert_eq!(
+        dst.start,
+        Position {
+            line: 3,
+            column: 10
+        }
+    );
+    assert_eq!(
+        dst.end,
+        Position {
+            line: 3,
+            column: 13
+        }
+    );
+
+    let n = details.iter().find(|d| d.variable == Some("$n")).unwrap();
+    assert_eq!(n.text, "n");
+    assert_eq!(
+        n.start,
+        Position {
+            line: 4,
+            column: 15
+        }
+    );
+
+    // The first capture is the enclosing function.
+    assert_eq!(details[0].variable, None);
+    assert_eq!(details[0].kind, "function_definition");
+    assert_eq!(details[0].start, Position { line: 1, column: 1 });
+    assert_eq!(details[0].end, Position { line: 5, column: 2 });
+}
+
+#[test]
+fn test_language() {
+    use weggli::language;
+
+    let cpp = language::by_name("cpp").unwrap();
+    assert!(cpp.is_cpp());
+    assert!(language::by_name("cobol").is_none());
+
+    let source = "void f() { obj->method(buf); ns::method(buf); }";
+    let qt = weggli::parse_search_pattern_with("method($b);", cpp, false, None).unwrap();
+    assert_eq!(qt.matches(cpp.parse(source).root_node(), source).len(), 2);
+}
+
+#[cfg(feature = "javascript")]
+#[test]
+fn test_javascript() {
+    let js = weggli::language::by_name("javascript").unwrap();
+    let matches = |pattern: &str, source: &str| {
+        let qt = weggli::parse_search_pattern_with(pattern, js, false, None).unwrap();
+        qt.matches(js.parse(source).root_node(), source).len()
+    };
+
+    let source = "function f(url) { let r = fetch(url); el.innerHTML = r.text; }";
+    assert_eq!(
+        matches("{$v = fetch($u); _.innerHTML = $v.text;}", source),
+        1
+    );
+    assert_eq!(matches("{$v = fetch($u); _.innerHTML = $u;}", source), 0);
+    assert_eq!(
+        matches("eval(_);", "const g = () => { window.eval(code); };"),
+        1
+    );
+
+    let source = "function h() { var d = open(); d.close(); d.write(1); }";
+    assert_eq!(matches("{$d = open(); $d.write(1);}", source), 1);
+    assert_eq!(
+        matches("{$d = open(); not: $d.close(); $d.write(1);}", source),
+        0
+    );
+}
+
+#[test]
+fn test_multi_arg_subexpr() {
+    let source = r"
+    void f(char *buf, int len, int off) {
+        int total = len;
+        memcpy(buf, src, len + off);
+        if (off > 10) {
+            read(fd, buf + off, total);
+        }
+        x = g(len) * h(off);
+    }";
+
+    assert_eq!(parse_and_match("memcpy(_, _, _(len, off));", source), 1);
+    // Variables can match on the same identifier.
+    assert_eq!(parse_and_match("memcpy(_, _, _($len, off));", source), 2);
+    assert_eq!(parse_and_match("memcpy(_, _, _(len, buf));", source), 0);
+    assert_eq!(parse_and_match("_(len, off) = _;", source), 0);
+    assert_eq!(parse_and_match("$x = _(len, off);", source), 1);
+    // At the statement level, both need to appear in the same statement.
+    assert_eq!(parse_and_match("{_(len, off);}", source), 2);
+    assert_eq!(parse_and_match("{_(buf, total);}", source), 1);
+    assert_eq!(parse_and_match("{int $t = len; _(buf, $t);}", source), 1);
+}
+
+#[test]
+fn test_parsed_source() {
+    let parsed = weggli::ParsedSource::new("void f() { memcpy(a, b, 10); }", false);
+    assert!(parsed
+        .sexp()
+        .starts_with("(translation_unit (function_definition"));
+    assert_eq!(parsed.root().kind(), "translation_unit");
+
+    let qt = weggli::parse_search_pattern("memcpy($a, _, _);", false, false, None).unwrap();
+    let results = parsed.matches(&qt);
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].value("$a", parsed.source()), Some("a"));
+}
+
+#[test]
+fn test_argument_ellipsis() {
+    let source = r"
+    void f(char *dst, char *src, int len) {
+        memcpy(dst, src, len);
+        memcpy(dst, len, src);
+        copy(dst, src, 0, len);
+        copy(len, dst);
+        g();
+    }";
+
+    let count = |needle: &str, source: &str| {
+        let qt = weggli::parse_search_pattern(needle, false, false, None).unwrap();
+        let source_tree = weggli::parse(source, false);
+        qt.matches(source_tree.root_node(), source).len()
+    };
+
+    assert_eq!(count("memcpy(dst, ..., len);", source), 1);
+    assert_eq!(count("$f(dst, ..., len);", source), 2);
+    assert_eq!(count("$f(dst, $...);", source), 3);
+    assert_eq!(count("$f(..., dst);", source), 1);
+    assert_eq!(count("copy(..., src, ...);", source), 1);
+    assert_eq!(count("$f(dst, ..., src, ...);", source), 3);
+    assert_eq!(count("$f(dst, ..., ...);", source), 3);
+    assert_eq!(count("$f(...);", source), 5);
+    assert_eq!(count("g(...);", source), 1);
+    // Without `...`, single arguments are still unanchored.
+    assert_eq!(count("copy(dst);", source), 2);
+
+    let variadic = "int printf(const char *fmt, ...);";
+    assert_eq!(count("int printf(const char *$f, ...);", variadic), 1);
+
+    // Arguments next to `...` are bound at the right positions, whatever their kind.
+    let source = r"
+    void f(struct s *a, char *d, int n) {
+        memcpy(a->b, d, n);
+        memcpy(d, s.t, foo(n));
+        memcpy(d, d, s.t);
+        memcpy(get(d), n);
+    }";
+    let values = |needle: &str, var: &str| -> Vec<String> {
+        let qt = weggli::parse_search_pattern(needle, false, false, None).unwrap();
+        let source_tree = weggli::parse(source, false);
+        qt.matches(source_tree.root_node(), source)
+            .iter()
+            .map(|r| r.value(var, source).unwrap().to_string())
+            .collect()
+    };
+    assert_eq!(values("memcpy(..., $n);", "$n"), ["n", "s.t", "n"]);
+    assert_eq!(values("memcpy(..., _, $n);", "$n"), ["n", "s.t", "n"]);
+    assert_eq!(values("memcpy($d, ..., $n);", "$d"), ["a->b", "d"]);
+    assert_eq!(values("memcpy($d, _, $n);", "$d"), ["a->b", "d"]);
+    assert_eq!(values("memcpy($d, ...);", "$d"), ["a->b", "d", "d"]);
+    assert_eq!(values("memcpy(get($d), ...);", "$d"), ["d"]);
+    assert_eq!(values("memcpy(..., $x, _);", "$x"), ["d", "s.t", "d"]);
+    assert_eq!(values("memcpy(_, ..., foo($x));", "$x"), ["n"]);
+    assert_eq!(values("memcpy(..., $x, ...);", "$x").len(), 9);
+    assert_eq!(count("memcpy(..., _, _, _, _);", source), 0);
+}
+
+#[test]
+fn test_count_quantifier() {
+    let source = r"
+    void f(char *p, char *q) {
+        use(p);
+        use(q);
+        use(p);
+        if (p) {
+            use(p);
+        }
+        free(q);
+    }
+    void g(char *p) {
+        init(p);
+        free(p);
+        free(p);
+    }
+    void h(char *p) {
+        init(p);
+        use(p);
+    }";
+
+    let count = |needle: &str| {
+        let qt = weggli::parse_search_pattern(needle, false, false, None).unwrap();
+        let source_tree = weggli::parse(source, false);
+        qt.matches(source_tree.root_node(), source).len()
+    };
+
+    assert_eq!(count("{count(>=3): $f($x);}"), 1);
+    assert_eq!(count("{count(>=3): use($x);}"), 1);
+    assert_eq!(count("{count(==1): use($x);}"), 2);
+    assert_eq!(count("{count(>1): free($p);}"), 1);
+    assert_eq!(count("count(>= 2): free($p);"), 1);
+    // Counted statements are ordered like all other statements.
+    assert_eq!(count("{free($p); count(>=2): use($p);}"), 0);
+    assert_eq!(count("{init($p); count(>=2): free($p);}"), 1);
+    // Quantifiers that allow zero occurrences also match if the statement is missing.
+    assert_eq!(count("{init($p); count(<1): free($p);}"), 1);
+    assert_eq!(count("{init($p); count(<=2): free($p);}"), 2);
+
+    let err =
+        weggli::parse_search_pattern("{count(>=99999999999999999999): f();}", false, false, None);
+    assert!(err.is_err());
+}
+
+#[test]
+fn test_variable_constraints() {
+    let source = r#"
+    void foo(char *a, char *b, int n) {
+        memcpy(a, b, n);
+        memcpy(a, a, n);
+        memcpy(b, b, b);
+    }
+    "#;
+
+    let matches = |needle: &str, constraints: &[&str]| {
+        let constraints = constraints.iter().map(|c| c.parse().unwrap()).collect();
+        let map = weggli::QueryOptions::default().with_variable_constraints(constraints);
+        let qt = weggli::parse_search_pattern(needle, false, false, Some(map)).unwrap();
+        let source_tree = weggli::parse(source, false);
+        qt.matches(source_tree.root_node(), source).len()
+    };
+
+    assert_eq!(matches("memcpy($dst, $src, $len);", &[]), 3);
+    assert_eq!(matches("memcpy($dst, $src, $len);", &["$dst!=$src"]), 1);
+    assert_eq!(matches("memcpy($dst, $src, $len);", &["$dst==$src"]), 2);
+    assert_eq!(
+        matches("memcpy($dst, $src, $len);", &["$dst==$src", "$src != $len"]),
+        1
+    );
+    // Constraints on variables that are not bound by a result are ignored.
+    assert_eq!(matches("memcpy($dst, $src, _);", &["$dst!=$len"]), 3);
+
+    assert!("$a<$b".parse::<weggli::VariableConstraint>().is_err());
+    assert!("$a==b".parse::<weggli::VariableConstraint>().is_err());
+    assert!("$a!=".parse::<weggli::VariableConstraint>().is_err());
+}
+
+#[test]
+fn test_normalization() {
+    let qt = weggli::parse_search_pattern("memcpy(_, _, $n)", false, false, None).unwrap();
+    assert_eq!(qt.pattern(), "{memcpy(_, _, $n);}");
+    assert_eq!(
+        qt.normalizations(),
+        &[
+            weggli::Normalization::AddSemicolon,
+            weggli::Normalization::AddBraces
+        ]
+    );
+
+    let qt = weggli::parse_search_pattern("{memcpy(_, _, $n[>10]);}", false, false, None).unwrap();
+    assert_eq!(qt.pattern(), "{memcpy(_, _, $n[>10]);}");
+    assert!(qt.normalizations().is_empty());
+
+    let strict = || Some(weggli::QueryOptions::default().without_normalization());
+    assert!(weggli::parse_search_pattern("memcpy(_, _, $n)", false, false, strict()).is_err());
+    assert!(weggli::parse_search_pattern("memcpy(_, _, $n);", false, false, strict()).is_err());
+    assert!(weggli::parse_search_pattern("{memcpy(_, _, $n);}", false, false, strict()).is_ok());
+}
+
+#[test]
+fn test_join() {
+    use std::collections::HashMap;
+    use weggli::result::{join_keys, JoinMode};
+
+    type Vars = HashMap<String, String>;
+    let vars = |v: &[(&str, &str)]| -> Vars {
+        v.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    };
+    let modes: HashMap<String, JoinMode> = [("$d".to_string(), JoinMode::Differ)].into();
+    let chainable = |a: &Vars, b: &Vars| {
+        a.keys().chain(b.keys()).all(|k| {
+            modes
+                .get(k)
+                .unwrap_or(&JoinMode::Equal)
+                .compatible(a.get(k).map(|v| v.as_str()), b.get(k).map(|v| v.as_str()))
+        })
+    };
+
+    let mut results = vec![
+        vec![
+            vars(&[("$x", "buf /* dst */"), ("$n", "16"), ("$d", "a")]),
+            vars(&[("$x", "other"), ("$n", "16"), ("$d", "a")]),
+            vars(&[("$x", "buf"), ("$n", "8"), ("$d", "a")]),
+            vars(&[("$x", "buf"), ("$n", "16"), ("$d", "b")]),
+        ],
+        vec![
+            vars(&[("$x", "buf"), ("$n", "0x10"), ("$d", "b")]),
+            vars(&[("$n", "8"), ("$d", "b")]),
+        ],
+    ];
+    weggli::vfs::join(
+        &mut results,
+        |r| join_keys(r.iter().map(|(k, v)| (k, v.as_str())), &modes),
+        |a, b| chainable(a, b),
+    );
+
+    assert_eq!(
+        results[0],
+        [
+            vars(&[("$x", "buf /* dst */"), ("$n", "16"), ("$d", "a")]),
+            vars(&[("$x", "buf"), ("$n", "8"), ("$d", "a")]),
+        ]
+    );
+    assert_eq!(results[1].len(), 2);
+}
+
+#[test]
+fn test_comment_root() {
+    let source = r"
+    /* TODO: check for overflow */
+    void f(char *a, int n) {
+        memcpy(a, b, n);
+    }
+    // TODO: integer overflow
+    // in the size calculation
+    void g(char *a, int n) {
+        memcpy(a, b, n + 1);
+    }
+    /* TODO: overflow */
+    int x;
+    void h(char *a, int n) {
+        memcpy(a, b, n);
+    }
+    /* FIXME: overflow */
+    void k(char *a, int n) {
+        strcpy(a, b);
+    }";
+
+    let matches = |needle: &str| {
+        let qt = weggli::parse_search_pattern(needle, false, false, None).unwrap();
+        let source_tree = weggli::parse(source, false);
+        qt.matches(source_tree.root_node(), source)
+            .iter()
+            .map(|m| m.value("$f", source).unwrap_or("").to_string())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        matches("/* TODO.*overflow */ _ $f(_) {memcpy(_, _, _);}"),
+        ["f", "g"]
+    );
+    assert_eq!(matches("// overflow\n_ $f(_) {_;}"), ["f", "g", "k"]);
+    assert_eq!(
+        matches("/* ^FIXME */ _ $f(_) {memcpy(_, _, _);}"),
+        Vec::<String>::new()
+    );
+    assert_eq!(matches("/* size */ _ $f(_) {memcpy(_, _, _);}"), ["g"]);
+
+    // The comment is captured and kept in the normalized pattern.
+    let qt =
+        weggli::parse_search_pattern("/* ^TODO */ memcpy(_, _, $n)", false, false, None).unwrap();
+    assert_eq!(qt.pattern(), "/* ^TODO */ {memcpy(_, _, $n);}");
+    let source_tree = weggli::parse(source, false);
+    let results = qt.matches(source_tree.root_node(), source);
+    assert_eq!(results.len(), 1);
+    assert!(results[0]
+        .display(source, 0, 0, false)
+        .contains("/* TODO: check for overflow */"));
+
+    assert!(weggli::parse_search_pattern("/* TODO[ */ {_;}", false, false, None).is_err());
+}
+
+#[test]
+fn test_asm() {
+    let source = r#"
+    void load(unsigned long x) {
+        asm volatile("mov %0, %%cr3" :: "r"(x) : "memory");
+        flush(x);
+    }
+    void other(void) {
+        __asm__ __volatile__("cli");
+        asm("nop");
+    }
+    unsigned long read(void) {
+        unsigned long v;
+        __asm__("mov %%cr3, %0"
+                : "=r"(v));
+        return v;
+    }"#;
+
+    let matches = |needle: &str| {
+        let qt = weggli::parse_search_pattern(needle, false, false, None).unwrap();
+        let source_tree = weggli::parse(source, false);
+        qt.matches(source_tree.root_node(), source)
+            .iter()
+            .map(|m| m.value("$f", source).unwrap_or("").to_string())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(matches("_ $f(_) {asm(\"cr3\");}"), ["load", "read"]);
+    assert_eq!(matches("_ $f(_) {asm(\"%%cr3, %0\");}"), ["read"]);
+    assert_eq!(matches("_ $f(_) {asm(\"cr3\"); flush(_);}"), ["load"]);
+    assert_eq!(matches("_ $f(_) {asm(\"cli\"); asm(\"nop\");}"), ["other"]);
+    assert_eq!(matches("_ $f(_) {__asm__(\"sti\");}"), Vec::<String>::new());
+
+    // Operands don't end up in the tree, the rest of the function still parses.
+    assert_eq!(matches("_ $f(_) {return $v;}"), ["read"]);
+
+    assert!(weggli::parse_search_pattern("{asm(\"(\");}", false, false, None).is_err());
+}
+
+#[test]
+fn test_display_long_line() {
+    colored::control::set_override(false);
+    let filler = "a = b + c; ".repeat(1000);
+    let source = format!(
+        "int x;\nint f() {{ {}memcpy(dst, src, len); {}}}\nint y;\n",
+        filler, filler
+    );
+
+    let qt = weggli::parse_search_pattern("{memcpy($d, _, _);}", false, false, None).unwrap();
+    let source_tree = weggli::parse(&source, false);
+    let results = qt.matches(source_tree.root_node(), &source);
+    assert_eq!(results.len(), 1);
+
+    let display = results[0].display(&source, 0, 0, true);
+    assert!(display.len() < 1000);
+    assert!(display.starts_with("   2: int f() { a = b + c;"));
+    assert!(display.contains("a = b + c; memcpy(dst, src, len); a = b + c;"));
+    assert_eq!(display.matches("...").count(), 2);
+    assert!(display.ends_with("..."));
+}
+
+#[test]
+fn serialize_query() {
+    let source = r#"
+    void a(char *p) {
+        char buf[16];
+        memcpy(buf, p, 32);
+    }
+    void b(char *p) {
+        char tmp[16];
+        if (check(p)) return;
+        memcpy(tmp, p, 32);
+    }
+    void c(char *p) {
+        char buf[16];
+        memcpy(buf, p, 8);
+    }"#;
+
+    let mut regexes = std::collections::HashMap::new();
+    regexes.insert("$f".to_string(), (true, regex::Regex::new("^c$").unwrap()));
+    let constraints = weggli::RegexMap::new(regexes).into();
+    let needle = "_ $f(_) {char $b[_]; not: check(_); memcpy($b, _, 32);}";
+    let qt = weggli::parse_search_pattern(needle, false, false, Some(constraints)).unwrap();
+
+    let json = serde_json::to_string(&qt).unwrap();
+    let loaded: weggli::query::QueryTree = serde_json::from_str(&json).unwrap();
+
+    let source_tree = weggli::parse(source, false);
+    let functions = |qt: &weggli::query::QueryTree| {
+        qt.matches(source_tree.root_node(), source)
+            .iter()
+            .map(|m| m.value("$f", source).unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(functions(&qt), ["a"]);
+    assert_eq!(functions(&loaded), functions(&qt));
+    assert_eq!(loaded.pattern(), qt.pattern());
+    assert_eq!(loaded.variables(), qt.variables());
+
+    // The generated queries change between versions.
+    let old = json.replace(env!("CARGO_PKG_VERSION"), "0.0.1");
+    let err = serde_json::from_str::<weggli::query::QueryTree>(&old).unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("query was compiled by weggli 0.0.1"));
+}
+
+#[test]
+fn test_uniqueness() {
+    let source = r#"
+    void foo(char *a, char *b, int n) {
+        memcpy(a, b, n);
+        memcpy(a->p, a -> p, n);
+        if (n) {
+            free(a);
+            use(b, a);
+        }
+    }
+    "#;
+
+    let matches = |needle: &str, uniqueness: weggli::Uniqueness| {
+        let map = weggli::QueryOptions::default().with_uniqueness(uniqueness);
+        let qt = weggli::parse_search_pattern(needle, false, false, Some(map)).unwrap();
+        let source_tree = weggli::parse(source, false);
+        qt.matches(source_tree.root_node(), source).len()
+    };
+
+    use weggli::Uniqueness::*;
+    assert_eq!(matches("memcpy($dst, $src, _);", Any), 2);
+    assert_eq!(matches("memcpy($dst, $src, _);", Distinct), 1);
+    assert_eq!(matches("memcpy($dst, $src, _);", RawDistinct), 2);
+
+    // Enforced when sub-patterns and nested blocks are merged
+    assert_eq!(matches("{memcpy($a, _, _); free($b);}", Any), 2);
+    assert_eq!(matches("{memcpy($a, _, _); free($b);}", Distinct), 1);
+    assert_eq!(
+        matches("{memcpy($a, $b, _); if (_) {use($b, $c);}}", Any),
+        1
+    );
+    assert_eq!(
+        matches("{memcpy($a, $b, _); if (_) {use($b, $c);}}", Distinct),
+        0
+    );
+}
+
+#[test]
+fn test_static_initializer() {
+    let source = r#"
+    static const struct file_operations fops = {
+        .owner = THIS_MODULE,
+        .read = dev_read,
+        .write = NULL,
+    };
+
+    struct ops other = { .read = r2, .write = w2 };
+
+    void foo() {
+        struct ops local = { .read = r3, .write = NULL };
+        bar(dev_read);
+    }
+    "#;
+
+    let needle = "{static const struct file_operations $o = { .read = $f, .write = NULL };}";
+    let matches = parse_and_match_helper(needle, source, false);
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].value("$o", source), Some("fops"));
+    assert_eq!(matches[0].value("$f", source), Some("dev_read"));
+
+    // Global declarations are anchored to themselves, local ones to their function
+    let matches = parse_and_match_helper("{_ $o = { .read = $f, .write = NULL };}", source, false);
+    assert_eq!(matches.len(), 2);
+    assert!(source[matches[0].function_range()].starts_with("static const struct"));
+    assert!(source[matches[0].function_range()].ends_with("NULL,\n    };"));
+    assert!(source[matches[1].function_range()].starts_with("void foo()"));
+
+    // Field name variables
+    assert_eq!(
+        parse_and_match("{struct $t $o = { .$field = dev_read };}", source),
+        1
+    );
+    assert_eq!(
+        parse_and_match("{struct ops other = { .$field = $f };}", source),
+        2
+    );
+    assert_eq!(
+        parse_and_match_cpp("{struct ops $o = { .read = $f };}", source),
+        2
+    );
+
+    // Declarations without an initializer list are still only matched in functions
+    assert_eq!(
+        parse_and_match(
+            "{struct ops $o;}",
+            "struct ops o; void f() { struct ops p; }"
+        ),
+        1
+    );
+}
+
+#[test]
+fn test_designated_initializers() {
+    let source = r#"
+    static const struct file_operations a_fops = {
+        .owner = THIS_MODULE,
+        .unlocked_ioctl = a_ioctl,
+        .compat_ioctl = a_compat,
+    };
+    static const struct file_operations b_fops = {
+        .unlocked_ioctl = b_ioctl,
+        .owner = THIS_MODULE,
+    };
+    static const struct file_operations c_fops = {
+        .compat_ioctl = c_ioctl,
+        .unlocked_ioctl = c_ioctl,
+    };
+    "#;
+
+    // not: designators are replaced before the pattern is parsed
+    let results = |needle: &str, source: &str| {
+        let qt = weggli::parse_search_pattern(needle, false, false, None).unwrap();
+        let source_tree = weggli::parse(source, false);
+        qt.matches(source_tree.root_node(), source)
+    };
+    let parse_and_match = |needle: &str, source: &str| results(needle, source).len();
+
+    // Designators match in any order
+    assert_eq!(
+        parse_and_match("{_ $o = { .unlocked_ioctl = $f, .owner = _ };}", source),
+        2
+    );
+    assert_eq!(
+        parse_and_match(
+            "{_ $o = { .compat_ioctl = _, .unlocked_ioctl = $f };}",
+            source
+        ),
+        2
+    );
+
+    // not: excludes lists with a matching element, regardless of its position
+    let needle = "{_ $o = { .unlocked_ioctl = $f, not: .compat_ioctl = _ };}";
+    let matches = results(needle, source);
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].value("$o", source), Some("b_fops"));
+    assert_eq!(
+        parse_and_match(
+            "{_ $o = { not: .compat_ioctl = _, .unlocked_ioctl = $f };}",
+            source
+        ),
+        1
+    );
+    assert_eq!(
+        parse_and_match(
+            "{_ $o = { .unlocked_ioctl = $f, not: .compat_ioctl = $f };}",
+            source
+        ),
+        2
+    );
+    assert_eq!(
+        parse_and_match("{_ $o = { ._ = _, NOT: .owner = _ };}", source),
+        1
+    );
+
+    // The pattern is displayed with the not: label
+    let qt = weggli::parse_search_pattern(needle, false, false, None).unwrap();
+    assert_eq!(qt.pattern(), needle);
+
+    // Positional initializers are still matched in order
+    let source = "int a[] = {1, 2, 3};";
+    assert_eq!(parse_and_match("{int $a[] = {1, 3};}", source), 1);
+    assert_eq!(parse_and_match("{int $a[] = {3, 1};}", source), 0);
+}
+
+#[test]
+fn test_fuzz_match() {
+    let source = b"void f() { char buf[10]; memcpy(buf, src, len); }";
+    let matches = weggli::fuzz_match(b"memcpy($b, _, _);", source).unwrap();
+    assert_eq!(matches.len(), 1);
+    assert_eq!(
+        matches[0].value("$b", std::str::from_utf8(source).unwrap()),
+        Some("buf")
+    );
+
+    // C++ snippets are parsed with the C++ grammar
+    let matches = weggli::fuzz_match(b"{$x->foo();}", b"class A { void f() { b->foo(); } };");
+    assert_eq!(matches.map(|m| m.len()), Some(1));
+
+    // Invalid queries and inputs are rejected
+    assert!(weggli::fuzz_match(b"memcpy(", source).is_none());
+    assert!(weggli::fuzz_match(b"{\xff}", source).is_none());
+    let large = vec![b' '; weggli::FUZZ_MAX_SOURCE_LEN + 1];
+    assert!(weggli::fuzz_match(b"{foo();}", &large).is_none());
+}
+
+#[test]
+fn test_raw_query() {
+    let source = r#"
+    int x = 1;
+    void foo() {
+        char buf[10];
+        memcpy(buf, src, sizeof(buf));
+        if (x) { memcpy(dst, buf, 10); }
+    }"#;
+    let tree = weggli::parse(source, false);
+    let results = |sexp: &str, constraints: Option<weggli::QueryOptions>| {
+        let qt = weggli::parse_raw_query(sexp, false, constraints).unwrap();
+        qt.matches(tree.root_node(), source)
+    };
+
+    let sexp = r#"(call_expression function: (identifier) @f (#eq? @f "memcpy")
+        arguments: (argument_list . (identifier) @dst))"#;
+    let matches = results(sexp, None);
+    assert_eq!(matches.len(), 2);
+    assert_eq!(matches[0].value("$dst", source), Some("buf"));
+    assert_eq!(matches[1].value("$dst", source), Some("dst"));
+    // Results are anchored to the enclosing function
+    assert!(source[matches[0].function_range()].starts_with("void foo()"));
+
+    // Regex constraints apply to the capture variables
+    let mut regexes = std::collections::HashMap::new();
+    regexes.insert(
+        "$dst".to_string(),
+        (false, regex::Regex::new("^d").unwrap()),
+    );
+    let matches = results(sexp, Some(weggli::RegexMap::new(regexes).into()));
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].value("$dst", source), Some("dst"));
+
+    // Patterns are alternatives, global declarations are anchored to themselves
+    let matches = results("(number_literal) @n (sizeof_expression) @s", None);
+    assert_eq!(matches.len(), 4);
+    let global = matches
+        .iter()
+        .find(|m| m.value("$n", source) == Some("1"))
+        .unwrap();
+    assert_eq!(&source[global.function_range()], "int x = 1;");
+
+    assert!(weggli::parse_raw_query("(call_expression", false, None).is_err());
+    assert!(weggli::parse_raw_query("(no_such_node) @x", false, None).is_err());
+    assert!(weggli::parse_raw_query("(call_expression)", false, None).is_err());
+}
@@ -0,0 +1,9 @@
+namespace net {
+
+int Packet::parse(const char *src, size_t size) {
+    char buf[10];
+    memcpy(buf, src, size);
+    return 0;
+}
+
+}
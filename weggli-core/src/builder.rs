@@ -0,0 +1,1289 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use std::collections::HashSet;
+
+use crate::capture::{add_capture, ArgumentPosition, Capture};
+use crate::language::{self, Language};
+use crate::query::{CompiledQuery, NegativeQuery, Quantifier, QueryTree};
+use crate::util::parse_number_literal;
+use crate::{Anchor, Comparison, QueryError, QueryOptions};
+use colored::Colorize;
+use regex::Regex;
+use tree_sitter::{Node, TreeCursor};
+
+/// Identifier that replaces `...` wildcards in argument lists before the
+/// query is parsed, see `parse_search_pattern`.
+pub(crate) const ELLIPSIS: &str = "$__ellipsis";
+
+/// Array designator that replaces `not:` in front of a field designator in an
+/// initializer list ({.read = $f, not: .write = _}), see `parse_search_pattern`.
+pub(crate) const NOT_DESIGNATOR: &str = "$__not";
+
+// Operators of count(..): quantifiers and their names in the replacement label.
+const COUNT_OPERATORS: [(&str, &str, Comparison); 6] = [
+    ("<", "lt", Comparison::Less),
+    ("<=", "le", Comparison::LessEqual),
+    (">", "gt", Comparison::Greater),
+    (">=", "ge", Comparison::GreaterEqual),
+    ("==", "eq", Comparison::Equal),
+    ("!=", "ne", Comparison::NotEqual),
+];
+
+/// Returns the label that replaces the quantifier `count(<op><n>):` before the
+/// query is parsed, e.g. count_ge_3 for count(>=3).
+pub(crate) fn count_label(op: &str, n: usize) -> Option<String> {
+    let (_, name, _) = COUNT_OPERATORS.iter().find(|(o, _, _)| *o == op)?;
+    Some(format!("count_{}_{}", name, n))
+}
+
+// Parse a label created by `count_label` into the operator symbol, the
+// comparison and the number of occurrences.
+fn parse_count_label(label: &str) -> Option<(&'static str, Comparison, usize)> {
+    let (name, n) = label.strip_prefix("count_")?.split_once('_')?;
+    let (op, _, comparison) = COUNT_OPERATORS.iter().find(|(_, o, _)| *o == name)?;
+    Some((op, *comparison, n.parse().ok()?))
+}
+
+/// Translate a parsed and validated input source (specified by `source` and `cursor`) into a `QueryTree`.
+/// When `is_cpp` is set, C++ specific features are enabled.
+pub fn build_query_tree(
+    source: &str,
+    cursor: &mut TreeCursor,
+    is_cpp: bool,
+    options: Option<QueryOptions>,
+) -> Result<QueryTree, QueryError> {
+    build_query_tree_with(source, cursor, language::for_cpp(is_cpp), options)
+}
+
+/// Translate a parsed and validated input source into a `QueryTree` for `language`.
+/// `source` needs to be parsed with the same language.
+pub fn build_query_tree_with(
+    source: &str,
+    cursor: &mut TreeCursor,
+    language: &'static dyn Language,
+    options: Option<QueryOptions>,
+) -> Result<QueryTree, QueryError> {
+    _build_query_tree(source, cursor, 0, language, false, false, options)
+}
+
+fn _build_query_tree(
+    source: &str,
+    c: &mut TreeCursor,
+    id: usize,
+    language: &'static dyn Language,
+    is_multi_pattern: bool,
+    strict_mode: bool,
+    options: Option<QueryOptions>,
+) -> Result<QueryTree, QueryError> {
+    let mut b = QueryBuilder {
+        query_source: source.to_string(),
+        captures: Vec::new(),
+        negations: Vec::new(),
+        id,
+        language,
+        options: options.unwrap_or_default(),
+        size_variables: array_size_variables(source, language),
+        block_capture: None,
+    };
+
+    // Skip the root node if it's a translation_unit.
+    if c.node().kind() == language.root_kind() {
+        debug!("query cursor specifies {}", language.root_kind());
+        c.goto_first_child();
+    }
+
+    let kind = c.node().kind();
+
+    let mut variables = HashSet::new();
+
+    // Source of each sub-pattern and the sub-pattern that created each capture.
+    let mut clauses = Vec::new();
+    let mut capture_clauses = Vec::new();
+
+    // count(..): quantifiers of the patterns of a compound query.
+    let mut counts = Vec::new();
+
+    // Sub-patterns that don't need to match for the whole query to match.
+    let mut optional_clauses = Vec::new();
+
+    let sexp = if !is_multi_pattern {
+        clauses.push(b.clause_text(c.node()));
+
+        // We want to wrap queries into a function_definition so we can easily
+        // extract the function that contains a match. Of course we should not do that
+        // if the user specifies a function_definition as part of the query.
+        let needs_anchor = c.node().kind() == language.block_kind() && id == 0;
+        debug!("query needs anchor: {}", needs_anchor);
+        let global_initializer = needs_anchor && is_initializer_declaration(c.node());
+
+        // The main work happens here. Iterate through the AST and create a tree-sitter query
+        let mut s = b.build(c, 0, strict_mode, kind)?;
+
+        // Make sure user supplied function headers are displayed by adding a Capture
+        if !needs_anchor {
+            s += "@";
+            s += &add_capture(&mut b.captures, Capture::Display);
+        }
+
+        // Iterate through all captures, add their constraints to the query and extract used variables
+        let predicates = process_captures(&b.captures, 0, &mut variables, &b.options);
+
+        // Optionally anchor query with a function_definition or the node
+        // selected with --anchor
+        if needs_anchor {
+            let capture = add_capture(&mut b.captures, Capture::Display);
+            // The sub query for the statements of the compound statement, if any.
+            let block_capture = match &b.block_capture {
+                Some(c) => format!(" @{}", c),
+                None => String::new(),
+            };
+            // The sub query is moved to the anchor node.
+            let anchor =
+                |node: &str| format!("{} @{}{}{}", node, capture, block_capture, predicates);
+            match b.options.anchor() {
+                Anchor::Function => {
+                    let block =
+                        format!("({}){}{}", language.block_kind(), block_capture, predicates);
+                    let mut functions: Vec<String> = language
+                        .function_kinds()
+                        .iter()
+                        .map(|f| format!("({} body: {}) @{}", f, block, capture))
+                        .collect();
+                    // Static initializers (e.g. tables of function pointers) are usually
+                    // global, so they are also matched outside of functions. The
+                    // declaration itself is the anchor.
+                    if global_initializer {
+                        for scope in language.global_scope_kinds() {
+                            functions.push(format!("({} {})", scope, anchor("(declaration)")));
+                        }
+                    }
+                    alternatives(&functions)
+                }
+                Anchor::Class => format!("({})", anchor(&any_kind(language.class_kinds()))),
+                Anchor::File => format!("({})", anchor(&format!("({})", language.root_kind()))),
+                Anchor::Compound => {
+                    format!("({})", anchor(&format!("({})", language.block_kind())))
+                }
+            }
+        } else {
+            "(".to_string() + &s + &predicates + ")"
+        }
+    } else {
+        // When building a QueryTree for a compound statement, we create a tree-sitter
+        // query with multiple root patterns for efficient searching.
+        // This code is only executed when creating sub queries so we can skip
+        // the whole anchoring logic needed for the single pattern case.
+
+        assert!(c.goto_first_child());
+        assert!(c.goto_next_sibling());
+
+        // Each pattern is a list of alternatives, their predicates and an optional
+        // count(..): quantifier. Statements with an or: label are added as an
+        // alternative to the previous statement.
+        let mut patterns: Vec<(Vec<String>, String, Option<Quantifier>)> = Vec::new();
+        // The sub-patterns (indices into `clauses`) of each pattern.
+        let mut pattern_clauses: Vec<Vec<usize>> = Vec::new();
+        loop {
+            let child = c.node();
+            if !c.goto_next_sibling() {
+                break;
+            }
+            // Commas between the elements of an initializer list.
+            if !child.is_named() {
+                continue;
+            }
+            if b.is_negated_designator(child) {
+                b.build_negative_designator(child)?;
+                continue;
+            }
+
+            let before = b.captures.len();
+            let mut cursor = child.walk();
+
+            let child_sexp = b.build(&mut cursor, 0, strict_mode, kind)?;
+
+            capture_clauses.resize(b.captures.len(), clauses.len());
+            clauses.push(b.clause_text(child));
+
+            let captures = &process_captures(&b.captures, before, &mut variables, &b.options);
+
+            if child_sexp.is_empty() {
+                continue;
+            }
+
+            let clause = clauses.len() - 1;
+            if b.is_label(child, "OR") {
+                match (patterns.last_mut(), pattern_clauses.last_mut()) {
+                    (Some((alternatives, predicates, _)), Some(pattern_clauses)) => {
+                        alternatives.push(child_sexp);
+                        *predicates += captures;
+                        pattern_clauses.push(clause);
+                    }
+                    _ => {
+                        return Err(QueryError {
+                            message: "or: needs to follow another statement".to_string(),
+                        })
+                    }
+                }
+            } else {
+                let count = b.count_quantifier(child);
+                patterns.push((vec![child_sexp], captures.to_string(), count));
+                pattern_clauses.push(vec![clause]);
+            }
+        }
+
+        counts = patterns.iter().map(|(_, _, count)| *count).collect();
+
+        // Only one of the alternatives of an or: group has to match and patterns with
+        // a count(..): quantifier such as ==0 or <n don't have to match at all.
+        optional_clauses = vec![false; clauses.len()];
+        for ((alternatives, _, count), pattern_clauses) in patterns.iter().zip(&pattern_clauses) {
+            let allows_zero = count.map_or(false, |(op, n)| op.compare(0, n));
+            if alternatives.len() > 1 || allows_zero {
+                for &clause in pattern_clauses {
+                    optional_clauses[clause] = true;
+                }
+            }
+        }
+
+        patterns
+            .into_iter()
+            .map(|(alternatives, predicates, _)| match alternatives.len() {
+                1 => format!("({} {})", alternatives[0], predicates),
+                _ => {
+                    // tree-sitter fails to match some nested alternations, so we flatten them.
+                    let alternatives: Vec<&str> =
+                        alternatives.iter().map(|a| strip_alternation(a)).collect();
+                    format!("([{}] {})", alternatives.join(" "), predicates)
+                }
+            })
+            .collect()
+    };
+
+    debug!("tree_sitter query {}: {}", id, sexp);
+
+    let comment = match b.options.comment() {
+        Some(regex) if id == 0 => {
+            let capture = b.captures.len() as u32;
+            b.captures.push(Capture::Display);
+            Some((regex.clone(), capture))
+        }
+        _ => None,
+    };
+
+    capture_clauses.resize(b.captures.len(), 0);
+
+    let mut qt = QueryTree::new(
+        CompiledQuery::new(sexp, language)?,
+        b.captures,
+        variables,
+        b.negations,
+        id,
+    );
+    qt.set_length_constraints(b.options.length_constraints());
+    qt.set_number_constraints(b.options.number_constraints());
+    qt.set_variable_constraints(b.options.variable_constraints());
+    qt.set_clauses(clauses, capture_clauses);
+    qt.set_counts(counts);
+    qt.set_optional_clauses(optional_clauses);
+    if id == 0 {
+        qt.set_anchor(b.options.anchor());
+        qt.set_uniqueness(b.options.uniqueness());
+    }
+    if let Some((regex, capture)) = comment {
+        qt.set_comment(regex, capture);
+    }
+    Ok(qt)
+}
+
+/// Returns a query that matches any of `queries`.
+fn alternatives<S: AsRef<str>>(queries: &[S]) -> String {
+    match queries {
+        [query] => query.as_ref().to_string(),
+        _ => {
+            let queries: Vec<&str> = queries.iter().map(|q| q.as_ref()).collect();
+            format!("[{}]", queries.join(" "))
+        }
+    }
+}
+
+/// Returns true if `block` only contains a declaration with an initializer list,
+/// such as `{static struct ops $o = { .read = $f };}`.
+fn is_initializer_declaration(block: Node) -> bool {
+    let declaration = match block.named_child(0) {
+        Some(d) if block.named_child_count() == 1 && d.kind() == "declaration" => d,
+        _ => return false,
+    };
+    let has_initializer_list = |d: Node| {
+        d.kind() == "init_declarator"
+            && d.child_by_field_name("value").map(|v| v.kind()) == Some("initializer_list")
+    };
+    let mut c = declaration.walk();
+    let found = declaration
+        .children_by_field_name("declarator", &mut c)
+        .any(has_initializer_list);
+    found
+}
+
+/// Returns a query that matches nodes of any of the `kinds`.
+fn any_kind(kinds: &[&str]) -> String {
+    let kinds: Vec<String> = kinds.iter().map(|k| format!("({})", k)).collect();
+    alternatives(&kinds)
+}
+
+/// Returns the content of `sexp` without the surrounding brackets if `sexp` is a single
+/// alternation ([(a) (b)] -> (a) (b)).
+fn strip_alternation(sexp: &str) -> &str {
+    let sexp = sexp.trim();
+    if !sexp.starts_with('[') {
+        return sexp;
+    }
+
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in sexp.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '[' | '(' if !in_string => depth += 1,
+            ']' | ')' if !in_string => {
+                depth -= 1;
+                if depth == 0 {
+                    return if i == sexp.len() - 1 {
+                        &sexp[1..i]
+                    } else {
+                        sexp
+                    };
+                }
+            }
+            _ => (),
+        }
+    }
+    sexp
+}
+
+/// Iterates through `captures` starting at `offset` and returns the necessary query predicates as a string.
+/// In addition, all captured variables are added to the `variables` set.
+///
+/// For constant captures (such as function or variable names), `process_captures` creates a equality predicate
+/// (#eq @0 "memcpy"). Equality between two occurences of the same variable is enforced in
+/// `QueryTree::process_match` because number literals need to be compared by value.
+/// Variables with a set of excluded identifiers get a #not-eq? or #not-match? predicate.
+fn process_captures(
+    captures: &[Capture],
+    offset: usize,
+    variables: &mut HashSet<String>,
+    options: &QueryOptions,
+) -> String {
+    // tree-sitter query predicates
+    let mut sexp = String::new();
+
+    // Note that we need offset to assign the right names to our
+    // capture predicates. So simply passing captures[offset..] to
+    // the function would not work.
+    for (i, c) in captures.iter().skip(offset).enumerate() {
+        match c {
+            Capture::Display => (),
+            Capture::Check(s) => {
+                sexp += &format!(r#"(#eq? @{} "{}")"#, (i + offset), s);
+            }
+            Capture::Variable(var, _) => {
+                // Add var to our result set
+                variables.insert(var.clone());
+
+                // Enforce identifier sets such as $f!{memcpy,memmove}
+                match options.excluded(var) {
+                    [] => (),
+                    [name] => sexp += &format!(r#"(#not-eq? @{} "{}")"#, (i + offset), name),
+                    names => {
+                        sexp += &format!(
+                            r#"(#not-match? @{} "^({})$")"#,
+                            (i + offset),
+                            names.join("|")
+                        )
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    sexp
+}
+
+/// `QueryBuilder` keeps the state we need while building queries.
+struct QueryBuilder {
+    query_source: String,
+    captures: Vec<Capture>, // captures such as variables ($x), constants (memcpy) or sub queries
+    negations: Vec<NegativeQuery>, // all negative sub queries (not: )
+    id: usize,              // a globally unique ID used for caching results see `query.rs`
+    language: &'static dyn Language,
+    options: QueryOptions,
+    size_variables: HashSet<String>, // variables used as array sizes ($n in char buf[$n])
+    block_capture: Option<String>,   // capture of the sub query for the root compound statement
+}
+
+/// Returns all variables that are used as the size of an array declarator in `source`.
+fn array_size_variables(source: &str, language: &dyn Language) -> HashSet<String> {
+    let tree = language.parse(source);
+    let mut result = HashSet::new();
+    let mut c = tree.walk();
+
+    loop {
+        let n = c.node();
+        if n.kind() == "array_declarator" {
+            if let Some(size) = n.child_by_field_name("size") {
+                let text = &source[size.byte_range()];
+                if size.kind() == "identifier" && text.starts_with('$') {
+                    result.insert(text.to_string());
+                }
+            }
+        }
+
+        if c.goto_first_child() {
+            continue;
+        }
+        while !c.goto_next_sibling() {
+            if !c.goto_parent() {
+                return result;
+            }
+        }
+    }
+}
+
+impl QueryBuilder {
+    // Returns true if `n` is a statement with the label `label` (e.g NOT for not: x;)
+    fn is_label(&self, n: tree_sitter::Node, label: &str) -> bool {
+        n.kind() == "labeled_statement"
+            && n.child(0)
+                .map_or(false, |l| self.get_text(&l).to_uppercase() == label)
+    }
+
+    // Map from an AST node to its input source
+    fn get_text(&self, n: &tree_sitter::Node) -> &str {
+        &self.query_source[n.byte_range()]
+    }
+
+    // Returns the quantifier of a statement with a count(..): label
+    fn count_quantifier(&self, n: tree_sitter::Node) -> Option<Quantifier> {
+        if n.kind() != "labeled_statement" {
+            return None;
+        }
+        let (_, comparison, count) = parse_count_label(self.get_text(&n.child(0)?))?;
+        Some((comparison, count))
+    }
+
+    // Source of the (sub-)pattern `n`, with `...` wildcards, count(..): quantifiers
+    // and not: designators restored.
+    fn clause_text(&self, n: tree_sitter::Node) -> String {
+        let text = self
+            .get_text(&n)
+            .replace(ELLIPSIS, "...")
+            .replace(&format!("[{}] ", NOT_DESIGNATOR), "not: ");
+        let label = n.child(0).filter(|_| n.kind() == "labeled_statement");
+        match label.and_then(|l| parse_count_label(self.get_text(&l))) {
+            Some((op, _, count)) => {
+                let label = self.get_text(&label.unwrap());
+                format!("count({}{}){}", op, count, &text[label.len()..])
+            }
+            None => text,
+        }
+    }
+
+    // Returns true if `n` is the NOT_DESIGNATOR of a negated initializer_pair.
+    fn is_not_designator(&self, n: Node) -> bool {
+        n.kind() == "subscript_designator"
+            && n.named_child(0)
+                .map_or(false, |i| self.get_text(&i) == NOT_DESIGNATOR)
+    }
+
+    // Returns true if `n` is an element of an initializer list with a not: label.
+    fn is_negated_designator(&self, n: Node) -> bool {
+        n.kind() == "initializer_pair"
+            && n.child_by_field_name("designator")
+                .map_or(false, |d| self.is_not_designator(d))
+    }
+
+    // Returns true if `n` is an initializer list that only contains designated
+    // initializers ({.read = $f, .write = _}).
+    fn is_designated_initializer_list(&self, n: Node) -> bool {
+        let mut c = n.walk();
+        let result = n.kind() == "initializer_list"
+            && n.named_child_count() > 0
+            && n.named_children(&mut c)
+                .all(|e| e.kind() == "initializer_pair");
+        result
+    }
+
+    // Returns true iff `query` is a wildcard function call _(..)
+    fn is_subexpr_wildcard(&self, query: Node) -> bool {
+        let call = self.language.call();
+        if query.kind() != call.kind {
+            return false;
+        }
+
+        let f = query.child_by_field_name(call.function).unwrap();
+        if f.utf8_text(self.query_source.as_bytes()).unwrap() == "_" {
+            return true;
+        }
+        false
+    }
+
+    // Returns true if `n` is a `...` wildcard, see ELLIPSIS. In parameter lists,
+    // the placeholder is parsed as a parameter declaration.
+    fn is_ellipsis(&self, n: Node) -> bool {
+        self.get_text(&n) == ELLIPSIS
+    }
+
+    // Returns true if one of the arguments in the argument list `n` is a `...` wildcard
+    fn has_ellipsis(&self, n: Node) -> bool {
+        let mut c = n.walk();
+        let result = n.named_children(&mut c).any(|a| self.is_ellipsis(a));
+        result
+    }
+
+    // Returns true if `n` is a comparison binary expression
+    fn is_comparison_binary_exp(&self, n: Node) -> bool {
+        assert!(n.kind() == "binary_expression");
+
+        if let Some(op) = n.child(1) {
+            [">", "<", "<=", ">="].contains(&op.kind())
+        } else {
+            false
+        }
+    }
+
+    // Returns true if `n` is a commutative binary expression
+    fn is_commutative_binary_exp(&self, n: Node) -> bool {
+        assert!(n.kind() == "binary_expression");
+
+        if let Some(op) = n.child(1) {
+            ["+", "*", "&", "|", "==", "!="].contains(&op.kind())
+        } else {
+            false
+        }
+    }
+
+    // Returns true if `n` is a binary expression that we can
+    // automatically transform into a more generic version.
+    fn is_transformable_binary_exp(&self, n: Node) -> bool {
+        self.is_comparison_binary_exp(n) || self.is_commutative_binary_exp(n)
+    }
+
+    /// Translate the tree below `c` into a tree-sitter query string.
+    /// This function is responsible for the weggli's greediness by turning
+    /// the fixed input AST into a tree-sitter query that can match on different but related
+    /// AST's in the queried source code. Besides returning the query string, `build` will
+    /// also add captures and negations to the active QueryBuilder.
+    fn build(
+        &mut self,
+        c: &mut TreeCursor,
+        depth: usize,
+        strict_mode: bool,
+        parent: &'static str,
+    ) -> Result<String, QueryError> {
+        // This function works by recursively processing every node in the tree,
+        // creating new sub queries, captures or negative queries when needed
+        // and slowly constructing the final tree-sitter query (note that query predicates are only
+        // added in build_query_tree after this function returns)
+        // Query building isn't performance critical as it's only done once at program startup, so
+        // we don't have to worry much about optimizing this code.
+
+        // Anonymous nodes are string constants like "+" or "+=".
+        // We can simply copy them into the query.
+        if !c.node().is_named() {
+            return Ok(format!(r#""{}""#, c.node().kind()));
+        }
+
+        let kind = c.node().kind();
+
+        // First handle special cases. Note the implicit fallthroughs to the
+        // default case after this match statement.
+        match kind {
+            "binary_expression" if self.is_transformable_binary_exp(c.node()) => {
+                assert!(c.goto_first_child());
+                let left = self.build(c, depth + 1, strict_mode, kind)?;
+
+                // operator
+                assert!(c.goto_next_sibling());
+                let op = c.node().kind();
+
+                let alt_op = match op {
+                    ">" => "<",
+                    "<" => ">",
+                    "<=" => ">=",
+                    ">=" => "<=",
+                    // handle +, *, &, |, == and !=
+                    _ => op,
+                };
+
+                assert!(c.goto_next_sibling());
+                let right = self.build(c, depth + 1, strict_mode, kind)?;
+
+                c.goto_parent();
+                return Ok(
+                    format! {"[(binary_expression left: {0} operator: \"{1}\" right: {2})
+                    (binary_expression left: {2} operator: \"{3}\" right: {0})]", left, op, right, alt_op},
+                );
+            }
+            // Handle not: xyz;
+            "labeled_statement" => {
+                let label = c.node().child(0).unwrap();
+                let negation = self.get_text(&label).to_uppercase();
+                if negation == "NOT" || negation == "NEVER" {
+                    self.build_negative_query(c, negation == "NEVER")?;
+                    // negative sub queries are special in that they do not add anything
+                    // to the main query. We just return an empty string, which will get
+                    // filtered out by _build_query_tree
+                    return Ok("".to_string());
+                } else if self.get_text(&label).to_uppercase() == "STRICT" {
+                    if let Some(child) = c.node().named_child(1) {
+                        return self.build(&mut child.walk(), depth, true, kind);
+                    } else {
+                        return Ok("".to_string());
+                    }
+                } else if self.get_text(&label).to_uppercase() == "OR"
+                    || parse_count_label(self.get_text(&label)).is_some()
+                {
+                    // or: adds an alternative for the previous statement of a compound
+                    // query and count(..): counts its occurrences, see _build_query_tree.
+                    // The statement itself is built normally.
+                    if let Some(child) = c.node().named_child(1) {
+                        return self.build(&mut child.walk(), depth, strict_mode, kind);
+                    } else {
+                        return Ok("".to_string());
+                    }
+                } else if self.get_text(&label).to_uppercase() == "SHOW" {
+                    // show: highlights the complete statement in the output
+                    // by adding a Display capture for it.
+                    if let Some(child) = c.node().named_child(1) {
+                        let s = self.build(&mut child.walk(), depth, strict_mode, kind)?;
+                        if s.is_empty() {
+                            return Ok(s);
+                        }
+                        return Ok(s + " @" + &add_capture(&mut self.captures, Capture::Display));
+                    } else {
+                        return Ok("".to_string());
+                    }
+                } else if self.get_text(&label).to_uppercase() == "UNUSED" {
+                    // unused: only matches expression statements, i.e. calls whose
+                    // return value is discarded. Unwrapping of the statement is disabled
+                    // below, but the statement itself is matched non-strictly.
+                    if let Some(child) = c.node().named_child(1) {
+                        return self.build(&mut child.walk(), depth, strict_mode, kind);
+                    } else {
+                        return Ok("".to_string());
+                    }
+                }
+            }
+            // Build a multi-pattern tree for {.., .., ..}
+            _ if kind == self.language.block_kind() && c.node().named_child_count() > 0 => {
+                self.id += 1;
+                let mut c = c.node().walk();
+                let capture = Capture::Subquery(Box::new(_build_query_tree(
+                    &self.query_source,
+                    &mut c,
+                    self.id,
+                    self.language,
+                    true,
+                    false, // limit strictness to current depth for now
+                    Some(self.options.clone()),
+                )?));
+                let capture = add_capture(&mut self.captures, capture);
+                // Remember the sub query of the root compound statement, which is moved
+                // to the anchor node in _build_query_tree.
+                if depth == 0 && self.block_capture.is_none() {
+                    self.block_capture = Some(capture.clone());
+                }
+                return Ok(format!("({}) @", kind) + &capture);
+            }
+            // Designated initializers are matched in any order, so their list is
+            // handled like a compound statement.
+            _ if self.is_designated_initializer_list(c.node()) => {
+                self.id += 1;
+                let mut c = c.node().walk();
+                let mut qt = _build_query_tree(
+                    &self.query_source,
+                    &mut c,
+                    self.id,
+                    self.language,
+                    true,
+                    false,
+                    Some(self.options.clone()),
+                )?;
+                qt.set_unordered();
+                let capture = Capture::Subquery(Box::new(qt));
+                return Ok(format!("({}) @", kind) + &add_capture(&mut self.captures, capture));
+            }
+            // Greedy matching of all type of identifiers + variable support
+            _ if self.language.identifier_kinds().contains(&kind) => {
+                return self.build_identifier(c, parent)
+            }
+            "assignment_expression" => return self.build_assignment(c, depth, strict_mode),
+            // Function calls (including wildcards)
+            _ if kind == self.language.call().kind => {
+                if let Some(s) = self.build_call_expr(c, depth, strict_mode, kind)? {
+                    return Ok(s);
+                }
+            }
+            // When the query contains an expression statement (e.g "func(x,y);")
+            // we insert a sub query for the expression instead. This ensures that
+            // we also match on x=func(x,y); or if (func(x,y))
+            // We can't unwrap the expression statements in all cases so make sure
+            // the parent node is either a compound statement, a TU or one of our
+            // two "magic" labels.
+            "expression_statement" => {
+                if let Some(child) = c.node().named_child(0) {
+                    if let Some(p) = c.node().parent() {
+                        if [
+                            self.language.block_kind(),
+                            "labeled_statement",
+                            self.language.root_kind(),
+                        ]
+                        .contains(&p.kind())
+                        {
+                            let mut unwrap = !strict_mode || self.is_subexpr_wildcard(child);
+                            if p.kind() == "labeled_statement" {
+                                let l = p.child(0).unwrap();
+                                let label = self.get_text(&l).to_uppercase();
+                                if !["NOT", "NEVER", "STRICT", "OR", "SHOW"]
+                                    .contains(&label.as_str())
+                                    && parse_count_label(self.get_text(&l)).is_none()
+                                {
+                                    unwrap = false;
+                                }
+                            }
+                            if self.get_text(&child) == "_" {
+                                unwrap = false
+                            }
+
+                            if unwrap {
+                                c.goto_first_child();
+                                return self.build(c, depth, strict_mode, kind);
+                            }
+                        }
+                    }
+                }
+            }
+            _ if kind == self.language.number_kind() => {
+                let pattern = self.get_text(&c.node());
+
+                let capture = if let Some(num) = parse_number_literal(pattern) {
+                    Capture::Number(num)
+                } else {
+                    warn! {"Could not parse {} as a number. Forcing string matching", pattern}
+                    Capture::Check(pattern.to_string())
+                };
+
+                return Ok(format! {"({}) @{}", kind, &add_capture(&mut self.captures, capture)});
+            }
+            _ if kind == self.language.string_kinds()[0] => {
+                let pattern = self.get_text(&c.node());
+                let unquoted = &pattern[1..pattern.len() - 1];
+
+                if unquoted.starts_with('$') {
+                    let c = Capture::Variable(
+                        unquoted.to_string(),
+                        self.options.regexes().get(unquoted),
+                    );
+                    return Ok(format! {"({}) @{}", kind, &add_capture(&mut self.captures, c)});
+                }
+            }
+            _ => (),
+        }
+
+        // Default case. Handle everything else
+
+        // Enforce ordering of arguments by anchoring them to each other if the user specified
+        // more than one arg.
+        let is_argument_list = kind == self.language.call().argument_list;
+        let anchoring = is_argument_list && c.node().named_child_count() > 1;
+
+        // Explicit `...` wildcards switch to exact argument positions: Arguments have to
+        // be at the start and end of the list and follow each other, unless a `...`
+        // sits in between. The positions are checked for each match, see ArgumentPosition.
+        let explicit_args = is_argument_list && self.has_ellipsis(c.node());
+        let mut after_ellipsis = false;
+        let mut position = ArgumentPosition {
+            // Unique for every argument list, as each argument adds a capture
+            list: self.captures.len(),
+            index: 0,
+            first: true,
+            last: false,
+            follows: false,
+        };
+        let num_args = if explicit_args {
+            let mut w = c.node().walk();
+            let args = c.node().named_children(&mut w);
+            args.filter(|a| !self.is_ellipsis(*a)).count()
+        } else {
+            0
+        };
+        let trailing_ellipsis = explicit_args
+            && c.node()
+                .named_child(c.node().named_child_count().saturating_sub(1))
+                .map_or(false, |a| self.is_ellipsis(a));
+
+        let is_funcdef = self.language.function_kinds().contains(&kind);
+
+        // Function declarations at the root of a query are signature queries. They match on
+        // prototypes as well as on the signature of function definitions.
+        let is_signature = depth == 0 && crate::is_function_declaration(c.node());
+
+        let mut result = format!("({}", c.node().kind());
+        if !c.goto_first_child() {
+            if !c.node().is_named() {
+                return Ok(format!(r#""{}""#, c.node().kind()));
+            }
+            return Ok(result + ")");
+        }
+
+        // Iterate through all fields
+        loop {
+            let name = c.field_name();
+
+            // The not: marker of a negated designator, see build_negative_designator.
+            if self.is_not_designator(c.node()) {
+                if !c.goto_next_sibling() {
+                    break;
+                }
+                continue;
+            }
+
+            // Named fields (for example "condition" and "consequence" for an if statement)
+            if let Some(n) = name {
+                result += &format!(" {}:", n);
+
+                // Recursively build the query for the child node.
+                let t = self.build(c, depth + 1, strict_mode, n)?;
+
+                if n == "declarator" && (is_funcdef || is_signature) {
+                    // hacky way to make "_ func()" match on "bar * func()".
+                    // The problem is that the pointer isn't part of the return
+                    // type but is a pointer_declaration wrapper
+                    // around the function_definition. We add a single level wildcard
+                    // to still match, but of course that still fails for bar** func() :/
+                    // TODO: Think about better ways to implement this, maybe we should just add another sub expression
+                    result += &format!("([(_ {}) ({})])", t, t);
+                } else {
+                    result += &t
+                }
+            // Argument Lists for function calls
+            } else if c.node().is_named() {
+                if self.is_ellipsis(c.node()) {
+                    if explicit_args {
+                        after_ellipsis = true;
+                        position.first = false;
+                    } else {
+                        // int f(char *fmt, ...)
+                        result += " (variadic_parameter)";
+                    }
+                    if !c.goto_next_sibling() {
+                        break;
+                    }
+                    continue;
+                }
+                if !explicit_args && anchoring {
+                    result += " .";
+                }
+                result += " ";
+                result += &self.build(c, depth + 1, strict_mode, kind)?;
+                if explicit_args {
+                    position.follows = position.index > 0 && !after_ellipsis;
+                    position.last = position.index + 1 == num_args && !trailing_ellipsis;
+                    result += " @";
+                    result += &add_capture(&mut self.captures, Capture::Argument(position));
+                    position.index += 1;
+                    position.first = false;
+                    after_ellipsis = false;
+                }
+            // Unnamed syntax nodes like {, ; or keywords.
+            } else {
+                let sexp = self.build(c, depth + 1, strict_mode, kind)?;
+                // We want to highlight keywords in our search results so we add Display captures
+                if sexp.chars().all(|c| char::is_alphanumeric(c) || c == '"') && sexp != "\"\"\"" {
+                    result += &format!(
+                        " {} @{}",
+                        sexp,
+                        &add_capture(&mut self.captures, Capture::Display)
+                    );
+                }
+            }
+
+            if !c.goto_next_sibling() {
+                break;
+            }
+        }
+        c.goto_parent();
+
+        if is_signature {
+            let fields = &result["(declaration".len()..];
+            result = format!("[{}) (function_definition{})]", result, fields);
+            debug!("generated query: {}", result);
+            return Ok(result);
+        }
+
+        debug!("generated query: {}", result);
+        Ok(result + ")")
+    }
+
+    // Create a negative query matching the statement after
+    // a NOT: label.
+    // Handle not: and never: statements. Matches for never: sub queries are searched
+    // in the whole enclosing function instead of only between the surrounding statements.
+    fn build_negative_query(
+        &mut self,
+        c: &mut TreeCursor,
+        anywhere: bool,
+    ) -> Result<(), QueryError> {
+        let negated_query = c.node().child(2).unwrap();
+        // Save a reference to the previous capture so
+        // query.rs can later enforce ordering. Argument captures
+        // aren't part of the results, so they are skipped.
+        let before = self
+            .captures
+            .iter()
+            .rposition(|c| !matches!(c, Capture::Argument(_)))
+            .map_or(-1, |i| i as i64);
+
+        self.id += 1;
+        self.negations.push(NegativeQuery {
+            qt: Box::new(_build_query_tree(
+                &self.query_source,
+                &mut negated_query.walk(),
+                self.id,
+                self.language,
+                false,
+                false, // TODO: should strict mode be supported in NOT queries?
+                Some(self.options.clone()),
+            )?),
+            previous_capture_index: before,
+            anywhere,
+            unordered: false,
+        });
+        Ok(())
+    }
+
+    // Handle `not: .field = _` in an initializer list. Unlike not: statements, the
+    // negation isn't ordered: No element of the list may match it.
+    fn build_negative_designator(&mut self, pair: Node) -> Result<(), QueryError> {
+        self.id += 1;
+        self.negations.push(NegativeQuery {
+            qt: Box::new(_build_query_tree(
+                &self.query_source,
+                &mut pair.walk(),
+                self.id,
+                self.language,
+                false,
+                false,
+                Some(self.options.clone()),
+            )?),
+            previous_capture_index: -1,
+            anywhere: false,
+            unordered: true,
+        });
+        Ok(())
+    }
+
+    // Handle $x, _, foo, char, ->field and co.
+    fn build_identifier(
+        &mut self,
+        c: &mut TreeCursor,
+        parent: &'static str,
+    ) -> Result<String, QueryError> {
+        let pattern = self.get_text(&c.node());
+        let kind = c.node().kind();
+
+        let is_num_var =
+            |p: &str| -> bool { p.starts_with('$') && p.chars().skip(1).all(|c| c.is_numeric()) };
+
+        if pattern == "_" {
+            return Ok("(_)".to_string());
+        }
+
+        let language = self.language;
+        let number = language.number_kind();
+        // Variables match on the node kinds listed by the language and optionally on literals.
+        let variable = |literals: &[&str]| {
+            let kinds: Vec<&str> = language
+                .variable_kinds()
+                .iter()
+                .chain(literals)
+                .copied()
+                .collect();
+            any_kind(&kinds)
+        };
+
+        let mut result = if kind == "type_identifier" && !language.type_kinds().is_empty() {
+            any_kind(language.type_kinds())
+        } else if kind == "identifier" && pattern.starts_with('$') {
+            if is_num_var(pattern) && parent!="declarator" {
+                format!("({})", number)
+            }
+            // case labels are usually constants or enum values and array sizes can
+            // be arbitrary expressions, so let variables match on anything.
+            else if matches!(
+                c.node().parent().map(|p| p.kind()),
+                Some("case_statement") | Some("array_declarator")
+            ) {
+                "(_)".to_string()
+            }
+            // Variables with a numeric range constraint only match number literals
+            else if self.options.is_number_variable(pattern) {
+                format!("({})", number)
+            }
+            // Variables used as array sizes also match on number literals
+            // to support queries like '{char $buf[$n]; memcpy($buf, _, $n);}'
+            else if self.size_variables.contains(pattern) {
+                variable(&[number])
+            }
+            // Variables with a --string-length constraint need to match string literals,
+            // variables used as the bound of such a constraint number literals.
+            else if self.options.is_string_variable(pattern) {
+                variable(language.string_kinds())
+            } else if self.options.is_length_bound(pattern) {
+                variable(&[number, language.string_kinds()[0]])
+            } else {
+                variable(&[])
+            }
+        } else {
+            format!("({})", kind)
+        };
+
+        let capture = if pattern.starts_with('$') {
+            Capture::Variable(pattern.to_string(), self.options.regexes().get(pattern))
+        } else {
+            Capture::Check(pattern.to_string())
+        };
+
+        result += " @";
+        result += &add_capture(&mut self.captures, capture);
+
+        Ok(result)
+    }
+
+    // Handle $foo() and _(). Returns None if the call does not need special handling.
+    fn build_call_expr(
+        &mut self,
+        c: &mut TreeCursor,
+        depth: usize,
+        strict_mode: bool,
+        parent: &'static str,
+    ) -> Result<Option<String>, QueryError> {
+        if self.is_subexpr_wildcard(c.node()) {
+            let arguments = self.language.call().arguments;
+            let arguments = c.node().child_by_field_name(arguments).unwrap();
+            let mut arg = arguments.walk();
+
+            arg.goto_first_child();
+            arg.goto_next_sibling();
+            let mut copy = arg.clone();
+            copy.goto_next_sibling();
+            if copy.goto_next_sibling() {
+                let args: Vec<Node> = arguments.named_children(&mut arguments.walk()).collect();
+                // _(_, $b) is most likely meant as a call to an arbitrary function.
+                if args.iter().any(|a| self.get_text(a) == "_") {
+                    warn! {"sub expression '{}' with a wildcard argument is not supported.
+                    Do you want to match on a function call '$foo()' instead?",
+                    self.get_text(&c.node()).to_string().red()};
+                    warn! {"converting to function call..."};
+                    return Ok(None);
+                }
+                return self
+                    .build_multi_subexpr(&args, depth, strict_mode)
+                    .map(Some);
+            }
+
+            // Wildcards for depth 0 are meaningless. Just unwrap it.
+            if depth == 0 {
+                return Ok(Some(self.build(&mut arg, depth, strict_mode, parent)?));
+            }
+            self.id += 1;
+            let capture = Capture::Subquery(Box::new(_build_query_tree(
+                &self.query_source,
+                &mut arg,
+                self.id,
+                self.language,
+                false,
+                strict_mode,
+                Some(self.options.clone()),
+            )?));
+            return Ok(Some(
+                "_ @".to_string() + &add_capture(&mut self.captures, capture),
+            ));
+        }
+        let call = self.language.call();
+        let function = c.node().child_by_field_name(call.function).unwrap();
+        let arguments = c.node().child_by_field_name(call.arguments).unwrap();
+
+        if let Some(result) = self.build_asm(&function, &arguments)? {
+            return Ok(Some(result));
+        }
+
+        if function.kind() == "identifier" {
+            let pattern = self.get_text(&function);
+            if !pattern.starts_with('$') {
+                let capture = Capture::Check(pattern.to_string());
+
+                let capture_str = "@".to_string() + &add_capture(&mut self.captures, capture);
+
+                let a = self.build(&mut arguments.walk(), depth + 1, false, parent)?;
+
+                let fs = if strict_mode {
+                    format! {"(identifier) {}",capture_str}
+                } else {
+                    self.language.callee(&capture_str)
+                };
+
+                let result = format! {"({} {}: {} {}: {})",
+                call.kind, call.function, fs, call.arguments, a};
+                return Ok(Some(result));
+            }
+        }
+        Ok(None)
+    }
+
+    // Handle asm("regex"): matches on inline assembly statements with a template
+    // that matches the regex. Their operands are not part of the tree (see
+    // Language::parse_with), so they can't be matched on.
+    fn build_asm(
+        &mut self,
+        function: &Node,
+        arguments: &Node,
+    ) -> Result<Option<String>, QueryError> {
+        let keywords = self.language.asm_keywords();
+        if function.kind() != "identifier" || !keywords.contains(&self.get_text(function)) {
+            return Ok(None);
+        }
+        let args: Vec<Node> = arguments.named_children(&mut arguments.walk()).collect();
+        let template = match args[..] {
+            [t] if t.kind() == self.language.string_kinds()[0] => self.get_text(&t).to_string(),
+            _ => return Ok(None),
+        };
+
+        let pattern = &template[1..template.len() - 1];
+        let call = self.language.call();
+        let regex = Regex::new(pattern).map_err(|e| QueryError {
+            message: format!(
+                "invalid regex in '{}({})': {}",
+                self.get_text(function),
+                template,
+                e
+            ),
+        })?;
+        let keyword = Regex::new(&format!("^({})$", keywords.join("|"))).unwrap();
+
+        let f = add_capture(&mut self.captures, Capture::Regex(keyword));
+        let t = add_capture(&mut self.captures, Capture::Regex(regex));
+        let strings: Vec<String> = self
+            .language
+            .string_kinds()
+            .iter()
+            .map(|k| format!("({})", k))
+            .collect();
+
+        Ok(Some(format!(
+            "({} {}: (identifier) @{} {}: ({} . [{}] @{} .))",
+            call.kind,
+            call.function,
+            f,
+            call.arguments,
+            call.argument_list,
+            strings.join(" "),
+            t
+        )))
+    }
+
+    // Handle _($a, $b): all arguments need to match somewhere below the same node.
+    // At the statement level (depth 0) the node is a statement of a block, otherwise
+    // any node.
+    fn build_multi_subexpr(
+        &mut self,
+        args: &[Node],
+        depth: usize,
+        strict_mode: bool,
+    ) -> Result<String, QueryError> {
+        let mut captures = String::new();
+        for arg in args {
+            self.id += 1;
+            let capture = Capture::Subquery(Box::new(_build_query_tree(
+                &self.query_source,
+                &mut arg.walk(),
+                self.id,
+                self.language,
+                false,
+                strict_mode,
+                Some(self.options.clone()),
+            )?));
+            captures += " @";
+            captures += &add_capture(&mut self.captures, capture);
+        }
+
+        if depth == 0 {
+            Ok(format!("({} (_){})", self.language.block_kind(), captures))
+        } else {
+            Ok(format!("_{}", captures))
+        }
+    }
+
+    // Handle $x = .., $y+= .. etc.
+    fn build_assignment(
+        &mut self,
+        c: &mut TreeCursor,
+        depth: usize,
+        strict_mode: bool,
+    ) -> Result<String, QueryError> {
+        let kind = c.node().kind();
+
+        assert!(c.goto_first_child());
+        let left = self.build(c, depth + 1, strict_mode, kind)?;
+
+        let left_is_identifier = c.node().kind() == "identifier";
+
+        // operator
+        assert!(c.goto_next_sibling());
+
+        // Match on assignments even if they include a cast
+        let cast = self.language.cast_kind();
+        let optional_cast = |r: String| match cast {
+            Some(cast) => format! {"[({} value: {}) {}]", cast, r, r},
+            None => r,
+        };
+
+        // handle += / -= / ..
+        let result = if c.node().kind() != "=" || !left_is_identifier {
+            let operator = self.build(c, depth + 1, strict_mode, kind)?;
+            assert!(c.goto_next_sibling());
+            let right = optional_cast(self.build(c, depth + 1, strict_mode, kind)?);
+
+            format! {"(assignment_expression left: {} {} right: {})" , left, operator, right}
+        } else {
+            // A query that searches for assignments (a = x;) should also match on init declarations (int a =x;)
+            assert!(c.goto_next_sibling());
+            let right = optional_cast(self.build(c, depth + 1, strict_mode, kind)?);
+
+            let mut queries =
+                vec![format! {"(assignment_expression left: {} right: {})", left, right}];
+            queries.extend(self.language.initializers(&left, &right));
+            alternatives(&queries)
+        };
+        c.goto_parent();
+        Ok(result)
+    }
+}
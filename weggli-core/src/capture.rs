@@ -0,0 +1,59 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// We use captures as a way to extend tree-sitter's query mechanism.
+/// Variable captures correspond to a weggli variable ($foo) and we enforce
+/// equality of a single variable for all queries in a tree.
+/// Check is used for weggli identifiers such as variable or function names.
+/// Regex requires the text of the captured node to match, see asm("regex").
+/// Subquery contains the QueryTree that needs to be executed on
+/// the captured AST node. Finally, Argument enforces the position of an
+/// argument next to a `...` wildcard (memcpy(..., $len)).
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Capture {
+    Display,
+    Variable(
+        String,
+        #[serde(with = "crate::util::serde_regex::constraint")] Option<(bool, Regex)>,
+    ),
+    Check(String),
+    Number(i128),
+    Regex(#[serde(with = "crate::util::serde_regex")] Regex),
+    Subquery(Box<crate::query::QueryTree>),
+    Argument(ArgumentPosition),
+}
+
+/// Position of an argument in an argument list with `...` wildcards. tree-sitter
+/// anchors aren't reliable for this, so positions are checked for every match.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ArgumentPosition {
+    // Argument list in the query and index of the argument in it
+    pub list: usize,
+    pub index: usize,
+    // The argument has to be the first or last one of the list, or directly
+    // follow the previous argument because there is no `...` in between.
+    pub first: bool,
+    pub last: bool,
+    pub follows: bool,
+}
+
+pub fn add_capture(captures: &mut Vec<Capture>, capture: Capture) -> String {
+    let idx = captures.len();
+    captures.push(capture);
+    idx.to_string()
+}
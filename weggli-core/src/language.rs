@@ -0,0 +1,583 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Grammars supported by weggli. Everything the query builder needs to know about
+//! the syntax tree of a language is described by the `Language` trait, so additional
+//! languages can be added as (feature gated) implementations of it.
+
+use tree_sitter::{Parser, Tree};
+
+extern "C" {
+    fn tree_sitter_c() -> tree_sitter::Language;
+    fn tree_sitter_cpp() -> tree_sitter::Language;
+}
+
+/// Node kind and field names of function calls.
+pub struct CallShape {
+    pub kind: &'static str,
+    /// Field containing the called function.
+    pub function: &'static str,
+    /// Field containing the argument list.
+    pub arguments: &'static str,
+    /// Kind of the argument list.
+    pub argument_list: &'static str,
+}
+
+const CALL_EXPRESSION: CallShape = CallShape {
+    kind: "call_expression",
+    function: "function",
+    arguments: "arguments",
+    argument_list: "argument_list",
+};
+
+/// A tree-sitter grammar and the node kinds weggli needs to build queries for it.
+pub trait Language: Sync + Send {
+    /// Name of the language, as used on the command line.
+    fn name(&self) -> &'static str;
+
+    fn grammar(&self) -> tree_sitter::Language;
+
+    /// Enables C++ specific query translations (qualified names, this, ...).
+    fn is_cpp(&self) -> bool {
+        false
+    }
+
+    /// Kind of the root node of a parsed file.
+    fn root_kind(&self) -> &'static str;
+
+    /// Kind of a block of statements ({ .. }).
+    fn block_kind(&self) -> &'static str;
+
+    /// Node kinds a query can be rooted in.
+    fn query_root_kinds(&self) -> &'static [&'static str];
+
+    /// Kinds of function definitions. Their body is stored in the `body` field.
+    fn function_kinds(&self) -> &'static [&'static str];
+
+    /// Kinds of class like definitions, see --anchor class.
+    fn class_kinds(&self) -> &'static [&'static str];
+
+    /// Kinds of nodes that contain global declarations, e.g. the file or an
+    /// extern "C" block. Queries for a single declaration with an initializer
+    /// list (`static struct ops $o = { .read = $f };`) also match declarations
+    /// directly inside them, not only those in functions.
+    fn global_scope_kinds(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Identifier-like leaf nodes. They are matched by name or bound to a variable.
+    fn identifier_kinds(&self) -> &'static [&'static str];
+
+    /// Node kinds a type name in a query (type_identifier) matches on.
+    fn type_kinds(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Node kinds a variable ($x) matches on.
+    fn variable_kinds(&self) -> &'static [&'static str];
+
+    fn number_kind(&self) -> &'static str;
+
+    /// String literal kinds. The first one is the kind of a single string literal.
+    fn string_kinds(&self) -> &'static [&'static str];
+
+    fn call(&self) -> CallShape {
+        CALL_EXPRESSION
+    }
+
+    /// Returns the query for the function of a call to a named function. `capture`
+    /// checks the name. Besides plain calls, this should match on method calls.
+    fn callee(&self, capture: &str) -> String;
+
+    /// Returns queries for declarations that initialize `left` with `right`.
+    /// A query for the assignment `left = right` also matches on them.
+    fn initializers(&self, _left: &str, _right: &str) -> Vec<String> {
+        vec![]
+    }
+
+    /// Kind of a cast expression with a `value` field. Assignments also match
+    /// if the assigned value is cast.
+    fn cast_kind(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Keywords of inline assembly statements. The grammar only parses them as
+    /// calls with a single string argument, see `parse_with`.
+    fn asm_keywords(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    fn parser(&self) -> Parser {
+        let mut parser = Parser::new();
+        if let Err(e) = parser.set_language(self.grammar()) {
+            eprintln!("{}", e);
+            panic!();
+        }
+        parser
+    }
+
+    /// Parse `source` into a tree. This won't fail, but the returned tree
+    /// might contain errors.
+    fn parse(&self, source: &str) -> Tree {
+        self.parse_with(&mut self.parser(), source)
+    }
+
+    /// Like `parse`, but reuses `parser`, which has to be created by `parser()`.
+    /// Operands and qualifiers of inline assembly statements are ignored, so
+    /// `asm volatile("mov %0, %%cr3" :: "r"(x))` is parsed as `asm("mov %0, %%cr3")`.
+    /// Byte offsets in the returned tree still refer to `source`.
+    fn parse_with(&self, parser: &mut Parser, source: &str) -> Tree {
+        self.try_parse_with(parser, source).unwrap()
+    }
+
+    /// Like `parse_with`, but returns None if parsing was stopped by the timeout
+    /// of `parser` (see `Parser::set_timeout_micros`).
+    fn try_parse_with(&self, parser: &mut Parser, source: &str) -> Option<Tree> {
+        match mask_asm(source, self.asm_keywords()) {
+            Some(masked) => parser.parse(masked, None),
+            None => parser.parse(source, None),
+        }
+    }
+}
+
+const ASM_QUALIFIERS: &[&str] = &[
+    "volatile",
+    "__volatile__",
+    "__volatile",
+    "inline",
+    "__inline__",
+    "__inline",
+    "goto",
+];
+
+const C_ASM_KEYWORDS: &[&str] = &["asm", "__asm__", "__asm"];
+
+/// Returns a copy of `source` in which the qualifiers and operands (everything
+/// from the first `:`) of all asm statements are replaced by spaces, or None if
+/// there is nothing to replace. Newlines are kept, so lines don't move either.
+fn mask_asm(source: &str, keywords: &[&str]) -> Option<String> {
+    if keywords.is_empty() {
+        return None;
+    }
+
+    let is_word = |b: u8| b.is_ascii_alphanumeric() || b == b'_' || b == b'$';
+    let bytes = source.as_bytes();
+    let mut masked: Option<Vec<u8>> = None;
+    let blank = |masked: &mut Option<Vec<u8>>, range: std::ops::Range<usize>| {
+        let m = masked.get_or_insert_with(|| bytes.to_vec());
+        for b in &mut m[range] {
+            if *b != b'\n' {
+                *b = b' ';
+            }
+        }
+    };
+
+    for found in memchr::memmem::find_iter(bytes, "asm") {
+        // Find the complete word containing "asm".
+        let mut start = found;
+        while start > 0 && is_word(bytes[start - 1]) {
+            start -= 1;
+        }
+        let mut pos = found + 3;
+        while pos < bytes.len() && is_word(bytes[pos]) {
+            pos += 1;
+        }
+        if !keywords.contains(&&source[start..pos]) {
+            continue;
+        }
+
+        // Qualifiers between the keyword and the parenthesis.
+        let mut qualifiers = Vec::new();
+        loop {
+            while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            let word = pos;
+            while pos < bytes.len() && is_word(bytes[pos]) {
+                pos += 1;
+            }
+            if word == pos {
+                break;
+            }
+            if !ASM_QUALIFIERS.contains(&&source[word..pos]) {
+                qualifiers.clear();
+                break;
+            }
+            qualifiers.push(word..pos);
+        }
+        if bytes.get(pos) != Some(&b'(') {
+            continue;
+        }
+
+        // Find the first top level ':' and the closing parenthesis. Give up on
+        // anything that doesn't look like an asm statement.
+        let mut depth = 0;
+        let mut operands = None;
+        let mut end = None;
+        while pos < bytes.len() {
+            match bytes[pos] {
+                b'(' => depth += 1,
+                b')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        end = Some(pos);
+                        break;
+                    }
+                }
+                b':' if depth == 1 && operands.is_none() => operands = Some(pos),
+                b';' | b'{' | b'}' => break,
+                q @ (b'"' | b'\'') => {
+                    pos += 1;
+                    while pos < bytes.len() && bytes[pos] != q && bytes[pos] != b'\n' {
+                        if bytes[pos] == b'\\' {
+                            pos += 1;
+                        }
+                        pos += 1;
+                    }
+                }
+                _ => (),
+            }
+            pos += 1;
+        }
+        let end = match end {
+            Some(end) => end,
+            None => continue,
+        };
+
+        for q in qualifiers {
+            blank(&mut masked, q);
+        }
+        if let Some(operands) = operands {
+            blank(&mut masked, operands..end);
+        }
+    }
+
+    // Only ASCII characters were replaced, so this is still valid UTF-8.
+    masked.map(|m| String::from_utf8(m).unwrap())
+}
+
+/// C, using our own slightly modified grammar that allows $ in identifiers.
+pub struct C;
+
+/// C++, using our own slightly modified grammar that allows $ in identifiers.
+pub struct Cpp;
+
+/// JavaScript. $ is part of valid identifiers, so the grammar can be used as is.
+#[cfg(feature = "javascript")]
+pub struct JavaScript;
+
+/// Returns the C or the C++ language.
+pub fn for_cpp(cpp: bool) -> &'static dyn Language {
+    if cpp {
+        &Cpp
+    } else {
+        &C
+    }
+}
+
+/// Returns all languages weggli was built with.
+pub fn all() -> Vec<&'static dyn Language> {
+    vec![
+        &C,
+        &Cpp,
+        #[cfg(feature = "javascript")]
+        &JavaScript,
+    ]
+}
+
+/// Returns the language called `name`.
+pub fn by_name(name: &str) -> Option<&'static dyn Language> {
+    all().into_iter().find(|l| l.name() == name)
+}
+
+const C_ROOT_KINDS: &[&str] = &[
+    "compound_statement",
+    "function_definition",
+    "struct_specifier",
+    "enum_specifier",
+    "union_specifier",
+    "class_specifier",
+];
+
+const C_IDENTIFIER_KINDS: &[&str] = &[
+    "identifier",
+    "type_identifier",
+    "field_identifier",
+    "sized_type_specifier",
+    "primitive_type",
+    "namespace_identifier",
+];
+
+// Namespaces and extern "C" blocks contain a declaration_list.
+const C_GLOBAL_SCOPE_KINDS: &[&str] = &["translation_unit", "declaration_list"];
+
+const C_TYPE_KINDS: &[&str] = &["type_identifier", "sized_type_specifier", "primitive_type"];
+
+fn c_initializers(left: &str, right: &str) -> Vec<String> {
+    vec![
+        format!("(init_declarator declarator: {} value: {})", left, right),
+        format!(
+            "(init_declarator declarator:(pointer_declarator declarator: {}) value: {})",
+            left, right
+        ),
+    ]
+}
+
+impl Language for C {
+    fn name(&self) -> &'static str {
+        "c"
+    }
+
+    fn grammar(&self) -> tree_sitter::Language {
+        unsafe { tree_sitter_c() }
+    }
+
+    fn root_kind(&self) -> &'static str {
+        "translation_unit"
+    }
+
+    fn block_kind(&self) -> &'static str {
+        "compound_statement"
+    }
+
+    fn query_root_kinds(&self) -> &'static [&'static str] {
+        C_ROOT_KINDS
+    }
+
+    fn function_kinds(&self) -> &'static [&'static str] {
+        &["function_definition"]
+    }
+
+    fn class_kinds(&self) -> &'static [&'static str] {
+        &["struct_specifier", "union_specifier"]
+    }
+
+    fn global_scope_kinds(&self) -> &'static [&'static str] {
+        C_GLOBAL_SCOPE_KINDS
+    }
+
+    fn identifier_kinds(&self) -> &'static [&'static str] {
+        C_IDENTIFIER_KINDS
+    }
+
+    fn type_kinds(&self) -> &'static [&'static str] {
+        C_TYPE_KINDS
+    }
+
+    fn variable_kinds(&self) -> &'static [&'static str] {
+        &["identifier", "field_expression", "field_identifier"]
+    }
+
+    fn number_kind(&self) -> &'static str {
+        "number_literal"
+    }
+
+    fn string_kinds(&self) -> &'static [&'static str] {
+        &["string_literal", "concatenated_string"]
+    }
+
+    fn callee(&self, capture: &str) -> String {
+        format! {"[(field_expression field: (field_identifier){0})
+        (identifier) {0}]", capture}
+    }
+
+    fn initializers(&self, left: &str, right: &str) -> Vec<String> {
+        c_initializers(left, right)
+    }
+
+    fn cast_kind(&self) -> Option<&'static str> {
+        Some("cast_expression")
+    }
+
+    fn asm_keywords(&self) -> &'static [&'static str] {
+        C_ASM_KEYWORDS
+    }
+}
+
+impl Language for Cpp {
+    fn name(&self) -> &'static str {
+        "cpp"
+    }
+
+    fn grammar(&self) -> tree_sitter::Language {
+        unsafe { tree_sitter_cpp() }
+    }
+
+    fn is_cpp(&self) -> bool {
+        true
+    }
+
+    fn root_kind(&self) -> &'static str {
+        "translation_unit"
+    }
+
+    fn block_kind(&self) -> &'static str {
+        "compound_statement"
+    }
+
+    fn query_root_kinds(&self) -> &'static [&'static str] {
+        C_ROOT_KINDS
+    }
+
+    fn function_kinds(&self) -> &'static [&'static str] {
+        &["function_definition"]
+    }
+
+    fn class_kinds(&self) -> &'static [&'static str] {
+        &["class_specifier", "struct_specifier", "union_specifier"]
+    }
+
+    fn global_scope_kinds(&self) -> &'static [&'static str] {
+        C_GLOBAL_SCOPE_KINDS
+    }
+
+    fn identifier_kinds(&self) -> &'static [&'static str] {
+        C_IDENTIFIER_KINDS
+    }
+
+    fn type_kinds(&self) -> &'static [&'static str] {
+        C_TYPE_KINDS
+    }
+
+    fn variable_kinds(&self) -> &'static [&'static str] {
+        &[
+            "identifier",
+            "field_expression",
+            "field_identifier",
+            "qualified_identifier",
+            "this",
+        ]
+    }
+
+    fn number_kind(&self) -> &'static str {
+        "number_literal"
+    }
+
+    fn string_kinds(&self) -> &'static [&'static str] {
+        &[
+            "string_literal",
+            "concatenated_string",
+            "raw_string_literal",
+        ]
+    }
+
+    fn callee(&self, capture: &str) -> String {
+        format! {"[(field_expression field: (field_identifier){0})
+                    (qualified_identifier name: (identifier){0})
+                    (qualified_identifier name: (qualified_identifier (identifier){0}))
+                    (qualified_identifier name: (qualified_identifier (qualified_identifier (identifier){0})))
+                    (qualified_identifier name: (qualified_identifier (qualified_identifier
+                        (qualified_identifier (identifier){0}))))
+                    (identifier) {0}]", capture}
+    }
+
+    fn initializers(&self, left: &str, right: &str) -> Vec<String> {
+        c_initializers(left, right)
+    }
+
+    fn cast_kind(&self) -> Option<&'static str> {
+        Some("cast_expression")
+    }
+
+    fn asm_keywords(&self) -> &'static [&'static str] {
+        C_ASM_KEYWORDS
+    }
+}
+
+#[cfg(feature = "javascript")]
+impl Language for JavaScript {
+    fn name(&self) -> &'static str {
+        "javascript"
+    }
+
+    fn grammar(&self) -> tree_sitter::Language {
+        tree_sitter_javascript::language()
+    }
+
+    fn root_kind(&self) -> &'static str {
+        "program"
+    }
+
+    fn block_kind(&self) -> &'static str {
+        "statement_block"
+    }
+
+    fn query_root_kinds(&self) -> &'static [&'static str] {
+        &[
+            "statement_block",
+            "function_declaration",
+            "class_declaration",
+        ]
+    }
+
+    fn function_kinds(&self) -> &'static [&'static str] {
+        &[
+            "function_declaration",
+            "function_expression",
+            "arrow_function",
+            "method_definition",
+            "generator_function_declaration",
+        ]
+    }
+
+    fn class_kinds(&self) -> &'static [&'static str] {
+        &["class_declaration", "class"]
+    }
+
+    fn identifier_kinds(&self) -> &'static [&'static str] {
+        &[
+            "identifier",
+            "property_identifier",
+            "shorthand_property_identifier",
+        ]
+    }
+
+    fn variable_kinds(&self) -> &'static [&'static str] {
+        &[
+            "identifier",
+            "member_expression",
+            "property_identifier",
+            "this",
+        ]
+    }
+
+    fn number_kind(&self) -> &'static str {
+        "number"
+    }
+
+    fn string_kinds(&self) -> &'static [&'static str] {
+        &["string", "template_string"]
+    }
+
+    fn call(&self) -> CallShape {
+        CallShape {
+            argument_list: "arguments",
+            ..CALL_EXPRESSION
+        }
+    }
+
+    fn callee(&self, capture: &str) -> String {
+        format! {"[(member_expression property: (property_identifier){0})
+        (identifier) {0}]", capture}
+    }
+
+    fn initializers(&self, left: &str, right: &str) -> Vec<String> {
+        vec![format!(
+            "(variable_declarator name: {} value: {})",
+            left, right
+        )]
+    }
+}
@@ -0,0 +1,1091 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! The query engine of weggli: parsing C and C++ code, building queries from
+//! search patterns and matching them. The `weggli` crate re-exports everything
+//! and adds searching files on disk and the command line tool.
+
+use std::collections::{hash_map::Keys, HashMap};
+use std::ops::Range;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use colored::Colorize;
+use language::Language;
+use query::QueryTree;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tree_sitter::{Parser, Query, Tree};
+
+#[macro_use]
+extern crate log;
+
+pub mod builder;
+mod capture;
+pub mod language;
+mod util;
+
+pub mod query;
+pub mod result;
+mod source;
+
+pub use source::ParsedSource;
+pub use util::{normalize_value, parse_number_literal, regex_can_match_number};
+
+#[derive(Debug, Clone)]
+pub struct QueryError {
+    pub message: String,
+}
+
+/// Helper function to parse an input string
+/// into a tree-sitter tree, using our own slightly modified
+/// C grammar. This function won't fail but the returned
+/// Tree might be invalid and contain errors.
+pub fn parse(source: &str, cpp: bool) -> Tree {
+    language::for_cpp(cpp).parse(source)
+}
+
+pub fn get_parser(cpp: bool) -> Parser {
+    language::for_cpp(cpp).parser()
+}
+
+/// File extensions that are always parsed with the C++ grammar.
+const CPP_EXTENSIONS: &[&str] = &["cc", "cpp", "cxx", "c++", "hpp", "hh", "hxx", "h++"];
+
+/// Source snippets that (almost) never show up in C code.
+const CPP_MARKERS: &[&[u8]] = &[b"::", b"template<", b"template <", b"namespace ", b"class "];
+
+/// Guess if the file at `path` with content `source` should be parsed in C++ mode.
+/// Well known extensions take precedence, for everything else (most importantly
+/// shared .h headers) we fall back to a simple search for C++ only constructs.
+pub fn is_cpp_file(path: &Path, source: &[u8]) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("c") => false,
+        Some(ext) if CPP_EXTENSIONS.contains(&ext) => true,
+        _ => CPP_MARKERS
+            .iter()
+            .any(|m| memchr::memmem::find(source, m).is_some()),
+    }
+}
+
+/// Largest query accepted by `fuzz_match`, in bytes.
+pub const FUZZ_MAX_QUERY_LEN: usize = 1024;
+/// Largest source snippet accepted by `fuzz_match`, in bytes.
+pub const FUZZ_MAX_SOURCE_LEN: usize = 64 * 1024;
+/// Time budget of `fuzz_match` for parsing and for matching.
+const FUZZ_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Build the query `query` and run it on the code snippet `source`, for end-to-end
+/// fuzzing of the query builder and the matching engine. `source` is parsed in C++
+/// mode if it looks like C++ (see `is_cpp_file`).
+/// Returns None for inputs that are not UTF-8 or too large, invalid queries and
+/// inputs that hit the time limit of parsing or matching. Crashes and hangs are
+/// bugs in weggli.
+pub fn fuzz_match(query: &[u8], source: &[u8]) -> Option<Vec<result::QueryResult>> {
+    if query.len() > FUZZ_MAX_QUERY_LEN || source.len() > FUZZ_MAX_SOURCE_LEN {
+        return None;
+    }
+    let query = std::str::from_utf8(query).ok()?;
+    let source = std::str::from_utf8(source).ok()?;
+    let language = language::for_cpp(is_cpp_file(Path::new(""), source.as_bytes()));
+
+    let qt = parse_search_pattern_with(query, language, false, None).ok()?;
+
+    let mut parser = language.parser();
+    parser.set_timeout_micros(FUZZ_TIMEOUT.as_micros() as u64);
+    let tree = language.try_parse_with(&mut parser, source)?;
+
+    qt.matches_until(tree.root_node(), source, Instant::now() + FUZZ_TIMEOUT)
+}
+
+// Internal helper function to create a new tree-sitter query.
+fn ts_query(sexpr: &str, language: &dyn Language) -> Result<tree_sitter::Query, QueryError> {
+    match Query::new(language.grammar(), sexpr) {
+        Ok(q) => Ok(q),
+        Err(e) => {
+            let errmsg = format!( "Tree sitter query generation failed: {:?}\n {} \n sexpr: {}\n This is a bug! Can't recover :/", e.kind, e.message, sexpr);
+            Err(QueryError { message: errmsg })
+        }
+    }
+}
+
+/// Map from variable names to a positive/negative regex constraint
+/// see --regex
+#[derive(Clone, Default)]
+pub struct RegexMap(HashMap<String, (bool, Regex)>);
+
+impl RegexMap {
+    pub fn new(m: HashMap<String, (bool, Regex)>) -> RegexMap {
+        RegexMap(m)
+    }
+
+    /// Add a (negative) regex constraint for `variable`, replacing any previous one.
+    pub fn constrain(&mut self, variable: &str, negative: bool, regex: Regex) {
+        self.0.insert(variable.to_string(), (negative, regex));
+    }
+
+    pub fn variables(&self) -> Keys<String, (bool, Regex)> {
+        self.0.keys()
+    }
+
+    pub fn get(&self, variable: &str) -> Option<(bool, Regex)> {
+        if let Some((b, r)) = self.0.get(variable) {
+            Some((*b, r.to_owned()))
+        } else {
+            None
+        }
+    }
+}
+
+/// Everything besides the pattern itself that changes how a query is built
+/// and matched: regex constraints (see `RegexMap`), string length constraints
+/// (see --string-length), numeric range constraints (see --num-constraint),
+/// sets of excluded identifiers ($f!{memcpy,memmove}), constraints between two
+/// variables (see --constraint), the node that matches are anchored to (see
+/// --anchor), whether variables may match identical values (see --unique),
+/// a comment regex and whether patterns are normalized (see --no-normalize).
+#[derive(Clone, Default)]
+pub struct QueryOptions {
+    regexes: RegexMap,
+    length_constraints: Vec<LengthConstraint>,
+    excluded: HashMap<String, Vec<String>>,
+    number_constraints: Vec<NumberConstraint>,
+    variable_constraints: Vec<VariableConstraint>,
+    anchor: Anchor,
+    uniqueness: Uniqueness,
+    skip_normalization: bool,
+    comment: Option<Regex>,
+}
+
+impl From<RegexMap> for QueryOptions {
+    fn from(regexes: RegexMap) -> QueryOptions {
+        QueryOptions {
+            regexes,
+            ..Default::default()
+        }
+    }
+}
+
+impl QueryOptions {
+    pub fn regexes(&self) -> &RegexMap {
+        &self.regexes
+    }
+
+    pub fn with_regexes(mut self, regexes: RegexMap) -> QueryOptions {
+        self.regexes = regexes;
+        self
+    }
+
+    pub fn with_length_constraints(mut self, constraints: Vec<LengthConstraint>) -> QueryOptions {
+        self.length_constraints = constraints;
+        self
+    }
+
+    pub fn length_constraints(&self) -> &[LengthConstraint] {
+        &self.length_constraints
+    }
+
+    pub fn with_number_constraints(mut self, constraints: Vec<NumberConstraint>) -> QueryOptions {
+        self.number_constraints = constraints;
+        self
+    }
+
+    pub fn number_constraints(&self) -> &[NumberConstraint] {
+        &self.number_constraints
+    }
+
+    pub fn with_variable_constraints(
+        mut self,
+        constraints: Vec<VariableConstraint>,
+    ) -> QueryOptions {
+        self.variable_constraints = constraints;
+        self
+    }
+
+    pub fn variable_constraints(&self) -> &[VariableConstraint] {
+        &self.variable_constraints
+    }
+
+    /// Returns true if `variable` has a numeric range constraint and thus
+    /// needs to match number literals.
+    pub fn is_number_variable(&self, variable: &str) -> bool {
+        self.number_constraints
+            .iter()
+            .any(|c| c.variable == variable)
+    }
+
+    pub fn with_anchor(mut self, anchor: Anchor) -> QueryOptions {
+        self.anchor = anchor;
+        self
+    }
+
+    pub fn anchor(&self) -> Anchor {
+        self.anchor
+    }
+
+    pub fn with_uniqueness(mut self, uniqueness: Uniqueness) -> QueryOptions {
+        self.uniqueness = uniqueness;
+        self
+    }
+
+    pub fn uniqueness(&self) -> Uniqueness {
+        self.uniqueness
+    }
+
+    /// Only match if the node a result is anchored to is preceded by a comment
+    /// matching `regex` (see `QueryTree::matches`).
+    pub fn with_comment(mut self, regex: Regex) -> QueryOptions {
+        self.comment = Some(regex);
+        self
+    }
+
+    pub fn comment(&self) -> Option<&Regex> {
+        self.comment.as_ref()
+    }
+
+    /// Disable the automatic normalization of patterns (see --no-normalize).
+    pub fn without_normalization(mut self) -> QueryOptions {
+        self.skip_normalization = true;
+        self
+    }
+
+    /// Returns true if `variable` is constrained by its string length and
+    /// thus needs to match string literals.
+    pub fn is_string_variable(&self, variable: &str) -> bool {
+        self.length_constraints
+            .iter()
+            .any(|c| c.variable == variable)
+    }
+
+    /// Returns true if `variable` is used as the bound of a length constraint
+    /// and thus needs to match number literals.
+    pub fn is_length_bound(&self, variable: &str) -> bool {
+        self.length_constraints
+            .iter()
+            .any(|c| matches!(&c.bound, Bound::Variable(v) if v == variable))
+    }
+
+    /// Add identifiers that `variable` must not match.
+    pub fn exclude(&mut self, variable: &str, identifiers: Vec<String>) {
+        self.excluded
+            .entry(variable.to_string())
+            .or_default()
+            .extend(identifiers);
+    }
+
+    /// Returns the identifiers `variable` must not match.
+    pub fn excluded(&self, variable: &str) -> &[String] {
+        self.excluded.get(variable).map_or(&[], |v| v.as_slice())
+    }
+}
+
+/// Comparison operator used by query constraints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Comparison {
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+}
+
+impl Comparison {
+    pub fn compare<T: PartialOrd>(&self, a: T, b: T) -> bool {
+        match self {
+            Comparison::Less => a < b,
+            Comparison::LessEqual => a <= b,
+            Comparison::Greater => a > b,
+            Comparison::GreaterEqual => a >= b,
+            Comparison::Equal => a == b,
+            Comparison::NotEqual => a != b,
+        }
+    }
+
+    /// Split `s` into the parts before and after the first comparison operator.
+    pub fn split(s: &str) -> Option<(&str, Comparison, &str)> {
+        let i = s.find(['<', '>', '=', '!'])?;
+        let (op, len) = match &s[i..] {
+            o if o.starts_with("<=") => (Comparison::LessEqual, 2),
+            o if o.starts_with(">=") => (Comparison::GreaterEqual, 2),
+            o if o.starts_with("==") => (Comparison::Equal, 2),
+            o if o.starts_with("!=") => (Comparison::NotEqual, 2),
+            o if o.starts_with('<') => (Comparison::Less, 1),
+            o if o.starts_with('>') => (Comparison::Greater, 1),
+            o if o.starts_with('=') => (Comparison::Equal, 1),
+            _ => return None,
+        };
+        Some((s[..i].trim(), op, s[i + len..].trim()))
+    }
+}
+
+/// Right hand side of a constraint: A constant or the value of another variable.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Bound {
+    Constant(usize),
+    Variable(String),
+}
+
+/// The node that the matches of a compound query are anchored to.
+/// The anchor is the scope in which all statements of the query have to match
+/// and is returned as `QueryResult::function_range`.
+/// see --anchor
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Anchor {
+    /// The enclosing function definition.
+    #[default]
+    Function,
+    /// The enclosing class, struct or union.
+    Class,
+    /// The whole file, including global declarations.
+    File,
+    /// The innermost compound statement ({..} block) containing the match.
+    Compound,
+}
+
+/// Whether different variables of a query may match identical values.
+/// see --unique
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Uniqueness {
+    /// Variables can match identical values.
+    #[default]
+    Any,
+    /// Variables need to match values that are not equal (see `values_equal`).
+    Distinct,
+    /// Variables need to match values that are not written identically
+    /// (see `raw_values_equal`).
+    RawDistinct,
+}
+
+impl Uniqueness {
+    /// Returns true if `a` and `b` count as identical values.
+    pub(crate) fn identical(self, a: &str, b: &str) -> bool {
+        match self {
+            Uniqueness::Any => false,
+            Uniqueness::Distinct => util::values_equal(a, b),
+            Uniqueness::RawDistinct => util::raw_values_equal(a, b),
+        }
+    }
+}
+
+impl std::str::FromStr for Anchor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "function" => Ok(Anchor::Function),
+            "class" => Ok(Anchor::Class),
+            "file" => Ok(Anchor::File),
+            "compound" => Ok(Anchor::Compound),
+            _ => Err(format!("unknown anchor '{}'", s)),
+        }
+    }
+}
+
+/// Constraint on the value of a number literal matched by a query variable.
+/// see --num-constraint
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NumberConstraint {
+    pub variable: String,
+    pub op: Comparison,
+    pub value: i128,
+}
+
+impl NumberConstraint {
+    /// Returns true if the number literal `value` satisfies the constraint.
+    /// Values that are not a number literal never do.
+    pub fn check(&self, value: &str) -> bool {
+        util::parse_number_literal(value).map_or(false, |v| self.op.compare(v, self.value))
+    }
+}
+
+impl std::str::FromStr for NumberConstraint {
+    type Err = String;
+
+    /// Parse constraints of the form `$size>=4096` or `len<0x100`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || {
+            format!(
+                "'{}' is not a valid constraint of the form var<op>number",
+                s
+            )
+        };
+        let (variable, op, value) = Comparison::split(s).ok_or_else(err)?;
+
+        if variable.is_empty() || variable == "$" {
+            return Err(err());
+        }
+        let value = util::parse_number_literal(value).ok_or_else(err)?;
+
+        Ok(NumberConstraint {
+            variable: if variable.starts_with('$') {
+                variable.to_string()
+            } else {
+                "$".to_string() + variable
+            },
+            op,
+            value,
+        })
+    }
+}
+
+/// Constraint on the length of a string literal matched by a query variable.
+/// see --string-length
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LengthConstraint {
+    pub variable: String,
+    pub op: Comparison,
+    pub bound: Bound,
+}
+
+impl std::str::FromStr for LengthConstraint {
+    type Err = String;
+
+    /// Parse constraints of the form `$fmt>100` or `$src>=$size`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("'{}' is not a valid constraint of the form var<op>value", s);
+        let (variable, op, bound) = Comparison::split(s).ok_or_else(err)?;
+
+        let variable_name = |v: &str| {
+            if v.starts_with('$') {
+                v.to_string()
+            } else {
+                "$".to_string() + v
+            }
+        };
+
+        if variable.is_empty() || bound.is_empty() {
+            return Err(err());
+        }
+
+        let bound = match bound.parse() {
+            Ok(n) => Bound::Constant(n),
+            Err(_)
+                if bound
+                    .chars()
+                    .all(|c| c == '$' || c == '_' || c.is_alphanumeric()) =>
+            {
+                Bound::Variable(variable_name(bound))
+            }
+            Err(_) => return Err(err()),
+        };
+
+        Ok(LengthConstraint {
+            variable: variable_name(variable),
+            op,
+            bound,
+        })
+    }
+}
+
+impl LengthConstraint {
+    /// Check the constraint for the captured `value` of the constrained variable.
+    /// `bound` is the captured value of the bound variable, if any.
+    /// Values that are not string literals never satisfy the constraint. Variable
+    /// bounds can be number literals or string literals (compared by length).
+    pub fn check(&self, value: &str, bound: Option<&str>) -> bool {
+        let length = match util::string_literal_length(value) {
+            Some(l) => l as i128,
+            None => return false,
+        };
+
+        let bound = match (&self.bound, bound) {
+            (Bound::Constant(n), _) => *n as i128,
+            (Bound::Variable(_), Some(b)) => {
+                match util::parse_number_literal(b)
+                    .or_else(|| util::string_literal_length(b).map(|l| l as i128))
+                {
+                    Some(b) => b,
+                    None => return false,
+                }
+            }
+            (Bound::Variable(_), None) => return false,
+        };
+
+        self.op.compare(length, bound)
+    }
+}
+
+/// Requires the values of two query variables to be equal or to differ.
+/// Values are compared without comments and whitespace.
+/// see --constraint
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VariableConstraint {
+    pub left: String,
+    /// Comparison::Equal or Comparison::NotEqual
+    pub op: Comparison,
+    pub right: String,
+}
+
+impl std::str::FromStr for VariableConstraint {
+    type Err = String;
+
+    /// Parse constraints of the form `$a!=$b` or `$a==$b`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || {
+            format!(
+                "'{}' is not a valid constraint of the form $a==$b or $a!=$b",
+                s
+            )
+        };
+        let (left, op, right) = Comparison::split(s).ok_or_else(err)?;
+
+        let is_variable = |v: &str| {
+            v.len() > 1
+                && v.starts_with('$')
+                && v[1..].chars().all(|c| c == '_' || c.is_alphanumeric())
+        };
+        if !matches!(op, Comparison::Equal | Comparison::NotEqual)
+            || !is_variable(left)
+            || !is_variable(right)
+        {
+            return Err(err());
+        }
+
+        Ok(VariableConstraint {
+            left: left.to_string(),
+            op,
+            right: right.to_string(),
+        })
+    }
+}
+
+impl VariableConstraint {
+    /// Check the constraint for the captured values of both variables. The
+    /// constraint is satisfied if one of them is not bound.
+    pub fn check(&self, left: Option<&str>, right: Option<&str>) -> bool {
+        match (left, right) {
+            (Some(a), Some(b)) => util::values_equal(a, b) == (self.op == Comparison::Equal),
+            _ => true,
+        }
+    }
+}
+
+/// A change that was made to a search pattern before it was compiled,
+/// see `QueryTree::normalizations`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Normalization {
+    /// A missing `;` was added at the end of the pattern.
+    AddSemicolon,
+    /// The pattern was wrapped into a compound statement `{..}`.
+    AddBraces,
+}
+
+impl std::fmt::Display for Normalization {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Normalization::AddSemicolon => write!(f, "added missing ';'"),
+            Normalization::AddBraces => write!(f, "wrapped in {{}}"),
+        }
+    }
+}
+
+/// Translate the search pattern in `pattern` into a weggli QueryTree.
+/// `is_cpp` enables C++ mode. `force_query` can be used to allow queries with syntax errors.
+/// We support some basic normalization (adding { } around queries) and store the normalized form
+/// in `normalized_patterns` to avoid lifetime issues.
+pub fn parse_search_pattern(
+    pattern: &str,
+    is_cpp: bool,
+    force_query: bool,
+    options: Option<QueryOptions>,
+) -> Result<QueryTree, QueryError> {
+    parse_search_pattern_with(pattern, language::for_cpp(is_cpp), force_query, options)
+}
+
+/// Translate the search pattern in `pattern` into a QueryTree for `language`.
+/// See `parse_search_pattern`.
+pub fn parse_search_pattern_with(
+    pattern: &str,
+    language: &'static dyn Language,
+    force_query: bool,
+    options: Option<QueryOptions>,
+) -> Result<QueryTree, QueryError> {
+    let mut options = options.unwrap_or_default();
+    let (comment, pattern) = &extract_root_comment(pattern, &mut options)?;
+    let original = pattern.as_str();
+    let mut source = SourceMap::new(original);
+    let pattern = &extract_regex_constraints(pattern, &mut options, &mut source)?;
+    let pattern = &extract_number_constraints(pattern, &mut options, &mut source)?;
+    let pattern = &extract_excluded_identifiers(pattern, &mut options, &mut source)?;
+    let pattern = &replace_argument_ellipses(pattern, &mut source);
+    let pattern = &replace_count_quantifiers(pattern, &mut source)?;
+    let pattern = &replace_designator_negations(pattern, &mut source);
+
+    let mut tree = language.parse(pattern);
+    let mut p = pattern.as_str();
+
+    let temp_pattern;
+
+    // The user supplied pattern with the same normalizations applied, see QueryTree::pattern.
+    let mut normalized = original.to_string();
+    let mut normalizations = Vec::new();
+    let skip_normalization = options.skip_normalization;
+
+    // Try to fix missing ';' at the end of a query.
+    // weggli 'memcpy(a,b,size)' should work.
+    if tree.root_node().has_error() && !pattern.ends_with(';') {
+        temp_pattern = format!("{};", &p);
+        let fixed_tree = language.parse(&temp_pattern);
+        if !fixed_tree.root_node().has_error() {
+            if skip_normalization {
+                return Err(QueryError {
+                    message: not_normalized(&format!("{};", original)),
+                });
+            }
+            info!("normalizing query: add missing ;");
+            tree = fixed_tree;
+            p = &temp_pattern;
+            normalized.push(';');
+            normalizations.push(Normalization::AddSemicolon);
+        }
+    }
+
+    let temp_pattern2;
+
+    // Try to do query normalization to support missing { }
+    // 'memcpy(_);' -> {memcpy(_);}
+    if !tree.root_node().has_error() {
+        let c = tree.root_node().child(0);
+        if let Some(n) = c {
+            if !language.query_root_kinds().contains(&n.kind()) && !is_function_declaration(n) {
+                temp_pattern2 = format!("{{{}}}", &p);
+                let fixed_tree = language.parse(&temp_pattern2);
+                if !fixed_tree.root_node().has_error() {
+                    if skip_normalization {
+                        return Err(QueryError {
+                            message: not_normalized(&format!("{}{{{}}}", comment, normalized)),
+                        });
+                    }
+                    info!("normalizing query: add {}", "{}");
+                    tree = fixed_tree;
+                    p = &temp_pattern2;
+                    normalized = format!("{{{}}}", normalized);
+                    normalizations.push(Normalization::AddBraces);
+                }
+            }
+        }
+    }
+
+    let mut c = validate_query(&tree, &normalized, &source, language, force_query)?;
+
+    let mut qt = builder::build_query_tree_with(p, &mut c, language, Some(options))?;
+    qt.set_pattern(format!("{}{}", comment, normalized), normalizations);
+    Ok(qt)
+}
+
+/// Translate the raw tree-sitter query `sexp` into a QueryTree, for node-level
+/// searches that can't be expressed as a weggli pattern. `is_cpp` selects the
+/// grammar the node kinds refer to. See `parse_raw_query_with`.
+pub fn parse_raw_query(
+    sexp: &str,
+    is_cpp: bool,
+    options: Option<QueryOptions>,
+) -> Result<QueryTree, QueryError> {
+    parse_raw_query_with(sexp, language::for_cpp(is_cpp), options)
+}
+
+/// Translate the raw tree-sitter query `sexp` into a QueryTree for `language`.
+/// Every capture `@name` binds the query variable `$name`, so that results can be
+/// displayed and constrained like the results of weggli patterns. Multiple
+/// patterns in `sexp` are alternatives. Results are anchored to the node enclosing
+/// the first capture (see --anchor), or the top-level node containing it.
+/// Text predicates such as `(#eq? @a @b)` and `(#match? @a "re")` are supported.
+pub fn parse_raw_query_with(
+    sexp: &str,
+    language: &'static dyn Language,
+    options: Option<QueryOptions>,
+) -> Result<QueryTree, QueryError> {
+    let options = options.unwrap_or_default();
+    let query = Query::new(language.grammar(), sexp).map_err(|e| QueryError {
+        message: format!(
+            "{}invalid tree-sitter query at {}:{}:\n{}",
+            "Error: ".red(),
+            e.row + 1,
+            e.column + 1,
+            e.message
+        ),
+    })?;
+    if query.capture_names().is_empty() {
+        return Err(QueryError {
+            message: format!(
+                "{}raw queries need at least one capture (@name)",
+                "Error: ".red()
+            ),
+        });
+    }
+
+    let variables: Vec<String> = query
+        .capture_names()
+        .iter()
+        .map(|n| format!("${}", n))
+        .collect();
+    let captures = variables
+        .iter()
+        .map(|v| capture::Capture::Variable(v.clone(), options.regexes().get(v)))
+        .collect();
+    let compiled = query::CompiledQuery::new(sexp.to_string(), language)?;
+
+    let mut qt = QueryTree::new(
+        compiled,
+        captures,
+        variables.iter().cloned().collect(),
+        vec![],
+        0,
+    );
+    qt.set_raw();
+    qt.set_length_constraints(options.length_constraints());
+    qt.set_number_constraints(options.number_constraints());
+    qt.set_variable_constraints(options.variable_constraints());
+    qt.set_anchor(options.anchor());
+    qt.set_uniqueness(options.uniqueness());
+    qt.set_clauses(vec![sexp.to_string()], vec![0; variables.len()]);
+    qt.set_pattern(sexp.to_string(), Vec::new());
+    Ok(qt)
+}
+
+// Error message for patterns that are only valid after normalization.
+fn not_normalized(normalized: &str) -> String {
+    format!(
+        "{} query parsing failed without normalization. Did you mean '{}'?",
+        "Error!".red().bold(),
+        normalized
+    )
+}
+
+/// Remove a leading comment from `pattern` and store its text as the comment regex
+/// in `options` ('/* TODO.*overflow */ {memcpy($d,_,_);}'). Returns the comment
+/// (followed by a space, or empty without one) and the rest of the pattern.
+fn extract_root_comment(
+    pattern: &str,
+    options: &mut QueryOptions,
+) -> Result<(String, String), QueryError> {
+    let comment = Regex::new(r"^\s*(/\*(?s:(.*?))\*/|//([^\n]*)(\n|$))").unwrap();
+
+    let c = match comment.captures(pattern) {
+        Some(c) => c,
+        None => return Ok((String::new(), pattern.to_string())),
+    };
+
+    let text = c.get(2).or_else(|| c.get(3)).unwrap().as_str().trim();
+    let regex = Regex::new(text).map_err(|e| QueryError {
+        message: format!("invalid regex in comment '{}': {}", c[1].trim(), e),
+    })?;
+    options.comment = Some(regex);
+
+    Ok((
+        format!("{} ", c[1].trim()),
+        pattern[c.get(0).unwrap().end()..].trim_start().to_string(),
+    ))
+}
+
+/// Remove inline regex constraints from variables in `pattern` ($f~"^mem" -> $f,
+/// $buf!~"^safe_" -> $buf) and add them to `options`, just like --regex.
+fn extract_regex_constraints(
+    pattern: &str,
+    options: &mut QueryOptions,
+    source: &mut SourceMap,
+) -> Result<String, QueryError> {
+    let inline = Regex::new(r#"(\$[[:word:]]+)(!?)~"((?:[^"\\]|\\.)*)""#).unwrap();
+
+    let mut inline_constraints: HashMap<&str, &str> = HashMap::new();
+    for c in inline.captures_iter(pattern) {
+        let var = c.get(1).unwrap().as_str();
+        let raw_regex = c[3].replace("\\\"", "\"");
+        if let Some(previous) = inline_constraints.insert(var, c.get(0).unwrap().as_str()) {
+            if previous != &c[0] {
+                return Err(QueryError {
+                    message: format!("'{}' has more than one regex constraint", var),
+                });
+            }
+        }
+        let regex = Regex::new(&raw_regex).map_err(|e| QueryError {
+            message: format!("invalid regex in '{}': {}", &c[0], e),
+        })?;
+        options.regexes.constrain(var, &c[2] == "!", regex);
+    }
+
+    Ok(source.replace_all(pattern, &inline, |c| c[1].to_string()))
+}
+
+/// Remove inline numeric range constraints from variables in `pattern`
+/// ($size[>4096] -> $size) and add them to `options`, just like --num-constraint.
+fn extract_number_constraints(
+    pattern: &str,
+    options: &mut QueryOptions,
+    source: &mut SourceMap,
+) -> Result<String, QueryError> {
+    let inline = Regex::new(r"(\$[[:word:]]+)\[\s*((?:<=|>=|==|!=|<|>)[^\]]*)\]").unwrap();
+
+    for c in inline.captures_iter(pattern) {
+        let constraint = format!("{}{}", &c[1], &c[2]);
+        let constraint = constraint.parse().map_err(|e| QueryError { message: e })?;
+        options.number_constraints.push(constraint);
+    }
+
+    Ok(source.replace_all(pattern, &inline, |c| c[1].to_string()))
+}
+
+/// Remove identifier sets from variables in `pattern` ($f!{memcpy,memmove} -> $f)
+/// and store them as excluded identifiers in `options`.
+fn extract_excluded_identifiers(
+    pattern: &str,
+    options: &mut QueryOptions,
+    source: &mut SourceMap,
+) -> Result<String, QueryError> {
+    let set = Regex::new(r"(\$[[:word:]]+)!\{([^}]*)\}").unwrap();
+    let identifier = Regex::new(r"^[[:alpha:]_][[:word:]]*(::[[:alpha:]_][[:word:]]*)*$").unwrap();
+
+    for c in set.captures_iter(pattern) {
+        let names: Vec<String> = c[2].split(',').map(|n| n.trim().to_string()).collect();
+        if let Some(n) = names.iter().find(|n| !identifier.is_match(n)) {
+            return Err(QueryError {
+                message: format!("'{}' in '{}' is not a valid identifier", n, &c[0]),
+            });
+        }
+        options.exclude(&c[1], names);
+    }
+
+    Ok(source.replace_all(pattern, &set, |c| c[1].to_string()))
+}
+
+/// Replace `...` and `$...` wildcards in argument lists (memcpy($dst, ..., $len))
+/// with an identifier placeholder, as neither of them can be parsed as an argument.
+fn replace_argument_ellipses(pattern: &str, source: &mut SourceMap) -> String {
+    let ellipsis = Regex::new(r"([(,]\s*)\$?\.\.\.(\s*[,)])").unwrap();
+
+    // Matches can't overlap, so f(..., ...) needs more than one pass.
+    let mut result = pattern.to_string();
+    while ellipsis.is_match(&result) {
+        result = source.replace_all(&result, &ellipsis, |c| {
+            format!("{}{}{}", &c[1], builder::ELLIPSIS, &c[2])
+        });
+    }
+    result
+}
+
+/// Replace `not:` in front of designated initializers ({.read = $f, not: .write = _})
+/// with an array designator that can be parsed, see builder::NOT_DESIGNATOR.
+fn replace_designator_negations(pattern: &str, source: &mut SourceMap) -> String {
+    let negation = Regex::new(r"([{,]\s*)(?i:not)\s*:\s*(\.\s*[A-Za-z_$])").unwrap();
+    source.replace_all(pattern, &negation, |c| {
+        format!("{}[{}] {}", &c[1], builder::NOT_DESIGNATOR, &c[2])
+    })
+}
+
+/// Replace occurrence-count quantifiers (count(>=3): $f($x);) in `pattern` with
+/// a label that can be parsed, see builder::count_label.
+fn replace_count_quantifiers(pattern: &str, source: &mut SourceMap) -> Result<String, QueryError> {
+    let quantifier = Regex::new(r"\bcount\(\s*(<=|>=|==|!=|<|>)\s*(\d+)\s*\)\s*:").unwrap();
+    let label = |c: &regex::Captures| {
+        c[2].parse()
+            .ok()
+            .and_then(|n| builder::count_label(&c[1], n))
+    };
+
+    if let Some(c) = quantifier
+        .captures_iter(pattern)
+        .find(|c| label(c).is_none())
+    {
+        return Err(QueryError {
+            message: format!("'{}' is not a valid count quantifier", &c[0]),
+        });
+    }
+    Ok(source.replace_all(pattern, &quantifier, |c| format!("{}:", label(c).unwrap())))
+}
+
+/// The edits made to a search pattern before it is parsed: inline constraints are
+/// removed, and wildcards, quantifiers and not: designators are replaced with placeholders. Used to
+/// quote the pattern as it was written in error messages.
+struct SourceMap<'a> {
+    written: &'a str,
+    // The replacements of every rewrite, as (range in its input, range in its output).
+    rewrites: Vec<Vec<(Range<usize>, Range<usize>)>>,
+}
+
+impl<'a> SourceMap<'a> {
+    fn new(written: &'a str) -> Self {
+        SourceMap {
+            written,
+            rewrites: Vec::new(),
+        }
+    }
+
+    /// Like `Regex::replace_all`, but records the replacements.
+    fn replace_all(
+        &mut self,
+        pattern: &str,
+        regex: &Regex,
+        mut replace: impl FnMut(&regex::Captures) -> String,
+    ) -> String {
+        let mut result = String::with_capacity(pattern.len());
+        let mut replacements = Vec::new();
+        let mut last = 0;
+        for c in regex.captures_iter(pattern) {
+            let m = c.get(0).unwrap();
+            result.push_str(&pattern[last..m.start()]);
+            let start = result.len();
+            result.push_str(&replace(&c));
+            replacements.push((m.range(), start..result.len()));
+            last = m.end();
+        }
+        result.push_str(&pattern[last..]);
+        self.rewrites.push(replacements);
+        result
+    }
+
+    /// Split the written pattern into the text before, within and after the text
+    /// that `range` of the rewritten pattern comes from. Each bound is mapped once,
+    /// so the three parts always add up to the written pattern.
+    fn split(&self, range: Range<usize>) -> (&'a str, &'a str, &'a str) {
+        let start = self.offset(range.start, false);
+        let end = self.offset(range.end, true).max(start);
+        (
+            &self.written[..start],
+            &self.written[start..end],
+            &self.written[end..],
+        )
+    }
+
+    // Map `offset` in the rewritten pattern to the written pattern. An offset within
+    // a replacement maps to the start of the replaced text, or to its end if `end` is set.
+    fn offset(&self, mut offset: usize, end: bool) -> usize {
+        for replacements in self.rewrites.iter().rev() {
+            let mut mapped = offset;
+            for (input, output) in replacements {
+                if offset <= output.start {
+                    break;
+                } else if offset >= output.end {
+                    mapped = offset - output.end + input.end;
+                } else {
+                    mapped = if end { input.end } else { input.start };
+                    break;
+                }
+            }
+            offset = mapped;
+        }
+        offset
+    }
+}
+
+/// Returns true if `n` is a function declaration (prototype) such as `int foo(char *, size_t);`
+/// Queries rooted in a function declaration match on prototypes and on the
+/// signature of function definitions.
+pub(crate) fn is_function_declaration(n: tree_sitter::Node) -> bool {
+    if n.kind() != "declaration"
+        || n.children_by_field_name("declarator", &mut n.walk())
+            .count()
+            != 1
+    {
+        return false;
+    }
+
+    let mut declarator = match n.child_by_field_name("declarator") {
+        Some(d) => d,
+        None => return false,
+    };
+
+    while declarator.kind() == "pointer_declarator" {
+        declarator = match declarator.child_by_field_name("declarator") {
+            Some(d) => d,
+            None => return false,
+        };
+    }
+
+    declarator.kind() == "function_declarator"
+}
+
+/// Validates the user supplied search query and quits with an error message in case
+/// it contains syntax errors or isn't rooted in one of the query root kinds of `language`.
+/// If `force` is true, syntax errors are ignored. Returns a cursor to the
+/// root node. Error messages quote the pattern as `written`, syntax errors are
+/// mapped back to it with `source`.
+fn validate_query<'a>(
+    tree: &'a tree_sitter::Tree,
+    written: &str,
+    source: &SourceMap,
+    language: &dyn Language,
+    force: bool,
+) -> Result<tree_sitter::TreeCursor<'a>, QueryError> {
+    if tree.root_node().has_error() && !force {
+        let mut errmsg = format!("{}", "Error! Query parsing failed:".red().bold());
+        let mut cursor = tree.root_node().walk();
+
+        let mut first_error = None;
+        loop {
+            let node = cursor.node();
+            if node.has_error() {
+                if node.is_error() || node.is_missing() {
+                    first_error = Some(node);
+                    break;
+                } else if !cursor.goto_first_child() {
+                    break;
+                }
+            } else if !cursor.goto_next_sibling() {
+                break;
+            }
+        }
+
+        if let Some(node) = first_error {
+            let (before, error, after) = source.split(node.start_byte()..node.end_byte());
+            errmsg.push_str(&format!(" {}", before.italic()));
+            if node.is_missing() {
+                errmsg.push_str(&format!(
+                    "{}{}{}",
+                    " [MISSING ".red(),
+                    node.kind().red().bold(),
+                    " ] ".red()
+                ));
+            }
+            errmsg.push_str(&format!(
+                "{}{}",
+                error.red().italic().bold(),
+                after.italic()
+            ));
+        }
+
+        return Err(QueryError { message: errmsg });
+    }
+
+    info!("query sexp: {}", tree.root_node().to_sexp());
+
+    let mut c = tree.walk();
+
+    if c.node().named_child_count() > 1 {
+        return Err(QueryError {
+            message: format!(
+                "{}'{}' query contains multiple root nodes",
+                "Error: ".red(),
+                written
+            ),
+        });
+    }
+
+    c.goto_first_child();
+
+    if !language.query_root_kinds().contains(&c.node().kind()) && !is_function_declaration(c.node())
+    {
+        return Err(QueryError {
+            message: format!(
+                "{}'{}' is not a supported query root node.",
+                "Error: ".red(),
+                written
+            ),
+        });
+    }
+
+    Ok(c)
+}
@@ -0,0 +1,993 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use regex::Regex;
+use rustc_hash::FxHashMap;
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+use std::cell::Cell;
+use std::collections::{BTreeSet, HashSet};
+use std::time::Instant;
+use tree_sitter::{Node, Query};
+
+use crate::capture::{ArgumentPosition, Capture};
+use crate::language::Language;
+use crate::result::{is_local_variable, CaptureResult, QueryResult};
+use crate::util::{parse_number_literal, values_equal};
+use crate::{
+    Anchor, Bound, Comparison, LengthConstraint, Normalization, NumberConstraint, QueryError,
+    Uniqueness, VariableConstraint,
+};
+
+/// A query tree is our internal representation of a weggli search query.
+/// tree-sitter's query syntax does not support all features that we need so
+/// one weggli query will be split up into a tree of sub-queries, each
+/// with it's own captures and variables.
+///
+/// Query trees can be serialized with serde, so that compiled queries can be
+/// cached. The tree-sitter queries are stored as s-expressions and compiled
+/// again when they are loaded, which is still much faster than building them.
+/// Serialized queries can only be loaded by the same version of weggli.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueryTree {
+    query: CompiledQuery,
+    captures: Vec<Capture>,
+    negations: Vec<NegativeQuery>,
+    // sorted, so that serialized queries are deterministic
+    variables: BTreeSet<String>,
+    id: usize,
+    length_constraints: Vec<LengthConstraint>,
+    number_constraints: Vec<NumberConstraint>,
+    variable_constraints: Vec<VariableConstraint>,
+    anchor: Anchor,
+    uniqueness: Uniqueness,
+    // source of the sub-patterns (e.g. the statements of a compound statement)
+    clauses: Vec<String>,
+    // index into `clauses` for each capture
+    capture_clauses: Vec<usize>,
+    // count(..): quantifier for each pattern of a compound query
+    counts: Vec<Option<Quantifier>>,
+    // for each entry of `clauses`, whether it can be missing from a match
+    #[serde(default)]
+    optional_clauses: Vec<bool>,
+    // the patterns of a compound query can match in any order (designated initializers)
+    #[serde(default)]
+    unordered: bool,
+    // a raw tree-sitter query: its patterns are alternatives and results are anchored
+    // to the node enclosing the first capture
+    #[serde(default)]
+    raw: bool,
+    // the search pattern after normalization and the normalizations that were applied
+    pattern: String,
+    normalizations: Vec<Normalization>,
+    // regex for the comment preceding the anchor node and the capture index of the comment
+    #[serde(with = "crate::util::serde_regex::comment")]
+    comment: Option<(Regex, u32)>,
+}
+
+/// A tree-sitter query together with the s-expression and the language it was
+/// compiled from. Only the latter are serialized.
+pub(crate) struct CompiledQuery {
+    query: Query,
+    sexp: String,
+    language: &'static dyn Language,
+}
+
+impl CompiledQuery {
+    pub(crate) fn new(
+        sexp: String,
+        language: &'static dyn Language,
+    ) -> Result<CompiledQuery, QueryError> {
+        Ok(CompiledQuery {
+            query: crate::ts_query(&sexp, language)?,
+            sexp,
+            language,
+        })
+    }
+}
+
+impl std::fmt::Debug for CompiledQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CompiledQuery")
+            .field("language", &self.language.name())
+            .field("sexp", &self.sexp)
+            .finish()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SerializedQuery<'a> {
+    version: &'a str,
+    language: &'a str,
+    sexp: &'a str,
+}
+
+impl Serialize for CompiledQuery {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        SerializedQuery {
+            version: env!("CARGO_PKG_VERSION"),
+            language: self.language.name(),
+            sexp: &self.sexp,
+        }
+        .serialize(s)
+    }
+}
+
+impl<'de> Deserialize<'de> for CompiledQuery {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        // Borrowing only works for formats without escapes, so the strings are owned.
+        #[derive(Deserialize)]
+        struct Owned {
+            version: String,
+            language: String,
+            sexp: String,
+        }
+        let q = Owned::deserialize(d)?;
+
+        // The generated s-expressions change between versions.
+        if q.version != env!("CARGO_PKG_VERSION") {
+            return Err(D::Error::custom(format!(
+                "query was compiled by weggli {}, this is {}",
+                q.version,
+                env!("CARGO_PKG_VERSION")
+            )));
+        }
+        let language = crate::language::by_name(&q.language)
+            .ok_or_else(|| D::Error::custom(format!("unknown language '{}'", q.language)))?;
+        CompiledQuery::new(q.sexp, language).map_err(|e| D::Error::custom(e.message))
+    }
+}
+
+/// A count(..): quantifier, the number of matches a statement needs to have.
+pub(crate) type Quantifier = (Comparison, usize);
+
+/// An internal cache for memoization of subquery results. It also carries the
+/// deadline of `matches_until`, as it is passed to all (sub)query executions.
+#[derive(Default)]
+struct Cache {
+    results: FxHashMap<CacheKey, Vec<QueryResult>>,
+    deadline: Option<Instant>,
+    timed_out: Cell<bool>,
+}
+
+impl Cache {
+    // Returns true once the deadline has passed.
+    fn timed_out(&self) -> bool {
+        if let (Some(deadline), false) = (self.deadline, self.timed_out.get()) {
+            self.timed_out.set(Instant::now() >= deadline);
+        }
+        self.timed_out.get()
+    }
+}
+
+/// Negative Queries are used to implement the not: feature.
+/// In addition to the QueryTree we also store the
+/// index of the previous capture in the parent query to enforce
+/// ordering later on. (e.g a match for the negative query is only valid
+/// if it comes AFTER the previous capture)
+/// Negative queries with `anywhere` set (never:) are not ordered and match
+/// anywhere in the enclosing function. Those with `unordered` set (not: in
+/// an initializer list) match anywhere in the node of the parent query.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NegativeQuery {
+    pub qt: Box<QueryTree>,
+    pub previous_capture_index: i64,
+    pub anywhere: bool,
+    #[serde(default)]
+    pub unordered: bool,
+}
+
+// Identify cache entries by the query id and the queried node.
+#[derive(PartialEq, Eq, Hash, Clone)]
+struct CacheKey {
+    query_id: usize,
+    node_id: usize,
+}
+
+impl QueryTree {
+    pub(crate) fn new(
+        query: CompiledQuery,
+        captures: Vec<Capture>,
+        variables: HashSet<String>,
+        negations: Vec<NegativeQuery>,
+        id: usize,
+    ) -> QueryTree {
+        QueryTree {
+            query,
+            captures,
+            variables: variables.into_iter().collect(),
+            negations,
+            id,
+            length_constraints: Vec::new(),
+            number_constraints: Vec::new(),
+            variable_constraints: Vec::new(),
+            anchor: Anchor::default(),
+            uniqueness: Uniqueness::default(),
+            clauses: Vec::new(),
+            capture_clauses: Vec::new(),
+            counts: Vec::new(),
+            optional_clauses: Vec::new(),
+            unordered: false,
+            raw: false,
+            pattern: String::new(),
+            normalizations: Vec::new(),
+            comment: None,
+        }
+    }
+
+    /// Set the string length constraints for this query.
+    /// Constraints with a constant bound are enforced as soon as the variable is captured,
+    /// constraints that depend on a second variable once the whole query matched.
+    pub(crate) fn set_length_constraints(&mut self, constraints: &[LengthConstraint]) {
+        self.length_constraints = constraints.to_vec();
+    }
+
+    /// Set the numeric range constraints for this query. They are enforced as soon
+    /// as the variable is captured.
+    pub(crate) fn set_number_constraints(&mut self, constraints: &[NumberConstraint]) {
+        self.number_constraints = constraints.to_vec();
+    }
+
+    /// Set the constraints between pairs of variables. They are enforced once the
+    /// whole query matched.
+    pub(crate) fn set_variable_constraints(&mut self, constraints: &[VariableConstraint]) {
+        self.variable_constraints = constraints.to_vec();
+    }
+
+    /// Set the node that matches are anchored to. This is only used to drop
+    /// duplicate results for enclosing compound statements, the anchor node
+    /// itself is part of the tree-sitter query.
+    pub(crate) fn set_anchor(&mut self, anchor: Anchor) {
+        self.anchor = anchor;
+    }
+
+    /// Require the variables of all results to match distinct values. This is
+    /// enforced for every partial result, so bindings with identical values are
+    /// dropped before they are merged with other sub-patterns. Applies to all
+    /// sub queries except negations, which only exclude results.
+    pub(crate) fn set_uniqueness(&mut self, uniqueness: Uniqueness) {
+        self.uniqueness = uniqueness;
+        for c in &mut self.captures {
+            if let Capture::Subquery(t) = c {
+                t.set_uniqueness(uniqueness);
+            }
+        }
+    }
+
+    // Returns true if the value of `variable` has to differ from the other variables.
+    // Local variables of sub queries are dropped from their results and are
+    // thus only compared in the outermost query.
+    fn is_unique_variable(&self, variable: &str) -> bool {
+        self.uniqueness != Uniqueness::Any && (self.id == 0 || !is_local_variable(variable))
+    }
+
+    // Returns false if two variables of `r` match identical values (see `set_uniqueness`).
+    fn is_unique(&self, r: &QueryResult, source: &str) -> bool {
+        if self.uniqueness == Uniqueness::Any {
+            return true;
+        }
+        let values: Vec<&str> = r
+            .vars
+            .keys()
+            .filter(|k| self.is_unique_variable(k))
+            .filter_map(|k| r.value(k, source))
+            .collect();
+        values.iter().enumerate().all(|(i, a)| {
+            values[i + 1..]
+                .iter()
+                .all(|b| !self.uniqueness.identical(a, b))
+        })
+    }
+
+    /// Set the sub-patterns of this query and the sub-pattern each capture belongs to.
+    pub(crate) fn set_clauses(&mut self, clauses: Vec<String>, capture_clauses: Vec<usize>) {
+        self.clauses = clauses;
+        self.capture_clauses = capture_clauses;
+    }
+
+    /// Set the count(..): quantifiers of the patterns of a compound query. Patterns with
+    /// a quantifier need to match the given number of times instead of at least once.
+    pub(crate) fn set_counts(&mut self, counts: Vec<Option<Quantifier>>) {
+        self.counts = counts;
+    }
+
+    /// Mark the sub-patterns that don't need to match for the whole query to match,
+    /// such as the alternatives of an or: group or statements with a count(==0): quantifier.
+    /// They are left out of `identifiers`.
+    pub(crate) fn set_optional_clauses(&mut self, optional_clauses: Vec<bool>) {
+        self.optional_clauses = optional_clauses;
+    }
+
+    // Returns true if capture `index` belongs to an optional sub-pattern.
+    fn is_optional_capture(&self, index: usize) -> bool {
+        self.capture_clauses
+            .get(index)
+            .and_then(|&clause| self.optional_clauses.get(clause))
+            .copied()
+            .unwrap_or(false)
+    }
+
+    /// Allow the patterns of a compound query to match in any order.
+    pub(crate) fn set_unordered(&mut self) {
+        self.unordered = true;
+    }
+
+    /// Mark this query as a raw tree-sitter query, see `parse_raw_query`.
+    pub(crate) fn set_raw(&mut self) {
+        self.raw = true;
+    }
+
+    // Returns the count(..): quantifier of pattern `index`.
+    fn count(&self, index: usize) -> Option<Quantifier> {
+        self.counts.get(index).copied().flatten()
+    }
+
+    /// Only keep results whose anchor node is preceded by a comment matching `regex`.
+    /// The comment is added to the results as capture `capture_idx`.
+    pub(crate) fn set_comment(&mut self, regex: Regex, capture_idx: u32) {
+        self.comment = Some((regex, capture_idx));
+    }
+
+    /// Set the search pattern this query was compiled from and the normalizations
+    /// that were applied to it.
+    pub(crate) fn set_pattern(&mut self, pattern: String, normalizations: Vec<Normalization>) {
+        self.pattern = pattern;
+        self.normalizations = normalizations;
+    }
+
+    /// The search pattern as it was compiled, after normalizations such as adding a
+    /// missing `;` or wrapping it into `{..}`. Empty for queries that were not built
+    /// with `parse_search_pattern`.
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// The language the query was built for. It only matches in files parsed
+    /// with the same grammar.
+    pub fn language(&self) -> &'static dyn Language {
+        self.query.language
+    }
+
+    /// The normalizations that were applied to the search pattern, see `pattern`.
+    pub fn normalizations(&self) -> &[Normalization] {
+        &self.normalizations
+    }
+
+    /// Return the source of the sub-pattern that produced a capture, identified
+    /// by the `query_id` and `clause` of its CaptureResult.
+    pub fn clause(&self, query_id: usize, clause: usize) -> Option<&str> {
+        self.find(query_id)?.clauses.get(clause).map(|c| c.as_str())
+    }
+
+    /// Return the variable bound by a capture, identified by the `query_id` and
+    /// `capture_idx` of its CaptureResult.
+    pub fn capture_variable(&self, query_id: usize, capture_idx: u32) -> Option<&str> {
+        match self.find(query_id)?.captures.get(capture_idx as usize)? {
+            Capture::Variable(s, _) => Some(s),
+            _ => None,
+        }
+    }
+
+    // Find the (sub)query with the id `query_id`.
+    fn find(&self, query_id: usize) -> Option<&QueryTree> {
+        if query_id == self.id {
+            return Some(self);
+        }
+        let subqueries = self.captures.iter().filter_map(|c| match c {
+            Capture::Subquery(t) => Some(t.as_ref()),
+            _ => None,
+        });
+        subqueries
+            .chain(self.negations.iter().map(|n| n.qt.as_ref()))
+            .find_map(|t| t.find(query_id))
+    }
+
+    /// Return all query variables used in a query.
+    pub fn variables(&self) -> HashSet<String> {
+        let mut result = HashSet::new();
+        self.collect_variables(true, &mut result);
+        result
+    }
+
+    /// Return the query variables that are bound in results. Unlike `variables`,
+    /// this skips variables that only appear in negative sub queries (not:, never:).
+    pub fn result_variables(&self) -> HashSet<String> {
+        let mut result = HashSet::new();
+        self.collect_variables(false, &mut result);
+        result
+    }
+
+    fn collect_variables(&self, negations: bool, result: &mut HashSet<String>) {
+        for c in &self.captures {
+            match c {
+                Capture::Variable(s, _) => {
+                    result.insert(s.to_string());
+                }
+                Capture::Subquery(t) => t.collect_variables(negations, result),
+                _ => (),
+            }
+        }
+
+        if negations {
+            for neg in &self.negations {
+                neg.qt.collect_variables(negations, result)
+            }
+        }
+    }
+
+    /// Return the variables with a numeric range constraint. They only match number literals.
+    pub fn number_variables(&self) -> HashSet<String> {
+        self.number_constraints
+            .iter()
+            .map(|c| c.variable.clone())
+            .collect()
+    }
+
+    /// Return all identifiers (function, variable and types) used in a query.
+    /// This can be used to filter inputs without doing a full parse, so identifiers
+    /// of sub-patterns that don't need to match (e.g. or: alternatives) are skipped.
+    pub fn identifiers(&self) -> Vec<String> {
+        let mut result = Vec::new();
+        for (i, c) in self.captures.iter().enumerate() {
+            if self.is_optional_capture(i) {
+                continue;
+            }
+            match c {
+                Capture::Check(s) => result.push(s.to_string()),
+                Capture::Subquery(t) => {
+                    let mut sub_identifiers = t.identifiers();
+                    result.append(&mut sub_identifiers);
+                }
+                _ => (),
+            }
+        }
+
+        result
+    }
+
+    // Find all matches for the input described by the AST `root` node and its source code.
+    // This is a simple wrapper around QueryTree::match_internal
+    pub fn matches(&self, root: Node, source: &str) -> Vec<QueryResult> {
+        self.matches_with(root, source, &mut Cache::default())
+    }
+
+    /// Like `matches`, but gives up once `deadline` has passed and returns None.
+    /// Pathological inputs (huge generated functions) can take minutes otherwise.
+    pub fn matches_until(
+        &self,
+        root: Node,
+        source: &str,
+        deadline: Instant,
+    ) -> Option<Vec<QueryResult>> {
+        let mut cache = Cache {
+            deadline: Some(deadline),
+            ..Default::default()
+        };
+        let results = self.matches_with(root, source, &mut cache);
+        if cache.timed_out.get() {
+            None
+        } else {
+            Some(results)
+        }
+    }
+
+    fn matches_with(&self, root: Node, source: &str, cache: &mut Cache) -> Vec<QueryResult> {
+        let mut results = self.match_internal(root, source, cache);
+        results.dedup();
+
+        // A match inside nested blocks is found once for every enclosing compound
+        // statement. Only keep the innermost one.
+        if self.anchor == Anchor::Compound {
+            results.sort_by_key(|r| r.function_range().len());
+            let mut seen = HashSet::new();
+            results.retain(|r| {
+                let function = r.function_range();
+                let mut key: Vec<(usize, usize)> = r
+                    .captures
+                    .iter()
+                    .filter(|c| c.range != function)
+                    .map(|c| (c.range.start, c.range.end))
+                    .collect();
+                key.sort_unstable();
+                seen.insert(key)
+            });
+            results.sort_by_key(|r| r.start_offset());
+        }
+
+        if self
+            .length_constraints
+            .iter()
+            .any(|c| matches!(c.bound, Bound::Variable(_)))
+        {
+            results.retain(|r| {
+                self.length_constraints.iter().all(|c| match &c.bound {
+                    Bound::Variable(v) => r
+                        .value(&c.variable, source)
+                        .map_or(true, |value| c.check(value, r.value(v, source))),
+                    Bound::Constant(_) => true,
+                })
+            });
+        }
+
+        if !self.variable_constraints.is_empty() {
+            results.retain(|r| {
+                self.variable_constraints
+                    .iter()
+                    .all(|c| c.check(r.value(&c.left, source), r.value(&c.right, source)))
+            });
+        }
+
+        if let Some((regex, capture_idx)) = &self.comment {
+            results.retain_mut(|r| {
+                let comment = attached_comment(root, r.function_range(), regex, source);
+                let comment = match comment {
+                    Some(comment) => comment,
+                    None => return false,
+                };
+                r.captures.push(CaptureResult {
+                    range: comment.byte_range(),
+                    query_id: self.id,
+                    capture_idx: *capture_idx,
+                    clause: 0,
+                    kind: comment.kind(),
+                });
+                true
+            });
+        }
+        results
+    }
+
+    /// This is the core method for query matching.
+    /// We start with outermost query and use tree-sitter's API to find all matching nodes.
+    //  Due to our query predicates, this already takes care of all identifiers and variables.
+    //  Once we have a match, we still need to recursively execute all subqueries and merge
+    //  their results. Merging will remove results where a subquery requires different
+    //  variable assignment from the rest.
+    //  To avoid repeated work, we memoize results of subqueries in the `cache` hashmap and
+    //  use them when feasible.
+    //  TODO: Benchmark if caching or earlier variable enforcement is faster.
+    fn match_internal(&self, root: Node, source: &str, cache: &mut Cache) -> Vec<QueryResult> {
+        let mut qc = tree_sitter::QueryCursor::new();
+
+        let num_patterns = self.query.query.pattern_count();
+        let mut pattern_results = Vec::with_capacity(num_patterns + 1);
+        for _ in 0..num_patterns {
+            pattern_results.push(Vec::new());
+        }
+
+        for m in qc.matches(&self.query.query, root, source.as_bytes()) {
+            if cache.timed_out() {
+                return vec![];
+            }
+            // Process the query match, run subqueries and store the final QueryResults in pattern_results
+            pattern_results[m.pattern_index].extend(self.process_match(cache, source, &m));
+        }
+
+        // The patterns of raw queries are independent alternatives, like in tree-sitter.
+        if self.raw {
+            return pattern_results.into_iter().flatten().collect();
+        }
+
+        // Return an empty result if any of our patterns have 0 results. Patterns with a
+        // count(..): quantifier can be allowed to not match at all.
+        let have_failed_pattern = pattern_results.iter().enumerate().any(|(i, pr)| {
+            pr.is_empty() && self.count(i).map_or(true, |(op, n)| !op.compare(0, n))
+        });
+        if have_failed_pattern {
+            return vec![];
+        }
+
+        // A query without any patterns (e.g a compound statement that only contains not: statements)
+        // matches on `root` as long as none of the negative sub queries match.
+        // The same is true for the matches of a leading count(..): statement, which are
+        // counted for this empty result.
+        let mut merged_results = Vec::new();
+        if num_patterns == 0 || self.count(0).is_some() {
+            merged_results.push(QueryResult::new(
+                Vec::new(),
+                FxHashMap::default(),
+                root.byte_range(),
+            ));
+        }
+
+        // Try to merge the results of all patterns. If this fails we return an empty result
+        for (i, pr) in pattern_results.into_iter().enumerate() {
+            if let Some(count) = self.count(i) {
+                merged_results =
+                    self.merge_counted_results(&merged_results, &pr, source, count, cache);
+                if merged_results.is_empty() {
+                    return merged_results;
+                }
+            } else if merged_results.is_empty() {
+                merged_results.extend(pr)
+            } else {
+                merged_results =
+                    self.merge_query_results(&merged_results, &pr, source, !self.unordered, cache);
+                if merged_results.is_empty() {
+                    return merged_results;
+                }
+            }
+        }
+
+        // Enforce negative sub queries.
+        merged_results
+            .into_iter()
+            .filter(|result| {
+                let negative_query_matched = self.negations.iter().any(|neg| {
+                    // run the negative sub query
+                    let scope = if neg.anywhere {
+                        enclosing_function(root, self.query.language.function_kinds())
+                    } else {
+                        root
+                    };
+                    let negative_results = neg.qt.match_internal(scope, source, cache);
+
+                    // check if any of its result are a valid match.
+                    negative_results.into_iter().any(|n| {
+                        // check if the negative match `m` is consistent with our result
+                        let n = n.without_local_variables();
+                        if n.merge(result, source, false).is_none() {
+                            return false;
+                        }
+
+                        if neg.anywhere || neg.unordered {
+                            return true;
+                        }
+
+                        // we have a match for the negative sub query, but we still need to enforce ordering.
+                        // We know that the negative match has to come _after_ the node captured by the index
+                        // previous_capture_index and _before_ the capture after that.
+                        let index = neg.previous_capture_index;
+                        if let Some(c) = result.get_capture_result(self.id, index as u32) {
+                            // negative match is too early. skip it
+                            if n.start_offset() < c.range.end {
+                                return false;
+                            }
+                        };
+                        if let Some(c) = result.get_capture_result(self.id, (index + 1) as u32) {
+                            // negative match comes too late. skip it
+                            if n.start_offset() > c.range.start {
+                                return false;
+                            }
+                        }
+
+                        true
+                    })
+                });
+
+                !negative_query_matched
+            })
+            .collect()
+    }
+
+    // Process a single tree-sitter match and return all query results
+    // This function is responsible for running all subqueries,
+    // and veriyfing that negations don't match.
+    fn process_match(
+        &self,
+        cache: &mut Cache,
+        source: &str,
+        m: &tree_sitter::QueryMatch,
+    ) -> Vec<QueryResult> {
+        let mut r = Vec::with_capacity(m.captures.len());
+        let mut vars: FxHashMap<String, usize> =
+            FxHashMap::with_capacity_and_hasher(self.variables.len(), Default::default());
+
+        let mut subqueries = Vec::new();
+        let mut arguments = Vec::new();
+
+        for c in m.captures {
+            let capture = &self.captures[c.index as usize];
+
+            let capture_result = CaptureResult {
+                range: c.node.byte_range(),
+                query_id: self.id,
+                capture_idx: c.index,
+                clause: self.capture_clauses[c.index as usize],
+                kind: c.node.kind(),
+            };
+
+            // TODO: Do we need to store sub queries in captures as well?
+            if !matches!(capture, Capture::Subquery(_) | Capture::Argument(_)) {
+                r.push(capture_result)
+            }
+
+            match capture {
+                Capture::Variable(s, regex_constraint) => {
+                    if let Some((negative, regex)) = regex_constraint {
+                        let m = regex.is_match(&source[c.node.byte_range()]);
+                        if (m && *negative) || (!m && !*negative) {
+                            return vec![];
+                        }
+                    }
+                    let value = &source[c.node.byte_range()];
+                    if self.length_constraints.iter().any(|l| {
+                        &l.variable == s
+                            && matches!(l.bound, Bound::Constant(_))
+                            && !l.check(value, None)
+                    }) {
+                        return vec![];
+                    }
+                    if self
+                        .number_constraints
+                        .iter()
+                        .any(|n| &n.variable == s && !n.check(value))
+                    {
+                        return vec![];
+                    }
+                    if let Some(&i) = vars.get(s) {
+                        if !values_equal(&source[r[i].range.clone()], value) {
+                            return vec![];
+                        }
+                    } else {
+                        // Enforce uniqueness as soon as the variable is captured
+                        if self.is_unique_variable(s)
+                            && vars.iter().any(|(k, &i)| {
+                                self.is_unique_variable(k)
+                                    && self
+                                        .uniqueness
+                                        .identical(&source[r[i].range.clone()], value)
+                            })
+                        {
+                            return vec![];
+                        }
+                        vars.insert(s.clone(), r.len() - 1);
+                    }
+                }
+                Capture::Subquery(t) => {
+                    subqueries.push((t, c));
+                }
+                Capture::Argument(p) => arguments.push((*p, c.node)),
+                Capture::Number(i) => {
+                    if let Some(y) = parse_number_literal(&source[c.node.byte_range()]) {
+                        if *i != y {
+                            return vec![];
+                        }
+                    } else {
+                        return vec![];
+                    }
+                }
+                Capture::Regex(regex) if !regex.is_match(&source[c.node.byte_range()]) => {
+                    return vec![];
+                }
+                _ => (),
+            }
+        }
+
+        if !arguments_in_place(&mut arguments) {
+            return vec![];
+        }
+
+        let function = if let (true, Some(c)) = (self.raw, m.captures.first()) {
+            raw_anchor(c.node, self.anchor, self.query.language).byte_range()
+        } else if let Some(c) = r.first() {
+            c.range.clone()
+        } else {
+            0usize..0usize
+        };
+
+        let qr = QueryResult::new(r, vars, function);
+
+        let query_results = subqueries.iter().fold(vec![qr], |results, (t, c)| {
+            // avoid running subqueries if merging failed.
+            if results.is_empty() {
+                return results;
+            }
+
+            let key = CacheKey {
+                query_id: t.id,
+                node_id: c.node.id(),
+            };
+
+            // can't use entry API because match_internal requires another mutable reference to `cache`
+            let sub_results = match cache.results.get(&key) {
+                None => {
+                    let v = t
+                        .match_internal(c.node, source, cache)
+                        .into_iter()
+                        .map(QueryResult::without_local_variables)
+                        .collect();
+                    cache.results.insert(key.clone(), v);
+                    cache.results.get(&key).unwrap()
+                }
+                Some(r) => r,
+            };
+            self.merge_query_results(&results, sub_results, source, false, cache)
+        });
+
+        query_results
+    }
+
+    // Try to merge all matches of a subquery (`sub_results`) into matches of the main query `results`.
+    // This enforces that variable assignments are coherent and can optionally enforce ordering
+    // so that nodes captured by the subquery have to come after nodes that are already stored in the result.
+    // This is used by multi pattern / compound queries to make sure `a` comes after `b` for the query `{a; b;}`
+    // Merged results that bind identical values to different variables are dropped with --unique.
+    fn merge_query_results(
+        &self,
+        results: &[QueryResult],
+        sub_results: &[QueryResult],
+        source: &str,
+        enforce_ordering: bool,
+        cache: &Cache,
+    ) -> Vec<QueryResult> {
+        let mut merged = Vec::new();
+        for r in results {
+            if cache.timed_out() {
+                return vec![];
+            }
+            merged.extend(
+                sub_results
+                    .iter()
+                    .filter_map(|s| r.merge(s, source, enforce_ordering))
+                    .filter(|m| self.is_unique(m, source)),
+            );
+        }
+        merged
+    }
+
+    // Merge the matches of a pattern with a count(..): quantifier into `results`.
+    // Matches are grouped by the values of their variables, and every group with the
+    // required number of matches is merged into a single result. If the quantifier allows
+    // zero matches, results without any compatible match are kept as they are.
+    fn merge_counted_results(
+        &self,
+        results: &[QueryResult],
+        sub_results: &[QueryResult],
+        source: &str,
+        (op, n): Quantifier,
+        cache: &Cache,
+    ) -> Vec<QueryResult> {
+        let mut merged = Vec::new();
+        for r in results {
+            if cache.timed_out() {
+                return vec![];
+            }
+            let mut groups: Vec<Vec<&QueryResult>> = Vec::new();
+            let compatible = sub_results
+                .iter()
+                .filter(|s| r.merge(s, source, !self.unordered).is_some());
+            for s in compatible {
+                let same_values = |g: &&mut Vec<&QueryResult>| {
+                    s.vars
+                        .keys()
+                        .all(|k| match (g[0].value(k, source), s.value(k, source)) {
+                            (Some(a), Some(b)) => values_equal(a, b),
+                            _ => false,
+                        })
+                };
+                match groups.iter_mut().find(same_values) {
+                    Some(g) if g.contains(&s) => (),
+                    Some(g) => g.push(s),
+                    None => groups.push(vec![s]),
+                }
+            }
+
+            if groups.is_empty() && op.compare(0, n) {
+                merged.push(r.clone());
+            }
+
+            for g in groups.into_iter().filter(|g| op.compare(g.len(), n)) {
+                // Ordering with respect to `r` was already checked for every match.
+                let result = g
+                    .iter()
+                    .try_fold(r.clone(), |acc, s| acc.merge(s, source, false));
+                merged.extend(result.filter(|m| self.is_unique(m, source)));
+            }
+        }
+        merged
+    }
+}
+
+/// Returns the comment directly preceding the node with the byte range `range` whose
+/// text (without comment delimiters) matches `regex`. Comments are attached to the
+/// following node, so all consecutive comments in front of it are checked.
+fn attached_comment<'a>(
+    root: Node<'a>,
+    range: std::ops::Range<usize>,
+    regex: &Regex,
+    source: &str,
+) -> Option<Node<'a>> {
+    let mut node = root.descendant_for_byte_range(range.start, range.end)?;
+    while let Some(p) = node
+        .parent()
+        .filter(|p| p.byte_range() == node.byte_range())
+    {
+        node = p;
+    }
+
+    let mut previous = node.prev_sibling();
+    while let Some(comment) = previous.filter(|n| n.kind() == "comment") {
+        let text = &source[comment.byte_range()];
+        let text = match text.strip_prefix("/*") {
+            Some(t) => t.strip_suffix("*/").unwrap_or(t),
+            None => text.trim_start_matches('/'),
+        };
+        if regex.is_match(text.trim()) {
+            return Some(comment);
+        }
+        previous = comment.prev_sibling();
+    }
+    None
+}
+
+/// Returns the function definition containing `node`, or the root of the tree
+/// for nodes outside of a function.
+// Returns the node that a result of a raw query with a capture of `node` is anchored to:
+// the enclosing `anchor` node, or the top-level node containing `node`.
+fn raw_anchor<'a>(node: Node<'a>, anchor: Anchor, language: &dyn Language) -> Node<'a> {
+    let block = [language.block_kind()];
+    let kinds: &[&str] = match anchor {
+        Anchor::Function => language.function_kinds(),
+        Anchor::Class => language.class_kinds(),
+        Anchor::Compound => &block,
+        Anchor::File => &[],
+    };
+    let mut n = node;
+    while let Some(p) = n.parent() {
+        if kinds.contains(&n.kind()) || (anchor != Anchor::File && p.parent().is_none()) {
+            break;
+        }
+        n = p;
+    }
+    n
+}
+
+fn enclosing_function<'a>(node: Node<'a>, function_kinds: &[&str]) -> Node<'a> {
+    let mut n = node;
+    while !function_kinds.contains(&n.kind()) {
+        match n.parent() {
+            Some(p) => n = p,
+            None => break,
+        }
+    }
+    n
+}
+
+// Returns true if the captured arguments of all argument lists with `...` wildcards
+// are at the positions required by the query, see ArgumentPosition.
+fn arguments_in_place(arguments: &mut [(ArgumentPosition, Node)]) -> bool {
+    arguments.sort_by_key(|(p, _)| (p.list, p.index));
+
+    // list and position of the previous argument
+    let mut previous = None;
+    for (p, node) in arguments.iter() {
+        let (index, count) = match argument_index(*node) {
+            Some(i) => i,
+            None => return false,
+        };
+        if (p.first && index != 0)
+            || (p.last && index + 1 != count)
+            || (p.follows && (index == 0 || previous != Some((p.list, index - 1))))
+        {
+            return false;
+        }
+        previous = Some((p.list, index));
+    }
+    true
+}
+
+// Returns the index of the argument `node` and the number of arguments in its
+// argument list. Comments are not counted.
+fn argument_index(node: Node) -> Option<(usize, usize)> {
+    let list = node.parent()?;
+    let mut c = list.walk();
+    let args: Vec<Node> = list
+        .named_children(&mut c)
+        .filter(|a| !a.is_extra())
+        .collect();
+    let index = args.iter().position(|a| a.id() == node.id())?;
+    Some((index, args.len()))
+}
@@ -0,0 +1,757 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use crate::query::QueryTree;
+use crate::util::{raw_value_key, raw_values_equal, value_key, values_equal};
+use colored::Colorize;
+use rustc_hash::FxHashMap;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// Struct for storing (partial) query matches.
+/// We really don't want to keep track of tree-sitter AST lifetimes so
+/// we do not store full nodes, but only their source range.
+/// TODO: Improve this struct + benchmarking
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct QueryResult {
+    // for each captured node we store the offset ranges of its src location
+    pub captures: Vec<CaptureResult>,
+    // Mapping from Variables to index in `captures`
+    pub vars: FxHashMap<String, usize>,
+    // Range of the outermost node. This is badly named as it does not have to be a
+    // function definition, but for final query results it normally is.
+    function: std::ops::Range<usize>,
+}
+
+/// Describes how a variable is used when joining the results of multiple queries.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum JoinMode {
+    /// Values need to be equal if the variable is bound in both results (default).
+    Equal,
+    /// The variable needs to be bound in both results and the values need to be equal.
+    RequiredEqual,
+    /// The variable is not used for joining.
+    Ignore,
+    /// Values need to be different if the variable is bound in both results.
+    Differ,
+    /// Like Equal, but values are compared as written, without removing comments
+    /// and whitespace.
+    Exact,
+}
+
+impl JoinMode {
+    /// Checks if two (optional) values of a variable are compatible.
+    pub fn compatible(&self, a: Option<&str>, b: Option<&str>) -> bool {
+        match (self, a, b) {
+            (JoinMode::Ignore, _, _) => true,
+            (JoinMode::Equal, Some(a), Some(b)) | (JoinMode::RequiredEqual, Some(a), Some(b)) => {
+                values_equal(a, b)
+            }
+            (JoinMode::RequiredEqual, _, _) => false,
+            (JoinMode::Differ, Some(a), Some(b)) => !values_equal(a, b),
+            (JoinMode::Exact, Some(a), Some(b)) => raw_values_equal(a, b),
+            _ => true,
+        }
+    }
+
+    /// Returns a key for `value` if the values of the variable need to be equal in
+    /// compatible results. Equal values always have the same key.
+    pub fn key(&self, value: &str) -> Option<String> {
+        match self {
+            JoinMode::Equal | JoinMode::RequiredEqual => Some(value_key(value)),
+            JoinMode::Exact => Some(raw_value_key(value).into_owned()),
+            JoinMode::Ignore | JoinMode::Differ => None,
+        }
+    }
+}
+
+impl FromStr for JoinMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<JoinMode, String> {
+        match s {
+            "equal" => Ok(JoinMode::Equal),
+            "required" | "required-equal" => Ok(JoinMode::RequiredEqual),
+            "ignore" | "ignored" => Ok(JoinMode::Ignore),
+            "differ" | "must-differ" => Ok(JoinMode::Differ),
+            "exact" => Ok(JoinMode::Exact),
+            _ => Err(format!(
+                "'{}' is not a valid join mode (equal, required, ignore, differ, exact)",
+                s
+            )),
+        }
+    }
+}
+
+/// (variable, key) pairs of the variables that need to be equal when joining the
+/// results of multiple queries, see `QueryResult::join_keys`.
+pub type JoinKeys = Vec<(String, String)>;
+
+/// Returns the join keys for the (variable, value) pairs in `vars`.
+pub fn join_keys<'a>(
+    vars: impl Iterator<Item = (&'a String, &'a str)>,
+    modes: &HashMap<String, JoinMode>,
+) -> JoinKeys {
+    let mut keys: JoinKeys = vars
+        .filter_map(|(var, value)| {
+            let key = modes.get(var).unwrap_or(&JoinMode::Equal).key(value)?;
+            Some((var.clone(), key))
+        })
+        .collect();
+    keys.sort();
+    keys
+}
+
+/// Stores the result (== source range) for a single capture.
+/// We also store the corresponding query id and capture index
+/// to make it possible to look up the result for a certain capture
+/// index (see QueryResult::get_capture_result), and the index of the
+/// sub-pattern in that query that produced the capture (see QueryTree::clause).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CaptureResult {
+    pub range: std::ops::Range<usize>,
+    pub query_id: usize,
+    pub capture_idx: u32,
+    pub clause: usize,
+    /// Kind of the captured tree-sitter node, e.g. `identifier` or `call_expression`.
+    pub kind: &'static str,
+}
+
+/// A 1-based line and column in a source file. Columns are counted in bytes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    /// Returns the position of the byte `offset` in `source`.
+    pub fn of(source: &str, offset: usize) -> Position {
+        let before = &source[..offset];
+        let line = before.matches('\n').count() + 1;
+        let column = offset - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+        Position { line, column }
+    }
+}
+
+/// A captured node of a QueryResult with its location in the source file
+/// (see QueryResult::capture_details).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CaptureDetails<'a> {
+    /// The query variable bound by the capture, if any.
+    pub variable: Option<&'a str>,
+    pub kind: &'static str,
+    pub range: std::ops::Range<usize>,
+    pub start: Position,
+    /// Position of the end of the node (exclusive).
+    pub end: Position,
+    pub text: &'a str,
+}
+
+/// How `QueryResult::display_with_header` shows the start of the outermost node,
+/// normally the signature of the function containing the match.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum Header {
+    /// The first line of the node.
+    #[default]
+    FirstLine,
+    /// Nothing, only the matched lines and their context are shown.
+    Omit,
+    /// All lines of the node up to the given byte offset, e.g. the whole
+    /// signature up to the function body.
+    Until(usize),
+    /// The given text instead of the first line (e.g. the function name), unless
+    /// the first line contains a captured node.
+    Text(String),
+}
+
+impl<'b> QueryResult {
+    pub fn new(
+        captures: Vec<CaptureResult>,
+        vars: FxHashMap<String, usize>,
+        function: std::ops::Range<usize>,
+    ) -> QueryResult {
+        QueryResult {
+            captures,
+            vars,
+            function,
+        }
+    }
+
+    pub fn start_offset(&self) -> usize {
+        self.function.start
+    }
+
+    /// Byte range of the node enclosing the match, normally a function definition.
+    pub fn function_range(&self) -> std::ops::Range<usize> {
+        self.function.clone()
+    }
+
+    /// Returns a colored String representation of the result with `before` + `after`
+    /// context lines around each captured node.
+    pub fn display(
+        &self,
+        source: &'b str,
+        before: usize,
+        after: usize,
+        enable_line_numbers: bool,
+    ) -> String {
+        self.display_with_context(source, &[], before, after, enable_line_numbers)
+    }
+
+    /// Like `display`, but additionally prints the lines spanned by the `context`
+    /// ranges, which may lie outside of the outermost node (see --context-statements).
+    pub fn display_with_context(
+        &self,
+        source: &'b str,
+        context: &[std::ops::Range<usize>],
+        before: usize,
+        after: usize,
+        enable_line_numbers: bool,
+    ) -> String {
+        let header = Header::FirstLine;
+        self.display_with_header(source, context, &header, before, after, enable_line_numbers)
+    }
+
+    /// Like `display_with_context`, but shows the start of the outermost node as
+    /// described by `header` instead of its first line.
+    pub fn display_with_header(
+        &self,
+        source: &'b str,
+        context: &[std::ops::Range<usize>],
+        header: &Header,
+        before: usize,
+        after: usize,
+        enable_line_numbers: bool,
+    ) -> String {
+        let mut sorted = self.captures.clone();
+        sorted.sort_by(|a, b| a.range.start.cmp(&b.range.start));
+
+        // Comments attached to the outermost node are captured in front of it.
+        let (attached, sorted): (Vec<_>, Vec<_>) = sorted
+            .into_iter()
+            .partition(|c| c.range.start < self.function.start);
+
+        let start = attached
+            .first()
+            .map_or(self.function.start, |c| c.range.start);
+        let span = context.iter().fold(start..self.function.end, |s, r| {
+            s.start.min(r.start)..s.end.max(r.end)
+        });
+        let mut d = DisplayHelper::new(source, span);
+
+        // Lines have to be added in order, so context ranges are added right
+        // before the first highlight that starts after them.
+        let mut context: Vec<std::ops::Range<usize>> = context.to_vec();
+        context.sort_by_key(|r| std::cmp::Reverse(r.start));
+        let mut add_context = |d: &mut DisplayHelper, offset: usize| {
+            while context.last().map_or(false, |r| r.start <= offset) {
+                d.add(context.pop().unwrap());
+            }
+        };
+
+        for c in attached {
+            add_context(&mut d, c.range.start);
+            d.highlight(c.range);
+        }
+
+        // add header
+        add_context(&mut d, self.function.start);
+        let start = self.function.start;
+        match header {
+            Header::FirstLine => d.add(start..start + 1),
+            // Without a header, before-context still starts at the outermost node.
+            Header::Omit => d.bound(start),
+            Header::Until(end) => d.add(start..(*end).max(start + 1)),
+            Header::Text(text) => d.replace(start, text),
+        }
+
+        // Before printing out the different nodes, we first filter out overlapping nodes.
+        // If we matched on `(a + b)` and also captured `b` clean_ranges will not contain
+        // the range for `b`.
+        let mut clean_ranges: Vec<std::ops::Range<usize>> = Vec::with_capacity(self.captures.len());
+        for r in sorted.into_iter().skip(1).map(|c| c.range) {
+            if !clean_ranges.is_empty() && clean_ranges.last().unwrap().contains(&r.start) {
+                continue;
+            }
+            clean_ranges.push(r.clone());
+        }
+
+        // Add highlighted elements
+        for r in clean_ranges.into_iter() {
+            add_context(&mut d, r.start);
+            d.highlight(r);
+        }
+
+        // add function ending
+        add_context(&mut d, self.function.end - 1);
+        d.add(self.function.end - 1..self.function.end);
+        add_context(&mut d, usize::MAX);
+
+        d.display(before, after, enable_line_numbers)
+    }
+
+    /// Return the captured value for a variable.
+    pub fn value(&self, var: &str, source: &'b str) -> Option<&'b str> {
+        match self.vars.get(var) {
+            None => None,
+            Some(i) => Some(&source[self.captures[*i].range.clone()]),
+        }
+    }
+
+    /// Returns the source lines spanned by the captured nodes (excluding the outermost
+    /// node). Falls back to the outermost node if there are no other captures.
+    pub fn matched_lines(&self, source: &'b str) -> &'b str {
+        &source[self.matched_range(source)]
+    }
+
+    /// Byte range of the lines returned by `matched_lines`.
+    pub fn matched_range(&self, source: &str) -> std::ops::Range<usize> {
+        let ranges = self
+            .captures
+            .iter()
+            .map(|c| c.range.clone())
+            .filter(|r| *r != self.function);
+
+        let start = ranges.clone().map(|r| r.start).min();
+        let end = ranges.map(|r| r.end).max();
+
+        match (start, end) {
+            (Some(start), Some(end)) => {
+                let start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+                let end = source[end..].find('\n').map_or(source.len(), |i| end + i);
+                start..end
+            }
+            _ => self.function.clone(),
+        }
+    }
+
+    /// Returns `matched_lines` with whitespace runs collapsed into a single space.
+    /// This is used to detect identical matches in different locations.
+    pub fn snippet(&self, source: &str) -> String {
+        self.matched_lines(source)
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Try to merge two QueryResults from the same source file.
+    /// The function returns None if the variable assignments for the two results differ.
+    /// If `enforce_order` is set this can fail because the new ranges
+    /// are not strictly after the current ranges.
+    pub fn merge(
+        &self,
+        other: &QueryResult,
+        source: &str,
+        enforce_order: bool,
+    ) -> Option<QueryResult> {
+        let mut vars = self.vars.clone();
+
+        let mut captures = self.captures.clone();
+
+        if enforce_order
+            && other
+                .captures
+                .iter()
+                .any(|r| self.captures.iter().any(|r2| r.range.start <= r2.range.end))
+        {
+            return None;
+        }
+
+        captures.extend(other.captures.clone());
+
+        for (k, v) in other.vars.iter() {
+            match self.value(k, source) {
+                None => {
+                    vars.insert(k.clone(), v + self.captures.len());
+                }
+                Some(s) => {
+                    if !values_equal(s, other.value(k, source).unwrap()) {
+                        return None;
+                    }
+                }
+            }
+        }
+
+        Some(QueryResult::new(captures, vars, self.function.clone()))
+    }
+
+    /// Remove all local variables ($_x) from the variable mapping. This is done for results
+    /// of sub queries and negations, so local variables are never unified with variables of
+    /// the same name in the enclosing query.
+    pub(crate) fn without_local_variables(mut self) -> QueryResult {
+        self.vars.retain(|k, _| !is_local_variable(k));
+        self
+    }
+
+    /// Checks if two QueryResults from different source files have compatible variable assignments
+    pub fn chainable(&self, source: &str, other: &QueryResult, other_source: &str) -> bool {
+        self.chainable_with(source, other, other_source, &HashMap::new())
+    }
+
+    /// Like `chainable`, but uses the given JoinMode for each variable.
+    /// Variables without an entry in `modes` use JoinMode::Equal.
+    pub fn chainable_with(
+        &self,
+        source: &str,
+        other: &QueryResult,
+        other_source: &str,
+        modes: &HashMap<String, JoinMode>,
+    ) -> bool {
+        self.vars.keys().chain(other.vars.keys()).all(|k| {
+            modes
+                .get(k)
+                .unwrap_or(&JoinMode::Equal)
+                .compatible(self.value(k, source), other.value(k, other_source))
+        })
+    }
+
+    /// Returns the keys (see `JoinMode::key`) of all variables that need to be equal
+    /// in results that are `chainable_with` this one, sorted by variable name.
+    pub fn join_keys(&self, source: &str, modes: &HashMap<String, JoinMode>) -> JoinKeys {
+        join_keys(
+            self.vars
+                .keys()
+                .map(|k| (k, self.value(k, source).unwrap())),
+            modes,
+        )
+    }
+
+    /// Checks if the enclosing functions (see `function_range`) of two results from
+    /// the same source file overlap.
+    pub fn same_function(&self, other: &QueryResult) -> bool {
+        self.function.start < other.function.end && other.function.start < self.function.end
+    }
+
+    /// Returns the variable name, node kind, range and line/column positions of all
+    /// captured nodes, ordered by their location in `source`. `qt` is the query
+    /// that produced the result.
+    pub fn capture_details<'a>(
+        &self,
+        qt: &'a QueryTree,
+        source: &'a str,
+    ) -> Vec<CaptureDetails<'a>> {
+        let mut details: Vec<CaptureDetails> = self
+            .captures
+            .iter()
+            .map(|c| CaptureDetails {
+                variable: qt.capture_variable(c.query_id, c.capture_idx),
+                kind: c.kind,
+                range: c.range.clone(),
+                start: Position::of(source, c.range.start),
+                end: Position::of(source, c.range.end),
+                text: &source[c.range.clone()],
+            })
+            .collect();
+        details.sort_by_key(|d| (d.range.start, std::cmp::Reverse(d.range.end)));
+        details
+    }
+
+    /// Try to find the result for the capture `capture_idx` in query `query_id`
+    pub fn get_capture_result(&self, query_id: usize, capture_idx: u32) -> Option<&CaptureResult> {
+        self.captures
+            .iter()
+            .find(|c| c.capture_idx == capture_idx && c.query_id == query_id)
+    }
+}
+
+// Try to merge sub_results into each result.
+/// Variables starting with $_ are local to the (sub) query they are used in.
+pub fn is_local_variable(name: &str) -> bool {
+    name.starts_with("$_") && name.len() > 2
+}
+
+pub fn merge_results(
+    results: &[QueryResult],
+    sub_results: &[QueryResult],
+    source: &str,
+    enforce_order: bool,
+) -> Vec<QueryResult> {
+    results
+        .iter()
+        .flat_map(|r| {
+            sub_results
+                .iter()
+                .filter_map(move |s| r.merge(s, source, enforce_order))
+        })
+        .collect()
+}
+
+// Lines longer than this (minified or generated code) are shortened to the parts
+// around highlights, with LINE_CONTEXT bytes on each side.
+const MAX_LINE_LENGTH: usize = 500;
+const LINE_CONTEXT: usize = 80;
+
+struct DisplayHelper<'a> {
+    source: &'a str,
+    // byte offset of the first line in `lines`
+    start: usize,
+    lines: Vec<(usize, &'a str, u8)>,
+    highlights: Vec<Range<usize>>,
+    // index of a line that is printed as the given text instead (Header::Text)
+    replacement: Option<(usize, String)>,
+    curr: usize,
+    first: usize,
+    last: usize,
+}
+
+impl<'a> DisplayHelper<'a> {
+    /// Only the lines of `span` (and the line after it) are indexed, so displaying
+    /// a result doesn't need to split the whole file into lines.
+    fn new(source: &'a str, span: Range<usize>) -> DisplayHelper<'a> {
+        let start = source[..span.start].rfind('\n').map_or(0, |i| i + 1);
+        let end = match source[span.end..].find('\n') {
+            Some(i) => {
+                let next = span.end + i + 1;
+                source[next..].find('\n').map_or(source.len(), |i| next + i)
+            }
+            None => source.len(),
+        };
+
+        let mut lines = Vec::new();
+        let mut offset = start;
+        for l in source[start..end].split('\n') {
+            lines.push((offset, l, 0));
+            offset += l.len() + 1;
+        }
+
+        DisplayHelper {
+            source,
+            start,
+            lines,
+            highlights: Vec::new(),
+            replacement: None,
+            curr: 0,
+            first: 0xFFFFFFFF,
+            last: 0,
+        }
+    }
+
+    fn highlight(&mut self, range: Range<usize>) {
+        self.add(range.clone());
+        self.highlights.push(range);
+    }
+
+    fn add(&mut self, range: Range<usize>) {
+        for (line_nr, (offset, l, print)) in self.lines.iter_mut().enumerate().skip(self.curr) {
+            if *print == 0 && range.start < (*offset + l.len()) && (*offset < range.end) {
+                self.first = line_nr.min(self.first);
+                self.last = line_nr.max(self.last);
+                *print = 1;
+            } else if *offset >= range.end {
+                self.curr -= 1;
+                break;
+            }
+            self.curr += 1;
+        }
+    }
+
+    /// Allow context lines from the line containing `offset` on, without printing it.
+    fn bound(&mut self, offset: usize) {
+        if let Some(index) = self
+            .lines
+            .iter()
+            .position(|(o, l, _)| offset <= *o + l.len())
+        {
+            self.first = index.min(self.first);
+        }
+    }
+
+    /// Print the line containing `offset` as `text`.
+    fn replace(&mut self, offset: usize, text: &str) {
+        let index = self
+            .lines
+            .iter()
+            .position(|(o, l, _)| *o <= offset && offset <= *o + l.len());
+        if let Some(index) = index {
+            self.replacement = Some((index, text.to_string()));
+        }
+        self.add(offset..offset + 1);
+    }
+
+    fn format(&self, start_offset: usize, l: &str, hindex: usize) -> String {
+        // highlights relative to the start of the line
+        let highlights: Vec<Range<usize>> = self
+            .highlights
+            .iter()
+            .skip(hindex)
+            .filter(|range| range.start <= (start_offset + l.len()) && start_offset <= range.end)
+            .map(|h| {
+                let start = if h.start > start_offset {
+                    h.start - start_offset
+                } else {
+                    0
+                };
+                let end = if h.end < start_offset + l.len() {
+                    h.end - start_offset
+                } else {
+                    l.len()
+                };
+                start..end
+            })
+            .collect();
+
+        let windows = if l.len() > MAX_LINE_LENGTH {
+            visible_windows(l, &highlights)
+        } else {
+            std::iter::once(0..l.len()).collect()
+        };
+
+        let mut result = String::new();
+        for w in &windows {
+            if w.start > 0 {
+                result += "...";
+            }
+            let mut current_offset = w.start;
+            for h in &highlights {
+                let start = h.start.max(current_offset);
+                let end = h.end.min(w.end);
+                if start >= end {
+                    continue;
+                }
+                result += &l[current_offset..start];
+                result += &format!("{}", l[start..end].red());
+                current_offset = end;
+            }
+            result += &l[current_offset..w.end];
+        }
+        if windows.last().map_or(false, |w| w.end < l.len()) {
+            result += "...";
+        }
+        result += "\n";
+        result
+    }
+
+    fn display(&mut self, before: usize, after: usize, enable_line_numbers: bool) -> String {
+        let mut result = String::new();
+        let mut skipped = true;
+
+        for i in self.first..self.last + 1 {
+            if self.lines[i].2 != 1 {
+                continue;
+            }
+
+            let b = if i >= before {
+                self.first.max(i - before)
+            } else {
+                self.first
+            };
+            let a = self.last.min(i.saturating_add(after));
+
+            for j in b..i {
+                self.lines[j].2 = 2;
+            }
+
+            for j in i..(a + 1) {
+                self.lines[j].2 = 2;
+            }
+        }
+
+        // Number of the first indexed line, only counted if it's needed.
+        let base = if enable_line_numbers {
+            memchr::memchr_iter(b'\n', &self.source.as_bytes()[..self.start]).count()
+        } else {
+            0
+        };
+
+        for (line_nr, (offset, l, p)) in self.lines.iter().enumerate() {
+            let line_nr = base + line_nr;
+            if *p == 0 {
+                if !skipped {
+                    skipped = true;
+                    if enable_line_numbers {
+                        let length = (line_nr - 1).to_string().len();
+                        if length < 4 {
+                            result += &" ".repeat(4 - length)
+                        }
+                        result += &".".repeat(length);
+                        result += "\n"
+                    } else {
+                        result += "...\n"
+                    }
+                }
+                continue;
+            }
+
+            if enable_line_numbers {
+                result += &format!("{:>4}: ", line_nr + 1);
+            }
+            match &self.replacement {
+                Some((index, text))
+                    if line_nr == base + index
+                        && !self
+                            .highlights
+                            .iter()
+                            .any(|h| h.start <= *offset + l.len() && *offset < h.end) =>
+                {
+                    result += text;
+                    result += "\n";
+                }
+                _ => result += &self.format(*offset, l, 0),
+            }
+            skipped = false;
+        }
+
+        let t = if skipped {
+            6
+        } else {
+            1
+        };
+
+        result.truncate(result.len() - t);
+
+        result
+    }
+}
+
+/// Returns the parts of the long line `l` that are displayed: The start of the line
+/// and LINE_CONTEXT bytes around the start and the end of each highlight, merged if
+/// they overlap.
+fn visible_windows(l: &str, highlights: &[Range<usize>]) -> Vec<Range<usize>> {
+    let floor = |mut i: usize| {
+        while !l.is_char_boundary(i) {
+            i -= 1;
+        }
+        i
+    };
+    let ceil = |mut i: usize| {
+        i = i.min(l.len());
+        while !l.is_char_boundary(i) {
+            i += 1;
+        }
+        i
+    };
+
+    let mut windows = Vec::with_capacity(2 * highlights.len() + 1);
+    windows.push(0..ceil(LINE_CONTEXT));
+    for h in highlights {
+        let start = h.start.saturating_sub(LINE_CONTEXT);
+        windows.push(floor(start)..ceil(h.start + LINE_CONTEXT));
+        windows
+            .push(floor(h.end.saturating_sub(LINE_CONTEXT).max(start))..ceil(h.end + LINE_CONTEXT));
+    }
+
+    windows.sort_by_key(|w| w.start);
+    let mut merged: Vec<Range<usize>> = Vec::with_capacity(windows.len());
+    for w in windows {
+        match merged.last_mut() {
+            Some(last) if w.start <= last.end => last.end = last.end.max(w.end),
+            _ => merged.push(w),
+        }
+    }
+    merged
+}
@@ -0,0 +1,77 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Source files bundled with their syntax tree.
+
+use tree_sitter::{Node, Tree};
+
+use crate::language::{self, Language};
+use crate::query::QueryTree;
+use crate::result::QueryResult;
+
+/// A parsed source file. Query results only store byte ranges, so the
+/// source text is kept together with its tree.
+pub struct ParsedSource {
+    source: String,
+    tree: Tree,
+}
+
+impl ParsedSource {
+    /// Parse `source` as C, or as C++ if `cpp` is set.
+    pub fn new(source: impl Into<String>, cpp: bool) -> ParsedSource {
+        ParsedSource::with_language(source, language::for_cpp(cpp))
+    }
+
+    pub fn with_language(source: impl Into<String>, language: &dyn Language) -> ParsedSource {
+        let source = source.into();
+        let tree = language.parse(&source);
+        ParsedSource { source, tree }
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn tree(&self) -> &Tree {
+        &self.tree
+    }
+
+    pub fn root(&self) -> Node<'_> {
+        self.tree.root_node()
+    }
+
+    /// Returns all results of `qt` in this source. `qt` needs to be built for
+    /// the language the source was parsed with.
+    pub fn matches(&self, qt: &QueryTree) -> Vec<QueryResult> {
+        qt.matches(self.root(), &self.source)
+    }
+
+    /// Returns the tree as an s-expression, useful for writing queries.
+    pub fn sexp(&self) -> String {
+        self.root().to_sexp()
+    }
+
+    /// Returns a colored string representation of `result`, see `QueryResult::display`.
+    pub fn display(
+        &self,
+        result: &QueryResult,
+        before: usize,
+        after: usize,
+        enable_line_numbers: bool,
+    ) -> String {
+        result.display(&self.source, before, after, enable_line_numbers)
+    }
+}
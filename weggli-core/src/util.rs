@@ -0,0 +1,418 @@
+/*
+Copyright 2021 Google LLC
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use std::borrow::Cow;
+
+// Try to parse a tree-sitter number literal into a constant value.
+// This function assumes that tree-sitter already parsed the input string
+// as a valid literal so we don't need to do much validation.
+// The function should work for all integer literals, but will fail for
+// floats.
+pub fn parse_number_literal(input: &str) -> Option<i128> {
+    // remove suffixes and '
+    let mut input: String = input
+        .chars()
+        .filter(|c| !['\'', 'u', 'U', 'l', 'L', 'z', 'Z'].contains(c))
+        .collect();
+
+    let negative = if input.starts_with('-') {
+        input.remove(0);
+        true
+    } else {
+        false
+    };
+
+    let (offset, radix) = match input.get(0..2) {
+        Some("0x") | Some("0X") => (2, 16),
+        Some("0b") | Some("0B") => (2, 2),
+        Some(s) if s.starts_with('0') => (1, 8),
+        None | Some(_) => (0, 10),
+    };
+
+    let value = i128::from_str_radix(&input[offset..], radix);
+
+    if let Ok(v) = value {
+        if negative {
+            Some(-v)
+        } else {
+            Some(v)
+        }
+    } else {
+        None
+    }
+}
+
+/// Compare two captured values of the same variable.
+/// Values are compared after removing comments and whitespace (see `normalize_value`),
+/// number literals are compared by value, so `0x10` and `16` are equal.
+pub fn values_equal(a: &str, b: &str) -> bool {
+    raw_values_equal(a, b) || normalize_value(a) == normalize_value(b)
+}
+
+/// Like `values_equal`, but without normalizing the values.
+pub fn raw_values_equal(a: &str, b: &str) -> bool {
+    if a == b {
+        return true;
+    }
+
+    let is_number = |s: &str| {
+        s.trim_start_matches('-')
+            .starts_with(|c: char| c.is_ascii_digit())
+    };
+    if !is_number(a) || !is_number(b) {
+        return false;
+    }
+
+    match (parse_number_literal(a), parse_number_literal(b)) {
+        (Some(x), Some(y)) => x == y,
+        _ => false,
+    }
+}
+
+/// Returns a key for `value` such that `values_equal(a, b)` implies equal keys.
+/// Used to index query results by their variable values before comparing them.
+pub fn value_key(value: &str) -> String {
+    raw_value_key(&normalize_value(value)).into_owned()
+}
+
+/// Like `value_key`, but for `raw_values_equal`: Number literals are replaced
+/// by their value, everything else is kept as is.
+pub fn raw_value_key(value: &str) -> Cow<'_, str> {
+    if value
+        .trim_start_matches('-')
+        .starts_with(|c: char| c.is_ascii_digit())
+    {
+        if let Some(n) = parse_number_literal(value) {
+            return Cow::Owned(n.to_string());
+        }
+    }
+    Cow::Borrowed(value)
+}
+
+/// Remove comments and whitespace from a captured value, so `buf /* dst */` and `buf`
+/// or `s -> len` and `s->len` are identical. Whitespace between two words (`unsigned int`)
+/// is replaced by a single space. String and character literals are not modified.
+pub fn normalize_value(value: &str) -> Cow<'_, str> {
+    if !value.contains(|c: char| c.is_whitespace() || c == '/') {
+        return Cow::Borrowed(value);
+    }
+
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars().peekable();
+    // whitespace or a comment was skipped since the last character
+    let mut separated = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut previous = ' ';
+                for c in chars.by_ref() {
+                    if previous == '*' && c == '/' {
+                        break;
+                    }
+                    previous = c;
+                }
+                separated = true;
+                continue;
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+                separated = true;
+                continue;
+            }
+            c if c.is_whitespace() => {
+                separated = true;
+                continue;
+            }
+            _ => (),
+        }
+
+        if separated && is_word(c) && result.ends_with(is_word) {
+            result.push(' ');
+        }
+        separated = false;
+        result.push(c);
+
+        if c == '"' || c == '\'' {
+            let mut escaped = false;
+            for l in chars.by_ref() {
+                result.push(l);
+                match l {
+                    _ if escaped => escaped = false,
+                    '\\' => escaped = true,
+                    l if l == c => break,
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    Cow::Owned(result)
+}
+
+/// Returns the length in bytes of the string stored by a C/C++ string literal
+/// (without the terminating NUL byte), taking escape sequences into account.
+/// Concatenated literals ("a" "b") are supported. Wide literals are counted
+/// in characters. Returns None if `input` is not a string literal.
+pub fn string_literal_length(input: &str) -> Option<usize> {
+    let mut rest = input.trim();
+    let mut length = 0;
+
+    if rest.is_empty() {
+        return None;
+    }
+
+    while !rest.is_empty() {
+        let quote = rest.find('"')?;
+        let prefix = &rest[..quote];
+        if !["", "L", "u", "U", "u8", "R", "LR", "uR", "UR", "u8R"].contains(&prefix) {
+            return None;
+        }
+
+        let literal = &rest[quote + 1..];
+        let end = if prefix.ends_with('R') {
+            // R"delim(...)delim"
+            let open = literal.find('(')?;
+            let close = format!("){}\"", &literal[..open]);
+            let end = literal[open..].find(&close)? + open;
+            length += end - open - 1;
+            end + close.len()
+        } else {
+            let (len, end) = escaped_length(literal)?;
+            length += len;
+            end + 1
+        };
+
+        rest = literal[end..].trim_start();
+    }
+
+    Some(length)
+}
+
+// Returns the length of the string up to the closing quote and the offset of the quote.
+fn escaped_length(literal: &str) -> Option<(usize, usize)> {
+    let mut length = 0;
+    let mut chars = literal.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((length, i)),
+            '\\' => {
+                let (_, e) = chars.next()?;
+                let mut take_digits = |max: usize, radix: u32| {
+                    let mut digits = String::new();
+                    while digits.len() < max {
+                        match chars.peek() {
+                            Some(&(_, d)) if d.is_digit(radix) => {
+                                digits.push(d);
+                                chars.next();
+                            }
+                            _ => break,
+                        }
+                    }
+                    u32::from_str_radix(&digits, radix).ok()
+                };
+                length += match e {
+                    '0'..='7' => {
+                        // the first digit was already consumed
+                        take_digits(2, 8);
+                        1
+                    }
+                    'x' => {
+                        take_digits(usize::MAX, 16)?;
+                        1
+                    }
+                    'u' | 'U' => {
+                        let max = if e == 'u' { 4 } else { 8 };
+                        char::from_u32(take_digits(max, 16)?).map_or(1, char::len_utf8)
+                    }
+                    _ => 1,
+                };
+            }
+            _ => length += c.len_utf8(),
+        }
+    }
+
+    None
+}
+
+#[test]
+fn test_string_literal_length() {
+    assert_eq!(string_literal_length(r#""""#), Some(0));
+    assert_eq!(string_literal_length(r#""abc""#), Some(3));
+    assert_eq!(string_literal_length(r#""a\nb""#), Some(3));
+    assert_eq!(string_literal_length(r#""\x41\101\0""#), Some(3));
+    assert_eq!(string_literal_length(r#""\"quoted\"""#), Some(8));
+    assert_eq!(string_literal_length(r#""\u00e4""#), Some(2));
+    assert_eq!(string_literal_length(r#""ab" "cd""#), Some(4));
+    assert_eq!(string_literal_length(r#"L"wide""#), Some(4));
+    assert_eq!(string_literal_length(r#"R"x(a"b)x""#), Some(3));
+    assert_eq!(string_literal_length("buf"), None);
+    assert_eq!(string_literal_length("10"), None);
+    assert_eq!(string_literal_length(r#""unterminated"#), None);
+}
+
+#[test]
+fn test_normalize_value() {
+    assert_eq!(normalize_value("buf"), "buf");
+    assert_eq!(normalize_value("buf /* dst */"), "buf");
+    assert_eq!(normalize_value("s -> len"), "s->len");
+    assert_eq!(normalize_value("s->\n    len // length\n"), "s->len");
+    assert_eq!(normalize_value("unsigned  int"), "unsigned int");
+    assert_eq!(normalize_value("sizeof /**/ x"), "sizeof x");
+    assert_eq!(normalize_value(r#"f("a  b", ' ')"#), r#"f("a  b",' ')"#);
+    assert_eq!(normalize_value(r#""a\" /* b */""#), r#""a\" /* b */""#);
+    assert_eq!(normalize_value("a / b"), "a/b");
+}
+
+#[test]
+fn test_values_equal() {
+    assert!(values_equal("foo", "foo"));
+    assert!(values_equal("buf", "buf /* dst */"));
+    assert!(values_equal("a.b[i + 1]", "a.b[i+1]"));
+    assert!(!values_equal("foo", "bar"));
+    assert!(values_equal("0x10", "16"));
+    assert!(values_equal("16u", "020"));
+    assert!(!values_equal("l1", "1"));
+    assert!(!values_equal("0x10", "17"));
+}
+
+#[test]
+fn test_parse_number_literal() {
+    assert_eq!(parse_number_literal("10"), Some(10));
+    assert_eq!(parse_number_literal("0x10"), Some(0x10));
+    assert_eq!(parse_number_literal("-0x10"), Some(-0x10));
+    assert_eq!(parse_number_literal("0b11"), Some(3));
+    assert_eq!(parse_number_literal("0"), Some(0));
+    assert_eq!(parse_number_literal(""), None);
+    assert_eq!(parse_number_literal("0xbeef"), Some(0xbeef));
+    assert_eq!(parse_number_literal("0xbeef"), Some(0xbeef));
+    assert_eq!(parse_number_literal("010"), Some(8));
+    assert_eq!(parse_number_literal("abcdef"), None);
+    assert_eq!(parse_number_literal("-0xbeef"), Some(-0xbeef));
+    assert_eq!(parse_number_literal("0x1ull"), Some(1));
+    assert_eq!(parse_number_literal("0x100ULL"), Some(0x100));
+    assert_eq!(parse_number_literal("0x100z"), Some(0x100));
+    assert_eq!(parse_number_literal("100'000"), Some(100000));
+    assert_eq!(parse_number_literal("0.0"), None);
+    assert_eq!(parse_number_literal("not-a-literal"), None);
+    assert_eq!(parse_number_literal("-"), None);
+}
+
+// Characters that can appear in a number literal: digits, hex digits, prefixes (0x, 0b),
+// exponents, suffixes, separators and signs.
+const NUMBER_CHARS: &str = "0123456789abcdefABCDEFxXbBpPuUlLzZ.'+-";
+
+/// Returns false if `regex` can't match anything in the text of a number literal,
+/// because every match needs a character that doesn't appear in number literals.
+/// This is conservative: true does not mean that a number literal can match.
+pub fn regex_can_match_number(regex: &str) -> bool {
+    use regex_syntax::hir::{Class, Hir, HirKind};
+
+    fn can_match(hir: &Hir) -> bool {
+        let allowed = |c: char| NUMBER_CHARS.contains(c);
+        match hir.kind() {
+            HirKind::Empty | HirKind::Look(_) => true,
+            HirKind::Literal(l) => String::from_utf8_lossy(&l.0).chars().all(allowed),
+            HirKind::Class(Class::Unicode(c)) => c
+                .ranges()
+                .iter()
+                .any(|r| NUMBER_CHARS.chars().any(|n| r.start() <= n && n <= r.end())),
+            HirKind::Class(Class::Bytes(c)) => c
+                .ranges()
+                .iter()
+                .any(|r| NUMBER_CHARS.bytes().any(|n| r.start() <= n && n <= r.end())),
+            HirKind::Repetition(r) => r.min == 0 || can_match(&r.sub),
+            HirKind::Capture(c) => can_match(&c.sub),
+            HirKind::Concat(hirs) => hirs.iter().all(can_match),
+            HirKind::Alternation(hirs) => hirs.iter().any(can_match),
+        }
+    }
+
+    match regex_syntax::Parser::new().parse(regex) {
+        Ok(hir) => can_match(&hir),
+        Err(_) => true,
+    }
+}
+
+/// Serialize regexes as their source and compile them again when they are
+/// deserialized, see the serde support of `QueryTree`.
+pub(crate) mod serde_regex {
+    use regex::Regex;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    fn compile<E: Error>(regex: &str) -> Result<Regex, E> {
+        Regex::new(regex).map_err(E::custom)
+    }
+
+    pub fn serialize<S: Serializer>(regex: &Regex, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(regex.as_str())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Regex, D::Error> {
+        compile(&String::deserialize(d)?)
+    }
+
+    /// A (negated, regex) constraint of a variable.
+    pub mod constraint {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            c: &Option<(bool, Regex)>,
+            s: S,
+        ) -> Result<S::Ok, S::Error> {
+            c.as_ref()
+                .map(|(negative, r)| (*negative, r.as_str()))
+                .serialize(s)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            d: D,
+        ) -> Result<Option<(bool, Regex)>, D::Error> {
+            match Option::<(bool, String)>::deserialize(d)? {
+                Some((negative, r)) => Ok(Some((negative, compile(&r)?))),
+                None => Ok(None),
+            }
+        }
+    }
+
+    /// The comment regex of a query and the index of its capture.
+    pub mod comment {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(c: &Option<(Regex, u32)>, s: S) -> Result<S::Ok, S::Error> {
+            c.as_ref()
+                .map(|(r, capture)| (r.as_str(), *capture))
+                .serialize(s)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            d: D,
+        ) -> Result<Option<(Regex, u32)>, D::Error> {
+            match Option::<(String, u32)>::deserialize(d)? {
+                Some((r, capture)) => Ok(Some((compile(&r)?, capture))),
+                None => Ok(None),
+            }
+        }
+    }
+}